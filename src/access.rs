@@ -0,0 +1,77 @@
+//! Checks whether current identity is allowed to access resources
+use crate::ratelimit::{self, RateLimiter};
+use k8s_openapi::api::authorization::v1::{
+    ResourceAttributes, SelfSubjectAccessReview, SelfSubjectAccessReviewSpec,
+};
+use kube::api::{Api, ApiResource, PostParams};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+/// (group, plural, namespace) of the reviewed list
+type ReviewKey = (String, String, Option<String>);
+
+/// Issues SelfSubjectAccessReviews and caches their results
+pub struct AccessChecker {
+    client: kube::Client,
+    limiter: Option<Arc<RateLimiter>>,
+    /// denial reason (None if access is allowed)
+    cache: Mutex<BTreeMap<ReviewKey, Option<String>>>,
+}
+
+impl AccessChecker {
+    pub fn new(client: kube::Client, limiter: Option<Arc<RateLimiter>>) -> AccessChecker {
+        AccessChecker {
+            client,
+            limiter,
+            cache: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Returns `None` if resource can be listed in `namespace` (in all
+    /// namespaces if it is not set), otherwise returns reason why it can
+    /// not be.
+    pub async fn check_list(
+        &self,
+        api_resource: &ApiResource,
+        namespace: Option<&str>,
+    ) -> anyhow::Result<Option<String>> {
+        let key = (
+            api_resource.group.clone(),
+            api_resource.plural.clone(),
+            namespace.map(ToString::to_string),
+        );
+        if let Some(cached) = self.cache.lock().unwrap().get(&key) {
+            return Ok(cached.clone());
+        }
+        let review = SelfSubjectAccessReview {
+            spec: SelfSubjectAccessReviewSpec {
+                resource_attributes: Some(ResourceAttributes {
+                    group: Some(api_resource.group.clone()),
+                    namespace: namespace.map(ToString::to_string),
+                    resource: Some(api_resource.plural.clone()),
+                    verb: Some("list".to_string()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let api = Api::<SelfSubjectAccessReview>::all(self.client.clone());
+        ratelimit::throttle(self.limiter.as_deref()).await;
+        let review = api.create(&PostParams::default(), &review).await?;
+        let denial = match review.status {
+            Some(status) if status.allowed => None,
+            Some(status) => Some(
+                status
+                    .reason
+                    .or(status.evaluation_error)
+                    .unwrap_or_else(|| "access denied".to_string()),
+            ),
+            None => Some("API server did not return review status".to_string()),
+        };
+        self.cache.lock().unwrap().insert(key, denial.clone());
+        Ok(denial)
+    }
+}
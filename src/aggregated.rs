@@ -0,0 +1,90 @@
+//! Support for API groups served by aggregated API servers (e.g. `metrics.k8s.io`).
+//! Such servers are often unavailable or slow, so they are handled separately.
+use kube::api::{Api, ApiResource, DynamicObject};
+use std::collections::BTreeMap;
+
+/// API groups backed by an APIService referencing a Service
+#[derive(Default)]
+pub struct AggregatedApis {
+    /// group -> backing service (`namespace/name`)
+    services: BTreeMap<String, String>,
+    /// group -> reason why it is unavailable
+    unavailable: BTreeMap<String, String>,
+}
+
+fn api_service_resource() -> ApiResource {
+    ApiResource {
+        group: "apiregistration.k8s.io".to_string(),
+        version: "v1".to_string(),
+        api_version: "apiregistration.k8s.io/v1".to_string(),
+        kind: "APIService".to_string(),
+        plural: "apiservices".to_string(),
+    }
+}
+
+impl AggregatedApis {
+    /// Lists APIServices and remembers those that are not served by
+    /// kube-apiserver itself
+    pub async fn detect(client: &kube::Client) -> anyhow::Result<AggregatedApis> {
+        let api = Api::<DynamicObject>::all_with(client.clone(), &api_service_resource());
+        let mut aggregated = AggregatedApis::default();
+        for api_service in api.list(&Default::default()).await? {
+            let spec = &api_service.data["spec"];
+            let group = match spec["group"].as_str() {
+                Some(g) => g,
+                None => continue,
+            };
+            let service = &spec["service"];
+            if service.is_null() {
+                continue;
+            }
+            aggregated.services.insert(
+                group.to_string(),
+                format!(
+                    "{}/{}",
+                    service["namespace"].as_str().unwrap_or_default(),
+                    service["name"].as_str().unwrap_or_default()
+                ),
+            );
+        }
+        Ok(aggregated)
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = &str> {
+        self.services.keys().map(String::as_str)
+    }
+
+    pub fn is_aggregated(&self, group: &str) -> bool {
+        self.services.contains_key(group)
+    }
+
+    pub fn mark_unavailable(&mut self, group: &str, reason: String) {
+        self.unavailable.insert(group.to_string(), reason);
+    }
+
+    /// Groups that failed during discovery
+    pub fn unavailable(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.unavailable
+            .iter()
+            .map(|(group, reason)| (group.as_str(), reason.as_str()))
+    }
+
+    /// Writes marker explaining that group could not be dumped
+    pub async fn write_marker(
+        &self,
+        env: &crate::Environment,
+        group: &str,
+        reason: &str,
+    ) -> anyhow::Result<()> {
+        let marker = serde_json::json!({
+            "group": group,
+            "service": self.services.get(group),
+            "reason": reason,
+        });
+        let path = env.layout.aggregated_api_unavailable(group);
+        env.storage
+            .write(&path, serde_json::to_string_pretty(&marker)?)
+            .await?;
+        Ok(())
+    }
+}
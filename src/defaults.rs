@@ -0,0 +1,129 @@
+//! Discovers defaults applied by API server (including mutating webhooks)
+//! by submitting dumped objects back as dry-run creates
+use kube::api::{Api, ApiResource, DynamicObject, PostParams};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Returns true if defaults should be recorded for objects of this resource.
+/// Selectors are either `Kind` or `group/Kind`.
+pub fn is_selected(selectors: &[String], api_resource: &ApiResource) -> bool {
+    selectors.iter().any(|sel| match sel.split_once('/') {
+        Some((group, kind)) => group == api_resource.group && kind == api_resource.kind,
+        None => sel == &api_resource.kind,
+    })
+}
+
+/// Removes all server-populated fields, leaving something resembling
+/// a manifest user could have submitted.
+fn normalize(object: &DynamicObject) -> DynamicObject {
+    let mut object = object.clone();
+    let meta = &mut object.metadata;
+    // dry-run create would conflict with original object, so we
+    // ask server to generate fresh name.
+    meta.generate_name = meta.name.take().map(|name| format!("{}-", name));
+    meta.uid = None;
+    meta.resource_version = None;
+    meta.creation_timestamp = None;
+    meta.deletion_timestamp = None;
+    meta.deletion_grace_period_seconds = None;
+    meta.generation = None;
+    meta.managed_fields.clear();
+    meta.self_link = None;
+    meta.owner_references.clear();
+    if let Value::Object(data) = &mut object.data {
+        data.remove("status");
+    }
+    object
+}
+
+/// Collects fields which are present in `defaulted`, but not in `original`
+/// (or have different value), keyed by JSON pointer
+fn collect_differences(
+    original: Option<&Value>,
+    defaulted: &Value,
+    pointer: &str,
+    out: &mut BTreeMap<String, Value>,
+) {
+    match (original, defaulted) {
+        (Some(Value::Object(original)), Value::Object(defaulted)) => {
+            for (key, value) in defaulted {
+                let key_pointer = format!("{}/{}", pointer, escape_pointer(key));
+                collect_differences(original.get(key), value, &key_pointer, out);
+            }
+        }
+        (Some(Value::Array(original)), Value::Array(defaulted))
+            if original.len() == defaulted.len() =>
+        {
+            for (i, (original, defaulted)) in original.iter().zip(defaulted).enumerate() {
+                collect_differences(
+                    Some(original),
+                    defaulted,
+                    &format!("{}/{}", pointer, i),
+                    out,
+                );
+            }
+        }
+        (Some(original), defaulted) if original == defaulted => {}
+        (_, defaulted) => {
+            out.insert(pointer.to_string(), defaulted.clone());
+        }
+    }
+}
+
+pub fn escape_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// Performs dry-run create and writes defaulted object, as well as
+/// list of fields that server filled in.
+pub async fn record(
+    env: &crate::Environment,
+    api_resource: &ApiResource,
+    object: &DynamicObject,
+    object_layout: &crate::layout::ObjectLayout,
+) -> anyhow::Result<()> {
+    let api = match object.metadata.namespace.as_deref() {
+        Some(ns) => Api::<DynamicObject>::namespaced_with(env.client.clone(), ns, api_resource),
+        None => Api::<DynamicObject>::all_with(env.client.clone(), api_resource),
+    };
+    let manifest = normalize(object);
+    let params = PostParams {
+        dry_run: true,
+        field_manager: Some("kube-dump".to_string()),
+    };
+    crate::ratelimit::throttle(env.limiter.as_deref()).await;
+    let defaulted = api.create(&params, &manifest).await?;
+
+    let manifest = serde_json::to_value(&manifest)?;
+    let mut defaulted = serde_json::to_value(&defaulted)?;
+    // these are expected to differ and only produce noise
+    if let Some(Value::Object(meta)) = defaulted.pointer_mut("/metadata") {
+        for field in &[
+            "name",
+            "uid",
+            "resourceVersion",
+            "creationTimestamp",
+            "generation",
+            "managedFields",
+            "selfLink",
+        ] {
+            meta.remove(*field);
+        }
+    }
+    let mut differences = BTreeMap::new();
+    collect_differences(Some(&manifest), &defaulted, "", &mut differences);
+
+    env.storage
+        .write(
+            &object_layout.defaulted(),
+            serde_json::to_string_pretty(&defaulted)?,
+        )
+        .await?;
+    env.storage
+        .write(
+            &object_layout.defaults(),
+            serde_json::to_string_pretty(&differences)?,
+        )
+        .await?;
+    Ok(())
+}
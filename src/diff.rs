@@ -0,0 +1,364 @@
+//! Comparing dumps with each other
+use crate::{defaults::escape_pointer, index::Entry, layout::ObjectKey};
+use anyhow::Context as _;
+use clap::Clap;
+use kube::api::{Api, ApiResource, DynamicObject, ListParams};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+};
+
+/// Compares two dumps (or dump and current cluster state) object by object
+#[derive(Clap)]
+pub struct DiffOpts {
+    /// Older dump
+    old: PathBuf,
+    /// Newer dump
+    #[clap(required_unless_present = "live")]
+    new: Option<PathBuf>,
+    /// Compare dump with objects currently present in the cluster. Only
+    /// kinds which are present in the dump are listed
+    #[clap(long, conflicts_with = "new")]
+    live: bool,
+    /// Output format: `text` or `json` (list of changes, with RFC 6902 JSON
+    /// patch for each changed object)
+    #[clap(long, default_value = "text")]
+    format: DiffFormat,
+}
+
+pub enum DiffFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for DiffFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(DiffFormat::Text),
+            "json" => Ok(DiffFormat::Json),
+            _ => anyhow::bail!("unknown diff format: {}", s),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// Single JSON patch operation
+#[derive(Serialize)]
+pub struct PatchOp {
+    op: &'static str,
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<Value>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectDiff {
+    change: ChangeKind,
+    group: String,
+    kind: String,
+    namespace: Option<String>,
+    name: String,
+    /// Transforms old object into new one (only for changed objects)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    patch: Vec<PatchOp>,
+}
+
+/// Fields which change without anyone modifying the object
+const IGNORED_POINTERS: &[&str] = &[
+    "/metadata/resourceVersion",
+    "/metadata/managedFields",
+    "/metadata/generation",
+];
+
+fn clean(object: &mut Value) {
+    for pointer in IGNORED_POINTERS {
+        let (parent, key) = pointer
+            .rsplit_once('/')
+            .expect("pointers always contain slash");
+        if let Some(Value::Object(parent)) = object.pointer_mut(parent) {
+            parent.remove(key);
+        }
+    }
+}
+
+/// Appends operations transforming `old` into `new` to `ops`
+pub fn json_patch(old: &Value, new: &Value, pointer: &str, ops: &mut Vec<PatchOp>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            for (key, old_value) in old {
+                let key_pointer = format!("{}/{}", pointer, escape_pointer(key));
+                match new.get(key) {
+                    Some(new_value) => json_patch(old_value, new_value, &key_pointer, ops),
+                    None => ops.push(PatchOp {
+                        op: "remove",
+                        path: key_pointer,
+                        value: None,
+                    }),
+                }
+            }
+            for (key, new_value) in new {
+                if !old.contains_key(key) {
+                    ops.push(PatchOp {
+                        op: "add",
+                        path: format!("{}/{}", pointer, escape_pointer(key)),
+                        value: Some(new_value.clone()),
+                    });
+                }
+            }
+        }
+        (Value::Array(old), Value::Array(new)) if old.len() == new.len() => {
+            for (i, (old, new)) in old.iter().zip(new).enumerate() {
+                json_patch(old, new, &format!("{}/{}", pointer, i), ops);
+            }
+        }
+        (old, new) if old == new => {}
+        (_, new) => ops.push(PatchOp {
+            op: "replace",
+            path: pointer.to_string(),
+            value: Some(new.clone()),
+        }),
+    }
+}
+
+/// Applies RFC 6902 patch, as produced by `json_patch`, to `object`
+pub fn apply_patch(object: &mut Value, patch: &Value) -> anyhow::Result<()> {
+    let ops = patch.as_array().context("patch must be array")?;
+    for op in ops {
+        let path = op["path"].as_str().context("operation has no path")?;
+        if path.is_empty() {
+            *object = op["value"].clone();
+            continue;
+        }
+        let (parent, key) = path.rsplit_once('/').context("invalid path")?;
+        let key = key.replace("~1", "/").replace("~0", "~");
+        let parent = object
+            .pointer_mut(parent)
+            .with_context(|| format!("{} does not exist", parent))?;
+        match (op["op"].as_str(), parent) {
+            (Some("add"), Value::Object(fields)) | (Some("replace"), Value::Object(fields)) => {
+                fields.insert(key, op["value"].clone());
+            }
+            (Some("remove"), Value::Object(fields)) => {
+                fields.remove(&key);
+            }
+            (Some("replace"), Value::Array(items)) => {
+                let item = key
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|i| items.get_mut(i))
+                    .with_context(|| format!("{} does not exist", path))?;
+                *item = op["value"].clone();
+            }
+            (other, _) => anyhow::bail!("unsupported operation {:?} at {}", other, path),
+        }
+    }
+    Ok(())
+}
+
+/// Keys loaded dump by object
+fn by_key(objects: Vec<(Entry, Value)>) -> BTreeMap<ObjectKey, Value> {
+    objects
+        .into_iter()
+        .map(|(entry, object)| {
+            let key = ObjectKey {
+                group: entry.group,
+                kind: entry.kind,
+                namespace: entry.namespace,
+                name: entry.name,
+            };
+            (key, object)
+        })
+        .collect()
+}
+
+/// Kinds which change all the time, so drift in them is meaningless
+const VOLATILE_KINDS: &[(&str, &str)] = &[("", "Event"), ("events.k8s.io", "Event")];
+
+/// Lists current objects of given kinds, as (group, version, kind)
+async fn list_live(
+    kinds: &BTreeSet<(String, String, String)>,
+) -> anyhow::Result<BTreeMap<ObjectKey, Value>> {
+    let client = kube::Client::try_default()
+        .await
+        .context("connection failed")?;
+    let resources = crate::restore::discover(&client)
+        .await
+        .context("discovery error")?;
+    let mut objects = BTreeMap::new();
+    for (group, version, kind) in kinds {
+        let (resource, _) = match resources.get(&(group.clone(), kind.clone())) {
+            Some(r) => r,
+            None => {
+                tracing::warn!("{} {} is no longer served by cluster", group, kind);
+                continue;
+            }
+        };
+        // compare objects in the same version they were dumped in
+        let api_version = if group.is_empty() {
+            version.clone()
+        } else {
+            format!("{}/{}", group, version)
+        };
+        let resource = ApiResource {
+            version: version.clone(),
+            api_version: api_version.clone(),
+            ..resource.clone()
+        };
+        let api = Api::<DynamicObject>::all_with(client.clone(), &resource);
+        let list = match api.list(&ListParams::default()).await {
+            Ok(list) => list,
+            Err(err) => {
+                tracing::warn!("Failed to list {}: {:#}", resource.plural, err);
+                continue;
+            }
+        };
+        for item in list.items {
+            let key = ObjectKey {
+                group: group.clone(),
+                kind: kind.clone(),
+                namespace: item.metadata.namespace.clone(),
+                name: item.metadata.name.clone().unwrap_or_default(),
+            };
+            let mut object = serde_json::to_value(&item)?;
+            if let Value::Object(fields) = &mut object {
+                fields.insert("apiVersion".to_string(), Value::String(api_version.clone()));
+                fields.insert("kind".to_string(), Value::String(kind.clone()));
+            }
+            objects.insert(key, object);
+        }
+    }
+    Ok(objects)
+}
+
+/// Removes from live object what was not recorded in the dumped one, e.g.
+/// because dump was made with `--export gitops` or `--generic-strip`
+fn match_dumped(dumped: &Value, live: &mut Value) {
+    if dumped.pointer("/metadata/uid").is_none() {
+        crate::generic::export_gitops(live);
+    }
+    if dumped.get("status").is_none() {
+        if let Value::Object(fields) = live {
+            fields.remove("status");
+        }
+    }
+}
+
+fn compare(
+    mut old: BTreeMap<ObjectKey, Value>,
+    new: BTreeMap<ObjectKey, Value>,
+) -> Vec<ObjectDiff> {
+    let mut diffs = Vec::new();
+    let mut push = |key: ObjectKey, change, patch| {
+        diffs.push(ObjectDiff {
+            change,
+            group: key.group,
+            kind: key.kind,
+            namespace: key.namespace,
+            name: key.name,
+            patch,
+        })
+    };
+    for (key, mut new_object) in new {
+        match old.remove(&key) {
+            Some(mut old_object) => {
+                clean(&mut old_object);
+                clean(&mut new_object);
+                let mut patch = Vec::new();
+                json_patch(&old_object, &new_object, "", &mut patch);
+                if !patch.is_empty() {
+                    push(key, ChangeKind::Changed, patch);
+                }
+            }
+            None => push(key, ChangeKind::Added, Vec::new()),
+        }
+    }
+    for (key, _) in old {
+        push(key, ChangeKind::Removed, Vec::new());
+    }
+    diffs.sort_by(|a, b| {
+        let key = |d: &ObjectDiff| (d.group.clone(), d.kind.clone(), d.namespace.clone());
+        (key(a), &a.name).cmp(&(key(b), &b.name))
+    });
+    diffs
+}
+
+fn print(diffs: &[ObjectDiff], format: &DiffFormat) -> anyhow::Result<()> {
+    match format {
+        DiffFormat::Json => println!("{}", serde_json::to_string_pretty(diffs)?),
+        DiffFormat::Text => {
+            for diff in diffs {
+                let sign = match diff.change {
+                    ChangeKind::Added => '+',
+                    ChangeKind::Removed => '-',
+                    ChangeKind::Changed => '~',
+                };
+                let kind = if diff.group.is_empty() {
+                    diff.kind.clone()
+                } else {
+                    format!("{}.{}", diff.kind, diff.group)
+                };
+                match &diff.namespace {
+                    Some(ns) => println!("{} {} {}/{}", sign, kind, ns, diff.name),
+                    None => println!("{} {} {}", sign, kind, diff.name),
+                }
+                for op in &diff.patch {
+                    match &op.value {
+                        Some(value) => println!("    {} {}: {}", op.op, op.path, value),
+                        None => println!("    {} {}", op.op, op.path),
+                    }
+                }
+            }
+            let count = |change| diffs.iter().filter(|d| d.change == change).count();
+            println!(
+                "{} added, {} removed, {} changed",
+                count(ChangeKind::Added),
+                count(ChangeKind::Removed),
+                count(ChangeKind::Changed)
+            );
+        }
+    }
+    Ok(())
+}
+
+pub async fn diff(opts: DiffOpts) -> anyhow::Result<()> {
+    let old = crate::load::load(&opts.old)?;
+    if !opts.live {
+        let new_dir = opts.new.as_ref().expect("required unless --live is given");
+        let new = crate::load::load(new_dir)?;
+        return print(&compare(by_key(old), by_key(new)), &opts.format);
+    }
+    let old: Vec<_> = old
+        .into_iter()
+        .filter(|(entry, _)| !VOLATILE_KINDS.contains(&(entry.group.as_str(), entry.kind.as_str())))
+        .collect();
+    let kinds = old
+        .iter()
+        .map(|(entry, _)| {
+            (
+                entry.group.clone(),
+                entry.version.clone(),
+                entry.kind.clone(),
+            )
+        })
+        .collect();
+    let old = by_key(old);
+    let mut live = list_live(&kinds).await?;
+    for (key, object) in live.iter_mut() {
+        if let Some(dumped) = old.get(key) {
+            match_dumped(dumped, object);
+        }
+    }
+    print(&compare(old, live), &opts.format)
+}
@@ -0,0 +1,159 @@
+//! Encrypting dump output, because it inevitably contains Secrets
+use anyhow::Context as _;
+use std::{path::Path, process::Stdio};
+use tokio::{io::AsyncWriteExt as _, process::Command};
+
+/// Encryption recipient, given to `--encrypt` as `age:<recipient>` or
+/// `gpg:<recipient>`
+#[derive(Clone)]
+pub enum Encryption {
+    /// age public key (requires `age`)
+    Age(String),
+    /// OpenPGP key ID, fingerprint or email from the local keyring (requires
+    /// `gpg`)
+    Gpg(String),
+}
+
+impl std::str::FromStr for Encryption {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, recipient) = s
+            .split_once(':')
+            .context("encryption must be given as <scheme>:<recipient>")?;
+        if recipient.is_empty() {
+            anyhow::bail!("recipient is missing in {}", s);
+        }
+        match scheme {
+            "age" => Ok(Encryption::Age(recipient.to_string())),
+            "gpg" => Ok(Encryption::Gpg(recipient.to_string())),
+            _ => anyhow::bail!("unknown encryption scheme: {}", scheme),
+        }
+    }
+}
+
+/// Which files are encrypted, selected with `--encrypt-scope`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// Whole output
+    All,
+    /// Only files of Secret objects, so that the rest of the dump stays
+    /// readable and diffable
+    Secrets,
+}
+
+impl std::str::FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Scope::All),
+            "secrets" => Ok(Scope::Secrets),
+            _ => anyhow::bail!("unknown encryption scope: {}", s),
+        }
+    }
+}
+
+/// Runs `cmd`, passing `data` to its stdin, and returns its stdout
+pub async fn filter(mut cmd: Command, program: &str, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("failed to run {}", program))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let data = data.to_vec();
+    // stdin is written concurrently, otherwise child could block on
+    // full stdout pipe
+    let feed = tokio::spawn(async move {
+        stdin.write_all(&data).await?;
+        drop(stdin);
+        Ok::<_, std::io::Error>(())
+    });
+    let out = child.wait_with_output().await?;
+    feed.await??;
+    if !out.status.success() {
+        anyhow::bail!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+    Ok(out.stdout)
+}
+
+impl Encryption {
+    /// Extension appended to names of encrypted files
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encryption::Age(_) => "age",
+            Encryption::Gpg(_) => "gpg",
+        }
+    }
+
+    /// Command reading plaintext from stdin and writing ciphertext to stdout
+    fn command(&self) -> Command {
+        match self {
+            Encryption::Age(recipient) => {
+                let mut cmd = Command::new("age");
+                cmd.args(["--encrypt", "--recipient", recipient]);
+                cmd
+            }
+            Encryption::Gpg(recipient) => {
+                let mut cmd = Command::new("gpg");
+                // keys are usually imported only for encryption, without
+                // being signed, so trust checks would always fail
+                cmd.args([
+                    "--batch",
+                    "--quiet",
+                    "--trust-model",
+                    "always",
+                    "--encrypt",
+                    "--recipient",
+                    recipient,
+                ]);
+                cmd
+            }
+        }
+    }
+
+    fn program(&self) -> &'static str {
+        match self {
+            Encryption::Age(_) => "age",
+            Encryption::Gpg(_) => "gpg",
+        }
+    }
+
+    /// Encrypts `data` in memory
+    pub async fn encrypt(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        filter(self.command(), self.program(), data).await
+    }
+
+    /// Encrypts file `from` into `to`
+    pub async fn encrypt_file(&self, from: &Path, to: &Path) -> anyhow::Result<()> {
+        let input = std::fs::File::open(from)
+            .with_context(|| format!("failed to open {}", from.display()))?;
+        let output = std::fs::File::create(to)
+            .with_context(|| format!("failed to create {}", to.display()))?;
+        let out = self
+            .command()
+            .stdin(input)
+            .stdout(output)
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .output()
+            .await
+            .with_context(|| format!("failed to run {}", self.program()))?;
+        if !out.status.success() {
+            anyhow::bail!(
+                "{} failed to encrypt {}: {}",
+                self.program(),
+                from.display(),
+                String::from_utf8_lossy(&out.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+}
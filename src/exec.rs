@@ -0,0 +1,318 @@
+//! Diagnostic commands run in pods (e.g. `nginx -T`) and files copied from
+//! them, configured by `--exec-config`. Nothing is ever run unless it is
+//! listed in the config.
+use crate::{layout::ObjectLayout, selector::PodFilter, Environment};
+use anyhow::Context as _;
+use k8s_openapi::{api::core::v1::Pod, apimachinery::pkg::apis::meta::v1::Status};
+use kube::api::{Api, AttachParams, ResourceExt};
+use serde::Deserialize;
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::io::AsyncReadExt as _;
+
+/// Larger outputs are refused, because they are kept in memory
+const MAX_OUTPUT: u64 = 64 << 20;
+
+/// Rule of the config, which is a YAML list of them:
+///
+/// ```yaml
+/// - name: nginx-config
+///   selector: app=nginx
+///   namespace: web
+///   container: nginx
+///   command: [nginx, -T]
+///   timeout: 10s
+/// - name: nginx-files
+///   selector: app=nginx
+///   copy: [/etc/nginx/conf.d]
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    /// Output is saved as `exec-<name>.txt`, and copied files are saved
+    /// into `files-<name>` directory
+    name: String,
+    /// All pods by default
+    selector: Option<String>,
+    /// All namespaces by default
+    namespace: Option<String>,
+    /// First container of the pod by default
+    container: Option<String>,
+    #[serde(default)]
+    command: Vec<String>,
+    /// Files or directories copied from the container (using `tar`)
+    #[serde(default)]
+    copy: Vec<String>,
+    /// 30 seconds by default
+    timeout: Option<String>,
+}
+
+enum Action {
+    Command(Vec<String>),
+    Copy(Vec<String>),
+}
+
+pub struct ExecRule {
+    name: String,
+    pods: PodFilter,
+    container: Option<String>,
+    action: Action,
+    timeout: Duration,
+}
+
+/// Reads rules from the config file
+pub fn load(path: &Path) -> anyhow::Result<Vec<ExecRule>> {
+    let data = std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let specs: Vec<RuleSpec> = serde_yaml::from_slice(&data).context("invalid exec config")?;
+    specs
+        .into_iter()
+        .map(|spec| {
+            crate::selector::check_rule_name("exec rule", &spec.name)?;
+            let action = match (spec.command.is_empty(), spec.copy.is_empty()) {
+                (false, true) => Action::Command(spec.command),
+                (true, false) => Action::Copy(spec.copy),
+                _ => anyhow::bail!("exec rule {} must have either command or copy", spec.name),
+            };
+            Ok(ExecRule {
+                pods: PodFilter::new(spec.selector.as_deref(), spec.namespace)?,
+                timeout: match &spec.timeout {
+                    Some(timeout) => humantime::parse_duration(timeout)?,
+                    None => Duration::from_secs(30),
+                },
+                name: spec.name,
+                container: spec.container,
+                action,
+            })
+        })
+        .collect()
+}
+
+/// Runs all rules matching the pod, saving command outputs and copied
+/// files. Failed rules are reported, and do not fail the dump.
+pub async fn dump_pod(pod: Pod, env: Arc<Environment>, layout: ObjectLayout) -> anyhow::Result<()> {
+    if !crate::is_running(&pod) {
+        return Ok(());
+    }
+    for rule in env.exec_rules.iter().filter(|rule| rule.pods.matches(&pod)) {
+        let result = match &rule.action {
+            Action::Command(command) => run_command(&env, &pod, rule, command, &layout).await,
+            Action::Copy(paths) => copy_files(&env, &pod, rule, paths, &layout).await,
+        };
+        if let Err(err) = result {
+            env.status.error(
+                format!(
+                    "exec {} in pod {}/{}",
+                    rule.name,
+                    pod.namespace().unwrap_or_default(),
+                    pod.name()
+                ),
+                format!("{:#}", err),
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn run_command(
+    env: &Environment,
+    pod: &Pod,
+    rule: &ExecRule,
+    command: &[String],
+    layout: &ObjectLayout,
+) -> anyhow::Result<()> {
+    let output = exec(env, pod, rule, command.to_vec()).await?;
+    // output of failed command is saved too, it often explains the failure
+    env.storage
+        .write(
+            &layout.exec_output(&format!("{}.txt", rule.name)),
+            output.stdout,
+        )
+        .await?;
+    if !output.stderr.is_empty() {
+        env.storage
+            .write(
+                &layout.exec_output(&format!("{}.stderr.txt", rule.name)),
+                output.stderr,
+            )
+            .await?;
+    }
+    match output.failure {
+        Some(failure) => anyhow::bail!("{}", failure),
+        None => Ok(()),
+    }
+}
+
+/// Copies files by running `tar` in the container, and unpacking regular
+/// files from its output
+async fn copy_files(
+    env: &Environment,
+    pod: &Pod,
+    rule: &ExecRule,
+    paths: &[String],
+    layout: &ObjectLayout,
+) -> anyhow::Result<()> {
+    let command = ["tar", "cf", "-"]
+        .iter()
+        .map(ToString::to_string)
+        .chain(paths.iter().cloned())
+        .collect();
+    let output = exec(env, pod, rule, command).await?;
+    if let Some(failure) = output.failure {
+        anyhow::bail!(
+            "tar failed ({}): {}",
+            failure,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let mut files = Vec::new();
+    let mut archive = tar::Archive::new(&output.stdout[..]);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.into_owned();
+        // tar strips leading `/`, and nothing may escape the directory
+        let path: PathBuf = path
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect();
+        let mut data = Vec::new();
+        std::io::Read::read_to_end(&mut entry, &mut data)?;
+        files.push((path, data));
+    }
+    for (path, data) in files {
+        env.storage
+            .write(&layout.copied_file(&rule.name, &path), data)
+            .await?;
+    }
+    Ok(())
+}
+
+struct Output {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    /// Present if command failed (e.g. exited with non-zero code)
+    failure: Option<String>,
+}
+
+/// Runs command in the container using `exec` subresource. Command is run
+/// under `timeout`, so that it is killed in the container once rule timeout
+/// passes; containers which do not have `timeout` run it directly.
+async fn exec(
+    env: &Environment,
+    pod: &Pod,
+    rule: &ExecRule,
+    command: Vec<String>,
+) -> anyhow::Result<Output> {
+    let limited = ["timeout", "-s", "KILL"]
+        .iter()
+        .map(ToString::to_string)
+        .chain(std::iter::once(rule.timeout.as_secs().max(1).to_string()))
+        .chain(command.iter().cloned())
+        .collect();
+    let output = exec_once(env, pod, rule, limited).await?;
+    let no_timeout = output
+        .failure
+        .as_ref()
+        .is_some_and(|failure| failure.contains("\"timeout\"") && failure.contains("not found"));
+    if no_timeout {
+        tracing::debug!(
+            "timeout is not available in pod {}, running {} directly",
+            pod.name(),
+            rule.name
+        );
+        return exec_once(env, pod, rule, command).await;
+    }
+    Ok(output)
+}
+
+/// Time command is given to exit by itself after rule timeout, before the
+/// session is closed
+const KILL_GRACE: Duration = Duration::from_secs(5);
+
+async fn exec_once(
+    env: &Environment,
+    pod: &Pod,
+    rule: &ExecRule,
+    command: Vec<String>,
+) -> anyhow::Result<Output> {
+    let container = match &rule.container {
+        Some(container) => container.clone(),
+        None => pod
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.containers.first())
+            .map(|container| container.name.clone())
+            .context("pod has no containers")?,
+    };
+    let api = Api::<Pod>::namespaced(env.client.clone(), &pod.namespace().unwrap_or_default());
+    // stdin is attached so that session can be closed by dropping it
+    let params = AttachParams::default()
+        .container(container)
+        .stdin(true)
+        .stdout(true)
+        .stderr(true);
+    let mut process = api.exec(&pod.name(), command, &params).await?;
+    let stdin = process.stdin().context("stdin is not attached")?;
+    let mut stdout_reader = process
+        .stdout()
+        .context("stdout is not attached")?
+        .take(MAX_OUTPUT + 1);
+    let mut stderr_reader = process
+        .stderr()
+        .context("stderr is not attached")?
+        .take(MAX_OUTPUT + 1);
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    let finished = async {
+        futures::future::try_join(
+            stdout_reader.read_to_end(&mut stdout),
+            stderr_reader.read_to_end(&mut stderr),
+        )
+        .await?;
+        // resolves to status sent by the API server once command exits
+        Ok::<_, anyhow::Error>(process.await)
+    };
+    let status = tokio::time::timeout(rule.timeout + KILL_GRACE, finished).await;
+    drop(stdin);
+    let status =
+        status.with_context(|| format!("command did not finish in {:?}", rule.timeout))??;
+    if stdout.len() as u64 > MAX_OUTPUT || stderr.len() as u64 > MAX_OUTPUT {
+        anyhow::bail!("output is larger than {} bytes", MAX_OUTPUT);
+    }
+    Ok(Output {
+        stdout,
+        stderr,
+        failure: failure(status),
+    })
+}
+
+/// Describes failure of the command using its exit status
+fn failure(status: Option<Status>) -> Option<String> {
+    let status = match status {
+        Some(status) => status,
+        None => return Some("exit status is unknown".to_string()),
+    };
+    if status.status.as_deref() == Some("Success") {
+        return None;
+    }
+    let exit_code = status
+        .details
+        .as_ref()
+        .and_then(|details| {
+            details
+                .causes
+                .iter()
+                .find(|cause| cause.reason.as_deref() == Some("ExitCode"))
+        })
+        .and_then(|cause| cause.message.clone());
+    Some(match (exit_code, status.message) {
+        (Some(code), _) => format!("command exited with code {}", code),
+        (None, Some(message)) => message,
+        (None, None) => "command failed".to_string(),
+    })
+}
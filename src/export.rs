@@ -0,0 +1,92 @@
+//! Converting dumps into manifests consumable by other tools
+use crate::index::Entry;
+use anyhow::Context as _;
+use clap::Clap;
+use serde_json::Value;
+use std::path::Path;
+
+mod fixture;
+mod helm;
+mod kustomize;
+
+/// Converts dump into manifests for other deployment tools
+#[derive(Clap)]
+pub struct ExportOpts {
+    #[clap(subcommand)]
+    target: ExportTarget,
+}
+
+#[derive(Clap)]
+enum ExportTarget {
+    /// Helm chart per release, with images and replica counts in values
+    Helm(helm::HelmOpts),
+    /// Kustomization per namespace, applicable with `kubectl apply -k`
+    Kustomize(kustomize::KustomizeOpts),
+    /// Single YAML file for reproducing workloads in kind or minikube
+    Fixture(fixture::FixtureOpts),
+}
+
+/// Loads objects of the dump which are managed by users (rather than by
+/// cluster or controllers), stripped of server-populated fields
+fn load_manifests(dir: &Path) -> anyhow::Result<Vec<(Entry, Value)>> {
+    let mut manifests = Vec::new();
+    let mut skipped = 0;
+    for (entry, mut object) in crate::load::load(dir)? {
+        let owned = object
+            .pointer("/metadata/ownerReferences")
+            .and_then(Value::as_array)
+            .is_some_and(|refs| !refs.is_empty());
+        let maintained =
+            crate::restore::SKIPPED_KINDS.contains(&(entry.group.as_str(), entry.kind.as_str()));
+        if owned || maintained {
+            skipped += 1;
+            continue;
+        }
+        crate::generic::export_gitops(&mut object);
+        manifests.push((entry, object));
+    }
+    tracing::info!(
+        "Exporting {} objects ({} maintained by cluster or controllers skipped)",
+        manifests.len(),
+        skipped
+    );
+    Ok(manifests)
+}
+
+/// Makes string usable as a single file name component
+fn file_name_component(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Fails if `out` exists and is not empty
+fn check_empty(out: &Path) -> anyhow::Result<()> {
+    if out.exists() && std::fs::read_dir(out)?.next().is_some() {
+        anyhow::bail!("{} is not empty", out.display());
+    }
+    Ok(())
+}
+
+fn write_file(path: &Path, data: &str) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, data).with_context(|| format!("failed to write {}", path.display()))
+}
+
+pub async fn export(opts: ExportOpts) -> anyhow::Result<()> {
+    tokio::task::spawn_blocking(move || match opts.target {
+        ExportTarget::Helm(helm_opts) => helm::export(&helm_opts),
+        ExportTarget::Kustomize(kustomize_opts) => kustomize::export(&kustomize_opts),
+        ExportTarget::Fixture(fixture_opts) => fixture::export(&fixture_opts),
+    })
+    .await
+    .unwrap()
+}
@@ -0,0 +1,152 @@
+//! Fixtures for local clusters (kind, minikube)
+use super::{load_manifests, write_file};
+use clap::Clap;
+use serde_json::Value;
+use std::path::PathBuf;
+
+/// Produces single YAML file that can be applied to a fresh local cluster
+/// (kind, minikube) to reproduce workloads of the dumped one
+#[derive(Clap)]
+pub struct FixtureOpts {
+    /// Dump to export
+    dir: PathBuf,
+    /// File fixture is written to
+    #[clap(long)]
+    out: PathBuf,
+    /// Storage class all volume claims are switched to (`standard` is the
+    /// default one in both kind and minikube)
+    #[clap(long, default_value = "standard")]
+    storage_class: String,
+    /// Only include these namespaces (and cluster-scoped objects)
+    #[clap(long)]
+    namespace: Vec<String>,
+}
+
+/// Namespaces populated by cluster itself
+const SYSTEM_NAMESPACES: &[&str] = &["kube-system", "kube-public", "kube-node-lease"];
+
+/// Cluster-scoped kinds describing infrastructure of the original cluster,
+/// as (group, kind)
+const INFRA_KINDS: &[(&str, &str)] = &[
+    ("", "PersistentVolume"),
+    ("storage.k8s.io", "StorageClass"),
+    ("storage.k8s.io", "CSIDriver"),
+    ("storage.k8s.io", "CSINode"),
+    ("storage.k8s.io", "VolumeAttachment"),
+    ("apiregistration.k8s.io", "APIService"),
+    // webhooks would reject everything until their backends are running
+    (
+        "admissionregistration.k8s.io",
+        "ValidatingWebhookConfiguration",
+    ),
+    (
+        "admissionregistration.k8s.io",
+        "MutatingWebhookConfiguration",
+    ),
+    ("flowcontrol.apiserver.k8s.io", "FlowSchema"),
+    ("flowcontrol.apiserver.k8s.io", "PriorityLevelConfiguration"),
+];
+
+/// Annotations binding object to particular node or volume
+const NODE_ANNOTATIONS: &[&str] = &[
+    "volume.kubernetes.io/selected-node",
+    "volume.beta.kubernetes.io/storage-provisioner",
+    "volume.kubernetes.io/storage-provisioner",
+];
+
+fn is_infra(group: &str, kind: &str, object: &Value) -> bool {
+    if INFRA_KINDS.contains(&(group, kind)) {
+        return true;
+    }
+    let name = object["metadata"]["name"].as_str().unwrap_or_default();
+    match (group, kind) {
+        ("", "Namespace") => SYSTEM_NAMESPACES.contains(&name) || name == "default",
+        // default roles are created by API server
+        ("rbac.authorization.k8s.io", "ClusterRole")
+        | ("rbac.authorization.k8s.io", "ClusterRoleBinding") => name.starts_with("system:"),
+        ("scheduling.k8s.io", "PriorityClass") => name.starts_with("system-"),
+        _ => false,
+    }
+}
+
+fn remove_field(object: &mut Value, pointer: &str) {
+    let (parent, key) = pointer
+        .rsplit_once('/')
+        .expect("pointers always contain slash");
+    if let Some(Value::Object(parent)) = object.pointer_mut(parent) {
+        parent.remove(key);
+    }
+}
+
+/// Strips node-specific fields and switches volumes to local storage class
+fn rewrite(kind: &str, object: &mut Value, storage_class: &str) {
+    if let Some(Value::Object(annotations)) = object.pointer_mut("/metadata/annotations") {
+        for annotation in NODE_ANNOTATIONS {
+            annotations.remove(*annotation);
+        }
+    }
+    match kind {
+        "Pod" => remove_field(object, "/spec/nodeName"),
+        "PersistentVolumeClaim" => {
+            // claims are bound to volumes of the original cluster
+            remove_field(object, "/spec/volumeName");
+            object["spec"]["storageClassName"] = storage_class.into();
+        }
+        "StatefulSet" => {
+            if let Some(Value::Array(templates)) = object.pointer_mut("/spec/volumeClaimTemplates")
+            {
+                for template in templates {
+                    template["spec"]["storageClassName"] = storage_class.into();
+                }
+            }
+        }
+        "Service" => {
+            // node ports and load balancer addresses are allocated by cluster
+            remove_field(object, "/spec/loadBalancerIP");
+            remove_field(object, "/spec/externalIPs");
+            if let Some(Value::Array(ports)) = object.pointer_mut("/spec/ports") {
+                for port in ports {
+                    remove_field(port, "/nodePort");
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+pub fn export(opts: &FixtureOpts) -> anyhow::Result<()> {
+    let mut objects = Vec::new();
+    let mut dropped = 0;
+    for (entry, mut object) in load_manifests(&opts.dir)? {
+        let excluded_namespace = match &entry.namespace {
+            Some(ns) => {
+                SYSTEM_NAMESPACES.contains(&ns.as_str())
+                    || (!opts.namespace.is_empty() && !opts.namespace.contains(ns))
+            }
+            None => false,
+        };
+        if excluded_namespace || is_infra(&entry.group, &entry.kind, &object) {
+            dropped += 1;
+            continue;
+        }
+        rewrite(&entry.kind, &mut object, &opts.storage_class);
+        objects.push((crate::restore::phase(&entry.group, &entry.kind), object));
+    }
+    // sort is stable, so objects keep index order within phase
+    objects.sort_by_key(|(phase, _)| *phase);
+    let mut fixture = String::new();
+    for (_, object) in &objects {
+        // serde_yaml starts each document with `---`
+        fixture.push_str(&serde_yaml::to_string(object)?);
+        if !fixture.ends_with('\n') {
+            fixture.push('\n');
+        }
+    }
+    write_file(&opts.out, &fixture)?;
+    tracing::info!(
+        "Fixture contains {} objects ({} system or filtered out objects dropped)",
+        objects.len(),
+        dropped
+    );
+    Ok(())
+}
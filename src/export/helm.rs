@@ -0,0 +1,227 @@
+//! Helm chart skeletons
+use super::{check_empty, file_name_component, load_manifests, write_file};
+use clap::Clap;
+use serde_json::{Map, Value};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+/// Generates Helm chart for each release found in the dump
+#[derive(Clap)]
+pub struct HelmOpts {
+    /// Dump to export
+    dir: PathBuf,
+    /// Directory charts are written to, as `<namespace>/<release>`
+    #[clap(long)]
+    out: PathBuf,
+}
+
+/// Annotations set by Helm on objects it manages
+const RELEASE_NAME_ANNOTATION: &str = "meta.helm.sh/release-name";
+const RELEASE_NAMESPACE_ANNOTATION: &str = "meta.helm.sh/release-namespace";
+/// Labels conventionally containing release name, used when object was
+/// not deployed by Helm 3
+const RELEASE_LABELS: &[&str] = &["app.kubernetes.io/instance", "release"];
+
+/// Returns (namespace, name) of the release object belongs to
+fn release_of(object: &Value) -> Option<(String, String)> {
+    let meta = &object["metadata"];
+    let name = meta["annotations"][RELEASE_NAME_ANNOTATION]
+        .as_str()
+        .or_else(|| {
+            RELEASE_LABELS
+                .iter()
+                .find_map(|label| meta["labels"][*label].as_str())
+        })?;
+    let namespace = meta["annotations"][RELEASE_NAMESPACE_ANNOTATION]
+        .as_str()
+        .or_else(|| meta["namespace"].as_str())
+        .unwrap_or("default");
+    Some((namespace.to_string(), name.to_string()))
+}
+
+/// Location of pod spec in workload objects
+fn pod_spec_pointer(group: &str, kind: &str) -> Option<&'static str> {
+    match (group, kind) {
+        ("apps", "Deployment")
+        | ("apps", "StatefulSet")
+        | ("apps", "DaemonSet")
+        | ("apps", "ReplicaSet")
+        | ("batch", "Job") => Some("/spec/template/spec"),
+        ("batch", "CronJob") => Some("/spec/jobTemplate/spec/template/spec"),
+        _ => None,
+    }
+}
+
+/// Escapes text so that Helm renders it verbatim
+fn escape_template(text: &str) -> String {
+    text.replace("{{", "\u{0}")
+        .replace("}}", "{{ \"}}\" }}")
+        .replace('\u{0}', "{{ \"{{\" }}")
+}
+
+/// Template pipeline referring to value at `path`
+fn value_ref(path: &[&str]) -> String {
+    let path: Vec<_> = path
+        .iter()
+        .map(|component| Value::String(component.to_string()).to_string())
+        .collect();
+    format!("index .Values {}", path.join(" "))
+}
+
+#[derive(Default)]
+struct Chart {
+    /// File name in `templates/` -> template
+    templates: BTreeMap<String, String>,
+    values: Map<String, Value>,
+    app_version: Option<String>,
+}
+
+impl Chart {
+    /// Moves replica count and images of workload into values, replacing
+    /// them with placeholders. Returns placeholders with expressions they
+    /// must be replaced with.
+    fn extract_values(&mut self, group: &str, object: &mut Value) -> Vec<(String, String)> {
+        let kind = object["kind"].as_str().unwrap_or_default().to_string();
+        let pod_spec = match pod_spec_pointer(group, &kind) {
+            Some(p) => p,
+            None => return Vec::new(),
+        };
+        let name = object["metadata"]["name"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        // e.g. `statefulSet`
+        let kind_key = kind[..1].to_lowercase() + &kind[1..];
+        let mut workload = Map::new();
+        let mut substitutions = Vec::new();
+        let mut substitute = |value: &mut Value, expression: String| {
+            let placeholder = format!("KUBE_DUMP_VALUE_{}", substitutions.len());
+            substitutions.push((placeholder.clone(), expression));
+            std::mem::replace(value, Value::String(placeholder))
+        };
+        if let Some(replicas) = object.pointer_mut("/spec/replicas") {
+            let expression = format!("{{{{ {} }}}}", value_ref(&[&kind_key, &name, "replicas"]));
+            workload.insert("replicas".to_string(), substitute(replicas, expression));
+        }
+        let mut images = Map::new();
+        for list in &["initContainers", "containers"] {
+            let containers = match object.pointer_mut(&format!("{}/{}", pod_spec, list)) {
+                Some(Value::Array(containers)) => containers,
+                _ => continue,
+            };
+            for container in containers {
+                let container_name = container["name"].as_str().unwrap_or_default().to_string();
+                if let Some(image) = container.get_mut("image") {
+                    let expression = format!(
+                        "{{{{ {} | quote }}}}",
+                        value_ref(&[&kind_key, &name, "images", &container_name])
+                    );
+                    images.insert(container_name, substitute(image, expression));
+                }
+            }
+        }
+        if !images.is_empty() {
+            workload.insert("images".to_string(), Value::Object(images));
+        }
+        if let Value::Object(workloads) = self
+            .values
+            .entry(kind_key)
+            .or_insert_with(|| Value::Object(Map::new()))
+        {
+            workloads.insert(name, Value::Object(workload));
+        }
+        substitutions
+    }
+
+    fn add(&mut self, group: &str, mut object: Value) -> anyhow::Result<()> {
+        if let Some(Value::Object(meta)) = object.get_mut("metadata") {
+            // objects are installed into release namespace
+            meta.remove("namespace");
+            if let Some(Value::Object(annotations)) = meta.get_mut("annotations") {
+                // `Map::retain` needs newer serde_json
+                *annotations = std::mem::take(annotations)
+                    .into_iter()
+                    .filter(|(name, _)| !name.starts_with("meta.helm.sh/"))
+                    .collect();
+                if annotations.is_empty() {
+                    meta.remove("annotations");
+                }
+            }
+            if self.app_version.is_none() {
+                self.app_version = meta
+                    .get("labels")
+                    .and_then(|labels| labels["app.kubernetes.io/version"].as_str())
+                    .map(ToString::to_string);
+            }
+        }
+        let substitutions = self.extract_values(group, &mut object);
+        let file_name = format!(
+            "{}-{}.yaml",
+            object["kind"].as_str().unwrap_or_default().to_lowercase(),
+            file_name_component(object["metadata"]["name"].as_str().unwrap_or_default())
+        );
+        let mut template = escape_template(&serde_yaml::to_string(&object)?);
+        // later placeholders first, so that `_1` does not match `_10`
+        for (placeholder, expression) in substitutions.iter().rev() {
+            template = template.replace(placeholder, expression);
+        }
+        self.templates.insert(file_name, template);
+        Ok(())
+    }
+
+    fn write(&self, dir: &Path, name: &str, namespace: &str) -> anyhow::Result<()> {
+        let mut chart = serde_json::json!({
+            "apiVersion": "v2",
+            "name": name,
+            "description": format!("Release {} in namespace {}, exported by kube-dump", name, namespace),
+            "type": "application",
+            "version": "0.1.0",
+        });
+        if let Some(app_version) = &self.app_version {
+            chart["appVersion"] = Value::String(app_version.clone());
+        }
+        write_file(&dir.join("Chart.yaml"), &serde_yaml::to_string(&chart)?)?;
+        let values = if self.values.is_empty() {
+            String::new()
+        } else {
+            serde_yaml::to_string(&self.values)?
+        };
+        write_file(&dir.join("values.yaml"), &values)?;
+        for (file_name, template) in &self.templates {
+            write_file(&dir.join("templates").join(file_name), template)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn export(opts: &HelmOpts) -> anyhow::Result<()> {
+    check_empty(&opts.out)?;
+    let mut charts: BTreeMap<(String, String), Chart> = BTreeMap::new();
+    let mut unreleased = 0;
+    for (entry, object) in load_manifests(&opts.dir)? {
+        match release_of(&object) {
+            Some(release) => charts
+                .entry(release)
+                .or_default()
+                .add(&entry.group, object)?,
+            None => unreleased += 1,
+        }
+    }
+    for ((namespace, name), chart) in &charts {
+        let dir = opts
+            .out
+            .join(file_name_component(namespace))
+            .join(file_name_component(name));
+        chart.write(&dir, name, namespace)?;
+    }
+    tracing::info!("Generated {} charts", charts.len());
+    if unreleased > 0 {
+        tracing::info!(
+            "{} objects do not belong to any release and were not exported",
+            unreleased
+        );
+    }
+    Ok(())
+}
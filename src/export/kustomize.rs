@@ -0,0 +1,78 @@
+//! Kustomize bases
+use super::{check_empty, file_name_component, load_manifests, write_file};
+use clap::Clap;
+use std::{collections::BTreeMap, path::PathBuf};
+
+/// Generates kustomization per namespace, and root kustomization referring
+/// to all of them, so that output can be applied with `kubectl apply -k`
+#[derive(Clap)]
+pub struct KustomizeOpts {
+    /// Dump to export
+    dir: PathBuf,
+    /// Directory kustomizations are written to
+    #[clap(long)]
+    out: PathBuf,
+}
+
+/// Directory for cluster-scoped objects (underscore can not appear in
+/// namespace names)
+const CLUSTER_DIR: &str = "_cluster";
+
+fn kustomization(namespace: Option<&str>, resources: Vec<String>) -> anyhow::Result<String> {
+    let mut kustomization = serde_json::json!({
+        "apiVersion": "kustomize.config.k8s.io/v1beta1",
+        "kind": "Kustomization",
+    });
+    if let Some(namespace) = namespace {
+        kustomization["namespace"] = namespace.into();
+    }
+    kustomization["resources"] = resources.into();
+    Ok(serde_yaml::to_string(&kustomization)?)
+}
+
+pub fn export(opts: &KustomizeOpts) -> anyhow::Result<()> {
+    check_empty(&opts.out)?;
+    // namespace -> file name -> manifest
+    let mut namespaces: BTreeMap<Option<String>, BTreeMap<String, String>> = BTreeMap::new();
+    for (entry, object) in load_manifests(&opts.dir)? {
+        let files = namespaces.entry(entry.namespace.clone()).or_default();
+        let name = file_name_component(&entry.name);
+        let mut file_name = format!("{}-{}.yaml", entry.kind.to_lowercase(), name);
+        if files.contains_key(&file_name) {
+            // same kind from different API groups
+            file_name = format!(
+                "{}.{}-{}.yaml",
+                entry.kind.to_lowercase(),
+                entry.group,
+                name
+            );
+        }
+        files.insert(file_name, serde_yaml::to_string(&object)?);
+    }
+    let mut bases = Vec::new();
+    for (namespace, files) in &namespaces {
+        let dir_name = match namespace {
+            Some(ns) => file_name_component(ns),
+            None => CLUSTER_DIR.to_string(),
+        };
+        let dir = opts.out.join(&dir_name);
+        for (file_name, manifest) in files {
+            write_file(&dir.join(file_name), manifest)?;
+        }
+        let resources = files.keys().cloned().collect();
+        write_file(
+            &dir.join("kustomization.yaml"),
+            &kustomization(namespace.as_deref(), resources)?,
+        )?;
+        bases.push(dir_name);
+    }
+    write_file(
+        &opts.out.join("kustomization.yaml"),
+        &kustomization(None, bases)?,
+    )?;
+    tracing::info!(
+        "Generated kustomizations for {} namespaces",
+        namespaces.len()
+    );
+    Ok(())
+}
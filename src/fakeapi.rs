@@ -0,0 +1,296 @@
+//! Read-only fake Kubernetes API serving objects reconstructed by `replay`,
+//! so that they can be explored with `kubectl --server http://<addr>`.
+//! Only discovery, `get` and `list` (with `labelSelector`) are supported.
+use crate::selector::LabelSelector;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, StatusCode,
+};
+use kube::api::ApiResource;
+use serde_json::json;
+use std::{collections::BTreeMap, convert::Infallible, net::SocketAddr, sync::Arc};
+
+/// Objects of single resource
+struct Resource {
+    api_resource: ApiResource,
+    /// Resource is considered namespaced if any of its objects has namespace
+    namespaced: bool,
+    /// (namespace, name) -> object
+    objects: BTreeMap<(Option<String>, String), serde_json::Value>,
+}
+
+/// Objects served by the fake API
+#[derive(Default)]
+pub struct State {
+    /// (group, version, plural) -> resource
+    resources: BTreeMap<(String, String, String), Resource>,
+}
+
+impl State {
+    pub fn insert(
+        &mut self,
+        api_resource: &ApiResource,
+        namespace: Option<String>,
+        name: String,
+        object: serde_json::Value,
+    ) {
+        let resource = self
+            .resources
+            .entry((
+                api_resource.group.clone(),
+                api_resource.version.clone(),
+                api_resource.plural.clone(),
+            ))
+            .or_insert_with(|| Resource {
+                api_resource: api_resource.clone(),
+                namespaced: false,
+                objects: BTreeMap::new(),
+            });
+        resource.namespaced |= namespace.is_some();
+        resource.objects.insert((namespace, name), object);
+    }
+
+    /// group -> versions
+    fn groups(&self) -> BTreeMap<&str, Vec<&str>> {
+        let mut groups: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for (group, version, _) in self.resources.keys() {
+            let versions = groups.entry(group).or_default();
+            if !versions.contains(&version.as_str()) {
+                versions.push(version);
+            }
+        }
+        groups
+    }
+}
+
+pub async fn serve(state: State, addr: SocketAddr) -> anyhow::Result<()> {
+    let state = Arc::new(state);
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(handle(&state, &request)) }
+            }))
+        }
+    });
+    let server = hyper::Server::try_bind(&addr)?.serve(make_service);
+    tracing::info!(
+        "Serving reconstructed state on {} (use `kubectl --server http://{}`)",
+        addr,
+        addr
+    );
+    server.await?;
+    Ok(())
+}
+
+fn json_response(status: StatusCode, body: &serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("response is valid")
+}
+
+/// Error in the form of Kubernetes `Status` object
+fn failure(status: StatusCode, reason: &str, message: String) -> Response<Body> {
+    json_response(
+        status,
+        &json!({
+            "kind": "Status",
+            "apiVersion": "v1",
+            "metadata": {},
+            "status": "Failure",
+            "message": message,
+            "reason": reason,
+            "code": status.as_u16(),
+        }),
+    )
+}
+
+fn handle(state: &State, request: &Request<Body>) -> Response<Body> {
+    if request.method() != Method::GET {
+        return failure(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "MethodNotAllowed",
+            "replayed state is read-only".to_string(),
+        );
+    }
+    let path: Vec<_> = request
+        .uri()
+        .path()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    let label_selector = request.uri().query().and_then(|query| {
+        form_urlencoded::parse(query.as_bytes()).find_map(|(key, value)| {
+            if key != "labelSelector" {
+                return None;
+            }
+            Some(
+                value
+                    .parse::<LabelSelector>()
+                    .map_err(|err| format!("{:#}", err)),
+            )
+        })
+    });
+    let label_selector = match label_selector.transpose() {
+        Ok(label_selector) => label_selector,
+        Err(message) => return failure(StatusCode::BAD_REQUEST, "BadRequest", message),
+    };
+    let groups = state.groups();
+    match &path[..] {
+        ["version"] => json_response(
+            StatusCode::OK,
+            &json!({
+                "major": "1",
+                "minor": "19",
+                "gitVersion": "v1.19.0+kube-dump-replay",
+                "platform": "replay",
+            }),
+        ),
+        ["api"] => json_response(
+            StatusCode::OK,
+            &json!({
+                "kind": "APIVersions",
+                "versions": groups.get("").cloned().unwrap_or_default(),
+                "serverAddressByClientCIDRs": [],
+            }),
+        ),
+        ["apis"] => {
+            let groups: Vec<_> = groups
+                .iter()
+                .filter(|(group, _)| !group.is_empty())
+                .map(|(group, versions)| {
+                    let versions: Vec<_> = versions
+                        .iter()
+                        .map(|version| {
+                            json!({
+                                "groupVersion": format!("{}/{}", group, version),
+                                "version": version,
+                            })
+                        })
+                        .collect();
+                    json!({
+                        "name": group,
+                        "preferredVersion": versions[0],
+                        "versions": versions,
+                    })
+                })
+                .collect();
+            json_response(
+                StatusCode::OK,
+                &json!({"kind": "APIGroupList", "apiVersion": "v1", "groups": groups}),
+            )
+        }
+        ["api", version, rest @ ..] => {
+            handle_group_version(state, "", version, rest, label_selector)
+        }
+        ["apis", group, version, rest @ ..] => {
+            handle_group_version(state, group, version, rest, label_selector)
+        }
+        _ => not_found(request.uri().path()),
+    }
+}
+
+fn not_found(what: &str) -> Response<Body> {
+    failure(
+        StatusCode::NOT_FOUND,
+        "NotFound",
+        format!("{} not found", what),
+    )
+}
+
+fn handle_group_version(
+    state: &State,
+    group: &str,
+    version: &str,
+    rest: &[&str],
+    label_selector: Option<LabelSelector>,
+) -> Response<Body> {
+    let group_version = if group.is_empty() {
+        version.to_string()
+    } else {
+        format!("{}/{}", group, version)
+    };
+    let resources = state
+        .resources
+        .range((group.to_string(), version.to_string(), String::new())..)
+        .take_while(|((g, v, _), _)| g == group && v == version)
+        .map(|(_, resource)| resource);
+    let (namespace, plural, name) = match rest {
+        [] => {
+            let resources: Vec<_> = resources
+                .map(|resource| {
+                    json!({
+                        "name": resource.api_resource.plural,
+                        "singularName": "",
+                        "namespaced": resource.namespaced,
+                        "kind": resource.api_resource.kind,
+                        "verbs": ["get", "list"],
+                    })
+                })
+                .collect();
+            if resources.is_empty() {
+                return not_found(&group_version);
+            }
+            return json_response(
+                StatusCode::OK,
+                &json!({
+                    "kind": "APIResourceList",
+                    "apiVersion": "v1",
+                    "groupVersion": group_version,
+                    "resources": resources,
+                }),
+            );
+        }
+        [plural] => (None, *plural, None),
+        [plural, name] => (None, *plural, Some(*name)),
+        ["namespaces", namespace, plural] => (Some(*namespace), *plural, None),
+        ["namespaces", namespace, plural, name] => (Some(*namespace), *plural, Some(*name)),
+        _ => return not_found(&rest.join("/")),
+    };
+    let resource =
+        match state
+            .resources
+            .get(&(group.to_string(), version.to_string(), plural.to_string()))
+        {
+            Some(resource) => resource,
+            None => return not_found(&format!("{}/{}", group_version, plural)),
+        };
+    if let Some(name) = name {
+        let key = (namespace.map(ToString::to_string), name.to_string());
+        return match resource.objects.get(&key) {
+            Some(object) => json_response(StatusCode::OK, object),
+            None => not_found(&format!("{} {:?}", resource.api_resource.kind, name)),
+        };
+    }
+    let items: Vec<_> = resource
+        .objects
+        .iter()
+        .filter(|((object_namespace, _), _)| {
+            namespace.is_none() || object_namespace.as_deref() == namespace
+        })
+        .filter(|(_, object)| match &label_selector {
+            Some(selector) => {
+                let labels = object
+                    .pointer("/metadata/labels")
+                    .cloned()
+                    .and_then(|labels| serde_json::from_value(labels).ok())
+                    .unwrap_or_default();
+                selector.matches(&labels)
+            }
+            None => true,
+        })
+        .map(|(_, object)| object)
+        .collect();
+    json_response(
+        StatusCode::OK,
+        &json!({
+            "kind": format!("{}List", resource.api_resource.kind),
+            "apiVersion": group_version,
+            "metadata": {"resourceVersion": ""},
+            "items": items,
+        }),
+    )
+}
@@ -1,5 +1,17 @@
 //! Generic dumping behavior
-use kube::api::{Api, ApiResource, DynamicObject};
+use crate::{
+    incremental::Change,
+    listing::{ListSplitter, StatusError},
+    memory,
+};
+use futures::stream::{FuturesUnordered, StreamExt as _};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ListMeta;
+use kube::{
+    api::{Api, ApiResource, DynamicObject, ListParams, Request, Resource},
+    discovery::ApiCapabilities,
+};
+use std::{collections::BTreeMap, sync::Arc};
+use tracing::Instrument as _;
 
 pub enum Strip {
     ManagedFields,
@@ -16,12 +28,83 @@ impl std::str::FromStr for Strip {
     }
 }
 
+/// What to do with objects exceeding `--max-object-size`
+pub enum Oversized {
+    /// Do not write object at all
+    Skip,
+    /// Write only `apiVersion`, `kind` and `metadata`, and a marker file
+    Truncate,
+}
+
+impl std::str::FromStr for Oversized {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Oversized::Skip),
+            "truncate" => Ok(Oversized::Truncate),
+            _ => anyhow::bail!("unknown oversized object policy: {}", s),
+        }
+    }
+}
+
+/// Transformation making dumped objects suitable for some other use
+pub enum Export {
+    /// Strip server-populated fields, so that objects can be applied
+    GitOps,
+}
+
+impl std::str::FromStr for Export {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gitops" => Ok(Export::GitOps),
+            _ => anyhow::bail!("unknown export mode: {}", s),
+        }
+    }
+}
+
+/// How dumped object representations are stored
+pub enum OutputFormat {
+    /// `raw.json` in the object directory
+    Dir,
+    /// Multi-document YAML stream per kind or per namespace
+    YamlStream(StreamGrouping),
+    /// `objects.jsonl` per API resource, one object per line
+    Jsonl,
+}
+
+pub enum StreamGrouping {
+    Kind,
+    Namespace,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dir" => Ok(OutputFormat::Dir),
+            "yaml-per-kind" => Ok(OutputFormat::YamlStream(StreamGrouping::Kind)),
+            "yaml-per-namespace" => Ok(OutputFormat::YamlStream(StreamGrouping::Namespace)),
+            "jsonl" => Ok(OutputFormat::Jsonl),
+            _ => anyhow::bail!("unknown output format: {}", s),
+        }
+    }
+}
+
 pub async fn dump(env: &crate::Environment) -> anyhow::Result<()> {
     // dump cluster-wide information
     {
-        let version = env.client.apiserver_version().await?;
+        let version = crate::retry::retry(env, "get API server version", || async {
+            Ok(env.client.apiserver_version().await?)
+        })
+        .await?;
         let version = serde_json::to_string_pretty(&version)?;
-        tokio::fs::write(env.layout.cluster_version(), version).await?;
+        env.storage
+            .write(&env.layout.cluster_version(), version)
+            .await?;
     }
     {
         let apis = env
@@ -49,20 +132,298 @@ pub async fn dump(env: &crate::Environment) -> anyhow::Result<()> {
             )
             .collect::<Vec<_>>();
         let apis = serde_json::to_string_pretty(&apis)?;
-        tokio::fs::write(env.layout.cluster_api_resources(), apis).await?;
+        env.storage
+            .write(&env.layout.cluster_api_resources(), apis)
+            .await?;
+    }
+    for (group, reason) in env.aggregated.unavailable() {
+        env.aggregated.write_marker(env, group, reason).await?;
+    }
+    let listable: Vec<_> = env
+        .apis
+        .iter()
+        .filter(|(_, caps)| caps.supports_operation(kube::discovery::verbs::LIST))
+        .filter(|(_, caps)| {
+            env.opts.namespace.is_none() || matches!(caps.scope, kube::discovery::Scope::Namespaced)
+        })
+        .collect();
+    let mut outcomes = Vec::new();
+    let mut pending = Vec::new();
+    for (i, resource) in listable.iter().enumerate() {
+        match env.progress.as_ref().and_then(|p| p.completed(&resource.0)) {
+            Some(resource_version) => {
+                outcomes.push((i, ResourceOutcome::Listed(resource_version)));
+            }
+            None => pending.push((i, resource)),
+        }
+    }
+    env.status.set_resources(listable.len());
+    if pending.len() < listable.len() {
+        env.status.println(&format!(
+            "{} resources were dumped by previous run",
+            listable.len() - pending.len()
+        ));
+        env.status
+            .resources_finished(listable.len() - pending.len());
+    }
+    // in snapshot mode, all lists are performed at this resourceVersion, so
+    // resources are listed one by one until it is known
+    let mut snapshot_version = env.progress.as_ref().and_then(|p| p.snapshot_version());
+    let mut remaining = pending.into_iter();
+    while env.opts.snapshot && snapshot_version.is_none() && !env.check_deadline() {
+        let (i, (api_resource, caps)) = match remaining.next() {
+            Some(r) => r,
+            None => break,
+        };
+        let outcome = process_resource(env, api_resource, caps, None, None).await?;
+        if let ResourceOutcome::Listed(resource_version) = &outcome {
+            snapshot_version = resource_version.clone();
+            if let (Some(progress), Some(version)) = (&env.progress, &snapshot_version) {
+                progress.set_snapshot_version(version)?;
+            }
+        }
+        outcomes.push((i, outcome));
+    }
+    *env.snapshot_version.lock().unwrap() = snapshot_version.clone();
+    let pinned = snapshot_version.as_deref();
+    let turns = Turns::new();
+    let mut in_flight = FuturesUnordered::new();
+    for (position, (i, (api_resource, caps))) in remaining.enumerate() {
+        if env.check_deadline() {
+            break;
+        }
+        if in_flight.len() >= env.opts.concurrency {
+            if let Some(result) = in_flight.next().await {
+                outcomes.push(result?);
+            }
+        }
+        let turn = Turn {
+            turns: &turns,
+            position,
+        };
+        in_flight.push(async move {
+            let outcome = process_resource(env, api_resource, caps, pinned, Some(turn)).await;
+            turn.wait().await;
+            turn.finish();
+            Ok::<_, anyhow::Error>((i, outcome?))
+        });
+    }
+    while let Some(result) = in_flight.next().await {
+        outcomes.push(result?);
+    }
+    // resources finish in arbitrary order
+    outcomes.sort_by_key(|(i, _)| *i);
+
+    let mut skipped = Vec::new();
+    // resource -> resourceVersion returned by its list
+    let mut resource_versions = BTreeMap::new();
+    for (i, outcome) in outcomes {
+        let (api_resource, _) = listable[i];
+        match outcome {
+            ResourceOutcome::Skipped(reason) => skipped.push(serde_json::json!({
+                "group": api_resource.group,
+                "version": api_resource.version,
+                "kind": api_resource.kind,
+                "plural": api_resource.plural,
+                "reason": reason,
+            })),
+            ResourceOutcome::Listed(resource_version) => {
+                resource_versions.insert(
+                    format!("{}/{}", api_resource.api_version, api_resource.plural),
+                    resource_version,
+                );
+            }
+            ResourceOutcome::Failed => (),
+        }
+    }
+    *env.list_versions.lock().unwrap() = resource_versions.clone();
+    let resource_versions = serde_json::json!({
+        "snapshotResourceVersion": snapshot_version,
+        "resources": resource_versions,
+    });
+    env.storage
+        .write(
+            &env.layout.resource_versions(),
+            serde_json::to_string_pretty(&resource_versions)?,
+        )
+        .await?;
+    if env.access.is_some() {
+        let skipped = serde_json::to_string_pretty(&skipped)?;
+        env.storage
+            .write(&env.layout.skipped_resources(), skipped)
+            .await?;
     }
-    for (api_resource, caps) in &env.apis {
-        if !caps.supports_operation(kube::discovery::verbs::LIST) {
-            continue;
+    Ok(())
+}
+
+/// Order in which concurrently dumped resources write their objects, so that
+/// output does not depend on which list finishes first
+struct Turns {
+    current: tokio::sync::watch::Sender<usize>,
+    receiver: tokio::sync::watch::Receiver<usize>,
+}
+
+impl Turns {
+    fn new() -> Self {
+        let (current, receiver) = tokio::sync::watch::channel(0);
+        Turns { current, receiver }
+    }
+}
+
+/// Position of the resource in `Turns`
+#[derive(Clone, Copy)]
+struct Turn<'a> {
+    turns: &'a Turns,
+    position: usize,
+}
+
+impl Turn<'_> {
+    fn is_current(&self) -> bool {
+        *self.turns.receiver.borrow() == self.position
+    }
+
+    async fn wait(&self) {
+        let mut receiver = self.turns.receiver.clone();
+        while *receiver.borrow() != self.position {
+            receiver.changed().await.expect("sender is owned by Turns");
         }
-        if let Err(err) = dump_api_group(env, api_resource).await {
-            eprintln!(
-                "Failed to dump {}.{}: {:#}",
+    }
+
+    /// Passes turn to the next resource. Must be called after `wait`.
+    fn finish(&self) {
+        self.turns.current.send(self.position + 1).ok();
+    }
+}
+
+/// Span of everything related to dumping given resource
+fn resource_span(api_resource: &ApiResource) -> tracing::Span {
+    tracing::info_span!(
+        "resource",
+        kind = %api_resource.kind,
+        group = %api_resource.group
+    )
+}
+
+/// Dumps resource within its span, recording progress and time it took
+async fn process_resource(
+    env: &crate::Environment,
+    api_resource: &ApiResource,
+    caps: &ApiCapabilities,
+    pinned: Option<&str>,
+    turn: Option<Turn<'_>>,
+) -> anyhow::Result<ResourceOutcome> {
+    let started = std::time::Instant::now();
+    let outcome = dump_resource(env, api_resource, caps, pinned, turn)
+        .instrument(resource_span(api_resource))
+        .await;
+    env.status.resource_timing(api_resource, started.elapsed());
+    env.status.resources_finished(1);
+    outcome
+}
+
+/// Result of dumping single API resource
+enum ResourceOutcome {
+    /// Listing is not allowed, for given reason
+    Skipped(String),
+    /// Resource was listed at given resourceVersion
+    Listed(Option<String>),
+    /// Failure was already reported
+    Failed,
+}
+
+async fn dump_resource(
+    env: &crate::Environment,
+    api_resource: &ApiResource,
+    caps: &ApiCapabilities,
+    pinned: Option<&str>,
+    turn: Option<Turn<'_>>,
+) -> anyhow::Result<ResourceOutcome> {
+    if let Some(access) = &env.access {
+        let namespace = match caps.scope {
+            kube::discovery::Scope::Namespaced => env.opts.namespace.as_deref(),
+            kube::discovery::Scope::Cluster => None,
+        };
+        match access.check_list(api_resource, namespace).await {
+            Ok(None) => (),
+            Ok(Some(reason)) => {
+                env.status.println(&format!(
+                    " - {}.{}: skipped ({})",
+                    api_resource.kind, api_resource.api_version, reason
+                ));
+                return Ok(ResourceOutcome::Skipped(reason));
+            }
+            Err(err) => env.status.eprintln(&format!(
+                "Failed to check access to {}.{}, will try anyway: {:#}",
                 api_resource.api_version, api_resource.kind, err
+            )),
+        }
+    }
+    let is_aggregated = env.aggregated.is_aggregated(&api_resource.group);
+    let result = if is_aggregated {
+        let dump = dump_api_group(env, api_resource, caps, pinned, turn);
+        match tokio::time::timeout(env.opts.aggregated_timeout, dump).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow::anyhow!("listing timed out")),
+        }
+    } else {
+        dump_api_group(env, api_resource, caps, pinned, turn).await
+    };
+    let result = match result {
+        Ok((resource_version, buffered)) => {
+            if let Some(turn) = turn {
+                turn.wait().await;
+            }
+            dump_objects(env, api_resource, caps, buffered)
+                .await
+                .map(|_| resource_version)
+        }
+        Err(err) => Err(err),
+    };
+    match result {
+        Ok(resource_version) => {
+            if let Some(progress) = &env.progress {
+                env.storage.flush().await?;
+                progress.complete(api_resource, resource_version.clone(), &env.index)?;
+            }
+            Ok(ResourceOutcome::Listed(resource_version))
+        }
+        Err(err) if err.is::<crate::retry::DeadlineReached>() => {
+            env.status.println(&format!(
+                " - {}.{}: deadline reached",
+                api_resource.kind, api_resource.api_version
+            ));
+            Ok(ResourceOutcome::Failed)
+        }
+        Err(err) if is_aggregated => {
+            let reason = format!("{}: {:#}", api_resource.plural, err);
+            env.status.error(
+                format!("{}.{}", api_resource.api_version, api_resource.kind),
+                format!("aggregated API unavailable: {}", reason),
+            );
+            env.aggregated
+                .write_marker(env, &api_resource.group, &reason)
+                .await?;
+            Ok(ResourceOutcome::Failed)
+        }
+        Err(err) => {
+            env.status.error(
+                format!("{}.{}", api_resource.api_version, api_resource.kind),
+                format!("failed to dump: {:#}", err),
             );
+            Ok(ResourceOutcome::Failed)
         }
     }
-    Ok(())
+}
+
+/// Identifies object in messages
+fn object_description(api_resource: &ApiResource, object: &DynamicObject) -> String {
+    format!(
+        "{}.{} {:?}/{}",
+        api_resource.api_version,
+        api_resource.kind,
+        object.metadata.namespace,
+        object.metadata.name.as_deref().unwrap_or_default()
+    )
 }
 
 /// Modifies `object` in-place, applying all requested strips
@@ -78,28 +439,555 @@ fn apply_strips(object: &mut serde_json::Value, strips: &[Strip]) {
     }
 }
 
-async fn dump_api_group(
+/// Metadata fields populated by API server
+const SERVER_METADATA_FIELDS: &[&str] = &[
+    "uid",
+    "resourceVersion",
+    "creationTimestamp",
+    "deletionTimestamp",
+    "deletionGracePeriodSeconds",
+    "generation",
+    "selfLink",
+    "managedFields",
+    "ownerReferences",
+];
+
+/// Annotations added by kubectl or controllers rather than by user
+const GENERATED_ANNOTATIONS: &[&str] = &[
+    "kubectl.kubernetes.io/last-applied-configuration",
+    "deployment.kubernetes.io/revision",
+    "control-plane.alpha.kubernetes.io/leader",
+    "pv.kubernetes.io/bind-completed",
+    "pv.kubernetes.io/bound-by-controller",
+    "pv.kubernetes.io/provisioned-by",
+    "volume.beta.kubernetes.io/storage-provisioner",
+];
+
+/// Modifies `object` in-place, so that it can be passed to `kubectl apply`
+pub fn export_gitops(object: &mut serde_json::Value) {
+    let is_service = object["apiVersion"] == "v1" && object["kind"] == "Service";
+    let object = match object.as_object_mut() {
+        Some(o) => o,
+        None => return,
+    };
+    object.remove("status");
+    if let Some(serde_json::Value::Object(meta)) = object.get_mut("metadata") {
+        for field in SERVER_METADATA_FIELDS {
+            meta.remove(*field);
+        }
+        if let Some(serde_json::Value::Object(annotations)) = meta.get_mut("annotations") {
+            for annotation in GENERATED_ANNOTATIONS {
+                annotations.remove(*annotation);
+            }
+            if annotations.is_empty() {
+                meta.remove("annotations");
+            }
+        }
+    }
+    if is_service {
+        // allocated by API server, and immutable afterwards
+        if let Some(serde_json::Value::Object(spec)) = object.get_mut("spec") {
+            spec.remove("clusterIP");
+            spec.remove("clusterIPs");
+        }
+    }
+}
+
+/// Fields that change even if object is not modified by anyone
+/// (e.g. because of heartbeats or leader election)
+const NONDETERMINISTIC_POINTERS: &[&str] = &[
+    "/metadata/resourceVersion",
+    "/metadata/annotations/control-plane.alpha.kubernetes.io~1leader",
+    "/spec/renewTime",
+];
+
+/// Names of fields inside `status.conditions` items that are updated on every heartbeat
+const NONDETERMINISTIC_CONDITION_FIELDS: &[&str] = &["lastHeartbeatTime", "lastProbeTime"];
+
+/// Modifies `object` in-place so that its serialization only depends on
+/// meaningful object state: removes nondeterministic fields. Keys need no
+/// sorting, because without `preserve_order` feature serde_json keeps
+/// them in a BTreeMap.
+fn canonicalize(object: &mut serde_json::Value) {
+    for pointer in NONDETERMINISTIC_POINTERS {
+        let (parent, key) = pointer
+            .rsplit_once('/')
+            .expect("pointers always contain slash");
+        if let Some(serde_json::Value::Object(parent)) = object.pointer_mut(parent) {
+            parent.remove(&key.replace("~1", "/").replace("~0", "~"));
+        }
+    }
+    if let Some(serde_json::Value::Array(managed_fields)) =
+        object.pointer_mut("/metadata/managedFields")
+    {
+        for entry in managed_fields.iter_mut().filter_map(|e| e.as_object_mut()) {
+            entry.remove("time");
+        }
+    }
+    if let Some(serde_json::Value::Array(conditions)) = object.pointer_mut("/status/conditions") {
+        for condition in conditions.iter_mut().filter_map(|c| c.as_object_mut()) {
+            for field in NONDETERMINISTIC_CONDITION_FIELDS {
+                condition.remove(*field);
+            }
+        }
+    }
+}
+
+/// Builds request listing `url_path`. If `pinned_version` is set, list is
+/// served exactly at that resourceVersion.
+pub fn list_request(
+    url_path: &str,
+    list_params: &ListParams,
+    pinned_version: Option<&str>,
+) -> anyhow::Result<http::Request<Vec<u8>>> {
+    let mut request = Request::new(url_path).list(list_params)?;
+    if let Some(resource_version) = pinned_version {
+        let pinned = form_urlencoded::Serializer::new(String::new())
+            .append_pair("resourceVersion", resource_version)
+            .append_pair("resourceVersionMatch", "Exact")
+            .finish();
+        let uri = request.uri();
+        let uri = match uri.query() {
+            Some(query) => format!("{}?{}&{}", uri.path(), query, pinned),
+            None => format!("{}?{}", uri.path(), pinned),
+        };
+        *request.uri_mut() = uri.parse()?;
+    }
+    Ok(request)
+}
+
+/// Explains failure of the list pinned to snapshot resourceVersion, if it
+/// failed because the server no longer has that version
+pub fn snapshot_expired(err: anyhow::Error, pinned_version: Option<&str>) -> anyhow::Error {
+    let resource_version = match pinned_version {
+        Some(resource_version) => resource_version,
+        None => return err,
+    };
+    let gone = err.chain().any(|cause| {
+        let api_gone = matches!(
+            cause.downcast_ref::<kube::Error>(),
+            Some(kube::Error::Api(response)) if response.code == 410
+        );
+        let status_gone = matches!(
+            cause.downcast_ref::<StatusError>(),
+            Some(status) if status.code == 410
+        );
+        api_gone || status_gone
+    });
+    if !gone {
+        return err;
+    }
+    err.context(format!(
+        "snapshot resourceVersion {} was compacted by the API server, so consistent dump \
+         can not be completed; start it again (without --resume)",
+        resource_version
+    ))
+}
+
+/// Lists one page of objects of given resource, dumping each object as soon
+/// as it is received, or returning them if `buffer` is set. If
+/// `pinned_version` is set, list is served exactly at that resourceVersion.
+async fn dump_page(
+    env: &crate::Environment,
+    api_resource: &ApiResource,
+    caps: &ApiCapabilities,
+    list_params: &ListParams,
+    pinned_version: Option<&str>,
+    buffer: bool,
+) -> anyhow::Result<(ListMeta, Vec<DynamicObject>)> {
+    let url_path = DynamicObject::url_path(api_resource, env.opts.namespace.as_deref());
+    let request = list_request(&url_path, list_params, pinned_version)?;
+    tracing::debug!(continue_token = ?list_params.continue_token, "listing page");
+    let mut response = Box::pin(env.client.request_text_stream(request).await?);
+    let mut splitter = ListSplitter::default();
+    // streams must be appended to in list order, so that output is
+    // deterministic
+    let sequential = !matches!(env.opts.format, OutputFormat::Dir);
+    let mut dumped_any = false;
+    let mut buffered = Vec::new();
+    let result = async {
+        let mut writes = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                Some(result) = writes.next(), if !writes.is_empty() => result?,
+                chunk = response.next(), if writes.len() < env.opts.write_concurrency
+                    && memory::has_room(env.memory.as_ref()) => {
+                    let chunk = match chunk {
+                        Some(chunk) => chunk?,
+                        None => break,
+                    };
+                    // released when all objects of the chunk are written
+                    let charge = Arc::new(memory::charge(env.memory.as_ref(), chunk.len()));
+                    for object in splitter.feed(&chunk)? {
+                        if buffer {
+                            buffered.push(object);
+                            continue;
+                        }
+                        dumped_any = true;
+                        if sequential {
+                            dump_object(env, api_resource, caps, object).await?;
+                        } else {
+                            claim_location(env, api_resource, &object)?;
+                            let charge = charge.clone();
+                            writes.push(async move {
+                                let _charge = charge;
+                                dump_object(env, api_resource, caps, object).await
+                            });
+                        }
+                    }
+                }
+                // memory is used by other resources
+                else => memory::wait_for_room(env.memory.as_ref()).await,
+            }
+        }
+        while let Some(result) = writes.next().await {
+            result?;
+        }
+        splitter.finish()
+    }
+    .await;
+    // repeating the list would dump same objects again
+    let list = match result {
+        Ok(list) => list,
+        Err(err) if dumped_any => return Err(crate::retry::permanent(err)),
+        Err(err) => return Err(snapshot_expired(err, pinned_version)),
+    };
+    let metadata = match list.get("metadata") {
+        Some(metadata) => serde_json::from_value(metadata.clone())?,
+        None => ListMeta::default(),
+    };
+    Ok((metadata, buffered))
+}
+
+/// Claims location of the object before its write starts, so that
+/// collision suffixes are assigned in list order
+fn claim_location(
     env: &crate::Environment,
     api_resource: &ApiResource,
+    object: &DynamicObject,
 ) -> anyhow::Result<()> {
-    println!(" - {}.{}", api_resource.kind, api_resource.api_version);
+    env.layout.object_layout(
+        api_resource,
+        object.metadata.namespace.as_deref(),
+        object.metadata.name.as_deref().unwrap(),
+    )?;
+    Ok(())
+}
 
-    let api = Api::<DynamicObject>::all_with(env.client.clone(), api_resource);
+/// Dumps objects which were listed while other resource was writing
+async fn dump_objects(
+    env: &crate::Environment,
+    api_resource: &ApiResource,
+    caps: &ApiCapabilities,
+    objects: Vec<DynamicObject>,
+) -> anyhow::Result<()> {
+    if !matches!(env.opts.format, OutputFormat::Dir) {
+        for object in objects {
+            dump_object(env, api_resource, caps, object).await?;
+        }
+        return Ok(());
+    }
+    for object in &objects {
+        claim_location(env, api_resource, object)?;
+    }
+    let mut writes = futures::stream::iter(objects)
+        .map(|object| dump_object(env, api_resource, caps, object))
+        .buffer_unordered(env.opts.write_concurrency);
+    while let Some(result) = writes.next().await {
+        result?;
+    }
+    Ok(())
+}
+
+/// Dumps all objects of the resource, returning resourceVersion of the list.
+/// Until it is resource's `turn` to write, objects are buffered and returned.
+async fn dump_api_group(
+    env: &crate::Environment,
+    api_resource: &ApiResource,
+    caps: &ApiCapabilities,
+    pinned_version: Option<&str>,
+    turn: Option<Turn<'_>>,
+) -> anyhow::Result<(Option<String>, Vec<DynamicObject>)> {
+    env.status.resource_started(format!(
+        "{}.{}",
+        api_resource.kind, api_resource.api_version
+    ));
 
-    let object_list: Vec<DynamicObject> = api.list(&Default::default()).await?.items;
-    for object in object_list {
-        let object_layout = env.layout.object_layout(
+    let mut list_params = ListParams::default();
+    if env.opts.page_size != 0 {
+        list_params.limit = Some(env.opts.page_size);
+    }
+    let mut list_version = None;
+    let mut buffered = Vec::new();
+    loop {
+        let buffer = matches!(turn, Some(turn) if !turn.is_current());
+        if !buffer && !buffered.is_empty() {
+            dump_objects(env, api_resource, caps, std::mem::take(&mut buffered)).await?;
+        }
+        // resourceVersion must only be set for the first page, later pages
+        // are consistent with it because of continue token
+        let pinned_version = if list_version.is_none() {
+            pinned_version
+        } else {
+            None
+        };
+        let what = format!("list {}", api_resource.plural);
+        let (metadata, objects) = crate::retry::retry(env, &what, || {
+            dump_page(
+                env,
+                api_resource,
+                caps,
+                &list_params,
+                pinned_version,
+                buffer,
+            )
+        })
+        .await?;
+        buffered.extend(objects);
+        if list_version.is_none() {
+            list_version = Some(metadata.resource_version.clone());
+        }
+        match metadata.continue_ {
+            Some(token) if !token.is_empty() => list_params.continue_token = Some(token),
+            _ => break,
+        }
+    }
+    Ok((list_version.flatten(), buffered))
+}
+
+/// Serializes object as it is written to the dump, applying `--strip`,
+/// `--export` and `--canonical`
+pub fn representation(
+    env: &crate::Environment,
+    object: &mut DynamicObject,
+) -> anyhow::Result<String> {
+    apply_strips(&mut object.data, &env.opts.strip);
+    if env.opts.canonical || env.opts.export.is_some() {
+        // object itself is kept intact, because index and other dumpers
+        // need server-populated fields
+        let mut value = serde_json::to_value(&*object)?;
+        if let Some(Export::GitOps) = env.opts.export {
+            export_gitops(&mut value);
+        }
+        if env.opts.canonical {
+            canonicalize(&mut value);
+        }
+        Ok(serde_json::to_string_pretty(&value)?)
+    } else {
+        Ok(serde_json::to_string_pretty(object)?)
+    }
+}
+
+async fn dump_object(
+    env: &crate::Environment,
+    api_resource: &ApiResource,
+    caps: &ApiCapabilities,
+    mut object: DynamicObject,
+) -> anyhow::Result<()> {
+    tracing::debug!(
+        namespace = ?object.metadata.namespace,
+        name = ?object.metadata.name,
+        "dumping object"
+    );
+    let object_layout = env.layout.object_layout(
+        api_resource,
+        object.metadata.namespace.as_deref(),
+        object.metadata.name.as_deref().unwrap(),
+    )?;
+    let repr_path = object_layout.representation();
+    let mut repr = representation(env, &mut object)?;
+    if let Some(max_size) = env.opts.max_object_size {
+        if repr.len() as u64 > max_size {
+            let size = repr.len();
+            match env.opts.oversized {
+                Oversized::Skip => {
+                    env.status.eprintln(&format!(
+                        "Skipping {}.{} {:?}/{}: size {} exceeds limit",
+                        api_resource.api_version,
+                        api_resource.kind,
+                        object.metadata.namespace,
+                        object.metadata.name.as_deref().unwrap_or_default(),
+                        size
+                    ));
+                    return Ok(());
+                }
+                Oversized::Truncate => {
+                    repr = serde_json::to_string_pretty(&serde_json::json!({
+                        "apiVersion": api_resource.api_version,
+                        "kind": api_resource.kind,
+                        "metadata": object.metadata,
+                    }))?;
+                    let marker = serde_json::json!({
+                        "size": size,
+                        "limit": max_size,
+                    });
+                    env.storage
+                        .write(
+                            &object_layout.truncation_marker(),
+                            serde_json::to_string_pretty(&marker)?,
+                        )
+                        .await?;
+                }
+            }
+        }
+    }
+    let size = repr.len() as u64;
+    let index_entry = |path: &std::path::Path, size: u64| crate::index::Entry {
+        group: api_resource.group.clone(),
+        version: api_resource.version.clone(),
+        kind: api_resource.kind.clone(),
+        namespace: object.metadata.namespace.clone(),
+        name: object.metadata.name.clone().unwrap_or_default(),
+        path: env.layout.relative(path).display().to_string(),
+        size,
+        resource_version: object.metadata.resource_version.clone(),
+        path_shortened: object_layout.is_shortened(),
+        patch: None,
+    };
+    // None if object is stored in base dump of incremental one
+    let written_path = match &env.opts.format {
+        OutputFormat::Dir => {
+            let change = match &env.base {
+                Some(base) => {
+                    base.compare(object_layout.key(), &api_resource.api_version, &repr)?
+                }
+                None => Change::Added,
+            };
+            match change {
+                Change::Unchanged => {
+                    let base = env.base.as_ref().expect("only base can be unchanged");
+                    env.index.record(base.record_unchanged(object_layout.key()));
+                    None
+                }
+                Change::Patched(patch) => {
+                    let base = env.base.as_ref().expect("only base can be patched");
+                    let patch_path = object_layout.patch();
+                    let entry = index_entry(&patch_path, patch.len() as u64);
+                    env.index
+                        .record(base.record_patched(object_layout.key(), entry));
+                    env.storage.write(&patch_path, patch).await?;
+                    None
+                }
+                Change::Added => {
+                    env.storage.write(&repr_path, &repr).await?;
+                    Some(repr_path)
+                }
+            }
+        }
+        OutputFormat::YamlStream(grouping) => {
+            let stream_path = match grouping {
+                StreamGrouping::Kind => env.layout.kind_stream(api_resource),
+                StreamGrouping::Namespace => env
+                    .layout
+                    .namespace_stream(object.metadata.namespace.as_deref()),
+            };
+            let value: serde_json::Value = serde_json::from_str(&repr)?;
+            let mut document = serde_yaml::to_string(&value)?;
+            if !document.ends_with('\n') {
+                document.push('\n');
+            }
+            env.storage.append(&stream_path, document).await?;
+            Some(stream_path)
+        }
+        OutputFormat::Jsonl => {
+            let value: serde_json::Value = serde_json::from_str(&repr)?;
+            let mut line = serde_json::to_string(&value)?;
+            line.push('\n');
+            let jsonl_path = env.layout.kind_jsonl(api_resource);
+            env.storage.append(&jsonl_path, line).await?;
+            Some(jsonl_path)
+        }
+    };
+    if let Some(written_path) = written_path {
+        env.index.record(index_entry(&written_path, size));
+        env.status.object_written(size);
+    }
+    if let crate::layout::Scheme::OwnerTree = env.layout.scheme() {
+        env.owners.record(api_resource, &object);
+    }
+    let key = crate::layout::ObjectKey {
+        group: api_resource.group.clone(),
+        kind: api_resource.kind.clone(),
+        namespace: object.metadata.namespace.clone(),
+        name: object.metadata.name.clone().unwrap_or_default(),
+    };
+    crate::report::write_object_page(env, &key, &repr).await?;
+    if env
+        .opts
+        .report
+        .contains(&crate::report::ReportFormat::Markdown)
+    {
+        env.report_data.observe(api_resource, &object);
+    }
+    if !env.opts.graph.is_empty() {
+        env.graph.record(api_resource, &object);
+    }
+    if env.opts.by_kind_links && matches!(env.opts.format, OutputFormat::Dir) {
+        let link = env.layout.kind_link(
             api_resource,
             object.metadata.namespace.as_deref(),
-            object.metadata.name.as_deref().unwrap(),
+            object.metadata.name.as_deref().unwrap_or_default(),
         );
-        let repr_path = object_layout.representation();
-        let mut object = object;
-        apply_strips(&mut object.data, &env.opts.strip);
-        let repr = serde_json::to_string_pretty(&object)?;
-        let parent = repr_path.parent().expect("Layout never returns root-path");
-        tokio::fs::create_dir_all(parent).await?;
-        tokio::fs::write(repr_path, repr).await?;
+        env.storage
+            .symlink(&link, &object_layout.location())
+            .await?;
+    }
+    if crate::defaults::is_selected(&env.opts.dry_run_defaults, api_resource) {
+        if let Err(err) = crate::defaults::record(env, api_resource, &object, &object_layout).await
+        {
+            env.status.error(
+                object_description(api_resource, &object),
+                format!("failed to record defaults: {:#}", err),
+            );
+        }
+    }
+    if env.opts.subresources {
+        if let Err(err) = dump_subresources(env, api_resource, caps, &object, &object_layout).await
+        {
+            env.status.error(
+                object_description(api_resource, &object),
+                format!("failed to dump subresources: {:#}", err),
+            );
+        }
+    }
+    Ok(())
+}
+
+fn has_subresource(caps: &ApiCapabilities, name: &str) -> bool {
+    caps.subresources.iter().any(|(sub_resource, sub_caps)| {
+        sub_resource.plural == name && sub_caps.supports_operation(kube::discovery::verbs::GET)
+    })
+}
+
+/// Fetches `status` and `scale` subresources separately, because they can
+/// differ from what main representation contains
+async fn dump_subresources(
+    env: &crate::Environment,
+    api_resource: &ApiResource,
+    caps: &ApiCapabilities,
+    object: &DynamicObject,
+    object_layout: &crate::layout::ObjectLayout,
+) -> anyhow::Result<()> {
+    let name = object.metadata.name.as_deref().unwrap();
+    let api = match object.metadata.namespace.as_deref() {
+        Some(ns) => Api::<DynamicObject>::namespaced_with(env.client.clone(), ns, api_resource),
+        None => Api::<DynamicObject>::all_with(env.client.clone(), api_resource),
+    };
+    if has_subresource(caps, "status") {
+        let status = crate::retry::retry(env, "get status", || async {
+            Ok(api.get_status(name).await?)
+        })
+        .await?;
+        let status = serde_json::to_string_pretty(&status)?;
+        env.storage.write(&object_layout.status(), status).await?;
+    }
+    if has_subresource(caps, "scale") {
+        let scale = crate::retry::retry(env, "get scale", || async {
+            Ok(api.get_scale(name).await?)
+        })
+        .await?;
+        let scale = serde_json::to_string_pretty(&scale)?;
+        env.storage.write(&object_layout.scale(), scale).await?;
     }
     Ok(())
 }
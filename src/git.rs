@@ -0,0 +1,91 @@
+//! Recording dumps as commits of a git repository, so that output
+//! directory keeps history of cluster state
+use anyhow::Context as _;
+use std::{path::Path, time::SystemTime};
+use tokio::process::Command;
+
+/// Files which should never be committed (older versions kept lock file
+/// inside the output)
+const EXCLUDED: &[&str] = &[".kube-dump.lock"];
+
+async fn git(repo: &Path, args: &[&str]) -> anyhow::Result<String> {
+    let out = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(args)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .context("failed to run git")?;
+    if !out.status.success() {
+        anyhow::bail!(
+            "`git {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// Initializes repository in `repo` unless it already exists
+async fn init(repo: &Path) -> anyhow::Result<()> {
+    if !repo.join(".git").exists() {
+        git(repo, &["init", "--quiet"]).await?;
+        tracing::info!("Initialized git repository in {}", repo.display());
+    }
+    let exclude = repo.join(".git/info/exclude");
+    let mut contents = tokio::fs::read_to_string(&exclude)
+        .await
+        .unwrap_or_default();
+    let mut changed = false;
+    for pattern in EXCLUDED {
+        if !contents.lines().any(|line| line == *pattern) {
+            if !contents.is_empty() && !contents.ends_with('\n') {
+                contents.push('\n');
+            }
+            contents.push_str(pattern);
+            contents.push('\n');
+            changed = true;
+        }
+    }
+    if changed {
+        tokio::fs::create_dir_all(exclude.parent().unwrap()).await?;
+        tokio::fs::write(&exclude, contents).await?;
+    }
+    Ok(())
+}
+
+/// Stages everything in `repo` and commits it (if anything changed),
+/// optionally pushing the commit to `origin`
+pub async fn commit(repo: &Path, cluster: &str, push: bool) -> anyhow::Result<()> {
+    init(repo).await?;
+    git(repo, &["add", "--all", "."]).await?;
+    if git(repo, &["status", "--porcelain"])
+        .await?
+        .trim()
+        .is_empty()
+    {
+        tracing::info!("Cluster state did not change since previous commit");
+    } else {
+        let time = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+        let message = format!("Dump of {} at {}", cluster, time);
+        let mut args = Vec::new();
+        // cron jobs often run without configured identity
+        if git(repo, &["config", "user.email"]).await.is_err() {
+            args.extend(&[
+                "-c",
+                "user.name=kube-dump",
+                "-c",
+                "user.email=kube-dump@localhost",
+            ]);
+        }
+        args.extend(&["commit", "--quiet", "--message", message.as_str()]);
+        git(repo, &args).await?;
+        tracing::info!("Committed dump: {}", message);
+    }
+    if push {
+        git(repo, &["push", "--quiet", "origin", "HEAD"]).await?;
+        tracing::info!("Pushed dump repository");
+    }
+    Ok(())
+}
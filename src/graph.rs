@@ -0,0 +1,224 @@
+//! Graph of relationships between dumped objects, for visualization
+use crate::layout::ObjectKey;
+use kube::api::{ApiResource, DynamicObject};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Write as _,
+    sync::Mutex,
+};
+
+/// Graph file format, selected with `--graph`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// Graphviz `graph.dot`
+    Dot,
+    /// Mermaid `graph.mmd`
+    Mermaid,
+}
+
+impl std::str::FromStr for GraphFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dot" => Ok(GraphFormat::Dot),
+            "mermaid" => Ok(GraphFormat::Mermaid),
+            _ => anyhow::bail!("unknown graph format: {}", s),
+        }
+    }
+}
+
+impl GraphFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            GraphFormat::Dot => "dot",
+            GraphFormat::Mermaid => "mmd",
+        }
+    }
+}
+
+type Edge = (ObjectKey, ObjectKey, &'static str);
+
+#[derive(Default)]
+struct State {
+    /// uid -> object
+    uids: HashMap<String, ObjectKey>,
+    /// (owned object, owner uid)
+    owner_refs: Vec<(ObjectKey, String)>,
+    /// (service, selector)
+    services: Vec<(ObjectKey, BTreeMap<String, String>)>,
+    /// (pod, labels)
+    pods: Vec<(ObjectKey, BTreeMap<String, String>)>,
+    /// Edges known without resolution
+    edges: Vec<Edge>,
+}
+
+/// Collects relationships while objects are dumped
+#[derive(Default)]
+pub struct RelationGraph {
+    state: Mutex<State>,
+}
+
+fn core_key(kind: &str, namespace: &Option<String>, name: &str) -> ObjectKey {
+    ObjectKey {
+        group: String::new(),
+        kind: kind.to_string(),
+        namespace: namespace.clone(),
+        name: name.to_string(),
+    }
+}
+
+fn string_map(value: &serde_json::Value) -> BTreeMap<String, String> {
+    value
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+        .collect()
+}
+
+impl RelationGraph {
+    pub fn record(&self, resource: &ApiResource, object: &DynamicObject) {
+        let key = ObjectKey {
+            group: resource.group.clone(),
+            kind: resource.kind.clone(),
+            namespace: object.metadata.namespace.clone(),
+            name: object.metadata.name.clone().unwrap_or_default(),
+        };
+        let namespace = &key.namespace;
+        let spec = &object.data["spec"];
+        let mut state = self.state.lock().unwrap();
+        if let Some(uid) = &object.metadata.uid {
+            state.uids.insert(uid.clone(), key.clone());
+        }
+        for owner in &object.metadata.owner_references {
+            state.owner_refs.push((key.clone(), owner.uid.clone()));
+        }
+        match (resource.group.as_str(), resource.kind.as_str()) {
+            ("", "Service") => {
+                let selector = string_map(&spec["selector"]);
+                if !selector.is_empty() {
+                    state.services.push((key.clone(), selector));
+                }
+            }
+            ("", "Pod") => {
+                state
+                    .pods
+                    .push((key.clone(), object.metadata.labels.clone()));
+                for volume in spec["volumes"].as_array().into_iter().flatten() {
+                    let target = if let Some(name) = volume["configMap"]["name"].as_str() {
+                        core_key("ConfigMap", namespace, name)
+                    } else if let Some(name) = volume["secret"]["secretName"].as_str() {
+                        core_key("Secret", namespace, name)
+                    } else if let Some(name) = volume["persistentVolumeClaim"]["claimName"].as_str()
+                    {
+                        core_key("PersistentVolumeClaim", namespace, name)
+                    } else {
+                        continue;
+                    };
+                    state.edges.push((key.clone(), target, "mounts"));
+                }
+            }
+            ("networking.k8s.io", "Ingress") | ("extensions", "Ingress") => {
+                let mut backends = vec![&spec["defaultBackend"], &spec["backend"]];
+                for rule in spec["rules"].as_array().into_iter().flatten() {
+                    for path in rule["http"]["paths"].as_array().into_iter().flatten() {
+                        backends.push(&path["backend"]);
+                    }
+                }
+                for backend in backends {
+                    // `networking.k8s.io/v1` and `v1beta1` backends respectively
+                    let service = backend["service"]["name"]
+                        .as_str()
+                        .or_else(|| backend["serviceName"].as_str());
+                    if let Some(service) = service {
+                        let target = core_key("Service", namespace, service);
+                        state.edges.push((key.clone(), target, "routes"));
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Resolves collected relationships into deduplicated edges
+    fn edges(&self) -> BTreeSet<Edge> {
+        let state = self.state.lock().unwrap();
+        let mut edges: BTreeSet<Edge> = state.edges.iter().cloned().collect();
+        for (owned, owner_uid) in &state.owner_refs {
+            if let Some(owner) = state.uids.get(owner_uid) {
+                edges.insert((owner.clone(), owned.clone(), "owns"));
+            }
+        }
+        for (service, selector) in &state.services {
+            for (pod, labels) in &state.pods {
+                let selected = pod.namespace == service.namespace
+                    && selector.iter().all(|(k, v)| labels.get(k) == Some(v));
+                if selected {
+                    edges.insert((service.clone(), pod.clone(), "selects"));
+                }
+            }
+        }
+        edges
+    }
+}
+
+fn node_label(key: &ObjectKey) -> String {
+    match &key.namespace {
+        Some(namespace) => format!("{} {}/{}", key.kind, namespace, key.name),
+        None => format!("{} {}", key.kind, key.name),
+    }
+}
+
+fn render(format: GraphFormat, edges: &BTreeSet<Edge>) -> anyhow::Result<String> {
+    let mut ids = BTreeMap::new();
+    for (from, to, _) in edges {
+        for key in &[from, to] {
+            let next_id = ids.len();
+            ids.entry(*key).or_insert(next_id);
+        }
+    }
+    let mut out = String::new();
+    match format {
+        GraphFormat::Dot => {
+            out.push_str("digraph cluster {\n    rankdir=LR;\n    node [shape=box];\n");
+            for (key, id) in &ids {
+                let label = node_label(key).replace('\\', "\\\\").replace('"', "\\\"");
+                writeln!(out, "    n{} [label=\"{}\"];", id, label)?;
+            }
+            for (from, to, relation) in edges {
+                writeln!(
+                    out,
+                    "    n{} -> n{} [label=\"{}\"];",
+                    ids[from], ids[to], relation
+                )?;
+            }
+            out.push_str("}\n");
+        }
+        GraphFormat::Mermaid => {
+            out.push_str("graph LR\n");
+            for (key, id) in &ids {
+                let label = node_label(key).replace('"', "#quot;");
+                writeln!(out, "    n{}[\"{}\"]", id, label)?;
+            }
+            for (from, to, relation) in edges {
+                writeln!(out, "    n{} -->|{}| n{}", ids[from], relation, ids[to])?;
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Writes graph in all selected formats
+pub async fn write(env: &crate::Environment) -> anyhow::Result<()> {
+    if env.opts.graph.is_empty() {
+        return Ok(());
+    }
+    let edges = env.graph.edges();
+    for format in &env.opts.graph {
+        let path = env.layout.graph(format.extension());
+        env.storage.write(&path, render(*format, &edges)?).await?;
+    }
+    tracing::info!("Wrote relationship graph with {} edges", edges.len());
+    Ok(())
+}
@@ -0,0 +1,150 @@
+//! Incremental dumps, which only store objects changed since base dump
+use crate::{index::Entry, layout::ObjectKey};
+use anyhow::Context as _;
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Contents of `incremental.json`
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    /// Dump this one is based on
+    pub base: PathBuf,
+    /// Objects identical to ones in base dump (paths point into base)
+    pub unchanged: Vec<Entry>,
+    /// Changed objects (paths point to RFC 6902 patches, which transform
+    /// base object into current one)
+    pub patched: Vec<Entry>,
+}
+
+/// How object differs from its version in base dump
+pub enum Change {
+    Unchanged,
+    /// Serialized JSON patch
+    Patched(String),
+    /// Object is not present in base dump
+    Added,
+}
+
+/// Base dump. Only its index is kept in memory, objects are read when
+/// they are compared.
+pub struct Base {
+    dir: PathBuf,
+    entries: HashMap<ObjectKey, Entry>,
+    unchanged: Mutex<Vec<Entry>>,
+    patched: Mutex<Vec<Entry>>,
+}
+
+impl Base {
+    pub fn load(dir: &Path) -> anyhow::Result<Base> {
+        if dir.join("incremental.json").exists() {
+            anyhow::bail!("{} is incremental dump, base must be full", dir.display());
+        }
+        let index = crate::load::read_index(dir)
+            .with_context(|| format!("failed to load base dump {}", dir.display()))?;
+        let mut paths = HashSet::new();
+        let mut entries = HashMap::new();
+        for entry in index {
+            // reading single object from stream file means parsing it all
+            if !paths.insert(entry.path.clone()) {
+                anyhow::bail!("base dump {} must use dir format", dir.display());
+            }
+            let key = ObjectKey {
+                group: entry.group.clone(),
+                kind: entry.kind.clone(),
+                namespace: entry.namespace.clone(),
+                name: entry.name.clone(),
+            };
+            entries.insert(key, entry);
+        }
+        Ok(Base {
+            dir: dir.canonicalize()?,
+            entries,
+            unchanged: Mutex::new(Vec::new()),
+            patched: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Compares object representation with base version. Base objects
+    /// always have `apiVersion` and `kind`, so they are added to `repr`
+    /// if missing.
+    pub fn compare(
+        &self,
+        key: &ObjectKey,
+        api_version: &str,
+        repr: &str,
+    ) -> anyhow::Result<Change> {
+        let entry = match self.entries.get(key) {
+            Some(entry) => entry,
+            None => return Ok(Change::Added),
+        };
+        let base = tokio::task::block_in_place(|| crate::load::load_entry(&self.dir, entry))
+            .with_context(|| format!("failed to read {} from base dump", entry.path))?;
+        let mut object: Value = serde_json::from_str(repr)?;
+        if let Value::Object(fields) = &mut object {
+            fields
+                .entry("apiVersion")
+                .or_insert_with(|| Value::String(api_version.to_string()));
+            fields
+                .entry("kind")
+                .or_insert_with(|| Value::String(key.kind.clone()));
+        }
+        let mut patch = Vec::new();
+        crate::diff::json_patch(&base, &object, "", &mut patch);
+        if patch.is_empty() {
+            Ok(Change::Unchanged)
+        } else {
+            Ok(Change::Patched(serde_json::to_string_pretty(&patch)?))
+        }
+    }
+
+    /// Records object identical to base one, returning its index entry
+    pub fn record_unchanged(&self, key: &ObjectKey) -> Entry {
+        let entry = &self.entries[key];
+        self.unchanged.lock().unwrap().push(entry.clone());
+        Entry {
+            path: self.dir.join(&entry.path).display().to_string(),
+            ..entry.clone()
+        }
+    }
+
+    /// Records changed object, whose patch is described by `entry`,
+    /// returning its index entry
+    pub fn record_patched(&self, key: &ObjectKey, entry: Entry) -> Entry {
+        let base_path = self.dir.join(&self.entries[key].path);
+        self.patched.lock().unwrap().push(entry.clone());
+        Entry {
+            path: base_path.display().to_string(),
+            patch: Some(entry.path.clone()),
+            ..entry
+        }
+    }
+
+    /// Writes `incremental.json`
+    pub async fn write_manifest(&self, env: &crate::Environment) -> anyhow::Result<()> {
+        let mut manifest = Manifest {
+            base: self.dir.clone(),
+            unchanged: self.unchanged.lock().unwrap().clone(),
+            patched: self.patched.lock().unwrap().clone(),
+        };
+        for entries in &mut [&mut manifest.unchanged, &mut manifest.patched] {
+            entries.sort_by(|a, b| {
+                (&a.group, &a.kind, &a.namespace, &a.name).cmp(&(
+                    &b.group,
+                    &b.kind,
+                    &b.namespace,
+                    &b.name,
+                ))
+            });
+        }
+        let data = serde_json::to_string_pretty(&manifest)?;
+        env.storage
+            .write(&env.layout.incremental_manifest(), data)
+            .await
+    }
+}
@@ -0,0 +1,79 @@
+//! Machine-readable index of all dumped objects
+use serde::{Deserialize, Serialize};
+use std::{path::Path, sync::Mutex};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Entry {
+    pub group: String,
+    pub version: String,
+    pub kind: String,
+    pub namespace: Option<String>,
+    pub name: String,
+    /// File containing object representation, relative to the dump root
+    pub path: String,
+    /// Size of the object representation in bytes
+    pub size: u64,
+    pub resource_version: Option<String>,
+    /// Name in `path` was truncated because it was too long, so `name`
+    /// is the only place real name can be found
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub path_shortened: bool,
+    /// Set for objects of incremental dump which changed since base dump:
+    /// RFC 6902 patch (relative to the dump root) transforming object at
+    /// `path` (absolute path into base dump) into current one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub patch: Option<String>,
+}
+
+/// Collects entries during the dump
+#[derive(Default)]
+pub struct DumpIndex {
+    entries: Mutex<Vec<Entry>>,
+}
+
+impl DumpIndex {
+    pub fn record(&self, entry: Entry) {
+        self.entries.lock().unwrap().push(entry);
+    }
+
+    pub fn entries(&self) -> Vec<Entry> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    /// Entries of objects of given resource
+    pub fn entries_of(&self, group: &str, version: &str, kind: &str) -> Vec<Entry> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|entry| entry.group == group && entry.version == version && entry.kind == kind)
+            .cloned()
+            .collect()
+    }
+
+    /// Updates paths of entries after file or directory was moved (both
+    /// paths are relative to the dump root)
+    pub fn relocate(&self, from: &Path, to: &Path) {
+        for entry in self.entries.lock().unwrap().iter_mut() {
+            if let Ok(rest) = Path::new(&entry.path).strip_prefix(from) {
+                entry.path = to.join(rest).display().to_string();
+            }
+        }
+    }
+
+    /// Writes `index.json`, sorted by object identity
+    pub async fn write(&self, env: &crate::Environment) -> anyhow::Result<()> {
+        let mut entries = self.entries.lock().unwrap().clone();
+        entries.sort_by(|a, b| {
+            (&a.group, &a.kind, &a.namespace, &a.name).cmp(&(
+                &b.group,
+                &b.kind,
+                &b.namespace,
+                &b.name,
+            ))
+        });
+        let index = serde_json::to_string_pretty(&entries)?;
+        env.storage.write(&env.layout.index(), index).await
+    }
+}
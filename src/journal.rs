@@ -0,0 +1,232 @@
+//! Change journal, recorded in watch mode, and tools to work with it
+use crate::layout::{Escape, Layout};
+use clap::Clap;
+use kube::api::ApiResource;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::BufRead,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Deleted,
+}
+
+/// Single line of the change journal
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Entry {
+    /// RFC 3339 timestamp of the moment change was observed
+    pub time: String,
+    #[serde(rename = "type")]
+    pub change: ChangeKind,
+    pub group: String,
+    pub version: String,
+    pub kind: String,
+    pub plural: String,
+    /// Object state after change (or last known state for deletions)
+    pub object: serde_json::Value,
+    /// RFC 6902 patch transforming previous state of modified object into
+    /// the new one (as represented in the dump), if previous state is known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub patch: Option<serde_json::Value>,
+}
+
+impl Entry {
+    pub fn api_resource(&self) -> ApiResource {
+        let api_version = if self.group.is_empty() {
+            self.version.clone()
+        } else {
+            format!("{}/{}", self.group, self.version)
+        };
+        ApiResource {
+            group: self.group.clone(),
+            version: self.version.clone(),
+            api_version,
+            kind: self.kind.clone(),
+            plural: self.plural.clone(),
+        }
+    }
+
+    fn namespace(&self) -> Option<&str> {
+        self.object.pointer("/metadata/namespace")?.as_str()
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.object.pointer("/metadata/name")?.as_str()
+    }
+}
+
+/// Reconstructs cluster state at some point from the change journal
+#[derive(Clap)]
+pub struct ReplayOpts {
+    /// Path to the change journal (`changes.ndjson`). Journal only records
+    /// changes, so objects of the dump containing it are replayed first
+    /// (in their latest dumped state)
+    journal: PathBuf,
+    /// Apply only changes observed at or before this moment (RFC 3339).
+    /// By default whole journal is replayed
+    #[clap(long)]
+    until: Option<String>,
+    /// Path reconstructed state should be written to
+    #[clap(long, required_unless_present = "serve")]
+    out: Option<PathBuf>,
+    /// Serve reconstructed state as read-only fake API on this address
+    /// (e.g. `127.0.0.1:8001`), until interrupted
+    #[clap(long)]
+    serve: Option<SocketAddr>,
+    /// Escape some chars in names (same as `--escape basic`)
+    #[clap(long)]
+    escape_paths: bool,
+    /// How names are escaped in paths: `none`, `basic` or `windows`
+    #[clap(long)]
+    escape: Option<Escape>,
+}
+
+/// (group, kind, namespace, name) -> (resource, latest object state)
+type State = BTreeMap<(String, String, Option<String>, String), (ApiResource, serde_json::Value)>;
+
+/// Loads objects of the dump at `dir`, which the journal starts from
+fn load_dumped(dir: &Path) -> anyhow::Result<State> {
+    let layout = Layout::from_root(dir.to_path_buf(), Escape::None);
+    if !layout.index().exists() {
+        tracing::warn!(
+            "{} is not a dump, only changes from the journal are replayed",
+            dir.display()
+        );
+        return Ok(State::new());
+    }
+    let apis = std::fs::read(layout.cluster_api_resources())?;
+    let apis: Vec<serde_json::Value> = serde_json::from_slice(&apis)?;
+    let mut resources = HashMap::new();
+    for api in apis {
+        let api_resource = ApiResource {
+            group: api["group"].as_str().unwrap_or_default().to_string(),
+            version: api["version"].as_str().unwrap_or_default().to_string(),
+            api_version: api["apiVersion"].as_str().unwrap_or_default().to_string(),
+            kind: api["kind"].as_str().unwrap_or_default().to_string(),
+            plural: api["plural"].as_str().unwrap_or_default().to_string(),
+        };
+        resources.insert(
+            (
+                api_resource.group.clone(),
+                api_resource.version.clone(),
+                api_resource.kind.clone(),
+            ),
+            api_resource,
+        );
+    }
+    let mut state = State::new();
+    for (entry, object) in crate::load::load(dir)? {
+        let api_resource = match resources.get(&(
+            entry.group.clone(),
+            entry.version.clone(),
+            entry.kind.clone(),
+        )) {
+            Some(r) => r.clone(),
+            None => {
+                tracing::warn!(
+                    "Skipping {} {}, its resource is missing in apis.json",
+                    entry.kind,
+                    entry.name
+                );
+                continue;
+            }
+        };
+        state.insert(
+            (entry.group, entry.kind, entry.namespace, entry.name),
+            (api_resource, object),
+        );
+    }
+    Ok(state)
+}
+
+pub async fn replay(opts: ReplayOpts) -> anyhow::Result<()> {
+    let until = match &opts.until {
+        Some(until) => Some(humantime::parse_rfc3339_weak(until)?),
+        None => None,
+    };
+    let journal = std::fs::File::open(&opts.journal)?;
+    let journal = std::io::BufReader::new(journal);
+
+    let dump = opts
+        .journal
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let mut state = load_dumped(dump)?;
+    let dumped = state.len();
+    let mut applied = 0;
+    for (line_no, line) in journal.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: Entry = serde_json::from_str(&line).map_err(|err| {
+            anyhow::anyhow!("{}:{}: {}", opts.journal.display(), line_no + 1, err)
+        })?;
+        let time: SystemTime = humantime::parse_rfc3339_weak(&entry.time)?;
+        if let Some(until) = until {
+            if time > until {
+                break;
+            }
+        }
+        let name = match entry.name() {
+            Some(n) => n.to_string(),
+            None => {
+                tracing::warn!(
+                    "Skipping journal entry without name at line {}",
+                    line_no + 1
+                );
+                continue;
+            }
+        };
+        let key = (
+            entry.group.clone(),
+            entry.kind.clone(),
+            entry.namespace().map(ToString::to_string),
+            name,
+        );
+        match entry.change {
+            ChangeKind::Added | ChangeKind::Modified => {
+                state.insert(key, (entry.api_resource(), entry.object));
+            }
+            ChangeKind::Deleted => {
+                state.remove(&key);
+            }
+        }
+        applied += 1;
+    }
+    tracing::info!(
+        "Applied {} changes to {} dumped objects, writing {} objects",
+        applied,
+        dumped,
+        state.len()
+    );
+
+    if let Some(out) = &opts.out {
+        let layout =
+            Layout::from_root(out.clone(), Escape::resolve(opts.escape, opts.escape_paths));
+        for ((_, _, namespace, name), (api_resource, object)) in &state {
+            let object_layout = layout.object_layout(api_resource, namespace.as_deref(), name)?;
+            let repr_path = object_layout.representation();
+            let parent = repr_path.parent().expect("Layout never returns root-path");
+            tokio::fs::create_dir_all(parent).await?;
+            tokio::fs::write(repr_path, serde_json::to_string_pretty(object)?).await?;
+        }
+    }
+    if let Some(addr) = opts.serve {
+        let mut served = crate::fakeapi::State::default();
+        for ((_, _, namespace, name), (api_resource, object)) in state {
+            served.insert(&api_resource, namespace, name, object);
+        }
+        crate::fakeapi::serve(served, addr).await?;
+    }
+    Ok(())
+}
@@ -1,6 +1,9 @@
 //! Utilities for running kubectl
 use anyhow::Context as _;
-use std::sync::Arc;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tokio::{process::Command, sync::Semaphore};
 const MAX_CONCURRENCY: usize = 3;
 
@@ -8,23 +11,29 @@ const MAX_CONCURRENCY: usize = 3;
 pub struct Kubectl {
     enabled: bool,
     sem: Arc<Semaphore>,
+    timeout: Duration,
+    /// Invocations which were killed because they did not finish in time
+    timed_out: Mutex<Vec<String>>,
 }
 
 impl Kubectl {
-    pub async fn try_new() -> Kubectl {
-        match Kubectl::new().await {
+    pub async fn try_new(timeout: Duration) -> Kubectl {
+        match Kubectl::new(timeout).await {
             Ok(k) => k,
             Err(err) => {
-                eprintln!("Kubectl integration will be disabled: {:#}", err);
+                tracing::warn!("Kubectl integration will be disabled: {:#}", err);
                 Kubectl::disabled()
             }
         }
     }
 
-    pub async fn new() -> anyhow::Result<Kubectl> {
+    pub async fn new(timeout: Duration) -> anyhow::Result<Kubectl> {
         let mut cmd = Command::new("kubectl");
         cmd.arg("version");
-        let out = cmd.output().await?;
+        cmd.kill_on_drop(true);
+        let out = tokio::time::timeout(timeout, cmd.output())
+            .await
+            .with_context(|| format!("`kubectl version` did not finish in {:?}", timeout))??;
         if !out.status.success() {
             // either kubectl not available, or it is unable to connect to cluster
             anyhow::bail!(
@@ -35,6 +44,8 @@ impl Kubectl {
         Ok(Kubectl {
             enabled: true,
             sem: Arc::new(Semaphore::new(MAX_CONCURRENCY)),
+            timeout,
+            timed_out: Mutex::new(Vec::new()),
         })
     }
 
@@ -42,9 +53,16 @@ impl Kubectl {
         Kubectl {
             enabled: false,
             sem: Arc::new(Semaphore::new(MAX_CONCURRENCY)),
+            timeout: Duration::default(),
+            timed_out: Mutex::new(Vec::new()),
         }
     }
 
+    /// Returns invocations (as command lines) that were killed on timeout
+    pub fn timed_out(&self) -> Vec<String> {
+        self.timed_out.lock().unwrap().clone()
+    }
+
     pub async fn exec<S: AsRef<std::ffi::OsStr>>(
         &self,
         args: &[S],
@@ -57,7 +75,22 @@ impl Kubectl {
         // disable colors
         cmd.env("TERM", "dumb");
         cmd.args(args);
-        let out = cmd.output().await?;
+        // if timeout expires, output future is dropped, and child gets killed
+        cmd.kill_on_drop(true);
+        let out = match tokio::time::timeout(self.timeout, cmd.output()).await {
+            Ok(out) => out?,
+            Err(_) => {
+                let command_line = std::iter::once("kubectl".to_string())
+                    .chain(
+                        args.iter()
+                            .map(|arg| arg.as_ref().to_string_lossy().into_owned()),
+                    )
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                self.timed_out.lock().unwrap().push(command_line.clone());
+                anyhow::bail!("`{}` timed out after {:?}", command_line, self.timeout);
+            }
+        };
         if !out.status.success() {
             anyhow::bail!("{}", String::from_utf8_lossy(&out.stderr));
         }
@@ -1,19 +1,323 @@
+pub mod sink;
+pub mod storage;
+
 use kube::api::ApiResource;
-use std::path::PathBuf;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// Compression applied to large object artifacts
+#[derive(Clone, Copy)]
+pub enum Compression {
+    Zstd,
+}
+
+impl std::str::FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zstd" => Ok(Compression::Zstd),
+            _ => anyhow::bail!("unknown compression: {}", s),
+        }
+    }
+}
+
+impl Compression {
+    fn extension(self) -> &'static str {
+        match self {
+            Compression::Zstd => ".zst",
+        }
+    }
+}
+
+/// How names are escaped before being used as path components
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Escape {
+    None,
+    /// Escape `~` and `:`
+    Basic,
+    /// Additionally escape everything Windows does not allow in file names
+    Windows,
+}
+
+impl std::str::FromStr for Escape {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Escape::None),
+            "basic" => Ok(Escape::Basic),
+            "windows" => Ok(Escape::Windows),
+            _ => anyhow::bail!("unknown escape mode: {}", s),
+        }
+    }
+}
+
+impl Escape {
+    /// Resolves `--escape` together with legacy `--escape-paths` flag
+    pub fn resolve(mode: Option<Escape>, escape_paths: bool) -> Escape {
+        match mode {
+            Some(mode) => mode,
+            None if escape_paths => Escape::Basic,
+            None => Escape::None,
+        }
+    }
+}
+
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+fn escape_windows_name(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for ch in name.chars() {
+        match ch {
+            '<' => out.push_str("~lt_"),
+            '>' => out.push_str("~gt_"),
+            '"' => out.push_str("~quot_"),
+            '/' => out.push_str("~slash_"),
+            '\\' => out.push_str("~backslash_"),
+            '|' => out.push_str("~pipe_"),
+            '?' => out.push_str("~question_"),
+            '*' => out.push_str("~star_"),
+            c if c.is_control() => out.push_str(&format!("~x{:02x}_", c as u32)),
+            c => out.push(c),
+        }
+    }
+    // Windows silently drops trailing dots and spaces
+    let trimmed = out.trim_end_matches(['.', ' ']);
+    let mut tail = String::new();
+    for ch in out[trimmed.len()..].chars() {
+        tail.push_str(if ch == '.' { "~dot_" } else { "~space_" });
+    }
+    let mut out = format!("{}{}", trimmed, tail);
+    let stem = out.split('.').next().unwrap_or_default();
+    if WINDOWS_RESERVED_NAMES
+        .iter()
+        .any(|r| r.eq_ignore_ascii_case(stem))
+    {
+        out.insert_str(0, "~reserved_");
+    }
+    out
+}
+
+/// Longest path component produced for object names. Keeps room for
+/// artifact suffixes (`.defaulted.json.zst` etc.) within common 255-byte
+/// file name limit
+const MAX_COMPONENT_LEN: usize = 200;
+
+/// Truncates over-long path component, appending short hash of the full
+/// value so that distinct names stay distinct. Returns `None` if component
+/// already fits
+fn shorten_component(component: &str) -> Option<String> {
+    use sha2::Digest;
+    if component.len() <= MAX_COMPONENT_LEN {
+        return None;
+    }
+    let hash = format!("{:x}", sha2::Sha256::digest(component.as_bytes()));
+    let suffix = format!("~h{}", &hash[..16]);
+    let mut end = MAX_COMPONENT_LEN - suffix.len();
+    while !component.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some(format!("{}{}", &component[..end], suffix))
+}
+
+fn full_kind(group: &str, kind: &str) -> String {
+    if !group.is_empty() {
+        format!("{}/{}", group, kind)
+    } else {
+        kind.to_string()
+    }
+}
+
+/// Identifies dumped object
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ObjectKey {
+    pub group: String,
+    pub kind: String,
+    pub namespace: Option<String>,
+    pub name: String,
+}
+
+/// Remembers which object each object-related file belongs to, and which
+/// files must be compressed
+#[derive(Clone, Default)]
+pub struct ObjectRegistry {
+    /// path -> (object, artifact name, e.g. `raw.json`)
+    artifacts: Arc<Mutex<HashMap<PathBuf, (ObjectKey, String)>>>,
+    compressed: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl ObjectRegistry {
+    fn register(&self, path: &Path, key: &ObjectKey, artifact: &str) {
+        self.artifacts
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), (key.clone(), artifact.to_string()));
+    }
+
+    fn mark_compressed(&self, path: &Path) {
+        self.compressed.lock().unwrap().insert(path.to_path_buf());
+    }
+
+    /// Drops what is known about the file once it is written. ObjectLayout
+    /// registers path again whenever it is requested, so this only bounds
+    /// memory used by the registry.
+    pub fn forget(&self, path: &Path) {
+        self.artifacts.lock().unwrap().remove(path);
+        self.compressed.lock().unwrap().remove(path);
+    }
+
+    /// Whether file was chosen to be compressed by ObjectLayout
+    pub fn is_compressed(&self, path: &Path) -> bool {
+        self.compressed.lock().unwrap().contains(path)
+    }
+
+    /// If path belongs to some object, returns that object and artifact name
+    pub fn lookup(&self, path: &Path) -> Option<(ObjectKey, String)> {
+        self.artifacts.lock().unwrap().get(path).cloned()
+    }
+}
+
+/// Order in which objects are grouped into directories
+#[derive(Clone, Copy)]
+pub enum Scheme {
+    /// `<namespace>/<kind>/<name>`
+    NamespaceFirst,
+    /// `<kind>/<namespace>/<name>`
+    KindFirst,
+    /// Single `objects` directory with `<group>_<kind>_<namespace>_<name>.json`
+    /// files (other object-related files get the same prefix)
+    Flat,
+    /// Like `namespace/kind`, but objects are nested beneath their owners:
+    /// `<namespace>/<owner kind>/<owner>/<kind>/<name>`
+    OwnerTree,
+}
+
+impl std::str::FromStr for Scheme {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "namespace/kind" => Ok(Scheme::NamespaceFirst),
+            "kind/namespace" => Ok(Scheme::KindFirst),
+            "flat" => Ok(Scheme::Flat),
+            "owner-tree" => Ok(Scheme::OwnerTree),
+            _ => anyhow::bail!("unknown layout scheme: {}", s),
+        }
+    }
+}
+
+/// User-provided template for object paths, e.g.
+/// `{namespace}/{kind}/{name}/raw.{ext}`
+#[derive(Clone)]
+pub struct PathTemplate(String);
+
+const TEMPLATE_PLACEHOLDERS: &[&str] = &["group", "version", "kind", "namespace", "name", "ext"];
+
+impl std::str::FromStr for PathTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| anyhow::anyhow!("unterminated placeholder in {}", s))?;
+            let placeholder = &rest[start + 1..start + end];
+            if !TEMPLATE_PLACEHOLDERS.contains(&placeholder) {
+                anyhow::bail!("unknown placeholder {{{}}}", placeholder);
+            }
+            rest = &rest[start + end + 1..];
+        }
+        if !s.contains("{name}") {
+            anyhow::bail!("path template must contain {{name}}");
+        }
+        if s.starts_with('/') || s.split('/').any(|c| c == "..") {
+            anyhow::bail!("path template must be relative and stay inside dump");
+        }
+        Ok(PathTemplate(s.to_string()))
+    }
+}
+
+/// Remembers which object got which location, so that objects whose
+/// escaped names coincide do not overwrite each other
+#[derive(Default)]
+struct PathClaims {
+    /// normalized location -> owner
+    owners: HashMap<String, ObjectKey>,
+    /// object -> disambiguating suffix (if any)
+    suffixes: HashMap<ObjectKey, Option<String>>,
+}
 
 /// Layout tells where specific thing should live
 pub struct Layout {
     root: PathBuf,
-    escape: bool,
+    escape: Escape,
+    scheme: Scheme,
+    /// Overrides `scheme` if set
+    template: Option<PathTemplate>,
+    compression: Option<Compression>,
+    /// Logs smaller than this are not compressed
+    compress_min_size: u64,
+    /// Logs larger than this are gzip-compressed
+    logs_gzip_threshold: Option<u64>,
+    /// Fail instead of disambiguating colliding paths
+    strict: bool,
+    claims: Mutex<PathClaims>,
+    /// object -> its owner, used by owner-tree scheme
+    owners: Mutex<HashMap<ObjectKey, ObjectKey>>,
+    registry: ObjectRegistry,
 }
 
 impl Layout {
     pub fn new(opts: &crate::Opts) -> Layout {
         Layout {
-            root: opts.out.clone(),
-            escape: opts.escape_paths,
+            compression: opts.compress,
+            compress_min_size: opts.compress_min_size,
+            logs_gzip_threshold: opts.compress_logs,
+            scheme: opts.layout,
+            template: opts.path_template.clone(),
+            strict: opts.strict_paths,
+            ..Layout::from_root(
+                opts.out().to_path_buf(),
+                Escape::resolve(opts.escape, opts.escape_paths),
+            )
+        }
+    }
+    pub fn from_root(root: PathBuf, escape: Escape) -> Layout {
+        Layout {
+            root,
+            escape,
+            scheme: Scheme::NamespaceFirst,
+            template: None,
+            compression: None,
+            compress_min_size: 0,
+            logs_gzip_threshold: None,
+            strict: false,
+            claims: Mutex::default(),
+            owners: Mutex::default(),
+            registry: ObjectRegistry::default(),
         }
     }
+    pub fn scheme(&self) -> Scheme {
+        self.scheme
+    }
+
+    /// Sets owners of objects for owner-tree scheme. Until this is called,
+    /// objects are laid out as if they did not have owners.
+    pub fn set_owners(&self, owners: HashMap<ObjectKey, ObjectKey>) {
+        *self.owners.lock().unwrap() = owners;
+    }
+
+    pub fn registry(&self) -> &ObjectRegistry {
+        &self.registry
+    }
     /// information, reported by `kubectl cluster-info`
     pub fn cluster_info(&self) -> PathBuf {
         self.root.join("cluster-info.txt")
@@ -26,66 +330,741 @@ impl Layout {
     pub fn cluster_api_resources(&self) -> PathBuf {
         self.root.join("apis.json")
     }
+    /// Converts path produced by Layout into path relative to dump root
+    pub fn relative<'a>(&self, path: &'a Path) -> &'a Path {
+        path.strip_prefix(&self.root)
+            .expect("path was not produced by Layout")
+    }
+    /// Index of all dumped objects
+    pub fn index(&self) -> PathBuf {
+        self.root.join("index.json")
+    }
+    /// SHA-256 checksums of all files in the dump
+    pub fn checksums(&self) -> PathBuf {
+        self.root.join("SHA256SUMS")
+    }
+    /// Detached signature of `SHA256SUMS`
+    pub fn checksums_signature(&self, extension: &str) -> PathBuf {
+        self.root.join(format!("SHA256SUMS.{}", extension))
+    }
+    /// resourceVersions at which each resource was listed
+    pub fn resource_versions(&self) -> PathBuf {
+        self.root.join("resource-versions.json")
+    }
+    /// Unchanged and patched objects of incremental dump
+    pub fn incremental_manifest(&self) -> PathBuf {
+        self.root.join("incremental.json")
+    }
+    /// Changes observed in watch mode
+    pub fn change_journal(&self) -> PathBuf {
+        self.root.join("changes.ndjson")
+    }
+    /// Marker for API group served by unavailable aggregated API server
+    pub fn aggregated_api_unavailable(&self, group: &str) -> PathBuf {
+        self.root
+            .join("unavailable-apis")
+            .join(format!("{}.json", self.maybe_escape_name(group)))
+    }
+    /// API resources that were not dumped because access was denied
+    pub fn skipped_resources(&self) -> PathBuf {
+        self.root.join("skipped.json")
+    }
 
     fn maybe_escape_name(&self, name: &str) -> String {
-        if !self.escape {
+        if self.escape == Escape::None {
             return name.to_string();
         }
-        name.replace("~", "~tilda_").replace(":", "~colon_")
+        let name = name.replace("~", "~tilda_").replace(":", "~colon_");
+        match self.escape {
+            Escape::Windows => escape_windows_name(&name),
+            _ => name,
+        }
     }
 
-    pub fn object_layout(
+    /// Shortens component if it is too long, remembering that it happened
+    fn fit_component(&self, component: String, shortened: &mut bool) -> String {
+        match shorten_component(&component) {
+            Some(short) => {
+                *shortened = true;
+                short
+            }
+            None => component,
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Relationship graph in format with given extension
+    pub fn graph(&self, extension: &str) -> PathBuf {
+        self.root.join(format!("graph.{}", extension))
+    }
+
+    /// Markdown overview of the cluster
+    pub fn summary(&self) -> PathBuf {
+        self.root.join("SUMMARY.md")
+    }
+    /// Statistics and errors of the run that produced the dump
+    pub fn run_summary(&self) -> PathBuf {
+        self.root.join("summary.json")
+    }
+    /// Duration of phases, API groups and log fetches
+    pub fn timings(&self) -> PathBuf {
+        self.root.join("timings.json")
+    }
+    /// Copy of `--log-file`
+    pub fn run_log(&self) -> PathBuf {
+        self.root.join("run.log")
+    }
+
+    /// Front page of HTML report
+    pub fn report_index(&self) -> PathBuf {
+        self.root.join("report").join("index.html")
+    }
+
+    /// HTML report page listing objects and events of the namespace
+    pub fn report_namespace_page(&self, namespace: Option<&str>) -> PathBuf {
+        let name = format!(
+            "{}.html",
+            self.maybe_escape_name(namespace.unwrap_or("_global_"))
+        );
+        self.root.join("report").join("namespaces").join(name)
+    }
+
+    /// HTML report page with pretty-printed object
+    pub fn report_object_page(&self, key: &ObjectKey) -> PathBuf {
+        let name = format!("{}.html", self.maybe_escape_name(&key.name));
+        let path = self
+            .root
+            .join("report")
+            .join("objects")
+            .join(self.maybe_escape_name(key.namespace.as_deref().unwrap_or("_global_")))
+            .join(full_kind(&key.group, &key.kind))
+            .join(shorten_component(&name).unwrap_or(name));
+        // page embeds the object, so it must be treated as its artifact
+        self.registry.register(&path, key, "report.html");
+        path
+    }
+
+    /// Entry of `by-kind` symlink tree, pointing to the object location
+    pub fn kind_link(
         &self,
         resource: &ApiResource,
         namespace: Option<&str>,
         name: &str,
-    ) -> ObjectLayout {
-        let mut p = self.root.clone();
-        if let Some(ns) = namespace {
-            p.push(format!("{}", ns));
+    ) -> PathBuf {
+        let group = if resource.group.is_empty() {
+            "core"
         } else {
-            p.push("_global_");
+            &resource.group
+        };
+        let link_name = format!(
+            "{}__{}",
+            self.maybe_escape_name(namespace.unwrap_or("_global_")),
+            self.maybe_escape_name(name)
+        );
+        let link_name = shorten_component(&link_name).unwrap_or(link_name);
+        self.root
+            .join("by-kind")
+            .join(group)
+            .join(&resource.kind)
+            .join(link_name)
+    }
+
+    /// Multi-document stream containing all objects of the kind
+    pub fn kind_stream(&self, resource: &ApiResource) -> PathBuf {
+        self.root
+            .join("_kinds_")
+            .join(self.full_kind(resource))
+            .join("dump.yaml")
+    }
+
+    /// All objects of the kind, one JSON document per line
+    pub fn kind_jsonl(&self, resource: &ApiResource) -> PathBuf {
+        self.root
+            .join("_kinds_")
+            .join(self.full_kind(resource))
+            .join("objects.jsonl")
+    }
+
+    /// Multi-document stream containing all objects in the namespace
+    pub fn namespace_stream(&self, namespace: Option<&str>) -> PathBuf {
+        self.root
+            .join(namespace.unwrap_or("_global_"))
+            .join("dump.yaml")
+    }
+
+    fn full_kind(&self, resource: &ApiResource) -> String {
+        full_kind(&resource.group, &resource.kind)
+    }
+
+    /// Directory of the object in owner-tree scheme
+    fn owner_tree_dir(&self, key: &ObjectKey, shortened: &mut bool) -> PathBuf {
+        // protects from ownership cycles
+        const MAX_DEPTH: usize = 16;
+        let owners = self.owners.lock().unwrap();
+        let mut chain = vec![key];
+        while let Some(owner) = owners.get(chain[chain.len() - 1]) {
+            if chain.len() == MAX_DEPTH || chain.contains(&owner) {
+                break;
+            }
+            chain.push(owner);
+        }
+        let top = chain[chain.len() - 1];
+        let mut p = self
+            .root
+            .join(self.maybe_escape_name(top.namespace.as_deref().unwrap_or("_global_")));
+        for key in chain.into_iter().rev() {
+            p.push(full_kind(&key.group, &key.kind));
+            p.push(self.fit_component(self.maybe_escape_name(&key.name), shortened));
         }
-        let full_kind = if !resource.group.is_empty() {
-            format!("{}/{}", resource.group, resource.kind)
+        p
+    }
+
+    /// Escapes value substituted into path template, so that it always
+    /// occupies exactly one path component
+    fn escape_template_value(&self, value: &str, shortened: &mut bool) -> String {
+        let value = self.maybe_escape_name(value).replace("/", "~slash_");
+        let value = match value.as_str() {
+            "" => "_empty_".to_string(),
+            "." | ".." => value.replace(".", "~dot_"),
+            _ => value,
+        };
+        self.fit_component(value, shortened)
+    }
+
+    /// Expands path template for the object representation
+    fn expand_template(
+        &self,
+        template: &PathTemplate,
+        resource: &ApiResource,
+        namespace: Option<&str>,
+        name: &str,
+        shortened: &mut bool,
+    ) -> PathBuf {
+        let group = if resource.group.is_empty() {
+            "core"
         } else {
-            resource.kind.clone()
+            &resource.group
         };
-        p.push(full_kind);
-        p.push(self.maybe_escape_name(name));
+        let mut expanded = template.0.clone();
+        for (placeholder, value) in &[
+            ("{group}", group),
+            ("{version}", resource.version.as_str()),
+            ("{kind}", resource.kind.as_str()),
+            ("{namespace}", namespace.unwrap_or("_global_")),
+            ("{name}", name),
+        ] {
+            expanded = expanded.replace(placeholder, &self.escape_template_value(value, shortened));
+        }
+        self.root.join(expanded.replace("{ext}", "json"))
+    }
 
-        ObjectLayout { root: p }
+    pub fn object_layout(
+        &self,
+        resource: &ApiResource,
+        namespace: Option<&str>,
+        name: &str,
+    ) -> anyhow::Result<ObjectLayout> {
+        let mut p = self.root.clone();
+        let namespace_dir = namespace.unwrap_or("_global_");
+        let mut prefix = None;
+        let mut representation_name = None;
+        let mut shortened = false;
+        if let Some(template) = &self.template {
+            let expanded =
+                self.expand_template(template, resource, namespace, name, &mut shortened);
+            let file_name = expanded
+                .file_name()
+                .expect("template never expands to root")
+                .to_string_lossy()
+                .into_owned();
+            p = expanded
+                .parent()
+                .expect("template never expands to root")
+                .to_path_buf();
+            let last_component = template.0.rsplit('/').next().unwrap_or_default();
+            if last_component.replace("{ext}", "").contains('{') {
+                // file name is object-specific, so other files of this
+                // object get it as a prefix
+                let stem = file_name.strip_suffix(".json").unwrap_or(&file_name);
+                prefix = Some(stem.to_string());
+            } else {
+                representation_name = Some(file_name);
+            }
+        }
+        let key = ObjectKey {
+            group: resource.group.clone(),
+            kind: resource.kind.clone(),
+            namespace: namespace.map(ToString::to_string),
+            name: name.to_string(),
+        };
+        if self.template.is_none() {
+            if let Scheme::OwnerTree = self.scheme {
+                p = self.owner_tree_dir(&key, &mut shortened);
+            } else {
+                let namespace_dir = self.maybe_escape_name(namespace_dir);
+                self.push_scheme_components(
+                    &mut p,
+                    &mut prefix,
+                    &mut shortened,
+                    resource,
+                    &namespace_dir,
+                    name,
+                );
+            }
+        }
+        let location = match (&prefix, &representation_name) {
+            (Some(name), _) | (None, Some(name)) => p.join(name),
+            (None, None) => p.clone(),
+        };
+        if let Some(suffix) = self.claim(&location, &key)? {
+            if let Some(prefix) = &mut prefix {
+                prefix.push_str(&suffix);
+            } else if let Some(name) = &mut representation_name {
+                let stem_len = name.strip_suffix(".json").unwrap_or(name).len();
+                name.insert_str(stem_len, &suffix);
+            } else {
+                let mut last = p.file_name().unwrap_or_default().to_os_string();
+                last.push(&suffix);
+                p.set_file_name(last);
+            }
+        }
+
+        Ok(ObjectLayout {
+            root: p,
+            prefix,
+            representation_name,
+            compression: self.compression,
+            compress_min_size: self.compress_min_size,
+            logs_gzip_threshold: self.logs_gzip_threshold,
+            shortened,
+            key,
+            registry: self.registry.clone(),
+        })
+    }
+
+    /// Claims location for the object. Returns suffix which must be added
+    /// to the location if it is already owned by another object
+    fn claim(&self, location: &Path, key: &ObjectKey) -> anyhow::Result<Option<String>> {
+        use sha2::Digest;
+        let mut claims = self.claims.lock().unwrap();
+        if let Some(suffix) = claims.suffixes.get(key) {
+            return Ok(suffix.clone());
+        }
+        let mut normalized = location.display().to_string();
+        if self.escape == Escape::Windows {
+            // Windows file systems are usually case-insensitive
+            normalized = normalized.to_lowercase();
+        }
+        let suffix = match claims.owners.get(&normalized) {
+            Some(owner) => {
+                if self.strict {
+                    anyhow::bail!(
+                        "{:?} and {:?} map to the same path {}",
+                        owner,
+                        key,
+                        location.display()
+                    );
+                }
+                // suffix only depends on object identity, so object gets
+                // the same path in every dump
+                let identity = format!(
+                    "{}/{}/{}/{}",
+                    key.group,
+                    key.kind,
+                    key.namespace.as_deref().unwrap_or_default(),
+                    key.name
+                );
+                let hash = format!("{:x}", sha2::Sha256::digest(identity.as_bytes()));
+                let suffix = format!("~c{}", &hash[..8]);
+                tracing::warn!(
+                    "{:?} collides with {:?} at {}, adding suffix {}",
+                    key,
+                    owner,
+                    location.display(),
+                    suffix
+                );
+                claims
+                    .owners
+                    .insert(format!("{}{}", normalized, suffix), key.clone());
+                Some(suffix)
+            }
+            None => {
+                claims.owners.insert(normalized, key.clone());
+                None
+            }
+        };
+        claims.suffixes.insert(key.clone(), suffix.clone());
+        Ok(suffix)
+    }
+
+    fn push_scheme_components(
+        &self,
+        p: &mut PathBuf,
+        prefix: &mut Option<String>,
+        shortened: &mut bool,
+        resource: &ApiResource,
+        namespace_dir: &str,
+        name: &str,
+    ) {
+        match self.scheme {
+            Scheme::NamespaceFirst | Scheme::OwnerTree => {
+                p.push(namespace_dir);
+                p.push(self.full_kind(resource));
+                p.push(self.fit_component(self.maybe_escape_name(name), shortened));
+            }
+            Scheme::KindFirst => {
+                p.push(self.full_kind(resource));
+                p.push(namespace_dir);
+                p.push(self.fit_component(self.maybe_escape_name(name), shortened));
+            }
+            Scheme::Flat => {
+                p.push("objects");
+                let group = if resource.group.is_empty() {
+                    "core"
+                } else {
+                    &resource.group
+                };
+                let flat_name = format!(
+                    "{}_{}_{}_{}",
+                    group,
+                    resource.kind,
+                    namespace_dir,
+                    self.maybe_escape_name(name)
+                );
+                *prefix = Some(self.fit_component(flat_name, shortened));
+            }
+        }
     }
 }
 
 /// ObjectLayout tells where specific object-related thing should live
 pub struct ObjectLayout {
     root: PathBuf,
+    /// In flat layout all objects share directory, so file names are
+    /// prefixed instead
+    prefix: Option<String>,
+    /// Overrides `raw.json` name of the representation (when using templates)
+    representation_name: Option<String>,
+    compression: Option<Compression>,
+    compress_min_size: u64,
+    logs_gzip_threshold: Option<u64>,
+    /// Some path component was truncated because it was too long
+    shortened: bool,
+    key: ObjectKey,
+    registry: ObjectRegistry,
 }
 
+#[derive(Clone, Copy)]
 pub enum LogsKind {
     Current,
     Previous,
 }
 
+/// Which list of pod spec the container belongs to
+#[derive(Clone, Copy)]
+pub enum ContainerKind {
+    Regular,
+    Init,
+    Ephemeral,
+}
+
+fn logs_file_name(container_kind: ContainerKind, kind: LogsKind, container_name: &str) -> String {
+    // `.` can not appear in container names, so these never clash
+    let pfx = match container_kind {
+        ContainerKind::Regular => "",
+        ContainerKind::Init => "init.",
+        ContainerKind::Ephemeral => "ephemeral.",
+    };
+    let sfx = match kind {
+        LogsKind::Current => "",
+        LogsKind::Previous => "-prev",
+    };
+    format!("logs-{}{}{}.txt", pfx, container_name, sfx)
+}
+
 impl ObjectLayout {
+    /// Returns path of the object-related file
+    fn artifact(&self, file_name: &str) -> PathBuf {
+        let path = match &self.prefix {
+            // representation is the object itself, so it is named
+            // `<prefix>.json` rather than `<prefix>.raw.json`
+            Some(prefix) => match file_name.strip_prefix("raw.") {
+                Some(rest) => self.root.join(format!("{}.{}", prefix, rest)),
+                None => self.root.join(format!("{}.{}", prefix, file_name)),
+            },
+            None => self.root.join(file_name),
+        };
+        self.registry.register(&path, &self.key, file_name);
+        path
+    }
+    /// Appends compression extension if compression is enabled, marking
+    /// file to be compressed by Storage
+    fn maybe_compressed(&self, file_name: &str) -> PathBuf {
+        match self.compression {
+            Some(c) => {
+                let path = self.artifact(&format!("{}{}", file_name, c.extension()));
+                self.registry.mark_compressed(&path);
+                path
+            }
+            None => self.artifact(file_name),
+        }
+    }
+    /// Appends `.gz` to logs of `size` bytes if they are large enough to be
+    /// gzip-compressed, and compression extension if they are large enough
+    /// to be compressed
+    fn logs_file(&self, file_name: &str, size: usize) -> PathBuf {
+        match self.logs_gzip_threshold {
+            Some(threshold) if size as u64 > threshold => {
+                self.artifact(&format!("{}.gz", file_name))
+            }
+            _ if (size as u64) < self.compress_min_size => self.artifact(file_name),
+            _ => self.maybe_compressed(file_name),
+        }
+    }
+    /// Directory containing all files of the object, or representation
+    /// itself if the object does not have dedicated directory
+    pub fn location(&self) -> PathBuf {
+        if self.prefix.is_none() && self.representation_name.is_none() {
+            self.root.clone()
+        } else {
+            self.representation()
+        }
+    }
+    /// Object this layout belongs to
+    pub fn key(&self) -> &ObjectKey {
+        &self.key
+    }
+    /// Whether object name had to be truncated to fit into path
+    pub fn is_shortened(&self) -> bool {
+        self.shortened
+    }
     pub fn representation(&self) -> PathBuf {
-        self.root.join("raw.json")
+        match &self.representation_name {
+            Some(name) => {
+                let mut name = name.clone();
+                let mut artifact = "raw.json".to_string();
+                if let Some(c) = self.compression {
+                    name.push_str(c.extension());
+                    artifact.push_str(c.extension());
+                }
+                let path = self.root.join(name);
+                self.registry.register(&path, &self.key, &artifact);
+                if self.compression.is_some() {
+                    self.registry.mark_compressed(&path);
+                }
+                path
+            }
+            None => self.maybe_compressed("raw.json"),
+        }
+    }
+    /// present if representation was truncated because object is too large
+    pub fn truncation_marker(&self) -> PathBuf {
+        self.artifact("truncated.json")
+    }
+    /// RFC 6902 patch from object in base dump, for incremental dumps
+    pub fn patch(&self) -> PathBuf {
+        self.maybe_compressed("patch.json")
+    }
+    /// `status` subresource, fetched separately
+    pub fn status(&self) -> PathBuf {
+        self.artifact("status.json")
+    }
+    /// `scale` subresource, fetched separately
+    pub fn scale(&self) -> PathBuf {
+        self.artifact("scale.json")
+    }
+    /// object as returned by server-side dry-run create
+    pub fn defaulted(&self) -> PathBuf {
+        self.artifact("defaulted.json")
+    }
+    /// fields that were filled in by server during dry-run create
+    pub fn defaults(&self) -> PathBuf {
+        self.artifact("defaults.json")
     }
     // for pods
-    pub fn logs(&self, kind: LogsKind, container_name: &str) -> PathBuf {
-        let sfx = match kind {
-            LogsKind::Current => "",
-            LogsKind::Previous => "-prev",
-        };
-        let file_name = format!("logs-{}{}.txt", container_name, sfx);
-        self.root.join(file_name)
+    pub fn logs(
+        &self,
+        container_kind: ContainerKind,
+        kind: LogsKind,
+        container_name: &str,
+        size: usize,
+    ) -> PathBuf {
+        self.logs_file(&logs_file_name(container_kind, kind, container_name), size)
+    }
+    /// logs of container run started after the dump (identified by its
+    /// restart count), followed in watch mode
+    pub fn restarted_logs(
+        &self,
+        container_kind: ContainerKind,
+        container_name: &str,
+        restart_count: i32,
+    ) -> PathBuf {
+        let file_name = logs_file_name(container_kind, LogsKind::Current, container_name);
+        let stem = file_name.strip_suffix(".txt").unwrap_or(&file_name);
+        self.artifact(&format!("{}.restart-{}.txt", stem, restart_count))
+    }
+    /// `n`-th part of logs followed in watch mode into `logs`, moved aside
+    /// by rotation
+    pub fn rotated_logs(&self, logs: &Path, n: u32) -> PathBuf {
+        let mut file_name = logs.as_os_str().to_owned();
+        file_name.push(format!(".{}", n));
+        PathBuf::from(file_name)
+    }
+    /// logs of all containers of the pod, ordered by time
+    pub fn merged_logs(&self, size: usize) -> PathBuf {
+        self.logs_file("logs-merged.txt", size)
+    }
+    /// `stats/summary` of the node kubelet
+    pub fn kubelet_stats(&self) -> PathBuf {
+        self.artifact("stats-summary.json")
+    }
+    /// effective configuration of the node kubelet
+    pub fn kubelet_config(&self) -> PathBuf {
+        self.artifact("kubelet-config.json")
+    }
+    /// log file served by the node kubelet
+    pub fn node_logs(&self, file_name: &str) -> PathBuf {
+        self.artifact(&format!("node-logs/{}", file_name))
+    }
+    /// resource usage from `metrics.k8s.io`
+    pub fn resource_metrics(&self) -> PathBuf {
+        self.artifact("metrics.json")
+    }
+    /// snapshot of Prometheus metrics exposed by the pod
+    pub fn metrics(&self) -> PathBuf {
+        self.artifact("metrics.txt")
+    }
+    /// response of pod endpoint, saved by probe
+    pub fn probe_output(&self, probe: &str, file_name: &str) -> PathBuf {
+        self.artifact(&format!("probe-{}/{}", probe, file_name))
+    }
+    /// output of command run in the pod
+    pub fn exec_output(&self, file_name: &str) -> PathBuf {
+        self.artifact(&format!("exec-{}", file_name))
+    }
+    /// file copied from the pod, `path` is relative
+    pub fn copied_file(&self, name: &str, path: &Path) -> PathBuf {
+        self.artifact(&format!("files-{}/{}", name, path.display()))
     }
     // for configmaps and secrets
     pub fn data_piece(&self, key: &str) -> PathBuf {
-        self.root.join(format!("data-{}", key))
+        let file_name = format!("data-{}", key);
+        match shorten_component(&file_name) {
+            Some(short) => self.artifact(&short),
+            None => self.artifact(&file_name),
+        }
     }
     pub fn event_log(&self) -> PathBuf {
-        self.root.join("events.txt")
+        self.artifact("events.txt")
+    }
+}
+
+/// Names of files and directories which do not depend on cluster contents,
+/// e.g. `index.json`, `_kinds_` or `status.json`. Object artifacts are also
+/// included without the object name prefix used by flat layout (e.g.
+/// `json`), and compressed.
+pub fn fixed_names() -> HashSet<String> {
+    // components containing placeholders come from cluster contents
+    const PLACEHOLDER: &str = "{name}";
+    let layout = Layout::from_root(PathBuf::new(), Escape::None);
+    let resource = ApiResource {
+        group: String::new(),
+        version: "v1".to_string(),
+        api_version: "v1".to_string(),
+        kind: PLACEHOLDER.to_string(),
+        plural: PLACEHOLDER.to_string(),
+    };
+    let mut paths = vec![
+        layout.cluster_info(),
+        layout.cluster_version(),
+        layout.cluster_api_resources(),
+        layout.index(),
+        layout.checksums(),
+        layout.resource_versions(),
+        layout.incremental_manifest(),
+        layout.change_journal(),
+        layout.aggregated_api_unavailable(PLACEHOLDER),
+        layout.skipped_resources(),
+        layout.summary(),
+        layout.run_summary(),
+        layout.timings(),
+        layout.run_log(),
+        layout.report_index(),
+        layout.report_namespace_page(None),
+        layout.kind_link(&resource, None, PLACEHOLDER),
+        layout.kind_stream(&resource),
+        layout.kind_jsonl(&resource),
+        layout.namespace_stream(None),
+    ];
+    for format in &[
+        crate::graph::GraphFormat::Dot,
+        crate::graph::GraphFormat::Mermaid,
+    ] {
+        paths.push(layout.graph(format.extension()));
+    }
+    for signer in &[
+        crate::sign::Signer::Gpg(String::new()),
+        crate::sign::Signer::Cosign(PathBuf::new()),
+        crate::sign::Signer::Keyless,
+    ] {
+        paths.push(layout.checksums_signature(signer.extension()));
+    }
+    let variants = [
+        (None, None, None),
+        (Some(PLACEHOLDER), None, None),
+        (None, Some(Compression::Zstd), None),
+        (Some(PLACEHOLDER), Some(Compression::Zstd), None),
+        (None, None, Some(0)),
+    ];
+    for (prefix, compression, logs_gzip_threshold) in variants {
+        let object = ObjectLayout {
+            root: PathBuf::from("objects"),
+            prefix: prefix.map(ToString::to_string),
+            representation_name: None,
+            compression,
+            compress_min_size: 0,
+            logs_gzip_threshold,
+            shortened: false,
+            key: ObjectKey {
+                group: String::new(),
+                kind: PLACEHOLDER.to_string(),
+                namespace: None,
+                name: PLACEHOLDER.to_string(),
+            },
+            registry: ObjectRegistry::default(),
+        };
+        paths.extend(vec![
+            object.representation(),
+            object.truncation_marker(),
+            object.patch(),
+            object.status(),
+            object.scale(),
+            object.defaulted(),
+            object.defaults(),
+            object.merged_logs(1),
+            object.kubelet_stats(),
+            object.kubelet_config(),
+            object.node_logs(PLACEHOLDER),
+            object.resource_metrics(),
+            object.metrics(),
+            object.event_log(),
+        ]);
     }
+    let prefix = format!("{}.", PLACEHOLDER);
+    paths
+        .iter()
+        .flat_map(|path| path.iter())
+        .filter_map(|component| {
+            let component = component.to_str()?;
+            let component = component.strip_prefix(&prefix).unwrap_or(component);
+            if component.contains(PLACEHOLDER) {
+                None
+            } else {
+                Some(component.to_string())
+            }
+        })
+        .collect()
 }
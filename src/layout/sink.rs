@@ -0,0 +1,570 @@
+//! Sinks are destinations Storage writes files to. All paths passed to
+//! sinks are relative to the dump root.
+use super::ObjectRegistry;
+use crate::upload::{object_key, ObjectStore, Uploader};
+use anyhow::Context as _;
+use flate2::{write::GzEncoder, Compression};
+use std::{
+    collections::{hash_map, BTreeMap, HashMap, HashSet},
+    convert::TryFrom as _,
+    fs::File,
+    io::{BufWriter, Write as _},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use tokio::{
+    io::AsyncWriteExt as _,
+    sync::{Semaphore, SemaphorePermit},
+};
+
+/// Destination of dump files
+#[async_trait::async_trait]
+pub trait DumpSink: Send + Sync {
+    /// Creates (or overwrites) file
+    async fn write(&self, path: &Path, data: &[u8]) -> anyhow::Result<()>;
+
+    /// Returns false if sink can not append to files. Storage buffers
+    /// streams for such sinks and writes them when finished.
+    fn supports_append(&self) -> bool {
+        false
+    }
+
+    /// Appends data to the file, truncating it first if `first` is set
+    async fn append(&self, path: &Path, _data: &[u8], _first: bool) -> anyhow::Result<()> {
+        anyhow::bail!("appending to {} is not supported", path.display())
+    }
+
+    /// Directory files are written to, if sink is a plain directory tree.
+    /// Symlinks, moves and pruning are only possible in this case.
+    fn local_dir(&self) -> Option<&Path> {
+        None
+    }
+
+    /// Writes out data buffered by sink, so that files can be moved or
+    /// linked to
+    async fn flush(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called once after all files are written
+    async fn finish(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// How directory sink writes files, selected with `--io-mode`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum IoMode {
+    /// Each file is written as soon as it is received
+    Direct,
+    /// Like `Direct`, but each file is also fsync-ed
+    Sync,
+    /// Small files are written in batches, and streams are kept open until
+    /// dump is finished. Files appear on disk with a delay.
+    Batched,
+}
+
+impl std::str::FromStr for IoMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "direct" => Ok(IoMode::Direct),
+            "sync" => Ok(IoMode::Sync),
+            "batched" => Ok(IoMode::Batched),
+            _ => anyhow::bail!("unknown I/O mode: {}", s),
+        }
+    }
+}
+
+/// Files smaller than this are batched in `batched` mode
+const BATCHED_FILE_SIZE: usize = 64 * 1024;
+/// Batch is written when it holds this much data
+const BATCH_SIZE: usize = 1024 * 1024;
+
+/// Files waiting to be written, sorted so that files of one directory are
+/// written together
+#[derive(Default)]
+struct Batch {
+    files: BTreeMap<PathBuf, Vec<u8>>,
+    size: usize,
+}
+
+/// File descriptors left for API connections, kubectl and so on
+const RESERVED_FILES: usize = 64;
+
+/// Returns how many output files can be open at once without exceeding
+/// RLIMIT_NOFILE
+fn open_files_limit() -> usize {
+    let mut limit = 1024;
+    #[cfg(unix)]
+    {
+        let mut rlimit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: rlimit is valid for writes
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlimit) } == 0
+            && rlimit.rlim_cur != libc::RLIM_INFINITY
+        {
+            limit = usize::try_from(rlimit.rlim_cur).unwrap_or(usize::MAX);
+        }
+    }
+    limit.saturating_sub(RESERVED_FILES).clamp(4, 4096)
+}
+
+/// Plain directory tree
+pub struct DirSink {
+    root: PathBuf,
+    io_mode: IoMode,
+    /// Bounds number of simultaneously open files, excluding streams
+    open_files: Semaphore,
+    batch: Mutex<Batch>,
+    /// Streams kept open in `batched` mode
+    streams: Mutex<HashMap<PathBuf, BufWriter<File>>>,
+    /// Streams are closed when there are this many
+    max_streams: usize,
+    /// Directories created since last flush in `batched` mode
+    dirs: Mutex<HashSet<PathBuf>>,
+}
+
+impl DirSink {
+    pub fn new(root: &Path, io_mode: IoMode) -> DirSink {
+        let limit = open_files_limit();
+        tracing::debug!(limit, "limiting open output files");
+        let max_streams = match io_mode {
+            IoMode::Batched => limit / 2,
+            IoMode::Direct | IoMode::Sync => 0,
+        };
+        DirSink {
+            root: root.to_path_buf(),
+            io_mode,
+            open_files: Semaphore::new(limit - max_streams),
+            batch: Mutex::new(Batch::default()),
+            streams: Mutex::new(HashMap::new()),
+            max_streams,
+            dirs: Mutex::new(HashSet::new()),
+        }
+    }
+
+    async fn acquire_file(&self) -> SemaphorePermit<'_> {
+        self.open_files
+            .acquire()
+            .await
+            .expect("semaphore is never closed")
+    }
+
+    fn create_parent(&self, path: &Path) -> std::io::Result<()> {
+        let parent = path.parent().expect("Layout never returns root-path");
+        if self.dirs.lock().unwrap().contains(parent) {
+            return Ok(());
+        }
+        std::fs::create_dir_all(parent)?;
+        self.dirs.lock().unwrap().insert(parent.to_path_buf());
+        Ok(())
+    }
+
+    fn write_batch(&self, batch: Batch) -> anyhow::Result<()> {
+        for (path, data) in batch.files {
+            self.create_parent(&path)?;
+            replace_file(&path, &data)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes file without modifying previous one in place, because it may be
+/// hard link created by deduplication
+fn replace_file(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    match std::fs::remove_file(path) {
+        Ok(()) => (),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err.into()),
+    }
+    std::fs::write(path, data).with_context(|| format!("Failed to write to {}", path.display()))
+}
+
+/// Prepares file for appending (or truncating, if `first`) in place, which
+/// must not affect other hard links created by deduplication
+fn detach_file(path: &Path, first: bool) -> std::io::Result<()> {
+    if first {
+        return match std::fs::remove_file(path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        };
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match std::fs::metadata(path) {
+            Ok(meta) if meta.nlink() > 1 => {
+                let mut copy = path.as_os_str().to_owned();
+                copy.push(".detached");
+                std::fs::copy(path, &copy)?;
+                std::fs::rename(&copy, path)?;
+            }
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+#[async_trait::async_trait]
+impl DumpSink for DirSink {
+    async fn write(&self, path: &Path, data: &[u8]) -> anyhow::Result<()> {
+        let path = self.root.join(path);
+        let _permit = self.acquire_file().await;
+        if self.io_mode == IoMode::Batched {
+            let full = {
+                let mut batch = self.batch.lock().unwrap();
+                if let Some(previous) = batch.files.remove(&path) {
+                    batch.size -= previous.len();
+                }
+                if data.len() < BATCHED_FILE_SIZE {
+                    batch.size += data.len();
+                    batch.files.insert(path, data.to_vec());
+                    if batch.size < BATCH_SIZE {
+                        return Ok(());
+                    }
+                    std::mem::take(&mut *batch)
+                } else {
+                    drop(batch);
+                    return tokio::task::block_in_place(|| {
+                        self.create_parent(&path)?;
+                        replace_file(&path, data)
+                    });
+                }
+            };
+            return tokio::task::block_in_place(|| self.write_batch(full));
+        }
+        let parent = path.parent().expect("Layout never returns root-path");
+        tokio::fs::create_dir_all(parent).await?;
+        // file may be hard link created by deduplication, which must not
+        // be modified in place
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => (),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err.into()),
+        }
+        if self.io_mode == IoMode::Sync {
+            return tokio::task::block_in_place(|| {
+                let mut file = File::create(&path)?;
+                file.write_all(data)?;
+                file.sync_all()
+            })
+            .with_context(|| format!("Failed to write to {}", path.display()));
+        }
+        tokio::fs::write(&path, data)
+            .await
+            .with_context(|| format!("Failed to write to {}", path.display()))
+    }
+
+    fn supports_append(&self) -> bool {
+        true
+    }
+
+    async fn append(&self, path: &Path, data: &[u8], first: bool) -> anyhow::Result<()> {
+        let path = self.root.join(path);
+        if self.io_mode == IoMode::Batched {
+            return tokio::task::block_in_place(|| {
+                let mut streams = self.streams.lock().unwrap();
+                if !streams.contains_key(&path) && streams.len() >= self.max_streams {
+                    // any stream is closed, and reopened if appended to again
+                    let evicted = streams.keys().next().cloned();
+                    if let Some(mut stream) = evicted.and_then(|key| streams.remove(&key)) {
+                        stream.flush()?;
+                    }
+                }
+                let stream = match streams.entry(path) {
+                    hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                    hash_map::Entry::Vacant(entry) => {
+                        self.create_parent(entry.key())?;
+                        detach_file(entry.key(), first)?;
+                        // stream may have been closed by flush
+                        let file = std::fs::OpenOptions::new()
+                            .create(true)
+                            .write(true)
+                            .append(!first)
+                            .truncate(first)
+                            .open(entry.key())?;
+                        entry.insert(BufWriter::new(file))
+                    }
+                };
+                stream.write_all(data)?;
+                Ok(())
+            });
+        }
+        if first {
+            let parent = path.parent().expect("Layout never returns root-path");
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let _permit = self.acquire_file().await;
+        tokio::task::block_in_place(|| detach_file(&path, first))?;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(!first)
+            .truncate(first)
+            .open(&path)
+            .await?;
+        file.write_all(data).await?;
+        file.flush().await?;
+        if self.io_mode == IoMode::Sync {
+            file.sync_data().await?;
+        }
+        Ok(())
+    }
+
+    fn local_dir(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
+
+    async fn flush(&self) -> anyhow::Result<()> {
+        if self.io_mode != IoMode::Batched {
+            return Ok(());
+        }
+        let _permit = self.acquire_file().await;
+        tokio::task::block_in_place(|| {
+            let batch = std::mem::take(&mut *self.batch.lock().unwrap());
+            self.write_batch(batch)?;
+            for (_, mut stream) in self.streams.lock().unwrap().drain() {
+                stream.flush()?;
+            }
+            // directories may be moved after flush
+            self.dirs.lock().unwrap().clear();
+            Ok(())
+        })
+    }
+
+    async fn finish(&self) -> anyhow::Result<()> {
+        self.flush().await
+    }
+}
+
+enum ArchiveWriter {
+    /// Gzip-compressed tar archive
+    TarGz(tar::Builder<GzEncoder<File>>),
+    Zip(zip::ZipWriter<File>),
+}
+
+impl ArchiveWriter {
+    fn add_entry(&mut self, path: &Path, data: &[u8]) -> anyhow::Result<()> {
+        match self {
+            ArchiveWriter::TarGz(builder) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                let mtime = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default();
+                header.set_mtime(mtime.as_secs());
+                builder.append_data(&mut header, path, data)?;
+            }
+            ArchiveWriter::Zip(writer) => {
+                let options = zip::write::FileOptions::default()
+                    .compression_method(zip::CompressionMethod::Deflated);
+                // zip entries always use forward slashes
+                writer.start_file(object_key(path), options)?;
+                writer.write_all(data)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        match self {
+            ArchiveWriter::TarGz(builder) => {
+                builder.into_inner()?.finish()?;
+            }
+            ArchiveWriter::Zip(mut writer) => {
+                writer.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Single-file archive
+pub struct ArchiveSink {
+    /// `None` after sink is finished
+    writer: Mutex<Option<ArchiveWriter>>,
+}
+
+impl ArchiveSink {
+    pub fn tar_gz(out: &Path) -> anyhow::Result<ArchiveSink> {
+        let file = create_file(out)?;
+        let encoder = GzEncoder::new(file, Compression::default());
+        Ok(ArchiveSink::from_writer(ArchiveWriter::TarGz(
+            tar::Builder::new(encoder),
+        )))
+    }
+
+    pub fn zip(out: &Path) -> anyhow::Result<ArchiveSink> {
+        let file = create_file(out)?;
+        Ok(ArchiveSink::from_writer(ArchiveWriter::Zip(
+            zip::ZipWriter::new(file),
+        )))
+    }
+
+    fn from_writer(writer: ArchiveWriter) -> ArchiveSink {
+        ArchiveSink {
+            writer: Mutex::new(Some(writer)),
+        }
+    }
+}
+
+fn create_file(out: &Path) -> anyhow::Result<File> {
+    File::create(out).with_context(|| format!("failed to create {}", out.display()))
+}
+
+#[async_trait::async_trait]
+impl DumpSink for ArchiveSink {
+    async fn write(&self, path: &Path, data: &[u8]) -> anyhow::Result<()> {
+        tokio::task::block_in_place(|| {
+            self.writer
+                .lock()
+                .unwrap()
+                .as_mut()
+                .context("storage was already finished")?
+                .add_entry(path, data)
+        })
+    }
+
+    async fn finish(&self) -> anyhow::Result<()> {
+        tokio::task::block_in_place(|| {
+            self.writer
+                .lock()
+                .unwrap()
+                .take()
+                .context("storage was already finished")?
+                .finish()
+        })
+    }
+}
+
+const SQLITE_SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS artifacts (
+    path TEXT PRIMARY KEY NOT NULL,
+    api_group TEXT,
+    kind TEXT,
+    namespace TEXT,
+    name TEXT,
+    file TEXT NOT NULL,
+    data BLOB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS artifacts_by_object ON artifacts (api_group, kind, namespace, name);
+CREATE VIEW IF NOT EXISTS objects AS
+    SELECT api_group, kind, namespace, name, data FROM artifacts
+    WHERE name IS NOT NULL AND file LIKE 'raw.json%';
+CREATE VIEW IF NOT EXISTS events AS
+    SELECT api_group, kind, namespace, name, data FROM artifacts
+    WHERE name IS NOT NULL AND file = 'events.txt';
+CREATE VIEW IF NOT EXISTS logs AS
+    SELECT namespace, name AS pod, file, data FROM artifacts
+    WHERE name IS NOT NULL AND file LIKE 'logs-%';
+";
+
+/// Number of files written in one SQLite transaction, so that interrupted
+/// dump keeps most of its content
+const SQLITE_BATCH: usize = 1000;
+
+/// SQLite database, with one row per file
+pub struct SqliteSink {
+    /// Connection with open transaction, and number of files written in it
+    conn: Mutex<(rusqlite::Connection, usize)>,
+    /// Dump root, registry is keyed by paths produced by Layout
+    root: PathBuf,
+    registry: ObjectRegistry,
+}
+
+impl SqliteSink {
+    pub fn open(db: &Path, root: &Path, registry: &ObjectRegistry) -> anyhow::Result<SqliteSink> {
+        let conn = rusqlite::Connection::open(db)
+            .with_context(|| format!("failed to open {}", db.display()))?;
+        conn.execute_batch(SQLITE_SCHEMA)?;
+        conn.execute_batch("BEGIN")?;
+        Ok(SqliteSink {
+            conn: Mutex::new((conn, 0)),
+            root: root.to_path_buf(),
+            registry: registry.clone(),
+        })
+    }
+
+    /// Commits files written so far, and starts new transaction
+    fn commit(&self) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.0.execute_batch("COMMIT; BEGIN")?;
+        conn.1 = 0;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl DumpSink for SqliteSink {
+    async fn write(&self, path: &Path, data: &[u8]) -> anyhow::Result<()> {
+        let (key, file) = match self.registry.lookup(&self.root.join(path)) {
+            Some((key, file)) => (Some(key), file),
+            None => (None, path.to_string_lossy().into_owned()),
+        };
+        tokio::task::block_in_place(|| {
+            let mut conn = self.conn.lock().unwrap();
+            conn.0.execute(
+                "INSERT OR REPLACE INTO artifacts (path, api_group, kind, namespace, name, file, data)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                rusqlite::params![
+                    path.to_string_lossy().into_owned(),
+                    key.as_ref().map(|k| &k.group),
+                    key.as_ref().map(|k| &k.kind),
+                    key.as_ref().and_then(|k| k.namespace.as_ref()),
+                    key.as_ref().map(|k| &k.name),
+                    file,
+                    data,
+                ],
+            )?;
+            conn.1 += 1;
+            if conn.1 >= SQLITE_BATCH {
+                conn.0.execute_batch("COMMIT; BEGIN")?;
+                conn.1 = 0;
+            }
+            Ok::<_, rusqlite::Error>(())
+        })?;
+        Ok(())
+    }
+
+    async fn flush(&self) -> anyhow::Result<()> {
+        tokio::task::block_in_place(|| self.commit())?;
+        Ok(())
+    }
+
+    async fn finish(&self) -> anyhow::Result<()> {
+        tokio::task::block_in_place(|| self.conn.lock().unwrap().0.execute_batch("COMMIT"))?;
+        Ok(())
+    }
+}
+
+/// Remote object store, each file becomes an object. Files are uploaded
+/// in background as soon as they are written.
+pub struct ObjectStoreSink {
+    uploader: Uploader,
+}
+
+impl ObjectStoreSink {
+    pub fn new(store: Arc<dyn ObjectStore>) -> ObjectStoreSink {
+        ObjectStoreSink {
+            uploader: Uploader::new(store),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl DumpSink for ObjectStoreSink {
+    async fn write(&self, path: &Path, data: &[u8]) -> anyhow::Result<()> {
+        self.uploader.submit(object_key(path), data.to_vec()).await
+    }
+
+    async fn finish(&self) -> anyhow::Result<()> {
+        self.uploader.wait().await
+    }
+}
@@ -0,0 +1,886 @@
+//! Storage is where files produced by the dump physically end up
+use super::{
+    sink::{ArchiveSink, DirSink, DumpSink, IoMode, ObjectStoreSink, SqliteSink},
+    ObjectRegistry,
+};
+use crate::{
+    encrypt::{Encryption, Scope},
+    sign::Signer,
+};
+use anyhow::Context as _;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{hash_map, BTreeMap, HashMap, HashSet},
+    io::{Read as _, Write as _},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// Kind of storage, selected with `--backend`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    Dir,
+    TarGz,
+    Zip,
+    Sqlite,
+    /// Object store, selected by giving URL (e.g. `gs://bucket/path`) as
+    /// output path
+    Remote,
+}
+
+impl std::str::FromStr for BackendKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dir" => Ok(BackendKind::Dir),
+            "tar" => Ok(BackendKind::TarGz),
+            "zip" => Ok(BackendKind::Zip),
+            "sqlite" => Ok(BackendKind::Sqlite),
+            _ => anyhow::bail!("unknown backend: {}", s),
+        }
+    }
+}
+
+impl BackendKind {
+    /// Guesses backend from output path: URLs denote object stores,
+    /// `.tar.gz`, `.tgz`, `.zip`, `.sqlite` and `.db` are recognized,
+    /// everything else is treated as directory
+    fn detect(out: &Path) -> BackendKind {
+        if out.to_string_lossy().contains("://") {
+            return BackendKind::Remote;
+        }
+        let file_name = out
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+        if file_name.ends_with(".tar.gz") || file_name.ends_with(".tgz") {
+            BackendKind::TarGz
+        } else if file_name.ends_with(".zip") {
+            BackendKind::Zip
+        } else if file_name.ends_with(".sqlite") || file_name.ends_with(".db") {
+            BackendKind::Sqlite
+        } else {
+            BackendKind::Dir
+        }
+    }
+}
+
+/// What to do if output already exists (hidden files, such as `.git`, do
+/// not count)
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ExistingOutput {
+    /// Fail
+    Refuse,
+    /// Delete previous content
+    Force,
+    /// Write new files alongside old ones, overwriting files with same names
+    Merge,
+}
+
+/// Writes files into directory, archive, database or remote store. All
+/// paths passed to Storage must be produced by Layout (i.e. start with its
+/// root).
+pub struct Storage {
+    root: PathBuf,
+    /// If set, everything is written here and moved to `root` when storage
+    /// is finished
+    staging: Option<PathBuf>,
+    /// If set, archive is split into parts of this size when finished
+    split_size: Option<u64>,
+    /// Each file is encrypted before it is passed to sink
+    file_encryption: Option<Encryption>,
+    /// Only files of Secrets are encrypted
+    secrets_only: bool,
+    /// Files which are never encrypted
+    plaintext: HashSet<PathBuf>,
+    /// Used to find out which object file belongs to
+    registry: ObjectRegistry,
+    /// Whole output file is encrypted when finished
+    output_encryption: Option<Encryption>,
+    sink: Box<dyn DumpSink>,
+    /// All files written so far, as stored (i.e. with encryption extension)
+    written: Mutex<HashSet<PathBuf>>,
+    /// Streams for sinks that can not append are kept in memory until the
+    /// end of the dump
+    pending_streams: Mutex<HashMap<PathBuf, Vec<u8>>>,
+    /// Running SHA-256 of every written file, keyed by relative path
+    checksums: Mutex<BTreeMap<PathBuf, Sha256>>,
+    /// Present if files with identical content are hard-linked
+    dedup: Option<Mutex<Dedup>>,
+    /// Total size of written data, as stored
+    bytes_written: AtomicU64,
+}
+
+/// Files written so far, for deduplication
+#[derive(Default)]
+struct Dedup {
+    /// First file with given SHA-256, relative to root
+    by_digest: HashMap<Vec<u8>, PathBuf>,
+    by_path: HashMap<PathBuf, Vec<u8>>,
+    /// Number of files which were hard-linked instead of written
+    files: usize,
+    /// Total size of such files
+    bytes: u64,
+}
+
+/// Where lock guarding the output lives: next to the output, so that it
+/// survives output being replaced (e.g. by atomic dump). Remote outputs are
+/// not locked.
+pub fn lock_path(out: &Path, kind: Option<BackendKind>) -> Option<PathBuf> {
+    match kind.unwrap_or_else(|| BackendKind::detect(out)) {
+        BackendKind::Remote => None,
+        _ => {
+            let mut path = out.as_os_str().to_os_string();
+            path.push(".lock");
+            Some(PathBuf::from(path))
+        }
+    }
+}
+
+impl Storage {
+    /// Creates storage of the given kind. If kind is not specified, it is
+    /// detected from the output path. Atomic storage writes to
+    /// `<out>.partial` and renames it to `out` when finished. If
+    /// `split_size` is given, archive is split into parts of at most that
+    /// size when finished. Encryption is applied to each file for
+    /// directories and remote stores (or when only Secrets are encrypted),
+    /// and to the whole output otherwise. `io_mode` only affects directories.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        out: &Path,
+        kind: Option<BackendKind>,
+        atomic: bool,
+        existing: ExistingOutput,
+        split_size: Option<u64>,
+        encryption: Option<(Encryption, Scope)>,
+        registry: &ObjectRegistry,
+        io_mode: IoMode,
+    ) -> anyhow::Result<Storage> {
+        let kind = kind.unwrap_or_else(|| BackendKind::detect(out));
+        let secrets_only = matches!(encryption, Some((_, Scope::Secrets)));
+        let encryption = encryption.map(|(encryption, _)| encryption);
+        let (file_encryption, output_encryption) = match kind {
+            BackendKind::Dir | BackendKind::Remote => (encryption, None),
+            _ if secrets_only => (encryption, None),
+            _ => (None, encryption),
+        };
+        if split_size.is_some() && kind != BackendKind::TarGz && kind != BackendKind::Zip {
+            anyhow::bail!("only archives can be split");
+        }
+        if split_size == Some(0) {
+            anyhow::bail!("split size must be positive");
+        }
+        if kind == BackendKind::Remote {
+            if atomic {
+                anyhow::bail!("atomic dump is not supported for remote outputs");
+            }
+            let target: crate::upload::UploadTarget = out.to_string_lossy().parse()?;
+            let mut storage =
+                Storage::with_sink(out, None, Box::new(ObjectStoreSink::new(target.store())));
+            storage.file_encryption = file_encryption;
+            storage.secrets_only = secrets_only;
+            storage.registry = registry.clone();
+            return Ok(storage);
+        }
+        let output = match &output_encryption {
+            Some(encryption) => with_extension(out, encryption.extension()),
+            None => out.to_path_buf(),
+        };
+        if has_content(&output)? || !split_parts(&output)?.is_empty() {
+            match existing {
+                ExistingOutput::Refuse => anyhow::bail!(
+                    "{} already exists; use --force to replace it or --merge to write into it",
+                    output.display()
+                ),
+                ExistingOutput::Merge if atomic => {
+                    anyhow::bail!("atomic dump always replaces output, so it can not be merged")
+                }
+                ExistingOutput::Merge
+                    if kind != BackendKind::Dir && kind != BackendKind::Sqlite =>
+                {
+                    anyhow::bail!("archives can not be merged")
+                }
+                ExistingOutput::Merge if output_encryption.is_some() => {
+                    anyhow::bail!("encrypted output can not be merged")
+                }
+                ExistingOutput::Merge => (),
+                // atomic dump replaces output when finished
+                ExistingOutput::Force if atomic => (),
+                ExistingOutput::Force => {
+                    if kind == BackendKind::Dir {
+                        clear_dir(out)?;
+                    } else {
+                        remove_path(&output)?;
+                    }
+                    for part in split_parts(&output)? {
+                        remove_path(&part)?;
+                    }
+                }
+            }
+        }
+        let staging = if atomic {
+            let mut staging = out.as_os_str().to_os_string();
+            staging.push(".partial");
+            let staging = PathBuf::from(staging);
+            // leftovers of interrupted run
+            remove_path(&staging)?;
+            Some(staging)
+        } else {
+            None
+        };
+        let target = staging.as_deref().unwrap_or(out);
+        if kind != BackendKind::Dir {
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+        let sink: Box<dyn DumpSink> = match kind {
+            BackendKind::Dir => Box::new(DirSink::new(target, io_mode)),
+            BackendKind::TarGz => Box::new(ArchiveSink::tar_gz(target)?),
+            BackendKind::Zip => Box::new(ArchiveSink::zip(target)?),
+            BackendKind::Sqlite => Box::new(SqliteSink::open(target, out, registry)?),
+            BackendKind::Remote => unreachable!("handled above"),
+        };
+        let mut storage = Storage::with_sink(out, staging, sink);
+        storage.split_size = split_size;
+        storage.file_encryption = file_encryption;
+        storage.secrets_only = secrets_only;
+        storage.registry = registry.clone();
+        storage.output_encryption = output_encryption;
+        Ok(storage)
+    }
+
+    fn with_sink(root: &Path, staging: Option<PathBuf>, sink: Box<dyn DumpSink>) -> Storage {
+        Storage {
+            root: root.to_path_buf(),
+            staging,
+            split_size: None,
+            file_encryption: None,
+            secrets_only: false,
+            plaintext: HashSet::new(),
+            registry: ObjectRegistry::default(),
+            output_encryption: None,
+            sink,
+            written: Mutex::new(HashSet::new()),
+            pending_streams: Mutex::new(HashMap::new()),
+            checksums: Mutex::new(BTreeMap::new()),
+            dedup: None,
+            bytes_written: AtomicU64::new(0),
+        }
+    }
+
+    /// Makes storage hard-link files with identical content to each other
+    /// instead of writing them again. Requires directory backend.
+    pub fn enable_dedup(&mut self) -> anyhow::Result<()> {
+        if self.sink.local_dir().is_none() {
+            anyhow::bail!("deduplication requires directory backend");
+        }
+        self.dedup = Some(Mutex::new(Dedup::default()));
+        Ok(())
+    }
+
+    /// Makes storage never encrypt given files, e.g. manifests which
+    /// contain no secret data and must stay readable for verification
+    pub fn keep_plaintext(&mut self, paths: impl IntoIterator<Item = PathBuf>) {
+        self.plaintext.extend(paths);
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// Returns number and total size of deduplicated files
+    pub fn deduplicated(&self) -> Option<(usize, u64)> {
+        let dedup = self.dedup.as_ref()?.lock().unwrap();
+        Some((dedup.files, dedup.bytes))
+    }
+
+    /// If file with the same content was already written, hard-links
+    /// `relative` to it. Returns false if file must be written instead.
+    async fn link_duplicate(
+        &self,
+        relative: &Path,
+        digest: Vec<u8>,
+        size: u64,
+    ) -> anyhow::Result<bool> {
+        let (dedup, local_dir) = match (&self.dedup, self.sink.local_dir()) {
+            (Some(dedup), Some(local_dir)) => (dedup, local_dir),
+            _ => return Ok(false),
+        };
+        let original = {
+            let mut dedup = dedup.lock().unwrap();
+            let previous = dedup.by_path.insert(relative.to_path_buf(), digest.clone());
+            // file is overwritten, so it no longer has previous content
+            if let Some(previous) = previous {
+                if dedup.by_digest.get(&previous).map(PathBuf::as_path) == Some(relative) {
+                    dedup.by_digest.remove(&previous);
+                }
+            }
+            match dedup.by_digest.entry(digest.clone()) {
+                hash_map::Entry::Occupied(entry) => entry.get().clone(),
+                hash_map::Entry::Vacant(entry) => {
+                    entry.insert(relative.to_path_buf());
+                    return Ok(false);
+                }
+            }
+        };
+        if original == relative {
+            return Ok(false);
+        }
+        // original may still be buffered
+        self.sink.flush().await?;
+        let link = local_dir.join(relative);
+        let parent = link.parent().expect("Layout never returns root-path");
+        tokio::fs::create_dir_all(parent).await?;
+        match tokio::fs::remove_file(&link).await {
+            Ok(()) => (),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err.into()),
+        }
+        let mut dedup = dedup.lock().unwrap();
+        match std::fs::hard_link(local_dir.join(&original), &link) {
+            Ok(()) => {
+                dedup.files += 1;
+                dedup.bytes += size;
+                Ok(true)
+            }
+            // e.g. original was moved away
+            Err(_) => {
+                dedup.by_digest.insert(digest, relative.to_path_buf());
+                Ok(false)
+            }
+        }
+    }
+
+    fn relative<'a>(&self, path: &'a Path) -> &'a Path {
+        path.strip_prefix(&self.root)
+            .expect("Storage only accepts paths produced by Layout")
+    }
+
+    /// Where file is actually written by directory sink
+    fn physical(&self, local_dir: &Path, path: &Path) -> PathBuf {
+        local_dir.join(self.relative(path))
+    }
+
+    /// Returns encryption which should be applied to the file
+    fn file_encryption(&self, path: &Path) -> Option<&Encryption> {
+        let encryption = self.file_encryption.as_ref()?;
+        if self.plaintext.contains(path) {
+            return None;
+        }
+        if self.secrets_only {
+            let (key, _) = self.registry.lookup(path)?;
+            if !key.group.is_empty() || key.kind != "Secret" {
+                return None;
+            }
+        }
+        Some(encryption)
+    }
+
+    /// Path file is stored at: with extension of encryption, if file is
+    /// encrypted
+    fn stored(&self, path: &Path) -> PathBuf {
+        match self.file_encryption(path) {
+            Some(encryption) => with_extension(path, encryption.extension()),
+            None => path.to_path_buf(),
+        }
+    }
+
+    /// Like `stored`, but keeps directories (and symlinks) unchanged
+    fn stored_entry(&self, path: &Path) -> PathBuf {
+        let stored = self.stored(path);
+        if self.written.lock().unwrap().contains(&stored) {
+            stored
+        } else {
+            path.to_path_buf()
+        }
+    }
+
+    /// Final location of the output, which differs from the output path
+    /// when whole output is encrypted
+    pub fn output(&self) -> PathBuf {
+        match &self.output_encryption {
+            Some(encryption) => with_extension(&self.root, encryption.extension()),
+            None => self.root.clone(),
+        }
+    }
+
+    /// Returns true if file was written during this dump
+    pub fn exists(&self, path: &Path) -> bool {
+        self.written.lock().unwrap().contains(&self.stored(path))
+    }
+
+    /// Reads back file written during this dump. Returns None if it was not
+    /// written, or can not be read (because it is encrypted, or sink is not
+    /// a directory).
+    pub async fn read(&self, path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+        let local_dir = match self.sink.local_dir() {
+            Some(local_dir) => local_dir,
+            None => return Ok(None),
+        };
+        if !self.exists(path) || self.file_encryption(path).is_some() {
+            return Ok(None);
+        }
+        self.sink.flush().await?;
+        let data = tokio::fs::read(self.physical(local_dir, path)).await?;
+        if self.registry.is_compressed(path) {
+            return Ok(Some(tokio::task::block_in_place(|| {
+                zstd::stream::decode_all(&data[..])
+            })?));
+        }
+        Ok(Some(data))
+    }
+
+    /// Creates (or overwrites) file. Files ObjectLayout chose to compress
+    /// are compressed transparently, and then encrypted if requested.
+    pub async fn write(&self, path: &Path, data: impl AsRef<[u8]>) -> anyhow::Result<()> {
+        let compressed;
+        let encrypted;
+        let mut data = data.as_ref();
+        if self.registry.is_compressed(path) {
+            compressed = tokio::task::block_in_place(|| zstd::stream::encode_all(data, 0))?;
+            data = &compressed[..];
+        }
+        let encryption = self.file_encryption(path);
+        if let Some(encryption) = encryption {
+            encrypted = encryption.encrypt(data).await?;
+            data = &encrypted[..];
+        }
+        let stored = self.stored(path);
+        let relative = self.relative(&stored);
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        let digest = hasher.clone().finalize().to_vec();
+        if !self
+            .link_duplicate(relative, digest, data.len() as u64)
+            .await?
+        {
+            self.sink.write(relative, data).await?;
+        }
+        self.checksums
+            .lock()
+            .unwrap()
+            .insert(relative.to_path_buf(), hasher);
+        self.written.lock().unwrap().insert(stored);
+        self.bytes_written
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        // when only Secrets are encrypted, owner of the file tells whether
+        // it is stored with encryption extension
+        if !self.secrets_only || encryption.is_none() {
+            self.registry.forget(path);
+        }
+        Ok(())
+    }
+
+    /// Appends data to the file. File is truncated when it is appended to
+    /// for the first time, so that stale content of previous runs is dropped.
+    pub async fn append(&self, path: &Path, data: impl AsRef<[u8]>) -> anyhow::Result<()> {
+        let data = data.as_ref();
+        // encrypted files can not be appended to
+        if self.sink.supports_append() && self.file_encryption(path).is_none() {
+            let first_write = self.written.lock().unwrap().insert(path.to_path_buf());
+            self.sink
+                .append(self.relative(path), data, first_write)
+                .await?;
+            self.checksums
+                .lock()
+                .unwrap()
+                .entry(self.relative(path).to_path_buf())
+                .or_default()
+                .update(data);
+            self.bytes_written
+                .fetch_add(data.len() as u64, Ordering::Relaxed);
+        } else {
+            self.pending_streams
+                .lock()
+                .unwrap()
+                .entry(path.to_path_buf())
+                .or_default()
+                .extend_from_slice(data);
+        }
+        Ok(())
+    }
+
+    /// Writes out files buffered by `batched` I/O mode, so that they are
+    /// on disk before progress is recorded
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        self.sink.flush().await
+    }
+
+    /// Returns true if sink can store symlinks
+    pub fn supports_symlinks(&self) -> bool {
+        self.sink.local_dir().is_some()
+    }
+
+    /// Creates symlink at `link` pointing to `target` (both produced by
+    /// Layout). Link target is stored relative, so dump can be moved.
+    pub async fn symlink(&self, link: &Path, target: &Path) -> anyhow::Result<()> {
+        let local_dir = self
+            .sink
+            .local_dir()
+            .context("symlinks are only supported by directory backend")?;
+        let parent = link.parent().expect("Layout never returns root-path");
+        let depth = self.relative(parent).components().count();
+        let physical_link = self.physical(local_dir, link);
+        tokio::fs::create_dir_all(physical_link.parent().expect("checked above")).await?;
+        let mut relative_target = PathBuf::new();
+        for _ in 0..depth {
+            relative_target.push("..");
+        }
+        relative_target.push(self.relative(&self.stored_entry(target)));
+        match tokio::fs::remove_file(&physical_link).await {
+            Ok(()) => (),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err.into()),
+        }
+        create_symlink(&relative_target, &physical_link)
+            .with_context(|| format!("Failed to create symlink {}", link.display()))?;
+        self.written.lock().unwrap().insert(link.to_path_buf());
+        Ok(())
+    }
+
+    /// Returns true if already written files can be moved
+    pub fn supports_moves(&self) -> bool {
+        self.sink.local_dir().is_some()
+    }
+
+    /// Moves file or directory (with everything inside) to another place
+    pub async fn rename(&self, from: &Path, to: &Path) -> anyhow::Result<()> {
+        let local_dir = self
+            .sink
+            .local_dir()
+            .context("moving files is only supported by directory backend")?;
+        self.sink.flush().await?;
+        let (from, to) = if self.stored_entry(from) != from {
+            (self.stored(from), self.stored(to))
+        } else {
+            (from.to_path_buf(), to.to_path_buf())
+        };
+        let (from, to) = (from.as_path(), to.as_path());
+        let physical_from = self.physical(local_dir, from);
+        let physical_to = self.physical(local_dir, to);
+        let parent = physical_to
+            .parent()
+            .expect("Layout never returns root-path");
+        tokio::fs::create_dir_all(parent).await?;
+        tokio::fs::rename(&physical_from, &physical_to)
+            .await
+            .with_context(|| format!("Failed to move {} to {}", from.display(), to.display()))?;
+        let moved = |path: &Path, from: &Path, to: &Path| {
+            path.strip_prefix(from).ok().map(|rest| to.join(rest))
+        };
+        let mut written = self.written.lock().unwrap();
+        *written = written
+            .drain()
+            .map(|path| moved(&path, from, to).unwrap_or(path))
+            .collect();
+        let (from, to) = (self.relative(from), self.relative(to));
+        let mut checksums = self.checksums.lock().unwrap();
+        *checksums = std::mem::take(&mut *checksums)
+            .into_iter()
+            .map(|(path, hasher)| (moved(&path, from, to).unwrap_or(path), hasher))
+            .collect();
+        if let Some(dedup) = &self.dedup {
+            let mut dedup = dedup.lock().unwrap();
+            for original in dedup.by_digest.values_mut() {
+                if let Some(path) = moved(original, from, to) {
+                    *original = path;
+                }
+            }
+            dedup.by_path = std::mem::take(&mut dedup.by_path)
+                .into_iter()
+                .map(|(path, digest)| (moved(&path, from, to).unwrap_or(path), digest))
+                .collect();
+        }
+        Ok(())
+    }
+
+    /// Deletes already written file or directory (with everything inside)
+    pub async fn remove(&self, path: &Path) -> anyhow::Result<()> {
+        let local_dir = self
+            .sink
+            .local_dir()
+            .context("deleting files is only supported by directory backend")?;
+        self.sink.flush().await?;
+        let path = self.stored_entry(path);
+        let physical = self.physical(local_dir, &path);
+        tokio::task::block_in_place(|| remove_path(&physical))?;
+        self.written
+            .lock()
+            .unwrap()
+            .retain(|written| !written.starts_with(&path));
+        let relative = self.relative(&path);
+        self.checksums
+            .lock()
+            .unwrap()
+            .retain(|checksummed, _| !checksummed.starts_with(relative));
+        Ok(())
+    }
+
+    /// Flushes all buffered data and writes checksums of all files (in
+    /// `sha256sum` format) to `checksums_path`, optionally signing them.
+    /// Must be called once after dump is finished.
+    pub async fn finish(
+        &self,
+        checksums_path: &Path,
+        signature: Option<(&Signer, PathBuf)>,
+    ) -> anyhow::Result<()> {
+        let streams = std::mem::take(&mut *self.pending_streams.lock().unwrap());
+        for (path, data) in streams {
+            // this is the first write of the stream for these sinks
+            self.write(&path, data).await?;
+        }
+        let checksums = std::mem::take(&mut *self.checksums.lock().unwrap());
+        let mut manifest = String::new();
+        for (path, hasher) in checksums {
+            manifest.push_str(&format!("{:x}  {}\n", hasher.finalize(), path.display()));
+        }
+        if let Some((signer, signature_path)) = signature {
+            let signature = signer
+                .sign(manifest.as_bytes())
+                .await
+                .context("failed to sign checksums")?;
+            self.write(&signature_path, signature).await?;
+        }
+        self.write(checksums_path, manifest).await?;
+        self.sink.finish().await?;
+        if let Some(staging) = &self.staging {
+            tokio::task::block_in_place(|| publish(staging, &self.root))?;
+        }
+        if let Some(encryption) = &self.output_encryption {
+            encryption.encrypt_file(&self.root, &self.output()).await?;
+            remove_path(&self.root)?;
+        }
+        if let Some(split_size) = self.split_size {
+            let output = self.output();
+            let parts = tokio::task::block_in_place(|| split_file(&output, split_size))?;
+            tracing::info!("Split archive into {} parts", parts);
+        }
+        Ok(())
+    }
+
+    /// Deletes files which were not written during this dump (e.g. files of
+    /// objects which no longer exist), along with directories that become
+    /// empty. Hidden files and directories (such as `.git`) are kept.
+    /// Returns number of deleted files. Must be called after storage is
+    /// finished.
+    pub fn prune(&self) -> anyhow::Result<usize> {
+        if self.sink.local_dir().is_none() {
+            anyhow::bail!("pruning is only supported by directory backend");
+        }
+        let written = self.written.lock().unwrap();
+        tokio::task::block_in_place(|| prune_dir(&self.root, &written))
+    }
+
+    /// Prepares directory left by interrupted run to be written into again:
+    /// removes its `INCOMPLETE` marker and treats files it contains as
+    /// written during this dump, so that they are checksummed and not
+    /// pruned. Returns number of such files.
+    pub fn resume(&self) -> anyhow::Result<usize> {
+        let local_dir = match self.sink.local_dir() {
+            Some(dir) => dir.to_path_buf(),
+            None => anyhow::bail!("only directory dumps can be resumed"),
+        };
+        let marker = self.physical(&local_dir, &self.root.join("INCOMPLETE"));
+        if marker.exists() {
+            std::fs::remove_file(&marker)?;
+        }
+        let mut files = Vec::new();
+        crate::upload::collect_files(&local_dir, &local_dir, &mut files)?;
+        tokio::task::block_in_place(|| {
+            let mut adopted = 0;
+            for (path, key) in files {
+                // checksums are computed again when dump is finished
+                if key.starts_with("SHA256SUMS")
+                    || std::fs::symlink_metadata(&path)?.file_type().is_symlink()
+                {
+                    continue;
+                }
+                let mut hasher = Sha256::new();
+                hasher.update(std::fs::read(&path)?);
+                let relative = PathBuf::from(key);
+                self.checksums
+                    .lock()
+                    .unwrap()
+                    .insert(relative.clone(), hasher);
+                self.written
+                    .lock()
+                    .unwrap()
+                    .insert(self.root.join(relative));
+                adopted += 1;
+            }
+            Ok(adopted)
+        })
+    }
+
+    /// Leaves a note explaining why dump is incomplete. Only directories
+    /// can be marked; for other backends `.partial` suffix (when atomic)
+    /// is the only indication.
+    pub async fn mark_incomplete(&self, reason: &str) {
+        let local_dir = match self.sink.local_dir() {
+            Some(dir) => dir,
+            None => return,
+        };
+        let marker = self.physical(local_dir, &self.root.join("INCOMPLETE"));
+        if let Err(err) = tokio::fs::write(&marker, format!("{}\n", reason)).await {
+            tracing::error!("Failed to write {}: {:#}", marker.display(), err);
+        }
+    }
+}
+
+/// Returns true if path is a file or a directory with non-hidden entries
+fn has_content(path: &Path) -> anyhow::Result<bool> {
+    match std::fs::metadata(path) {
+        Ok(meta) if meta.is_dir() => Ok(std::fs::read_dir(path)?
+            .filter_map(Result::ok)
+            .any(|item| !item.file_name().to_string_lossy().starts_with('.'))),
+        Ok(_) => Ok(true),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Removes all non-hidden entries of the directory
+fn clear_dir(dir: &Path) -> anyhow::Result<()> {
+    for item in std::fs::read_dir(dir)? {
+        let item = item?;
+        if !item.file_name().to_string_lossy().starts_with('.') {
+            remove_path(&item.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn prune_dir(dir: &Path, written: &HashSet<PathBuf>) -> anyhow::Result<usize> {
+    let mut deleted = 0;
+    for item in std::fs::read_dir(dir)? {
+        let item = item?;
+        if item.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = item.path();
+        if item.file_type()?.is_dir() {
+            deleted += prune_dir(&path, written)?;
+            if std::fs::read_dir(&path)?.next().is_none() {
+                std::fs::remove_dir(&path)?;
+            }
+        } else if !written.contains(&path) {
+            std::fs::remove_file(&path)
+                .with_context(|| format!("failed to remove {}", path.display()))?;
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+/// Appends `.<extension>` to the file name
+fn with_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_os_string();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+/// Returns parts (`<out>.000`, `<out>.001`, ...) of split archive, in order
+pub fn split_parts(out: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let (dir, name) = match (out.parent(), out.file_name()) {
+        (Some(dir), Some(name)) => (dir, name.to_string_lossy().into_owned()),
+        _ => return Ok(Vec::new()),
+    };
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut parts = Vec::new();
+    for item in entries {
+        let item_name = item?.file_name().to_string_lossy().into_owned();
+        let is_part = item_name
+            .strip_prefix(name.as_str())
+            .and_then(|rest| rest.strip_prefix('.'))
+            .is_some_and(|index| index.len() >= 3 && index.chars().all(|c| c.is_ascii_digit()));
+        if is_part {
+            let part = out.with_file_name(&item_name);
+            parts.push((item_name, part));
+        }
+    }
+    // indices are zero-padded, but can have more than 3 digits
+    parts.sort_by(|a, b| (a.0.len(), &a.0).cmp(&(b.0.len(), &b.0)));
+    Ok(parts.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Replaces `file` with parts of at most `part_size` bytes. Parts of
+/// previous dumps are deleted. Returns number of parts.
+fn split_file(file: &Path, part_size: u64) -> anyhow::Result<usize> {
+    for stale in split_parts(file)? {
+        remove_path(&stale)?;
+    }
+    let mut input =
+        std::fs::File::open(file).with_context(|| format!("failed to open {}", file.display()))?;
+    let total = input.metadata()?.len();
+    let mut count = 0;
+    let mut offset = 0;
+    // empty archive is still stored as one (empty) part
+    while count == 0 || offset < total {
+        let mut part_path = file.as_os_str().to_os_string();
+        part_path.push(format!(".{:03}", count));
+        let part_path = PathBuf::from(part_path);
+        let mut part = std::fs::File::create(&part_path)
+            .with_context(|| format!("failed to create {}", part_path.display()))?;
+        let copied = std::io::copy(&mut (&mut input).take(part_size), &mut part)?;
+        part.flush()?;
+        offset += copied;
+        count += 1;
+    }
+    std::fs::remove_file(file)?;
+    Ok(count)
+}
+
+/// Replaces `out` with completely written `staging`
+fn publish(staging: &Path, out: &Path) -> anyhow::Result<()> {
+    let mut old = out.as_os_str().to_os_string();
+    old.push(".old");
+    let old = PathBuf::from(old);
+    remove_path(&old)?;
+    let had_previous = out.exists();
+    if had_previous {
+        std::fs::rename(out, &old)
+            .with_context(|| format!("failed to move away previous {}", out.display()))?;
+    }
+    std::fs::rename(staging, out)
+        .with_context(|| format!("failed to move {} to {}", staging.display(), out.display()))?;
+    if had_previous {
+        remove_path(&old)?;
+    }
+    Ok(())
+}
+
+/// Removes file or directory, if it exists
+fn remove_path(path: &Path) -> anyhow::Result<()> {
+    let result = match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => Err(err),
+    };
+    result.with_context(|| format!("failed to remove {}", path.display()))
+}
+
+#[cfg(unix)]
+pub fn create_symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+pub fn create_symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_dir(target, link)
+}
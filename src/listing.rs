@@ -0,0 +1,144 @@
+//! Incremental parsing of list responses, so that objects can be processed
+//! while the rest of the list is still being received
+use kube::api::DynamicObject;
+use serde_json::Value;
+use std::{fmt, time::Duration};
+
+/// Status returned by API server instead of list
+#[derive(Debug)]
+pub struct StatusError {
+    pub code: u64,
+    pub message: String,
+    /// Delay suggested by server before request is retried
+    pub retry_after: Option<Duration>,
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "list failed: {}", self.message)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// Extracts items from JSON list as its bytes arrive. Only the item being
+/// received and list fields other than `items` are kept in memory.
+#[derive(Default)]
+pub struct ListSplitter {
+    /// Received bytes which may still be needed
+    buf: Vec<u8>,
+    /// Nesting depth of arrays and objects
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    /// Start of the string being read in top-level object (it may be key)
+    string_start: Option<usize>,
+    /// Last string read in top-level object
+    last_string: Option<String>,
+    /// Key whose value is being read in top-level object
+    key: Option<String>,
+    /// Inside `items` array
+    in_items: bool,
+    /// Start of the item being read
+    item_start: Option<usize>,
+    /// List without items
+    skeleton: Vec<u8>,
+}
+
+impl ListSplitter {
+    /// Consumes next chunk of the response, returning items completed by it
+    pub fn feed(&mut self, chunk: &[u8]) -> anyhow::Result<Vec<DynamicObject>> {
+        let scanned = self.buf.len();
+        self.buf.extend_from_slice(chunk);
+        let mut items = Vec::new();
+        for i in scanned..self.buf.len() {
+            let byte = self.buf[i];
+            let was_in_items = self.in_items;
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if byte == b'\\' {
+                    self.escaped = true;
+                } else if byte == b'"' {
+                    self.in_string = false;
+                    if let Some(start) = self.string_start.take() {
+                        self.last_string = Some(serde_json::from_slice(&self.buf[start..=i])?);
+                    }
+                }
+            } else {
+                match byte {
+                    b'"' => {
+                        self.in_string = true;
+                        if self.depth == 1 {
+                            self.string_start = Some(i);
+                        }
+                    }
+                    b':' if self.depth == 1 => self.key = self.last_string.take(),
+                    b',' if self.depth == 1 => self.key = None,
+                    b'{' | b'[' => {
+                        if self.depth == 1 && byte == b'[' && self.key.as_deref() == Some("items") {
+                            self.in_items = true;
+                        } else if self.in_items && self.depth == 2 {
+                            self.item_start = Some(i);
+                        }
+                        self.depth += 1;
+                    }
+                    b'}' | b']' => {
+                        self.depth = self
+                            .depth
+                            .checked_sub(1)
+                            .ok_or_else(|| anyhow::anyhow!("unbalanced list response"))?;
+                        if self.in_items && self.depth == 2 {
+                            let start = self.item_start.take().expect("item was started");
+                            items.push(serde_json::from_slice(&self.buf[start..=i])?);
+                        } else if self.in_items && self.depth == 1 {
+                            self.in_items = false;
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            if !was_in_items || !self.in_items {
+                self.skeleton.push(byte);
+            }
+        }
+        // drop everything which will not be looked at again
+        let keep_from = self
+            .item_start
+            .into_iter()
+            .chain(self.string_start)
+            .min()
+            .unwrap_or(self.buf.len());
+        self.buf.drain(..keep_from);
+        if let Some(start) = &mut self.item_start {
+            *start -= keep_from;
+        }
+        if let Some(start) = &mut self.string_start {
+            *start -= keep_from;
+        }
+        Ok(items)
+    }
+
+    /// Must be called after the whole response was fed. Returns list with
+    /// empty `items`, failing if server returned error instead of list.
+    pub fn finish(self) -> anyhow::Result<Value> {
+        if self.depth != 0 || self.in_string {
+            anyhow::bail!("list response is truncated");
+        }
+        let list: Value = serde_json::from_slice(&self.skeleton)?;
+        if list["kind"] == "Status" {
+            return Err(StatusError {
+                code: list["code"].as_u64().unwrap_or_default(),
+                message: list["message"]
+                    .as_str()
+                    .unwrap_or("unknown error")
+                    .to_string(),
+                retry_after: list["details"]["retryAfterSeconds"]
+                    .as_u64()
+                    .map(Duration::from_secs),
+            }
+            .into());
+        }
+        Ok(list)
+    }
+}
@@ -0,0 +1,172 @@
+//! Reading objects back from existing dumps
+use crate::index::Entry;
+use anyhow::Context as _;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+/// Object identity within a file: (kind, namespace, name). Listed objects
+/// usually do not have `kind`, so it is only known for some of them.
+pub type Key = (Option<String>, Option<String>, String);
+
+pub fn object_key(object: &Value) -> Option<Key> {
+    Some((
+        object["kind"].as_str().map(ToString::to_string),
+        object
+            .pointer("/metadata/namespace")
+            .and_then(Value::as_str)
+            .map(ToString::to_string),
+        object.pointer("/metadata/name")?.as_str()?.to_string(),
+    ))
+}
+
+/// Parses file containing one or several object representations, in any
+/// of the output formats
+pub fn parse_file(path: &Path) -> anyhow::Result<Vec<Value>> {
+    let mut data = std::fs::read(path)?;
+    let mut name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if let Some(stripped) = name.strip_suffix(".zst") {
+        data = zstd::stream::decode_all(&data[..])?;
+        name = stripped.to_string();
+    }
+    if name.ends_with(".age") || name.ends_with(".gpg") {
+        anyhow::bail!("encrypted files must be decrypted first");
+    }
+    let text = String::from_utf8(data).context("file is not utf-8")?;
+    if name.ends_with(".jsonl") {
+        text.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| Ok(serde_json::from_str(line)?))
+            .collect()
+    } else if name.ends_with(".yaml") {
+        // streams are produced by kube-dump, so documents are always
+        // separated by `---` lines
+        let mut documents = Vec::new();
+        let mut current = String::new();
+        for line in text.lines().chain(std::iter::once("---")) {
+            if line == "---" {
+                if !current.trim().is_empty() {
+                    documents.push(serde_yaml::from_str(&current)?);
+                }
+                current.clear();
+            } else {
+                current.push_str(line);
+                current.push('\n');
+            }
+        }
+        Ok(documents)
+    } else {
+        Ok(vec![serde_json::from_str(&text)?])
+    }
+}
+
+/// Reads `index.json` of the dump at `dir`
+pub fn read_index(dir: &Path) -> anyhow::Result<Vec<Entry>> {
+    let index_path = dir.join("index.json");
+    let index = std::fs::read(&index_path)
+        .with_context(|| format!("failed to read {}", index_path.display()))?;
+    serde_json::from_slice(&index)
+        .with_context(|| format!("failed to parse {}", index_path.display()))
+}
+
+/// Takes object described by `entry` out of parsed file, completing it
+/// with `apiVersion` and `kind` if they were missing
+fn take_object(file: &mut HashMap<Key, Value>, entry: &Entry) -> Option<Value> {
+    let key = (
+        Some(entry.kind.clone()),
+        entry.namespace.clone(),
+        entry.name.clone(),
+    );
+    let mut object = file
+        .remove(&key)
+        .or_else(|| file.remove(&(None, key.1.clone(), key.2.clone())))?;
+    if let Value::Object(fields) = &mut object {
+        let api_version = if entry.group.is_empty() {
+            entry.version.clone()
+        } else {
+            format!("{}/{}", entry.group, entry.version)
+        };
+        fields
+            .entry("apiVersion")
+            .or_insert_with(|| Value::String(api_version));
+        fields
+            .entry("kind")
+            .or_insert_with(|| Value::String(entry.kind.clone()));
+    }
+    Some(object)
+}
+
+fn parse_objects(path: &Path) -> anyhow::Result<HashMap<Key, Value>> {
+    Ok(parse_file(path)
+        .with_context(|| format!("failed to read {}", path.display()))?
+        .into_iter()
+        .filter_map(|object| Some((object_key(&object)?, object)))
+        .collect())
+}
+
+/// Applies patch of incremental dump at `dir` to the base object
+fn apply_entry_patch(dir: &Path, entry: &Entry, object: &mut Value) -> anyhow::Result<()> {
+    if let Some(patch) = &entry.patch {
+        let path = dir.join(patch);
+        let patch = parse_file(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?
+            .pop()
+            .context("patch is empty")?;
+        crate::diff::apply_patch(object, &patch)
+            .with_context(|| format!("failed to apply {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Loads single object listed in `index.json` of the dump at `dir`
+pub fn load_entry(dir: &Path, entry: &Entry) -> anyhow::Result<Value> {
+    let mut file = parse_objects(&dir.join(&entry.path))?;
+    let mut object = take_object(&mut file, entry).with_context(|| {
+        format!(
+            "{} {:?}/{} is missing in {}",
+            entry.kind, entry.namespace, entry.name, entry.path
+        )
+    })?;
+    apply_entry_patch(dir, entry, &mut object)?;
+    Ok(object)
+}
+
+/// Loads all objects listed in `index.json` of the dump at `dir`. Objects
+/// are completed with `apiVersion` and `kind`, if they were missing.
+/// Objects of incremental dump are read from its base.
+pub fn load(dir: &Path) -> anyhow::Result<Vec<(Entry, Value)>> {
+    let entries = read_index(dir)?;
+    // stream files contain many objects, so each file is parsed once
+    let mut files: BTreeMap<&str, HashMap<Key, Value>> = BTreeMap::new();
+    for entry in &entries {
+        if files.contains_key(entry.path.as_str()) {
+            continue;
+        }
+        files.insert(&entry.path, parse_objects(&dir.join(&entry.path))?);
+    }
+    let mut objects = Vec::new();
+    for entry in &entries {
+        let file = files
+            .get_mut(entry.path.as_str())
+            .expect("all files were parsed above");
+        match take_object(file, entry) {
+            Some(mut object) => {
+                apply_entry_patch(dir, entry, &mut object)?;
+                objects.push((entry.clone(), object))
+            }
+            None => tracing::warn!(
+                "{} {:?}/{} is missing in {}",
+                entry.kind,
+                entry.namespace,
+                entry.name,
+                entry.path
+            ),
+        }
+    }
+    Ok(objects)
+}
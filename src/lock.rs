@@ -0,0 +1,53 @@
+//! Advisory lock preventing concurrent dumps into the same output
+use anyhow::Context as _;
+use fs2::FileExt as _;
+use std::{
+    fs::File,
+    io::{Read as _, Seek as _, SeekFrom, Write as _},
+    path::Path,
+};
+
+/// Lock is held until this is dropped
+pub struct OutputLock {
+    _file: File,
+}
+
+impl OutputLock {
+    /// Locks file at `path`, recording PID and host of this process in it.
+    /// If lock is already held, error names its holder.
+    pub fn acquire(path: &Path) -> anyhow::Result<OutputLock> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("failed to open lock file {}", path.display()))?;
+        if file.try_lock_exclusive().is_err() {
+            let mut holder = String::new();
+            file.read_to_string(&mut holder).ok();
+            anyhow::bail!(
+                "output is being written by another kube-dump ({}); lock file: {}",
+                holder.trim(),
+                path.display()
+            );
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "pid {} on {}", std::process::id(), hostname())?;
+        file.flush()?;
+        Ok(OutputLock { _file: file })
+    }
+}
+
+fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "unknown host".to_string())
+}
@@ -1,6 +1,45 @@
+mod access;
+mod aggregated;
+mod defaults;
+mod diff;
+mod encrypt;
+mod exec;
+mod export;
+mod fakeapi;
 mod generic;
+mod git;
+mod graph;
+mod incremental;
+mod index;
+mod journal;
 mod kubectl;
 mod layout;
+mod listing;
+mod load;
+mod lock;
+mod memory;
+mod merge;
+mod metrics;
+mod node;
+mod notify;
+mod operator;
+mod owners;
+mod ratelimit;
+mod report;
+mod restore;
+mod resume;
+mod retry;
+mod rotate;
+mod schedule;
+mod scrape;
+mod selector;
+mod serve;
+mod sign;
+mod status;
+mod synthesize;
+mod upload;
+mod verify;
+mod watch;
 
 use self::layout::ObjectLayout;
 
@@ -8,88 +47,1309 @@ use anyhow::Context as _;
 use clap::Clap;
 use k8s_openapi::api::core::v1::{ConfigMap, Event, Pod, Secret};
 use kube::{
-    api::{Api, ApiResource, LogParams, Resource, ResourceExt},
+    api::{Api, ApiResource, ListParams, LogParams, Resource, ResourceExt},
     discovery::{ApiCapabilities, Discovery},
 };
 use serde::de::DeserializeOwned;
-use std::{collections::BTreeMap, fmt::Debug, future::Future, path::PathBuf, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::TryFrom as _,
+    fmt::Debug,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::Semaphore;
+use tracing::Instrument as _;
+use tracing_subscriber::{prelude::*, EnvFilter};
 
 #[derive(Clap)]
 pub struct Opts {
-    /// Path dump should be written to
-    out: PathBuf,
+    #[clap(subcommand)]
+    command: Option<Command>,
+    /// Path dump should be written to. If it ends with `.tar.gz` or `.tgz`
+    /// (or `.zip`), dump is written into gzip-compressed tar archive (or zip
+    /// archive) instead of directory. URL (e.g. `gs://bucket/path`) makes
+    /// dump written directly to object store. Required unless subcommand
+    /// is given
+    out: Option<PathBuf>,
+    /// Only dump objects in this namespace. Cluster-scoped resources and
+    /// node metrics are skipped
+    #[clap(long, conflicts_with_all = &["watch", "kubelet-stats", "kubelet-config", "node-logs"])]
+    namespace: Option<String>,
     /// Strips certain data from dumped object representations.
     /// Supported options (comma-separated):
     /// `managed-fields`: strip `managedFields` from object metadatas (this field usually is
     /// not very helpful and wastes much screen space)
     #[clap(long = "generic-strip")]
     strip: Vec<generic::Strip>,
-    /// Escape some chars in names
+    /// Escape some chars in names (same as `--escape basic`)
     #[clap(long)]
     escape_paths: bool,
+    /// How names are escaped in paths: `none`, `basic` (`~` and `:`) or
+    /// `windows` (everything Windows cannot store in file names)
+    #[clap(long)]
+    escape: Option<layout::Escape>,
+    /// Fail if two objects map to the same path (by default such objects
+    /// are disambiguated with hash suffix)
+    #[clap(long)]
+    strict_paths: bool,
+    /// Write dump to `<out>.partial` and rename it to `<out>` (replacing
+    /// previous dump) only if it succeeded
+    #[clap(long)]
+    atomic: bool,
+    /// Delete files left from previous dumps into the same directory (e.g.
+    /// files of objects which no longer exist). Hidden files are kept.
+    /// Implies `--merge`
+    #[clap(long)]
+    prune: bool,
+    /// If output already exists, delete its content (hidden files, such as
+    /// `.git`, are kept unless `--atomic` is used)
+    #[clap(long, conflicts_with = "merge")]
+    force: bool,
+    /// If output already exists, write into it, overwriting files with same
+    /// names and keeping the rest
+    #[clap(long)]
+    merge: bool,
+    /// Continue dump interrupted by failure or `--deadline`, skipping
+    /// resources it completed (progress of directory dumps is tracked in
+    /// `.kube-dump-state.jsonl`)
+    #[clap(long, conflicts_with_all = &["force", "atomic", "rotate", "incremental", "report", "graph"])]
+    resume: bool,
+    /// After dump is finished, keep watching all resources, updating the
+    /// dump and appending changes to `changes.ndjson`, until interrupted with
+    /// Ctrl-C or `--deadline`
+    #[clap(long, conflicts_with_all = &["atomic", "incremental"])]
+    watch: bool,
+    /// Stop watching after this long (e.g. `30m`)
+    #[clap(long, requires = "watch", parse(try_from_str = humantime::parse_duration))]
+    watch_duration: Option<Duration>,
+    /// While watching, also follow logs of all containers, appending them
+    /// to their log files (containers restarted after the dump get
+    /// `logs-<container>.restart-<n>.txt`)
+    #[clap(long)]
+    follow_logs: bool,
+    /// Followed log file is moved aside (as `<file>.<n>`) once it grows
+    /// larger than this
+    #[clap(long, default_value = "10MiB", parse(try_from_str = parse_size))]
+    logs_rotate_size: u64,
+    /// Treat output as parent directory: write dump into its new
+    /// subdirectory named after current time, and then delete oldest
+    /// dumps, so that given number remains (`keep=<count>`)
+    #[clap(long, conflicts_with_all = &["merge", "prune", "git"])]
+    rotate: Option<rotate::RotatePolicy>,
+    /// Keep running, and repeat dump periodically: every given interval
+    /// (e.g. `6h`) or according to cron expression in UTC (e.g.
+    /// `'0 */6 * * *'`). Requires `--rotate`, `--force` or `--merge`
+    #[clap(long)]
+    every: Option<schedule::Schedule>,
+    /// Create `by-kind/<group>/<kind>/<namespace>__<name>` symlinks pointing
+    /// to object directories
+    #[clap(long)]
+    by_kind_links: bool,
+    /// Treat output directory as git repository (initializing it if needed)
+    /// and commit the dump, with cluster and time in commit message.
+    /// Implies `--force` unless `--merge` is given
+    #[clap(long)]
+    git: bool,
+    /// Push the commit created by `--git` to `origin`
+    #[clap(long, requires = "git")]
+    git_push: bool,
+    /// Only store objects which changed since `--base` dump: changed objects
+    /// are written as RFC 6902 JSON patches against base, and unchanged ones
+    /// are listed in `incremental.json`
+    #[clap(long, requires = "base", conflicts_with_all = &["merge", "prune"])]
+    incremental: bool,
+    /// Full dump `--incremental` dump is based on
+    #[clap(long, requires = "incremental")]
+    base: Option<PathBuf>,
+    /// Store files with identical content (e.g. logs of replicated pods or
+    /// shared ConfigMap data) once, hard-linking duplicates to it. Requires
+    /// directory backend
+    #[clap(long)]
+    dedup: bool,
+    /// Kill kubectl invocations which did not finish in this time (e.g. `30s`, `2m`)
+    #[clap(long, default_value = "30s", parse(try_from_str = humantime::parse_duration))]
+    kubectl_timeout: Duration,
+    /// Additionally fetch `status` and `scale` subresources and store them
+    /// as separate files
+    #[clap(long)]
+    subresources: bool,
+    /// For objects of given kinds (`Kind` or `group/Kind`), perform
+    /// server-side dry-run create of normalized manifest, and record which
+    /// fields were defaulted or mutated by API server
+    #[clap(long, use_delimiter = true)]
+    dry_run_defaults: Vec<String>,
+    /// Before listing each resource, check (using SelfSubjectAccessReview)
+    /// that it is allowed, and skip it otherwise
+    #[clap(long)]
+    check_access: bool,
+    /// Objects whose serialized representation is larger than this (e.g.
+    /// `512KiB`, `1MiB`) are handled according to `--oversized`
+    #[clap(long, parse(try_from_str = parse_size))]
+    max_object_size: Option<u64>,
+    /// What to do with objects exceeding `--max-object-size`: `skip` or
+    /// `truncate` (keep only metadata and write marker file)
+    #[clap(long, default_value = "skip")]
+    oversized: generic::Oversized,
+    /// Timeout for discovering and listing each API group served by an
+    /// aggregated API server (such as `metrics.k8s.io`)
+    #[clap(long, default_value = "15s", parse(try_from_str = humantime::parse_duration))]
+    aggregated_timeout: Duration,
+    /// Maximum number of objects requested in one list call (0 disables pagination)
+    #[clap(long, default_value = "500")]
+    page_size: u32,
+    /// Number of API resources listed and dumped concurrently. Resources are
+    /// still written in discovery order, so later ones are kept in memory
+    /// until earlier ones are written.
+    #[clap(long, default_value = "1")]
+    concurrency: usize,
+    /// Number of objects of single API resource written concurrently (only
+    /// for dir format, streams are always written in order)
+    #[clap(long, default_value = "16")]
+    write_concurrency: usize,
+    /// Perform all generic lists at the resourceVersion of the first one, so
+    /// that dump represents single consistent snapshot of the cluster
+    #[clap(long)]
+    snapshot: bool,
+    /// Which events should be dumped: `all`, `warning-only` or `none`
+    #[clap(long, default_value = "all")]
+    events: EventsMode,
+    /// Only fetch logs of pods matching this label selector (e.g. `app=foo`).
+    /// Manifests of all pods are dumped regardless
+    #[clap(long)]
+    logs_selector: Option<selector::LabelSelector>,
+    /// Do not fetch pod logs
+    #[clap(long)]
+    no_logs: bool,
+    /// Which pods logs are fetched for: `all`, or `only-unhealthy` (pods
+    /// which are not Running or Succeeded, or have restarted containers)
+    #[clap(long, default_value = "all")]
+    logs: LogsMode,
+    /// Only fetch this many last lines of each container log
+    #[clap(long)]
+    logs_tail_lines: Option<u32>,
+    /// Only fetch log lines written during this period before the dump
+    /// (e.g. `1h`)
+    #[clap(long, parse(try_from_str = humantime::parse_duration))]
+    logs_since: Option<Duration>,
+    /// Maximum size of each container log (e.g. `10MiB`). Longer logs are
+    /// cut, and marked as truncated
+    #[clap(long, parse(try_from_str = parse_size))]
+    logs_max_bytes: Option<u64>,
+    /// Also write `logs-merged.txt` for each pod, with lines of all its
+    /// containers ordered by timestamp
+    #[clap(long)]
+    merge_logs: bool,
+    /// Gzip-compress container logs larger than this size (e.g. `1MiB`),
+    /// appending `.gz` to their file names
+    #[clap(long, parse(try_from_str = parse_size))]
+    compress_logs: Option<u64>,
+    /// Exit with code 4 if anything failed to dump or deadline was reached
+    /// (connection failures always exit with 2, and output failures with 3)
+    #[clap(long)]
+    strict: bool,
+    /// How progress is reported: `auto` (live status display if stderr is
+    /// a terminal, plain lines otherwise), `plain`, or `json` (JSON event
+    /// per line on stdout)
+    #[clap(long, default_value = "auto")]
+    progress: status::ProgressMode,
+    /// File verbose log of the run (including every skipped or failed
+    /// object) is written to, regardless of `RUST_LOG`. Its copy is placed
+    /// into the dump as `run.log`
+    #[clap(long)]
+    log_file: Option<PathBuf>,
+    /// OTLP/gRPC endpoint of OpenTelemetry collector traces are exported to
+    /// (e.g. `http://localhost:4317`), with span per phase and per resource
+    #[clap(long)]
+    otlp_endpoint: Option<String>,
+    /// Maximum number of container logs fetched concurrently
+    #[clap(long, default_value = "8")]
+    log_concurrency: usize,
+    /// Maximum average number of API requests per second (unlimited by
+    /// default)
+    #[clap(long)]
+    qps: Option<f64>,
+    /// Number of API requests that can be made at once before `--qps`
+    /// limit applies (defaults to `--qps` rounded up)
+    #[clap(long, requires = "qps")]
+    burst: Option<u32>,
+    /// How many times API requests failed with transient errors (throttling,
+    /// server errors, connection failures) are retried
+    #[clap(long, default_value = "3")]
+    retries: u32,
+    /// Fail API requests if server does not send any data for this long
+    /// (e.g. `30s`)
+    #[clap(long, parse(try_from_str = humantime::parse_duration))]
+    request_timeout: Option<Duration>,
+    /// Approximate limit of memory (e.g. `512MiB`) used by received objects
+    /// and logs which are not written yet. Listing is paused while it is
+    /// exceeded
+    #[clap(long, parse(try_from_str = parse_size))]
+    max_memory: Option<u64>,
+    /// Stop dumping when this much time (e.g. `1h`) passed since start.
+    /// Whatever was dumped so far is kept, and index is still written
+    #[clap(long, parse(try_from_str = humantime::parse_duration))]
+    deadline: Option<Duration>,
+    /// Do not extract ConfigMap data into separate files
+    #[clap(long)]
+    no_configmap_data: bool,
+    /// Do not extract Secret data into separate files
+    #[clap(long)]
+    no_secret_data: bool,
+    /// YAML file listing commands which are run in matching pods, and files
+    /// which are copied from them (using `exec` subresource). Results are
+    /// saved next to the pod. Nothing is run in pods without it
+    #[clap(long)]
+    exec_config: Option<PathBuf>,
+    /// Save snapshot of metrics of pods with `prometheus.io/scrape`
+    /// annotation, fetched through API server pod proxy (requires `get` on
+    /// `pods/proxy`, port-forwarding is not used)
+    #[clap(long)]
+    scrape_metrics: bool,
+    /// YAML file listing HTTP endpoints (e.g. `/healthz` or
+    /// `/debug/pprof/heap`) of matching pods, whose responses are saved next
+    /// to the pod. Fetched through API server pod proxy, like
+    /// `--scrape-metrics`
+    #[clap(long)]
+    probe_config: Option<PathBuf>,
+    /// Save kubelet stats summary (filesystem, network and memory usage of
+    /// containers) of each node, fetched through API server proxy
+    #[clap(long)]
+    kubelet_stats: bool,
+    /// Save effective kubelet configuration of each node (from its `configz`
+    /// endpoint) as `kubelet-config.json`
+    #[clap(long)]
+    kubelet_config: bool,
+    /// Log files served by kubelet `/logs/` endpoint (where it is enabled)
+    /// which are saved for each node, e.g. `kubelet.log,syslog`. Only the
+    /// last `--logs-max-bytes` (64MiB by default) of each file are kept
+    #[clap(long, use_delimiter = true)]
+    node_logs: Vec<String>,
+    /// Only save logs of nodes matching this label selector
+    #[clap(long, requires = "node-logs")]
+    node_logs_selector: Option<selector::LabelSelector>,
+    /// How object representations are written: `dir` (`raw.json` per object),
+    /// `yaml-per-kind` or `yaml-per-namespace` (one multi-document `dump.yaml`
+    /// per kind or namespace), `jsonl` (one `objects.jsonl` per kind, with
+    /// object per line)
+    #[clap(long, default_value = "dir")]
+    format: generic::OutputFormat,
+    /// Reports to generate after dump: `html` (static site in `report/`),
+    /// `markdown` (`SUMMARY.md` with cluster overview)
+    #[clap(long, use_delimiter = true)]
+    report: Vec<report::ReportFormat>,
+    /// Write graph of relationships between objects (owner references,
+    /// Service selectors, Ingress routes, volume mounts): `dot`, `mermaid`
+    #[clap(long, use_delimiter = true)]
+    graph: Vec<graph::GraphFormat>,
+    /// After dump is finished, upload it to object store or file server:
+    /// `gs://bucket/path` (requires `gsutil`), `s3://bucket/path` (requires
+    /// `aws`), `sftp://user@host/path`,
+    /// `webdav://host/path` or `webdav+http://host/path` (require `curl`)
+    #[clap(long)]
+    upload: Option<upload::UploadTarget>,
+    /// When dump completes (or fails), POST JSON summary to this URL
+    /// (requires `curl`). Slack incoming webhooks are supported
+    #[clap(long)]
+    notify_url: Option<String>,
+    /// Compress object representations and logs (`zstd`), appending
+    /// extension to their file names
+    #[clap(long)]
+    compress: Option<layout::Compression>,
+    /// Logs smaller than this are not compressed by `--compress`.
+    /// Representations are always compressed, so that their names do not
+    /// depend on size
+    #[clap(long, default_value = "4KiB", parse(try_from_str = parse_size))]
+    compress_min_size: u64,
+    /// Where dump is stored: `dir`, `tar` (gzip-compressed), `zip` or
+    /// `sqlite` (single database with one row per file). Detected from output
+    /// path by default
+    #[clap(long)]
+    backend: Option<layout::storage::BackendKind>,
+    /// How files are written to directory: `direct` (each file at once),
+    /// `sync` (each file is also fsync-ed) or `batched` (small files are
+    /// written in batches and streams are kept open, which is much faster
+    /// for many tiny files)
+    #[clap(long, default_value = "direct")]
+    io_mode: layout::sink::IoMode,
+    /// Split archive into parts of at most this size (e.g. `1GiB`), named
+    /// `<out>.000`, `<out>.001`, etc. Parts can be joined back with `cat`
+    #[clap(long, parse(try_from_str = parse_size))]
+    split_size: Option<u64>,
+    /// Encrypt output to given recipient: `age:<public key>` (requires
+    /// `age`) or `gpg:<key id or email>` (requires `gpg` with the key in
+    /// keyring). Archives are encrypted as a whole, files in directories and
+    /// remote stores are encrypted one by one, except for manifests
+    /// (`index.json`, `SHA256SUMS` and its signature) which hold no secret
+    /// data
+    #[clap(long)]
+    encrypt: Option<encrypt::Encryption>,
+    /// What `--encrypt` applies to: `all` or `secrets` (only files of
+    /// Secret objects, leaving the rest of the dump readable)
+    #[clap(long, requires = "encrypt")]
+    encrypt_scope: Option<encrypt::Scope>,
+    /// Sign `SHA256SUMS` and store signature next to it: `gpg:<key>`,
+    /// `cosign:<key file>` or `sigstore` (keyless, requires `cosign`)
+    #[clap(long)]
+    sign: Option<sign::Signer>,
+    /// Produce deterministic object representations: sort keys and drop
+    /// fields that change without object being modified (heartbeats,
+    /// resourceVersion, etc), so that dumps of unchanged cluster are identical
+    #[clap(long)]
+    canonical: bool,
+    /// Transform dumped objects for other uses: `gitops` strips
+    /// server-populated fields (status, uid, resourceVersion, generated
+    /// annotations, etc), so that output can be applied with `kubectl apply`
+    #[clap(long)]
+    export: Option<generic::Export>,
+    /// How object directories are grouped: `namespace/kind`, `kind/namespace`,
+    /// `flat` (all objects in single directory) or `owner-tree` (objects are
+    /// nested beneath their owners)
+    #[clap(long, default_value = "namespace/kind")]
+    layout: layout::Scheme,
+    /// Template for object representation paths, overriding `--layout`.
+    /// Supported placeholders: `{group}`, `{version}`, `{kind}`, `{namespace}`,
+    /// `{name}`, `{ext}`. Substituted values are escaped so that each of them
+    /// is exactly one path component. Other object files are placed next to
+    /// the representation (prefixed with its name, if it is object-specific)
+    #[clap(long)]
+    path_template: Option<layout::PathTemplate>,
+}
+
+pub enum EventsMode {
+    All,
+    WarningOnly,
+    None,
+}
+
+impl std::str::FromStr for EventsMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(EventsMode::All),
+            "warning-only" => Ok(EventsMode::WarningOnly),
+            "none" => Ok(EventsMode::None),
+            _ => anyhow::bail!("unknown events mode: {}", s),
+        }
+    }
+}
+
+pub enum LogsMode {
+    All,
+    OnlyUnhealthy,
+}
+
+impl std::str::FromStr for LogsMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(LogsMode::All),
+            "only-unhealthy" => Ok(LogsMode::OnlyUnhealthy),
+            _ => anyhow::bail!("unknown logs mode: {}", s),
+        }
+    }
+}
+
+/// Parses human-readable size, such as `100`, `10KiB`, `1MB` or `2GiB`
+fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number.parse().context("invalid size")?;
+    let multiplier: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        other => anyhow::bail!("unknown size unit: {}", other),
+    };
+    number.checked_mul(multiplier).context("size is too large")
+}
+
+impl Opts {
+    fn out(&self) -> &Path {
+        self.out
+            .as_deref()
+            .expect("output path is required unless subcommand is given")
+    }
+}
+
+#[derive(Clap)]
+enum Command {
+    /// Reconstructs dump from the watch-mode change journal
+    Replay(journal::ReplayOpts),
+    /// Dumps cluster and then records all its changes for some time
+    /// (options of the dump go before the command)
+    Record(watch::RecordOpts),
+    /// Runs controller which performs dumps described by `DumpRequest`
+    /// custom resources, and writes results to their status
+    Operator(operator::OperatorOpts),
+    /// Runs HTTP server which performs requested dumps and serves their
+    /// archives
+    Serve(serve::ServeOpts),
+    /// Produces anonymized copy of a dump with the same structure
+    Synthesize(synthesize::SynthesizeOpts),
+    /// Applies dumped objects to the cluster
+    Restore(restore::RestoreOpts),
+    /// Shows objects added, removed or changed between two dumps
+    Diff(diff::DiffOpts),
+    /// Combines several partial dumps into one
+    Merge(merge::MergeOpts),
+    /// Checks dump integrity: checksums, file syntax and index
+    Verify(verify::VerifyOpts),
+    /// Converts dump into manifests for other tools
+    Export(export::ExportOpts),
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let opts: Opts = Opts::parse();
-    println!("Connecting to cluster");
-    let client = kube::Client::try_default()
+    init_tracing(
+        opts.log_file.as_deref(),
+        opts.otlp_endpoint.as_deref(),
+        opts.progress,
+    )?;
+    let result = run(opts).await;
+    // sends spans which are not exported yet
+    opentelemetry::global::shutdown_tracer_provider();
+    if let Err(err) = &result {
+        if let Some(failure) = err.downcast_ref::<Failure>() {
+            eprintln!("Error: {:?}", err);
+            std::process::exit(failure.exit_code());
+        }
+    }
+    result
+}
+
+async fn run(mut opts: Opts) -> anyhow::Result<()> {
+    if let Some(command) = opts.command.take() {
+        let record_opts = match command {
+            Command::Record(record_opts) => record_opts,
+            Command::Replay(replay_opts) => return journal::replay(replay_opts).await,
+            Command::Synthesize(synthesize_opts) => {
+                return synthesize::synthesize(synthesize_opts).await
+            }
+            Command::Restore(restore_opts) => return restore::restore(restore_opts).await,
+            Command::Diff(diff_opts) => return diff::diff(diff_opts).await,
+            Command::Merge(merge_opts) => return merge::merge(merge_opts).await,
+            Command::Verify(verify_opts) => return verify::verify(verify_opts).await,
+            Command::Export(export_opts) => return export::export(export_opts).await,
+            Command::Operator(operator_opts) => return operator::run(operator_opts).await,
+            Command::Serve(serve_opts) => return serve::serve(serve_opts).await,
+        };
+        record_opts.apply(&mut opts);
+    }
+    if opts.out.is_none() {
+        anyhow::bail!("output path is required unless subcommand is given");
+    }
+    if let Some(schedule) = opts.every.take() {
+        if opts.watch {
+            anyhow::bail!("periodic dumps can not be combined with watching");
+        }
+        if opts.rotate.is_none() && !opts.force && !opts.merge {
+            anyhow::bail!(
+                "--every requires --rotate, --force or --merge, so that dumps do not conflict"
+            );
+        }
+        return run_scheduled(&schedule).await;
+    }
+    dump_and_notify(opts).await
+}
+
+/// Performs the dump, sending notification about it if requested
+async fn dump_and_notify(opts: Opts) -> anyhow::Result<()> {
+    let notify_url = opts.notify_url.clone();
+    let mut summary = notify::Summary::start();
+    let result = dump(opts, &mut summary)
+        .instrument(tracing::info_span!("dump"))
+        .await;
+    if let Some(url) = &notify_url {
+        if let Err(err) = notify::send(url, &summary, &result).await {
+            tracing::error!("Failed to send notification: {:#}", err);
+        }
+    }
+    result
+}
+
+/// Options of dumps requested remotely (by operator or server). Others
+/// could read local files, send dump elsewhere, delete data or make dump
+/// never finish.
+const REQUESTABLE_FLAGS: &[&str] = &[
+    "--namespace",
+    "--format",
+    "--generic-strip",
+    "--escape",
+    "--escape-paths",
+    "--events",
+    "--no-logs",
+    "--logs",
+    "--logs-selector",
+    "--logs-tail-lines",
+    "--logs-since",
+    "--logs-max-bytes",
+    "--no-configmap-data",
+    "--no-secret-data",
+    "--export",
+    "--canonical",
+    "--compress",
+    "--snapshot",
+    "--page-size",
+    "--max-object-size",
+    "--oversized",
+];
+
+/// Parses options of dump requested remotely (by operator or server),
+/// refusing ones which are not in `REQUESTABLE_FLAGS`
+fn parse_requested_dump(args: &[String], out: &str) -> anyhow::Result<Opts> {
+    for arg in args.iter().filter(|arg| arg.starts_with('-')) {
+        let flag = arg.split('=').next().unwrap_or_default();
+        if !REQUESTABLE_FLAGS.contains(&flag) {
+            anyhow::bail!("{} can not be requested", flag);
+        }
+    }
+    let args = std::iter::once("kube-dump")
+        .chain(args.iter().map(String::as_str))
+        .chain(std::iter::once("--"))
+        .chain(std::iter::once(out));
+    let opts = Opts::try_parse_from(args)?;
+    if opts.command.is_some() {
+        anyhow::bail!("only single dump can be requested");
+    }
+    Ok(opts)
+}
+
+/// Repeats dumps according to `schedule` forever. Failed dumps are
+/// reported, and do not stop following ones.
+async fn run_scheduled(schedule: &schedule::Schedule) -> anyhow::Result<()> {
+    let mut next = schedule.first(std::time::SystemTime::now());
+    while let Some(start) = next {
+        let wait = start
+            .duration_since(std::time::SystemTime::now())
+            .unwrap_or_default();
+        tracing::info!("Next dump at {}", humantime::format_rfc3339_seconds(start));
+        tokio::time::sleep(wait).await;
+        let started = std::time::SystemTime::now();
+        // options can not be cloned, so they are parsed again for each dump
+        let mut opts = Opts::parse();
+        opts.every = None;
+        if let Err(err) = dump_and_notify(opts).await {
+            tracing::error!("Dump failed: {:?}", err);
+        }
+        next = schedule.next(started);
+    }
+    anyhow::bail!("schedule never fires")
+}
+
+fn init_tracing(
+    log_file: Option<&Path>,
+    otlp_endpoint: Option<&str>,
+    progress: status::ProgressMode,
+) -> anyhow::Result<()> {
+    // e.g. `RUST_LOG='kube_dump[resource{kind=Pod}]=debug'` to debug dumping
+    // of single kind
+    let console_filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("kube_dump=info"));
+    // in JSON mode, status prints events to stdout by itself
+    let console_filter = match progress {
+        status::ProgressMode::Json => {
+            console_filter.add_directive("kube_dump::status=off".parse()?)
+        }
+        _ => console_filter,
+    };
+    let console = tracing_subscriber::fmt::layer()
+        .with_writer(status::ConsoleWriter)
+        .without_time()
+        .with_target(false)
+        .with_filter(console_filter);
+    let file = match log_file {
+        Some(path) => {
+            let file = std::fs::File::create(path)
+                .with_context(|| format!("failed to create log file {}", path.display()))?;
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_writer(std::sync::Mutex::new(file))
+                    .with_ansi(false)
+                    .with_filter(EnvFilter::new("kube_dump=debug")),
+            )
+        }
+        None => None,
+    };
+    let otlp = match otlp_endpoint {
+        Some(endpoint) => {
+            use opentelemetry_otlp::WithExportConfig as _;
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(endpoint),
+                )
+                .with_trace_config(opentelemetry::sdk::trace::config().with_resource(
+                    opentelemetry::sdk::Resource::new(vec![opentelemetry::KeyValue::new(
+                        "service.name",
+                        "kube-dump",
+                    )]),
+                ))
+                .install_batch(opentelemetry::runtime::Tokio)
+                .context("failed to set up trace export")?;
+            Some(
+                tracing_opentelemetry::layer()
+                    .with_tracer(tracer)
+                    .with_filter(EnvFilter::new("kube_dump=info")),
+            )
+        }
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(console)
+        .with(file)
+        .with(otlp)
+        .init();
+    Ok(())
+}
+
+/// Kind of dump failure, which determines exit code (other errors exit
+/// with 1)
+#[derive(Debug, Clone, Copy)]
+enum Failure {
+    /// Cluster could not be reached, or API requests failed
+    Connection,
+    /// Output could not be written
+    Output,
+    /// Dump finished, but some resources were not dumped (only with
+    /// `--strict`)
+    Partial,
+}
+
+impl Failure {
+    fn exit_code(self) -> i32 {
+        match self {
+            Failure::Connection => 2,
+            Failure::Output => 3,
+            Failure::Partial => 4,
+        }
+    }
+
+    /// Classifies error which aborted the dump
+    fn of(err: &anyhow::Error) -> Failure {
+        if err.chain().any(|cause| cause.is::<kube::Error>()) {
+            Failure::Connection
+        } else {
+            Failure::Output
+        }
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Failure::Connection => "could not communicate with cluster",
+            Failure::Output => "could not write dump",
+            Failure::Partial => "dump is incomplete",
+        })
+    }
+}
+
+/// Performs the dump, recording information for notification into `summary`
+async fn dump(mut opts: Opts, summary: &mut notify::Summary) -> anyhow::Result<()> {
+    let status = status::Status::new(opts.progress);
+    let exec_rules = match &opts.exec_config {
+        Some(path) => exec::load(path)?,
+        None => Vec::new(),
+    };
+    let probes = match &opts.probe_config {
+        Some(path) => scrape::load_probes(path)?,
+        None => Vec::new(),
+    };
+    let deadline = opts
+        .deadline
+        .map(|deadline| tokio::time::Instant::now() + deadline);
+    let rotate_parent = if opts.rotate.is_some() {
+        if opts.backend.unwrap_or(layout::storage::BackendKind::Dir)
+            != layout::storage::BackendKind::Dir
+            || opts.out().to_string_lossy().contains("://")
+        {
+            anyhow::bail!("--rotate requires local directory output");
+        }
+        let parent = opts.out().to_path_buf();
+        opts.out = Some(rotate::dump_dir(&parent));
+        Some(parent)
+    } else {
+        None
+    };
+    status.println("Connecting to cluster");
+    let mut config = kube::Config::infer()
         .await
-        .context("connection failed")?;
+        .context("failed to load kubeconfig")
+        .context(Failure::Connection)?;
+    if let Some(timeout) = opts.request_timeout {
+        config.timeout = Some(timeout);
+    }
+    let cluster = config.cluster_url.to_string();
+    summary.cluster = Some(cluster.clone());
+    let client = kube::Client::try_from(config)
+        .context("connection failed")
+        .context(Failure::Connection)?;
     let kube_version = client
         .apiserver_version()
         .await
-        .context("failed to get kubernetes verion")?;
-    println!(
+        .context("failed to get kubernetes verion")
+        .context(Failure::Connection)?;
+    status.println(&format!(
         "successfully connected to Kubernetes v{}.{}",
         kube_version.major, kube_version.minor
-    );
+    ));
 
-    let apis = discover_apis(&client).await.context("discovery error")?;
-    println!("Discovered {} api resources", apis.len());
+    status.phase("discovery");
+    let (apis, aggregated) = discover_apis(&client, &opts, &status)
+        .instrument(tracing::info_span!("discovery"))
+        .await
+        .context("discovery error")
+        .context(Failure::Connection)?;
+    status.println(&format!("Discovered {} api resources", apis.len()));
 
+    let limiter = match opts.qps {
+        Some(qps) if qps.is_nan() || qps <= 0.0 => anyhow::bail!("--qps must be positive"),
+        Some(qps) => {
+            let burst = opts.burst.unwrap_or(qps.ceil() as u32);
+            Some(Arc::new(ratelimit::RateLimiter::new(qps, burst)))
+        }
+        None => None,
+    };
+    let access = if opts.check_access {
+        Some(access::AccessChecker::new(client.clone(), limiter.clone()))
+    } else {
+        None
+    };
+    let layout = layout::Layout::new(&opts);
+    let _lock = match layout::storage::lock_path(opts.out(), opts.backend) {
+        Some(path) => Some(lock::OutputLock::acquire(&path).context(Failure::Output)?),
+        None => None,
+    };
+    // streams mix Secrets with other objects
+    if opts.encrypt_scope == Some(encrypt::Scope::Secrets)
+        && !matches!(opts.format, generic::OutputFormat::Dir)
+    {
+        anyhow::bail!("--encrypt-scope secrets requires dir format");
+    }
+    if opts.concurrency == 0 || opts.write_concurrency == 0 || opts.log_concurrency == 0 {
+        anyhow::bail!("concurrency must be positive");
+    }
+    if opts.logs_max_bytes == Some(0) {
+        anyhow::bail!("--logs-max-bytes must be positive");
+    }
+    if opts.git && opts.atomic {
+        anyhow::bail!("--git can not be combined with --atomic, which would replace repository");
+    }
+    let existing = if opts.force || (opts.git && !opts.merge && !opts.prune && !opts.resume) {
+        layout::storage::ExistingOutput::Force
+    } else if opts.merge || opts.prune || opts.resume {
+        layout::storage::ExistingOutput::Merge
+    } else {
+        layout::storage::ExistingOutput::Refuse
+    };
+    let mut storage = layout::storage::Storage::new(
+        opts.out(),
+        opts.backend,
+        opts.atomic,
+        existing,
+        opts.split_size,
+        opts.encrypt.clone().map(|encryption| {
+            (
+                encryption,
+                opts.encrypt_scope.unwrap_or(encrypt::Scope::All),
+            )
+        }),
+        layout.registry(),
+        opts.io_mode,
+    )
+    .context(Failure::Output)?;
+    if let layout::Scheme::OwnerTree = opts.layout {
+        if opts.path_template.is_some() {
+            anyhow::bail!("owner-tree layout can not be combined with --path-template");
+        }
+        if !storage.supports_moves() {
+            anyhow::bail!("owner-tree layout requires directory backend");
+        }
+    }
+    if opts.prune && !storage.supports_moves() {
+        anyhow::bail!("--prune requires directory backend");
+    }
+    if opts.by_kind_links && !storage.supports_symlinks() {
+        anyhow::bail!("--by-kind-links requires directory backend");
+    }
+    storage.keep_plaintext(
+        vec![
+            layout.index(),
+            layout.checksums(),
+            layout.resource_versions(),
+            layout.incremental_manifest(),
+        ]
+        .into_iter()
+        .chain(
+            opts.sign
+                .as_ref()
+                .map(|signer| layout.checksums_signature(signer.extension())),
+        ),
+    );
+    if opts.dedup {
+        storage.enable_dedup()?;
+    }
+    if opts.incremental && !matches!(opts.format, generic::OutputFormat::Dir) {
+        anyhow::bail!("--incremental requires dir format");
+    }
+    let base = match &opts.base {
+        Some(dir) => Some(incremental::Base::load(dir)?),
+        None => None,
+    };
+    if opts.git && !storage.supports_moves() {
+        anyhow::bail!("--git requires directory backend");
+    }
+    // streams of resumed dump would be truncated, and owner tree would be
+    // built from resumed part only
+    if opts.resume
+        && (!storage.supports_moves()
+            || !matches!(opts.format, generic::OutputFormat::Dir)
+            || matches!(opts.layout, layout::Scheme::OwnerTree))
+    {
+        anyhow::bail!("--resume requires directory backend, dir format and default layout");
+    }
+    // objects are updated and deleted in place
+    if opts.watch
+        && (!storage.supports_moves()
+            || !matches!(opts.format, generic::OutputFormat::Dir)
+            || matches!(opts.layout, layout::Scheme::OwnerTree))
+    {
+        anyhow::bail!("--watch requires directory backend, dir format and default layout");
+    }
+    if opts.follow_logs {
+        if !opts.watch || opts.no_logs {
+            anyhow::bail!("--follow-logs requires --watch, and can not be used with --no-logs");
+        }
+        // compressed files can not be appended to
+        if opts.compress.is_some() || opts.compress_logs.is_some() {
+            anyhow::bail!("--follow-logs can not be combined with log compression");
+        }
+    }
+    let progress = if storage.supports_moves() && !opts.atomic {
+        let progress = resume::Progress::start(opts.out(), opts.resume)?;
+        if opts.resume {
+            let adopted = storage.resume()?;
+            status.println(&format!("Resuming dump, {} files are kept", adopted));
+        }
+        Some(progress)
+    } else {
+        None
+    };
     let env = Environment {
         client,
-        layout: layout::Layout::new(&opts),
+        access,
+        layout,
         apis,
+        aggregated,
+        storage,
+        index: Default::default(),
+        owners: Default::default(),
+        report_data: Default::default(),
+        graph: Default::default(),
+        kubectl: kubectl::Kubectl::try_new(opts.kubectl_timeout).await,
+        base,
+        log_permits: Semaphore::new(opts.log_concurrency),
+        exec_rules,
+        probes,
+        limiter,
+        list_versions: Default::default(),
+        snapshot_version: Default::default(),
+        memory: opts.max_memory.map(memory::MemoryBudget::new),
+        deadline,
+        interrupted: AtomicBool::new(false),
+        progress,
+        dumped_logs: Default::default(),
+        status,
         opts,
-        kubectl: kubectl::Kubectl::try_new().await,
     };
-    if let Some(cluster_info) = env.kubectl.exec(&["cluster-info"]).await? {
-        tokio::fs::write(env.layout.cluster_info(), cluster_info).await?;
+    if let Some(progress) = &env.progress {
+        for entry in progress.restored_entries() {
+            env.index.record(entry);
+        }
     }
-    println!("Running generic dumper");
-    generic::dump(&env).await?;
     let env = Arc::new(env);
-    println!("Running Pod dumper");
-    dump_typed_simple(dump_pod, &env).await?;
-    println!("Running ConfigMap dumper");
-    dump_typed_simple(dump_config_map, &env).await?;
-    println!("Running Secret dumper");
-    dump_typed_simple(dump_secret, &env).await?;
-    println!("Running Event dumper");
-    dump_events(&env).await?;
+    summary.output = Some(env.storage.output().display().to_string());
+    let result = run_dumpers(&env).await;
+    env.status.finish();
+    let result = match result {
+        Ok(()) => {
+            let signature = env
+                .opts
+                .sign
+                .as_ref()
+                .map(|signer| (signer, env.layout.checksums_signature(signer.extension())));
+            env.storage.finish(&env.layout.checksums(), signature).await
+        }
+        Err(err) => Err(err),
+    };
+    summary.record_objects(&env.index.entries());
+    if let Err(err) = result {
+        env.storage.mark_incomplete(&format!("{:#}", err)).await;
+        let failure = Failure::of(&err);
+        return Err(err.context(failure));
+    }
+    // dump stopped by deadline can still be resumed
+    if let Some(progress) = &env.progress {
+        if !env.interrupted.load(Ordering::Relaxed) {
+            progress.finish()?;
+        }
+    }
+    // atomic dump always starts from scratch, and files of objects which
+    // were not reached before deadline are not stale
+    if env.opts.prune && !env.opts.atomic && !env.interrupted.load(Ordering::Relaxed) {
+        let deleted = env.storage.prune()?;
+        env.status
+            .println(&format!("Pruned {} stale files", deleted));
+    }
+    if env.opts.git {
+        git::commit(env.opts.out(), &cluster, env.opts.git_push).await?;
+    }
+    if let Some(target) = &env.opts.upload {
+        env.status.println("Uploading dump");
+        let uploaded = upload::upload(target, &env.storage.output()).await?;
+        env.status.println(&format!("Uploaded {} files", uploaded));
+    }
+    if let (Some(policy), Some(parent)) = (&env.opts.rotate, &rotate_parent) {
+        let deleted = rotate::cleanup(parent, policy)?;
+        env.status
+            .println(&format!("Deleted {} old dumps", deleted));
+    }
+    print_summary(&env);
+    if env.opts.strict {
+        let errors = env.status.error_count();
+        if errors > 0 {
+            return Err(anyhow::anyhow!("{} error(s) occurred", errors).context(Failure::Partial));
+        }
+        if env.interrupted.load(Ordering::Relaxed) {
+            return Err(anyhow::anyhow!("deadline was reached").context(Failure::Partial));
+        }
+    }
+    Ok(())
+}
+
+async fn run_dumpers(env: &Arc<Environment>) -> anyhow::Result<()> {
+    match env.kubectl.exec(&["cluster-info"]).await {
+        Ok(Some(cluster_info)) => {
+            env.storage
+                .write(&env.layout.cluster_info(), cluster_info)
+                .await?;
+        }
+        Ok(None) => {}
+        Err(err) => env
+            .status
+            .error("cluster-info".to_string(), format!("{:#}", err)),
+    }
+    env.status.phase("generic dumper");
+    allow_deadline(
+        generic::dump(env)
+            .instrument(tracing::info_span!("dumper", name = "generic"))
+            .await,
+    )?;
+    if let layout::Scheme::OwnerTree = env.layout.scheme() {
+        if let generic::OutputFormat::Dir = env.opts.format {
+            owners::resolve(env)
+                .instrument(tracing::info_span!("owners"))
+                .await?;
+        }
+    }
+    if !env.opts.no_logs {
+        run_dumper(env, "Pod", dump_typed_simple(dump_pod, env)).await?;
+    }
+    if !env.opts.no_configmap_data {
+        run_dumper(env, "ConfigMap", dump_typed_simple(dump_config_map, env)).await?;
+    }
+    if !env.opts.no_secret_data {
+        run_dumper(env, "Secret", dump_typed_simple(dump_secret, env)).await?;
+    }
+    if !matches!(env.opts.events, EventsMode::None) {
+        run_dumper(env, "Event", dump_events(env)).await?;
+    }
+    run_dumper(env, "PodMetrics", metrics::dump_pods(env)).await?;
+    if env.opts.namespace.is_none() {
+        run_dumper(env, "NodeMetrics", metrics::dump_nodes(env)).await?;
+    }
+    if env.opts.kubelet_stats || env.opts.kubelet_config || !env.opts.node_logs.is_empty() {
+        run_dumper(env, "Kubelet", dump_typed_simple(node::dump_node, env)).await?;
+    }
+    if !env.exec_rules.is_empty() {
+        run_dumper(env, "Exec", dump_typed_simple(exec::dump_pod, env)).await?;
+    }
+    if env.opts.scrape_metrics {
+        run_dumper(env, "Metrics", dump_typed_simple(scrape::scrape_pod, env)).await?;
+    }
+    if !env.probes.is_empty() {
+        run_dumper(env, "Probe", dump_typed_simple(scrape::probe_pod, env)).await?;
+    }
+    report::write(env).await?;
+    graph::write(env).await?;
+    if let Some(base) = &env.base {
+        base.write_manifest(env).await?;
+    }
+    env.index
+        .write(env)
+        .instrument(tracing::info_span!("index"))
+        .await?;
+    if env.opts.watch && !env.check_deadline() {
+        env.status.phase("watch");
+        watch::watch(env)
+            .instrument(tracing::info_span!("watch"))
+            .await?;
+    }
+    let summary = env
+        .status
+        .run_summary(&env.index.entries(), env.storage.bytes_written());
+    env.storage
+        .write(
+            &env.layout.run_summary(),
+            serde_json::to_string_pretty(&summary)?,
+        )
+        .await?;
+    env.storage
+        .write(
+            &env.layout.timings(),
+            serde_json::to_string_pretty(&env.status.timings())?,
+        )
+        .await?;
+    if let Some(path) = &env.opts.log_file {
+        // log of the run so far, so it ends here
+        let log = tokio::fs::read(path).await?;
+        env.storage.write(&env.layout.run_log(), log).await?;
+    }
     Ok(())
 }
 
-async fn discover_apis(k: &kube::Client) -> anyhow::Result<Vec<(ApiResource, ApiCapabilities)>> {
-    let discovery = Discovery::new(k.clone()).run().await?;
+/// Runs typed dumper, unless deadline was reached or dumper was completed by
+/// the run being resumed
+async fn run_dumper(
+    env: &Environment,
+    name: &str,
+    dumper: impl Future<Output = anyhow::Result<()>>,
+) -> anyhow::Result<()> {
+    if env.check_deadline() {
+        return Ok(());
+    }
+    if let Some(progress) = &env.progress {
+        if progress.dumper_done(name) {
+            env.status.println(&format!(
+                "Skipping {} dumper, it was completed by previous run",
+                name
+            ));
+            return Ok(());
+        }
+    }
+    env.status.phase(&format!("{} dumper", name));
+    allow_deadline(dumper.instrument(tracing::info_span!("dumper", name)).await)?;
+    match &env.progress {
+        Some(progress) if !env.interrupted.load(Ordering::Relaxed) => {
+            env.storage.flush().await?;
+            progress.complete_dumper(name)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Dumper stopped by `--deadline` did not fail, and dump is finished normally
+fn allow_deadline(result: anyhow::Result<()>) -> anyhow::Result<()> {
+    match result {
+        Err(err) if err.is::<retry::DeadlineReached>() => Ok(()),
+        result => result,
+    }
+}
+
+fn print_summary(env: &Environment) {
+    env.status.print_summary(
+        &env.status
+            .run_summary(&env.index.entries(), env.storage.bytes_written()),
+    );
+    if env.interrupted.load(Ordering::Relaxed) {
+        env.status
+            .println("Deadline was reached before dump was finished, some objects were not dumped");
+    }
+    if let Some((files, bytes)) = env.storage.deduplicated() {
+        env.status.println(&format!(
+            "{} duplicate file(s) ({} bytes) were hard-linked",
+            files, bytes
+        ));
+    }
+    let timed_out = env.kubectl.timed_out();
+    if !timed_out.is_empty() {
+        env.status.println(&format!(
+            "{} kubectl invocation(s) timed out:",
+            timed_out.len()
+        ));
+        for command_line in timed_out {
+            env.status.println(&format!(" - {}", command_line));
+        }
+    }
+}
+
+async fn discover_apis(
+    k: &kube::Client,
+    opts: &Opts,
+    status: &status::Status,
+) -> anyhow::Result<(
+    Vec<(ApiResource, ApiCapabilities)>,
+    aggregated::AggregatedApis,
+)> {
+    let mut aggregated = match aggregated::AggregatedApis::detect(k).await {
+        Ok(a) => a,
+        Err(err) => {
+            status.error(
+                "apiregistration.k8s.io/v1.APIService".to_string(),
+                format!("failed to detect aggregated APIs: {:#}", err),
+            );
+            Default::default()
+        }
+    };
+    // aggregated groups are discovered separately, so that unavailable
+    // aggregated server does not break whole discovery
+    let aggregated_groups = aggregated
+        .groups()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    let excluded = aggregated_groups
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    let discovery = Discovery::new(k.clone()).exclude(&excluded).run().await?;
     let mut res = Vec::new();
+    collect_resources(&discovery, &mut res);
+    for group in &aggregated_groups {
+        let discovery = Discovery::new(k.clone()).filter(&[group.as_str()]).run();
+        match tokio::time::timeout(opts.aggregated_timeout, discovery).await {
+            Ok(Ok(discovery)) => collect_resources(&discovery, &mut res),
+            Ok(Err(err)) => {
+                status.error(
+                    group.clone(),
+                    format!("aggregated API is unavailable: {:#}", err),
+                );
+                aggregated.mark_unavailable(group, format!("discovery failed: {:#}", err));
+            }
+            Err(_) => {
+                status.error(
+                    group.clone(),
+                    "aggregated API is unavailable: timed out".to_string(),
+                );
+                aggregated.mark_unavailable(group, "discovery timed out".to_string());
+            }
+        }
+    }
+    Ok((res, aggregated))
+}
+
+fn collect_resources(discovery: &Discovery, res: &mut Vec<(ApiResource, ApiCapabilities)>) {
     for g in discovery.groups() {
         let v = g.preferred_version_or_latest();
         let mut resources = g.versioned_resources(v).into_iter().collect();
         res.append(&mut resources);
     }
-    Ok(res)
 }
 
 /// Contains data passed to dumpers
 pub struct Environment {
     client: kube::Client,
+    /// Present if access should be checked before listing resources
+    access: Option<access::AccessChecker>,
     layout: layout::Layout,
     apis: Vec<(ApiResource, ApiCapabilities)>,
+    aggregated: aggregated::AggregatedApis,
+    storage: layout::storage::Storage,
+    index: index::DumpIndex,
+    owners: owners::OwnerRefs,
+    report_data: report::ReportData,
+    graph: graph::RelationGraph,
     opts: Opts,
     kubectl: kubectl::Kubectl,
+    /// Present in incremental mode
+    base: Option<incremental::Base>,
+    /// Limits number of concurrent log fetches
+    log_permits: Semaphore,
+    /// Commands run in pods, from `--exec-config`
+    exec_rules: Vec<exec::ExecRule>,
+    /// Endpoints of pods saved, from `--probe-config`
+    probes: Vec<scrape::Probe>,
+    /// Present if API requests are rate limited
+    limiter: Option<Arc<ratelimit::RateLimiter>>,
+    /// `apiVersion/plural` -> resourceVersion of the list, filled by
+    /// generic dumper
+    list_versions: std::sync::Mutex<BTreeMap<String, Option<String>>>,
+    /// resourceVersion all lists are performed at in `--snapshot` mode,
+    /// known once generic dumper lists first resource
+    snapshot_version: std::sync::Mutex<Option<String>>,
+    /// Present if `--max-memory` is given
+    memory: Option<memory::MemoryBudget>,
+    /// Set if `--deadline` is given
+    deadline: Option<tokio::time::Instant>,
+    /// Set when something was not dumped because deadline was reached
+    interrupted: AtomicBool,
+    /// Present if dump can be resumed
+    progress: Option<resume::Progress>,
+    /// (namespace, pod, container) -> current logs written by the dump,
+    /// filled if `--follow-logs` is given
+    dumped_logs: std::sync::Mutex<HashMap<(String, String, String), watch::DumpedLogs>>,
+    status: status::Status,
+}
+
+impl Environment {
+    /// Returns true if dump must be stopped because deadline was reached
+    fn check_deadline(&self) -> bool {
+        match self.deadline {
+            Some(deadline) if tokio::time::Instant::now() >= deadline => {
+                self.interrupted.store(true, Ordering::Relaxed);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Returns false if access checks are enabled and resource is not allowed
+/// to be listed. Denials are already reported by generic dumper.
+async fn is_allowed(api_resource: &ApiResource, env: &Environment) -> bool {
+    // `--namespace` conflicts with dumpers of cluster-scoped kinds
+    let namespace = env.opts.namespace.as_deref();
+    match &env.access {
+        Some(access) => !matches!(
+            access.check_list(api_resource, namespace).await,
+            Ok(Some(_))
+        ),
+        None => true,
+    }
+}
+
+/// Lists page of objects of kind `K`, at `pinned_version` if it is set
+async fn list_typed<K>(
+    env: &Environment,
+    what: &str,
+    list_params: &ListParams,
+    pinned_version: Option<&str>,
+) -> anyhow::Result<kube::api::ObjectList<K>>
+where
+    K: Resource<DynamicType = ()> + Clone + DeserializeOwned + Debug,
+{
+    let url_path = K::url_path(&(), env.opts.namespace.as_deref());
+    retry::retry(env, what, || async {
+        let request = generic::list_request(&url_path, list_params, pinned_version)?;
+        Ok(env.client.request(request).await?)
+    })
+    .await
+    .map_err(|err| generic::snapshot_expired(err, pinned_version))
 }
 
 async fn dump_typed_simple<K, F, Fut>(func: F, env: &Arc<Environment>) -> anyhow::Result<()>
@@ -98,77 +1358,307 @@ where
     F: Fn(K, Arc<Environment>, ObjectLayout) -> Fut,
     Fut: Future<Output = anyhow::Result<()>>,
 {
-    let api = Api::<K>::all(env.client.clone());
-    let objects = api
-        .list(&Default::default())
-        .await
-        .context("failed to list pods")?;
-    for obj in objects {
-        let name = obj.name();
-        let namespace = obj.namespace();
-        let object_layout =
-            env.layout
-                .object_layout(&ApiResource::erase::<K>(&()), namespace.as_deref(), &name);
-        func(obj, env.clone(), object_layout)
+    if !is_allowed(&ApiResource::erase::<K>(&()), env).await {
+        return Ok(());
+    }
+    let what = format!("list {}", K::plural(&()));
+    let mut list_params = ListParams::default();
+    if env.opts.page_size != 0 {
+        list_params.limit = Some(env.opts.page_size);
+    }
+    let snapshot_version = env.snapshot_version.lock().unwrap().clone();
+    let mut pinned_version = snapshot_version.as_deref();
+    loop {
+        // next page is not requested while logs of previous ones use
+        // too much memory
+        memory::wait_for_room(env.memory.as_ref()).await;
+        let objects = list_typed::<K>(env, &what, &list_params, pinned_version)
             .await
-            .with_context(|| format!("failed to dump object {:?}/{}", namespace, name))?;
+            .with_context(|| format!("failed to list {}", K::plural(&())))?;
+        // later pages are consistent with the first one because of
+        // continue token
+        pinned_version = None;
+        let continue_token = objects.metadata.continue_.clone();
+        for obj in objects {
+            let name = obj.name();
+            let namespace = obj.namespace();
+            tracing::debug!(?namespace, %name, "dumping object");
+            let object_layout = env.layout.object_layout(
+                &ApiResource::erase::<K>(&()),
+                namespace.as_deref(),
+                &name,
+            )?;
+            func(obj, env.clone(), object_layout)
+                .await
+                .with_context(|| format!("failed to dump object {:?}/{}", namespace, name))?;
+        }
+        match continue_token {
+            Some(token) if !token.is_empty() => list_params.continue_token = Some(token),
+            _ => return Ok(()),
+        }
     }
-    Ok(())
 }
 
 async fn dump_pod(pod: Pod, env: Arc<Environment>, layout: ObjectLayout) -> anyhow::Result<()> {
+    if !logs_wanted(&env.opts, &pod) {
+        return Ok(());
+    }
     let pod_name = pod.name();
     let pod_namespace = pod.namespace().unwrap();
     let namespaced_pods_api = Api::<Pod>::namespaced(env.client.clone(), &pod_namespace);
     let pod_spec = pod.spec.as_ref().unwrap();
-    for container in &pod_spec.containers {
-        let mut log_params = LogParams {
-            container: Some(container.name.clone()),
-            follow: false,
-            pretty: true,
-            previous: false,
-            since_seconds: None,
-            tail_lines: None,
-            timestamps: true,
-            limit_bytes: None,
+    let containers = pod_spec
+        .init_containers
+        .iter()
+        .map(|container| (layout::ContainerKind::Init, &container.name))
+        .chain(
+            pod_spec
+                .containers
+                .iter()
+                .map(|container| (layout::ContainerKind::Regular, &container.name)),
+        )
+        .chain(
+            pod_spec
+                .ephemeral_containers
+                .iter()
+                .map(|container| (layout::ContainerKind::Ephemeral, &container.name)),
+        );
+    let mut fetches = Vec::new();
+    for (container_kind, container_name) in containers {
+        fetches.push((container_kind, container_name, layout::LogsKind::Current));
+        // ephemeral containers are never restarted
+        if !matches!(container_kind, layout::ContainerKind::Ephemeral) {
+            fetches.push((container_kind, container_name, layout::LogsKind::Previous));
+        }
+    }
+    // fetched concurrently, but written in order, so that report is stable
+    let logs = futures::future::join_all(fetches.iter().map(|(_, container_name, kind)| {
+        fetch_logs(&env, &namespaced_pods_api, &pod_name, container_name, *kind)
+    }))
+    .await;
+    let merged = if env.opts.merge_logs {
+        let current: Vec<_> = fetches
+            .iter()
+            .zip(&logs)
+            .filter(|((_, _, kind), _)| matches!(kind, layout::LogsKind::Current))
+            .filter_map(|((container_kind, container_name, _), logs)| {
+                let (logs, _) = logs.as_ref()?;
+                Some((
+                    container_title(*container_kind, container_name),
+                    logs.as_str(),
+                ))
+            })
+            .collect();
+        // file of container named `merged` is not overwritten
+        let clashes = fetches.iter().any(|(_, name, _)| name.as_str() == "merged");
+        if current.len() > 1 && !clashes {
+            Some(merge_logs(&current))
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+    for ((container_kind, container_name, kind), logs) in fetches.into_iter().zip(logs) {
+        let (logs, _charge) = match logs {
+            Some(logs) => logs,
+            None => continue,
         };
-        let current_logs = namespaced_pods_api.logs(&pod_name, &log_params).await.ok();
-        if let Some(current_logs) = current_logs {
-            tokio::fs::write(
-                layout.logs(layout::LogsKind::Current, &container.name),
-                current_logs,
-            )
-            .await?;
+        let path = layout.logs(container_kind, kind, container_name, logs.len());
+        if env.opts.follow_logs && matches!(kind, layout::LogsKind::Current) {
+            let dumped = watch::DumpedLogs::new(path.clone(), &logs);
+            env.dumped_logs.lock().unwrap().insert(
+                (
+                    pod_namespace.clone(),
+                    pod_name.clone(),
+                    container_name.clone(),
+                ),
+                dumped,
+            );
         }
-
-        log_params.previous = true;
-        let prev_logs = namespaced_pods_api.logs(&pod_name, &log_params).await.ok();
-        if let Some(prev_logs) = prev_logs {
-            tokio::fs::write(
-                layout.logs(layout::LogsKind::Previous, &container.name),
-                prev_logs,
-            )
-            .await?;
+        write_logs(&env, &path, logs).await?;
+        let mut title = container_title(container_kind, container_name);
+        if let layout::LogsKind::Previous = kind {
+            title.push_str(" (previous)");
         }
+        env.report_data
+            .record_log(layout.key().clone(), title, path);
+    }
+    if let Some(merged) = merged {
+        let path = layout.merged_logs(merged.len());
+        write_logs(&env, &path, merged).await?;
+        env.report_data
+            .record_log(layout.key().clone(), "all containers".to_string(), path);
     }
 
     Ok(())
 }
 
+/// Writes logs, gzip-compressing them if layout placed them into `.gz` file
+async fn write_logs(env: &Environment, path: &Path, logs: String) -> anyhow::Result<()> {
+    if path.extension().is_some_and(|ext| ext == "gz") {
+        let compressed = tokio::task::block_in_place(|| {
+            use std::io::Write as _;
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(logs.as_bytes())?;
+            encoder.finish()
+        })?;
+        env.storage.write(path, compressed).await
+    } else {
+        env.storage.write(path, logs).await
+    }
+}
+
+fn container_title(container_kind: layout::ContainerKind, container_name: &str) -> String {
+    match container_kind {
+        layout::ContainerKind::Regular => container_name.to_string(),
+        layout::ContainerKind::Init => format!("{} (init)", container_name),
+        layout::ContainerKind::Ephemeral => format!("{} (ephemeral)", container_name),
+    }
+}
+
+/// Interleaves lines of `(container, logs)` pairs by their timestamps,
+/// prefixing each line with its container. Lines without timestamp stay
+/// after the preceding line.
+fn merge_logs(logs: &[(String, &str)]) -> String {
+    let mut lines = Vec::new();
+    for (container, logs) in logs {
+        let mut time = std::time::UNIX_EPOCH;
+        for line in logs.lines() {
+            let timestamp = line.split(' ').next().unwrap_or_default();
+            // timestamps have varying number of fractional digits, so they
+            // are not compared as strings
+            if let Ok(parsed) = humantime::parse_rfc3339(timestamp) {
+                time = parsed;
+            }
+            lines.push((time, container, line));
+        }
+    }
+    // stable, so lines with equal timestamps keep their order
+    lines.sort_by_key(|(time, _, _)| *time);
+    let mut merged = String::new();
+    for (_, container, line) in lines {
+        merged.push_str(&format!("[{}] {}\n", container, line));
+    }
+    merged
+}
+
+/// Checks whether logs of the pod should be fetched, according to
+/// `--logs-selector` and `--logs`
+fn logs_wanted(opts: &Opts, pod: &Pod) -> bool {
+    if let Some(selector) = &opts.logs_selector {
+        if !selector.matches(pod.labels()) {
+            return false;
+        }
+    }
+    match opts.logs {
+        LogsMode::All => true,
+        LogsMode::OnlyUnhealthy => is_unhealthy(pod),
+    }
+}
+
+/// Pod is unhealthy if it is not Running or Succeeded, or any of its
+/// containers was restarted
+fn is_unhealthy(pod: &Pod) -> bool {
+    let status = match &pod.status {
+        Some(status) => status,
+        None => return true,
+    };
+    let phase_ok = matches!(status.phase.as_deref(), Some("Running") | Some("Succeeded"));
+    let restarted = status
+        .init_container_statuses
+        .iter()
+        .chain(&status.container_statuses)
+        .any(|container| container.restart_count > 0);
+    !phase_ok || restarted
+}
+
+fn is_running(pod: &Pod) -> bool {
+    pod.status
+        .as_ref()
+        .and_then(|status| status.phase.as_deref())
+        == Some("Running")
+}
+
+/// Fetches logs of the container, waiting until global limit of concurrent
+/// fetches allows it. Returns None if logs are not available. Logs are
+/// charged to memory budget until returned charge is dropped.
+async fn fetch_logs<'a>(
+    env: &'a Environment,
+    api: &Api<Pod>,
+    pod_name: &str,
+    container_name: &str,
+    kind: layout::LogsKind,
+) -> Option<(String, memory::Charge<'a>)> {
+    memory::wait_for_room(env.memory.as_ref()).await;
+    let _permit = env
+        .log_permits
+        .acquire()
+        .await
+        .expect("semaphore is never closed");
+    let log_params = LogParams {
+        container: Some(container_name.to_string()),
+        follow: false,
+        pretty: true,
+        previous: matches!(kind, layout::LogsKind::Previous),
+        // API does not accept zero
+        since_seconds: env
+            .opts
+            .logs_since
+            .map(|since| since.as_secs().max(1) as i64),
+        tail_lines: env.opts.logs_tail_lines.map(i64::from),
+        timestamps: true,
+        limit_bytes: env.opts.logs_max_bytes.map(|max| max as i64),
+    };
+    let started = std::time::Instant::now();
+    let logs = retry::retry(env, "fetch logs", || async {
+        Ok(api.logs(pod_name, &log_params).await?)
+    })
+    .await
+    .ok();
+    env.status.logs_fetched(started.elapsed());
+    logs.map(|mut logs| {
+        if let Some(max) = env.opts.logs_max_bytes {
+            truncate_logs(&mut logs, max);
+        }
+        let charge = memory::charge(env.memory.as_ref(), logs.len());
+        (logs, charge)
+    })
+}
+
+/// Cuts logs to at most `max` bytes, appending truncation marker. Logs of
+/// exactly `max` bytes are considered truncated too, because API cuts them
+/// to this size.
+fn truncate_logs(logs: &mut String, max: u64) {
+    if (logs.len() as u64) < max {
+        return;
+    }
+    let mut end = logs.len().min(max as usize);
+    while !logs.is_char_boundary(end) {
+        end -= 1;
+    }
+    logs.truncate(end);
+    if !logs.ends_with('\n') {
+        logs.push('\n');
+    }
+    logs.push_str(&format!(
+        "[kube-dump: log truncated to {} bytes by --logs-max-bytes]\n",
+        max
+    ));
+}
+
 async fn dump_config_map(
     cmap: ConfigMap,
-    _env: Arc<Environment>,
+    env: Arc<Environment>,
     layout: ObjectLayout,
 ) -> anyhow::Result<()> {
     for (key, value) in cmap.binary_data {
-        tokio::fs::write(layout.data_piece(&key), value.0).await?;
+        env.storage.write(&layout.data_piece(&key), value.0).await?;
     }
 
     for (key, value) in cmap.data {
-        let path = layout.data_piece(&key);
-        tokio::fs::write(&path, value)
-            .await
-            .with_context(|| format!("Failed to write to {}", path.display()))?;
+        env.storage.write(&layout.data_piece(&key), value).await?;
     }
 
     Ok(())
@@ -176,11 +1666,11 @@ async fn dump_config_map(
 
 async fn dump_secret(
     secret: Secret,
-    _env: Arc<Environment>,
+    env: Arc<Environment>,
     layout: ObjectLayout,
 ) -> anyhow::Result<()> {
     for (key, value) in secret.data {
-        tokio::fs::write(layout.data_piece(&key), value.0).await?;
+        env.storage.write(&layout.data_piece(&key), value.0).await?;
     }
 
     Ok(())
@@ -202,9 +1692,8 @@ impl InvolvedObject {
                 .api_version
                 .as_deref()
                 .unwrap_or("v1")
-                .rsplitn(2, '/')
-                .nth(1)
-                .map(ToString::to_string),
+                .rsplit_once('/')
+                .map(|(group, _)| group.to_string()),
             namespace: ev.involved_object.namespace.clone(),
             name: ev.involved_object.name.clone()?,
             kind: ev.involved_object.kind.clone()?,
@@ -219,51 +1708,96 @@ fn event_to_string(ev: Event) -> String {
 }
 
 async fn dump_events(env: &Environment) -> anyhow::Result<()> {
-    let events_api = Api::<Event>::all(env.client.clone());
-    let events = events_api.list(&Default::default()).await?.items;
+    if !is_allowed(&ApiResource::erase::<Event>(&()), env).await {
+        return Ok(());
+    }
+    let warning_only = matches!(env.opts.events, EventsMode::WarningOnly);
+    let mut list_params = ListParams::default();
+    if warning_only {
+        // Normal events are most of the volume, so they are filtered out
+        // by the server
+        list_params = list_params.fields("type=Warning");
+    }
+    if env.opts.page_size != 0 {
+        list_params.limit = Some(env.opts.page_size);
+    }
+    let snapshot_version = env.snapshot_version.lock().unwrap().clone();
+    let mut pinned_version = snapshot_version.as_deref();
 
     let mut mapping = BTreeMap::new();
-    for event in events {
-        let obj = match InvolvedObject::from_event(&event) {
-            Some(o) => o,
-            None => {
-                eprintln!(
-                    "Skipping dangling event {}/{}",
-                    event.namespace().unwrap(),
-                    event.name()
-                );
+    loop {
+        let events = list_typed::<Event>(env, "list events", &list_params, pinned_version).await?;
+        // later pages are consistent with the first one because of
+        // continue token
+        pinned_version = None;
+        let continue_token = events.metadata.continue_.clone();
+        for event in events {
+            // in case server ignored field selector
+            if warning_only && event.type_.as_deref() != Some("Warning") {
                 continue;
             }
-        };
-        mapping.entry(obj).or_insert_with(Vec::new).push(event);
+            let obj = match InvolvedObject::from_event(&event) {
+                Some(o) => o,
+                None => {
+                    tracing::debug!(
+                        "Skipping dangling event {}/{}",
+                        event.namespace().unwrap(),
+                        event.name()
+                    );
+                    continue;
+                }
+            };
+            env.report_data.record_event(&event);
+            mapping.entry(obj).or_insert_with(Vec::new).push(event);
+        }
+        match continue_token {
+            Some(token) if !token.is_empty() => list_params.continue_token = Some(token),
+            _ => break,
+        }
     }
+    // stream formats have no per-object files, so index is the only
+    // reliable source of dumped objects
+    let dumped: HashSet<_> = env
+        .index
+        .entries()
+        .into_iter()
+        .map(|entry| (entry.group, entry.kind, entry.namespace, entry.name))
+        .collect();
     for (object, events) in mapping {
-        let resource = ApiResource {
-            kind: object.kind,
-            group: object.group.unwrap_or_default(),
-            api_version: "BUG".to_string(),
-            version: "BUG".to_string(),
-            plural: "BUG".to_string(),
+        let InvolvedObject {
+            group,
+            kind,
+            namespace,
+            name,
+        } = object;
+        let group = group.unwrap_or_default();
+        let resource = match env
+            .apis
+            .iter()
+            .find(|(resource, _)| resource.group == group && resource.kind == kind)
+        {
+            Some((resource, _)) => resource,
+            None => {
+                tracing::debug!("Skipping event referencing unknown kind {}", kind);
+                continue;
+            }
         };
-        let layout = env
-            .layout
-            .object_layout(&resource, object.namespace.as_deref(), &object.name);
-        let repr_path = layout.representation();
-        let exists = tokio::task::spawn_blocking(move || repr_path.exists())
-            .await
-            .unwrap();
-        if !exists {
-            eprintln!("Skipping event referencing not existing object");
+        let key = (group, kind, namespace, name);
+        if !dumped.contains(&key) {
+            tracing::debug!("Skipping event referencing not existing object");
             continue;
         }
+        let (_, _, namespace, name) = key;
+        let layout = env
+            .layout
+            .object_layout(resource, namespace.as_deref(), &name)?;
         let log = events
             .into_iter()
             .map(event_to_string)
             .collect::<Vec<_>>()
             .join("\n");
 
-        let path = layout.event_log();
-        tokio::fs::write(path, log).await?;
+        env.storage.write(&layout.event_log(), log).await?;
     }
     Ok(())
 }
@@ -0,0 +1,88 @@
+//! Bounding memory used by objects and logs which were received, but not
+//! written yet
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+/// Budget of `--max-memory`. It is soft: data is charged after it is
+/// received, and receiving waits until charged data fits the limit.
+pub struct MemoryBudget {
+    limit: u64,
+    used: Mutex<u64>,
+    freed: Notify,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: u64) -> MemoryBudget {
+        MemoryBudget {
+            limit,
+            used: Mutex::new(0),
+            freed: Notify::new(),
+        }
+    }
+
+    pub fn has_room(&self) -> bool {
+        *self.used.lock().unwrap() < self.limit
+    }
+
+    /// Waits until some memory is freed, if limit is reached
+    pub async fn wait_for_room(&self) {
+        loop {
+            // registered before checking, so that release is not missed
+            let freed = self.freed.notified();
+            if self.has_room() {
+                return;
+            }
+            freed.await;
+        }
+    }
+
+    pub fn charge(&self, size: u64) -> Charge<'_> {
+        *self.used.lock().unwrap() += size;
+        Charge {
+            budget: Some(self),
+            size,
+        }
+    }
+
+    fn release(&self, size: u64) {
+        *self.used.lock().unwrap() -= size;
+        self.freed.notify_waiters();
+    }
+}
+
+/// Memory held by received data, released on drop
+pub struct Charge<'a> {
+    budget: Option<&'a MemoryBudget>,
+    size: u64,
+}
+
+impl Drop for Charge<'_> {
+    fn drop(&mut self) {
+        if let Some(budget) = self.budget {
+            budget.release(self.size);
+        }
+    }
+}
+
+/// True unless memory limit is enabled and reached
+pub fn has_room(budget: Option<&MemoryBudget>) -> bool {
+    budget.is_none_or(MemoryBudget::has_room)
+}
+
+/// Waits for room in `budget`, if memory limit is enabled
+pub async fn wait_for_room(budget: Option<&MemoryBudget>) {
+    if let Some(budget) = budget {
+        budget.wait_for_room().await;
+    }
+}
+
+/// Charges `size` bytes to `budget`, if memory limit is enabled
+pub fn charge(budget: Option<&MemoryBudget>, size: usize) -> Charge<'_> {
+    match budget {
+        Some(budget) => budget.charge(size as u64),
+        None => Charge {
+            budget: None,
+            size: 0,
+        },
+    }
+}
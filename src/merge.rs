@@ -0,0 +1,263 @@
+//! Combining several partial dumps into one
+use crate::{index::Entry, layout::ObjectKey};
+use anyhow::Context as _;
+use clap::Clap;
+use serde_json::Value;
+use sha2::{Digest as _, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::PathBuf,
+};
+
+/// Merges partial dumps (e.g. made for different namespaces) into a single
+/// dump with unified index
+#[derive(Clap)]
+pub struct MergeOpts {
+    /// Dumps to merge. If the same object is present in several of them,
+    /// the one with the newest resourceVersion wins
+    #[clap(required = true, min_values = 2)]
+    inputs: Vec<PathBuf>,
+    /// Directory merged dump should be written to
+    #[clap(long)]
+    out: PathBuf,
+}
+
+fn key_of(entry: &Entry) -> ObjectKey {
+    ObjectKey {
+        group: entry.group.clone(),
+        kind: entry.kind.clone(),
+        namespace: entry.namespace.clone(),
+        name: entry.name.clone(),
+    }
+}
+
+/// Returns true if `candidate` resource version is known to be newer than
+/// `current`. Resource versions are opaque, but in practice they are
+/// increasing integers.
+fn is_newer(candidate: Option<&str>, current: Option<&str>) -> bool {
+    match (
+        candidate.and_then(|rv| rv.parse::<u64>().ok()),
+        current.and_then(|rv| rv.parse::<u64>().ok()),
+    ) {
+        (Some(candidate), Some(current)) => candidate > current,
+        _ => false,
+    }
+}
+
+fn object_resource_version(object: &Value) -> Option<&str> {
+    object
+        .pointer("/metadata/resourceVersion")
+        .and_then(Value::as_str)
+}
+
+/// Common prefix of all files belonging to the object stored at `path`:
+/// `raw.json` files live in per-object directories, other layouts put
+/// object name in front of the extension
+fn object_stem(path: &str) -> String {
+    let (dir, file) = match path.rsplit_once('/') {
+        Some((dir, file)) => (format!("{}/", dir), file),
+        None => (String::new(), path),
+    };
+    if file.starts_with("raw.") {
+        return dir;
+    }
+    let mut name = file;
+    for ext in &[".zst", ".age", ".gpg", ".json", ".yaml", ".jsonl"] {
+        name = name.strip_suffix(ext).unwrap_or(name);
+    }
+    format!("{}{}.", dir, name)
+}
+
+/// Finds object owning the file, by the longest matching stem
+fn owner<'a>(stems: &'a HashMap<String, ObjectKey>, path: &str) -> Option<&'a ObjectKey> {
+    path.char_indices()
+        .rev()
+        .filter(|(_, c)| *c == '/' || *c == '.')
+        .find_map(|(i, _)| stems.get(&path[..=i]))
+}
+
+/// Files which are regenerated for the merged dump
+fn is_regenerated(path: &str) -> bool {
+    path == "index.json" || path == "SHA256SUMS" || path.starts_with("SHA256SUMS.")
+}
+
+struct Output {
+    root: PathBuf,
+    checksums: BTreeMap<String, String>,
+}
+
+impl Output {
+    fn write(&mut self, path: &str, data: &[u8]) -> anyhow::Result<()> {
+        let full = self.root.join(path);
+        if let Some(parent) = full.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&full, data)
+            .with_context(|| format!("failed to write {}", full.display()))?;
+        self.checksums
+            .insert(path.to_string(), format!("{:x}", Sha256::digest(data)));
+        Ok(())
+    }
+}
+
+/// Merges stream files (containing many objects), keeping the newest
+/// version of each object
+fn merge_stream(sources: &[(usize, PathBuf)]) -> anyhow::Result<Vec<u8>> {
+    let mut objects: BTreeMap<crate::load::Key, Value> = BTreeMap::new();
+    for (_, path) in sources {
+        let parsed = crate::load::parse_file(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        for object in parsed {
+            let key = match crate::load::object_key(&object) {
+                Some(k) => k,
+                None => continue,
+            };
+            let replace = match objects.get(&key) {
+                Some(current) => is_newer(
+                    object_resource_version(&object),
+                    object_resource_version(current),
+                ),
+                None => true,
+            };
+            if replace {
+                objects.insert(key, object);
+            }
+        }
+    }
+    let name = sources[0].1.to_string_lossy().into_owned();
+    let compressed = name.ends_with(".zst");
+    let name = name.strip_suffix(".zst").unwrap_or(&name);
+    let mut data = String::new();
+    for object in objects.values() {
+        if name.ends_with(".yaml") {
+            // serde_yaml starts each document with `---`
+            data.push_str(&serde_yaml::to_string(object)?);
+            if !data.ends_with('\n') {
+                data.push('\n');
+            }
+        } else {
+            data.push_str(&serde_json::to_string(object)?);
+            data.push('\n');
+        }
+    }
+    if compressed {
+        Ok(zstd::stream::encode_all(data.as_bytes(), 0)?)
+    } else {
+        Ok(data.into_bytes())
+    }
+}
+
+fn merge_dumps(opts: &MergeOpts) -> anyhow::Result<()> {
+    if opts.out.exists() && std::fs::read_dir(&opts.out)?.next().is_some() {
+        anyhow::bail!("{} is not empty", opts.out.display());
+    }
+    // newest version of each object, along with input it was taken from
+    let mut winners: BTreeMap<ObjectKey, (usize, Entry)> = BTreeMap::new();
+    let mut stems = HashMap::new();
+    let mut streams = HashSet::new();
+    for (i, input) in opts.inputs.iter().enumerate() {
+        let entries = crate::load::read_index(input)?;
+        let mut paths = HashSet::new();
+        for entry in &entries {
+            if !paths.insert(entry.path.clone()) {
+                streams.insert(entry.path.clone());
+            }
+        }
+        for entry in entries {
+            let key = key_of(&entry);
+            stems.insert(object_stem(&entry.path), key.clone());
+            let replace = match winners.get(&key) {
+                Some((_, current)) => is_newer(
+                    entry.resource_version.as_deref(),
+                    current.resource_version.as_deref(),
+                ),
+                None => true,
+            };
+            if replace {
+                winners.insert(key, (i, entry));
+            }
+        }
+    }
+    for stream in &streams {
+        stems.remove(&object_stem(stream));
+    }
+
+    let mut files: BTreeMap<String, Vec<(usize, PathBuf)>> = BTreeMap::new();
+    for (i, input) in opts.inputs.iter().enumerate() {
+        let mut found = Vec::new();
+        crate::upload::collect_files(input, input, &mut found)?;
+        for (path, key) in found {
+            if !is_regenerated(&key) {
+                files.entry(key).or_default().push((i, path));
+            }
+        }
+    }
+
+    let mut output = Output {
+        root: opts.out.clone(),
+        checksums: BTreeMap::new(),
+    };
+    let mut conflicts = 0;
+    for (path, sources) in &files {
+        if streams.contains(path) && sources.len() > 1 {
+            output.write(path, &merge_stream(sources)?)?;
+            continue;
+        }
+        let contents = sources
+            .iter()
+            .map(|(_, file)| {
+                std::fs::read(file).with_context(|| format!("failed to read {}", file.display()))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if contents.iter().all(|data| *data == contents[0]) {
+            output.write(path, &contents[0])?;
+            continue;
+        }
+        let winner = owner(&stems, path)
+            .and_then(|key| winners.get(key))
+            .and_then(|(input, _)| sources.iter().position(|(i, _)| i == input));
+        if let Some(pos) = winner {
+            output.write(path, &contents[pos])?;
+            continue;
+        }
+        // unrelated files which happen to have the same path: keep all
+        tracing::warn!(
+            "{} differs between inputs, other versions are stored as {}~<input number>",
+            path,
+            path
+        );
+        conflicts += 1;
+        output.write(path, &contents[0])?;
+        for ((input, _), data) in sources.iter().zip(&contents).skip(1) {
+            output.write(&format!("{}~{}", path, input + 1), data)?;
+        }
+    }
+
+    let mut entries: Vec<Entry> = winners.into_values().map(|(_, entry)| entry).collect();
+    entries.sort_by(|a, b| {
+        (&a.group, &a.kind, &a.namespace, &a.name).cmp(&(&b.group, &b.kind, &b.namespace, &b.name))
+    });
+    output.write(
+        "index.json",
+        serde_json::to_string_pretty(&entries)?.as_bytes(),
+    )?;
+    let manifest: String = output
+        .checksums
+        .iter()
+        .map(|(path, hash)| format!("{}  {}\n", hash, path))
+        .collect();
+    std::fs::write(opts.out.join("SHA256SUMS"), manifest)?;
+    tracing::info!(
+        "Merged {} objects from {} dumps ({} conflicting files)",
+        entries.len(),
+        opts.inputs.len(),
+        conflicts
+    );
+    Ok(())
+}
+
+pub async fn merge(opts: MergeOpts) -> anyhow::Result<()> {
+    tokio::task::spawn_blocking(move || merge_dumps(&opts))
+        .await
+        .unwrap()
+}
@@ -0,0 +1,75 @@
+//! Resource usage reported by `metrics.k8s.io`, saved next to the objects
+//! it describes
+use crate::Environment;
+use anyhow::Context as _;
+use k8s_openapi::api::core::v1::{Node, Pod};
+use kube::api::{Api, ApiResource, DynamicObject, ListParams, ResourceExt};
+
+const METRICS_GROUP: &str = "metrics.k8s.io";
+
+/// Writes usage of each object of kind `K` as `metrics.json`, taking it
+/// from `plural` resource of the metrics API. Nothing is written if the
+/// metrics API is not available, and failures of the API are reported
+/// without failing the dump.
+async fn dump_usage<K>(env: &Environment, plural: &str) -> anyhow::Result<()>
+where
+    K: kube::Resource<DynamicType = ()>,
+{
+    let metrics_resource = env
+        .apis
+        .iter()
+        .map(|(api_resource, _)| api_resource)
+        .find(|api_resource| api_resource.group == METRICS_GROUP && api_resource.plural == plural);
+    let metrics_resource = match metrics_resource {
+        Some(metrics_resource) => metrics_resource,
+        None => {
+            tracing::debug!("{} API is not available", METRICS_GROUP);
+            return Ok(());
+        }
+    };
+    let api = match &env.opts.namespace {
+        Some(namespace) => {
+            Api::<DynamicObject>::namespaced_with(env.client.clone(), namespace, metrics_resource)
+        }
+        None => Api::<DynamicObject>::all_with(env.client.clone(), metrics_resource),
+    };
+    let what = format!("list {}.{}", plural, METRICS_GROUP);
+    // aggregated API servers are often slow
+    let usages = tokio::time::timeout(
+        env.opts.aggregated_timeout,
+        crate::retry::retry(env, &what, || async {
+            Ok(api.list(&ListParams::default()).await?)
+        }),
+    )
+    .await
+    .with_context(|| format!("{} timed out", what))
+    .and_then(|result| result);
+    let usages = match usages {
+        Ok(usages) => usages,
+        Err(err) => {
+            env.status.error(what, format!("{:#}", err));
+            return Ok(());
+        }
+    };
+    let resource = ApiResource::erase::<K>(&());
+    for usage in usages {
+        let layout =
+            env.layout
+                .object_layout(&resource, usage.namespace().as_deref(), &usage.name())?;
+        env.storage
+            .write(
+                &layout.resource_metrics(),
+                serde_json::to_string_pretty(&usage)?,
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+pub async fn dump_pods(env: &Environment) -> anyhow::Result<()> {
+    dump_usage::<Pod>(env, "pods").await
+}
+
+pub async fn dump_nodes(env: &Environment) -> anyhow::Result<()> {
+    dump_usage::<Node>(env, "nodes").await
+}
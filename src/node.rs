@@ -0,0 +1,120 @@
+//! Data served by kubelets, fetched through the API server node proxy
+use crate::{layout::ObjectLayout, Environment};
+use futures::TryStreamExt as _;
+use k8s_openapi::api::core::v1::Node;
+use kube::api::ResourceExt;
+use std::{collections::VecDeque, path::PathBuf, sync::Arc};
+
+/// Size of node log tail which is kept if `--logs-max-bytes` is not given
+const NODE_LOGS_MAX_BYTES: u64 = 64 << 20;
+
+fn proxy_request(node: &Node, path: &str) -> anyhow::Result<http::Request<Vec<u8>>> {
+    let url = format!("/api/v1/nodes/{}/proxy/{}", node.name(), path);
+    Ok(http::Request::get(url).body(Vec::new())?)
+}
+
+/// Fetches `path` of the kubelet API of the node
+async fn proxy_get(env: &Environment, node: &Node, path: &str) -> anyhow::Result<String> {
+    Ok(env.client.request_text(proxy_request(node, path)?).await?)
+}
+
+/// Fetches log file at `path` of the kubelet API of the node, keeping only
+/// last `max` bytes (starting from a whole line)
+async fn fetch_log_tail(
+    env: &Environment,
+    node: &Node,
+    path: &str,
+    max: u64,
+) -> anyhow::Result<Vec<u8>> {
+    let mut chunks = Box::pin(
+        env.client
+            .request_text_stream(proxy_request(node, path)?)
+            .await?,
+    );
+    let mut tail = VecDeque::new();
+    let mut truncated = false;
+    while let Some(chunk) = chunks.try_next().await? {
+        tail.extend(chunk.iter());
+        if tail.len() as u64 > max {
+            tail.drain(..tail.len() - max as usize);
+            truncated = true;
+        }
+    }
+    let mut tail = Vec::from(tail);
+    if truncated {
+        let line_start = tail
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(0, |newline| newline + 1);
+        let mut logs = format!(
+            "[kube-dump: log truncated to last {} bytes by --logs-max-bytes]\n",
+            max
+        )
+        .into_bytes();
+        logs.extend_from_slice(&tail[line_start..]);
+        tail = logs;
+    }
+    Ok(tail)
+}
+
+/// Saves response of kubelet endpoint into `file`, after transforming it
+/// with `convert`. Failures are reported, and do not fail the dump.
+async fn save(
+    env: &Environment,
+    node: &Node,
+    path: &str,
+    file: PathBuf,
+    convert: impl FnOnce(String) -> anyhow::Result<String>,
+) -> anyhow::Result<()> {
+    match proxy_get(env, node, path).await.and_then(convert) {
+        Ok(response) => env.storage.write(&file, response).await,
+        Err(err) => {
+            env.status.error(
+                format!("{} of node {}", path, node.name()),
+                format!("{:#}", err),
+            );
+            Ok(())
+        }
+    }
+}
+
+pub async fn dump_node(
+    node: Node,
+    env: Arc<Environment>,
+    layout: ObjectLayout,
+) -> anyhow::Result<()> {
+    if env.opts.kubelet_stats {
+        save(&env, &node, "stats/summary", layout.kubelet_stats(), Ok).await?;
+    }
+    if env.opts.kubelet_config {
+        save(&env, &node, "configz", layout.kubelet_config(), |configz| {
+            // configuration is wrapped into `{"kubeletconfig": ...}`
+            let mut configz: serde_json::Value = serde_json::from_str(&configz)?;
+            let config = configz
+                .get_mut("kubeletconfig")
+                .map(serde_json::Value::take)
+                .unwrap_or(configz);
+            Ok(serde_json::to_string_pretty(&config)?)
+        })
+        .await?;
+    }
+    let logs_wanted = match &env.opts.node_logs_selector {
+        Some(selector) => selector.matches(node.labels()),
+        None => true,
+    };
+    if logs_wanted {
+        let max = env.opts.logs_max_bytes.unwrap_or(NODE_LOGS_MAX_BYTES);
+        for path in &env.opts.node_logs {
+            let file = layout.node_logs(&crate::scrape::endpoint_file_name(path));
+            let path = format!("logs/{}", path.trim_start_matches('/'));
+            match fetch_log_tail(&env, &node, &path, max).await {
+                Ok(logs) => env.storage.write(&file, logs).await?,
+                Err(err) => env.status.error(
+                    format!("{} of node {}", path, node.name()),
+                    format!("{:#}", err),
+                ),
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,82 @@
+//! Notifying external systems (webhooks, Slack) when dump completes
+use std::{collections::BTreeMap, time::Instant};
+
+/// Information about the dump, filled as it progresses
+pub struct Summary {
+    started: Instant,
+    pub cluster: Option<String>,
+    /// Final location of the output
+    pub output: Option<String>,
+    /// Number of dumped objects per kind
+    objects: BTreeMap<String, usize>,
+}
+
+impl Summary {
+    pub fn start() -> Summary {
+        Summary {
+            started: Instant::now(),
+            cluster: None,
+            output: None,
+            objects: BTreeMap::new(),
+        }
+    }
+
+    pub fn record_objects(&mut self, entries: &[crate::index::Entry]) {
+        for entry in entries {
+            let kind = if entry.group.is_empty() {
+                entry.kind.clone()
+            } else {
+                format!("{}.{}", entry.kind, entry.group)
+            };
+            *self.objects.entry(kind).or_default() += 1;
+        }
+    }
+}
+
+/// POSTs JSON summary of the dump to `url`. Payload has `text` field, so it
+/// is also accepted by Slack incoming webhooks.
+pub async fn send(url: &str, summary: &Summary, result: &anyhow::Result<()>) -> anyhow::Result<()> {
+    let duration = summary.started.elapsed();
+    let total: usize = summary.objects.values().sum();
+    let cluster = summary.cluster.as_deref().unwrap_or("cluster");
+    let text = match result {
+        Ok(()) => format!(
+            "kube-dump of {} succeeded in {}s: {} objects written to {}",
+            cluster,
+            duration.as_secs(),
+            total,
+            summary.output.as_deref().unwrap_or("(unknown)")
+        ),
+        Err(err) => format!(
+            "kube-dump of {} failed after {}s: {:#}",
+            cluster,
+            duration.as_secs(),
+            err
+        ),
+    };
+    let payload = serde_json::json!({
+        "text": text,
+        "success": result.is_ok(),
+        "error": result.as_ref().err().map(|err| format!("{:#}", err)),
+        "cluster": summary.cluster,
+        "output": summary.output,
+        "durationSeconds": duration.as_secs_f64(),
+        "objects": {
+            "total": total,
+            "byKind": summary.objects,
+        },
+    });
+    crate::upload::curl(
+        &[
+            "--request",
+            "POST",
+            "--header",
+            "Content-Type: application/json",
+            "--data-binary",
+            "@-",
+            url,
+        ],
+        serde_json::to_string(&payload)?.as_bytes(),
+    )
+    .await
+}
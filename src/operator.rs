@@ -0,0 +1,220 @@
+//! Operator mode: dumps are requested by creating `DumpRequest` custom
+//! resources, and their results are written to the status of the resource
+use anyhow::Context as _;
+use clap::Clap;
+use kube::api::{Api, ApiResource, DynamicObject, ListParams, Patch, PatchParams, ResourceExt};
+use serde::Deserialize;
+use std::{
+    path::{Component, Path},
+    time::{Duration, SystemTime},
+};
+
+/// CustomResourceDefinition of `DumpRequest`, printed by `--print-crd`
+const CRD: &str = r#"apiVersion: apiextensions.k8s.io/v1
+kind: CustomResourceDefinition
+metadata:
+  name: dumprequests.kube-dump.io
+spec:
+  group: kube-dump.io
+  scope: Namespaced
+  names:
+    kind: DumpRequest
+    plural: dumprequests
+    singular: dumprequest
+  versions:
+    - name: v1alpha1
+      served: true
+      storage: true
+      subresources:
+        status: {}
+      additionalPrinterColumns:
+        - name: Phase
+          type: string
+          jsonPath: .status.phase
+        - name: Destination
+          type: string
+          jsonPath: .spec.destination
+      schema:
+        openAPIV3Schema:
+          type: object
+          properties:
+            spec:
+              type: object
+              required: [destination]
+              properties:
+                destination:
+                  description: Path or URL dump is written to (same as kube-dump output)
+                  type: string
+                args:
+                  description: Additional kube-dump options, e.g. ["--format", "jsonl"]
+                  type: array
+                  items:
+                    type: string
+            status:
+              type: object
+              properties:
+                phase:
+                  description: Running, Succeeded or Failed
+                  type: string
+                message:
+                  type: string
+                startTime:
+                  type: string
+                  format: date-time
+                completionTime:
+                  type: string
+                  format: date-time
+"#;
+
+fn dump_request_resource() -> ApiResource {
+    ApiResource {
+        group: "kube-dump.io".to_string(),
+        version: "v1alpha1".to_string(),
+        api_version: "kube-dump.io/v1alpha1".to_string(),
+        kind: "DumpRequest".to_string(),
+        plural: "dumprequests".to_string(),
+    }
+}
+
+/// Performs dumps described by `DumpRequest` resources
+#[derive(Clap)]
+pub struct OperatorOpts {
+    /// Only handle DumpRequests in this namespace (all namespaces by default)
+    #[clap(long)]
+    namespace: Option<String>,
+    /// DumpRequests in these namespaces may dump the whole cluster. Others
+    /// only dump objects in their own namespace
+    #[clap(long)]
+    admin_namespace: Vec<String>,
+    /// Only allow destinations starting with this prefix (e.g. `/dumps/` or
+    /// `gs://bucket/`). Destinations containing `..` are always refused
+    #[clap(long)]
+    destination_prefix: Option<String>,
+    /// How often new DumpRequests are looked for
+    #[clap(long, default_value = "10s", parse(try_from_str = humantime::parse_duration))]
+    poll_interval: Duration,
+    /// Print CustomResourceDefinition of DumpRequest and exit
+    #[clap(long)]
+    print_crd: bool,
+}
+
+#[derive(Deserialize)]
+struct DumpRequestSpec {
+    destination: String,
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+pub async fn run(opts: OperatorOpts) -> anyhow::Result<()> {
+    if opts.print_crd {
+        print!("{}", CRD);
+        return Ok(());
+    }
+    let client = kube::Client::try_default()
+        .await
+        .context("failed to connect to cluster")?;
+    let resource = dump_request_resource();
+    let api: Api<DynamicObject> = match &opts.namespace {
+        Some(namespace) => Api::namespaced_with(client.clone(), namespace, &resource),
+        None => Api::all_with(client.clone(), &resource),
+    };
+    tracing::info!("Waiting for DumpRequests");
+    loop {
+        match api.list(&ListParams::default()).await {
+            Ok(requests) => {
+                // requests are handled one by one, in order of creation
+                let mut pending: Vec<_> = requests
+                    .into_iter()
+                    .filter(|request| request.data.pointer("/status/phase").is_none())
+                    .collect();
+                pending.sort_by_key(|request| request.metadata.creation_timestamp.clone());
+                for request in pending {
+                    if let Err(err) = process(&client, &resource, &opts, request).await {
+                        tracing::error!("Failed to handle DumpRequest: {:#}", err);
+                    }
+                }
+            }
+            Err(err) => tracing::warn!("Failed to list DumpRequests: {}", err),
+        }
+        tokio::time::sleep(opts.poll_interval).await;
+    }
+}
+
+fn now() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()
+}
+
+async fn set_status(
+    api: &Api<DynamicObject>,
+    name: &str,
+    status: serde_json::Value,
+) -> anyhow::Result<()> {
+    let patch = serde_json::json!({ "status": status });
+    api.patch_status(name, &PatchParams::default(), &Patch::Merge(&patch))
+        .await?;
+    Ok(())
+}
+
+async fn process(
+    client: &kube::Client,
+    resource: &ApiResource,
+    opts: &OperatorOpts,
+    request: DynamicObject,
+) -> anyhow::Result<()> {
+    let name = request.name();
+    let namespace = request
+        .namespace()
+        .context("DumpRequest must be namespaced")?;
+    let api = Api::<DynamicObject>::namespaced_with(client.clone(), &namespace, resource);
+    tracing::info!("Handling DumpRequest {}/{}", namespace, name);
+    set_status(
+        &api,
+        &name,
+        serde_json::json!({"phase": "Running", "startTime": now()}),
+    )
+    .await?;
+    let status = match perform(opts, &request, &namespace).await {
+        Ok(()) => serde_json::json!({
+            "phase": "Succeeded",
+            "message": "",
+            "completionTime": now(),
+        }),
+        Err(err) => serde_json::json!({
+            "phase": "Failed",
+            "message": format!("{:#}", err),
+            "completionTime": now(),
+        }),
+    };
+    set_status(&api, &name, status).await
+}
+
+/// Performs the dump described by request created in `namespace`
+async fn perform(
+    opts: &OperatorOpts,
+    request: &DynamicObject,
+    namespace: &str,
+) -> anyhow::Result<()> {
+    let spec = request.data.get("spec").cloned().unwrap_or_default();
+    let spec: DumpRequestSpec = serde_json::from_value(spec).context("invalid spec")?;
+    if let Some(prefix) = &opts.destination_prefix {
+        if !spec.destination.starts_with(prefix.as_str()) {
+            anyhow::bail!("destination must start with {}", prefix);
+        }
+    }
+    if Path::new(&spec.destination)
+        .components()
+        .any(|component| component == Component::ParentDir)
+    {
+        anyhow::bail!("destination must not contain '..'");
+    }
+    let mut dump_opts = crate::parse_requested_dump(&spec.args, &spec.destination)?;
+    if !opts.admin_namespace.iter().any(|admin| admin == namespace) {
+        match &dump_opts.namespace {
+            Some(requested) if requested != namespace => {
+                anyhow::bail!("only namespace {} can be dumped", namespace)
+            }
+            _ => dump_opts.namespace = Some(namespace.to_string()),
+        }
+    }
+    crate::dump_and_notify(dump_opts).await
+}
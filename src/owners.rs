@@ -0,0 +1,106 @@
+//! Support for `owner-tree` layout scheme. Objects are first dumped as usual,
+//! and after generic dump is finished they are moved beneath their owners.
+use crate::layout::ObjectKey;
+use kube::api::{ApiResource, DynamicObject};
+use std::{collections::HashMap, sync::Mutex};
+
+#[derive(Default)]
+struct State {
+    /// uid -> object
+    uids: HashMap<String, ObjectKey>,
+    /// object -> (its resource, uid of its controller or first owner)
+    owned: HashMap<ObjectKey, (ApiResource, String)>,
+}
+
+/// Owner references observed during generic dump
+#[derive(Default)]
+pub struct OwnerRefs {
+    state: Mutex<State>,
+}
+
+impl OwnerRefs {
+    pub fn record(&self, resource: &ApiResource, object: &DynamicObject) {
+        let key = ObjectKey {
+            group: resource.group.clone(),
+            kind: resource.kind.clone(),
+            namespace: object.metadata.namespace.clone(),
+            name: object.metadata.name.clone().unwrap_or_default(),
+        };
+        let mut state = self.state.lock().unwrap();
+        if let Some(uid) = &object.metadata.uid {
+            state.uids.insert(uid.clone(), key.clone());
+        }
+        let owner_refs = &object.metadata.owner_references;
+        let owner = owner_refs
+            .iter()
+            .find(|r| r.controller == Some(true))
+            .or_else(|| owner_refs.first());
+        if let Some(owner) = owner {
+            state
+                .owned
+                .insert(key, (resource.clone(), owner.uid.clone()));
+        }
+    }
+}
+
+/// Number of owners above the object (limited, in case owners form a cycle)
+fn depth<'a>(owners: &'a HashMap<ObjectKey, ObjectKey>, mut key: &'a ObjectKey) -> usize {
+    let mut depth = 0;
+    while let Some(owner) = owners.get(key) {
+        depth += 1;
+        if depth > owners.len() {
+            break;
+        }
+        key = owner;
+    }
+    depth
+}
+
+/// Moves all owned objects beneath their owners and makes Layout place
+/// files produced later there as well
+pub async fn resolve(env: &crate::Environment) -> anyhow::Result<()> {
+    let state = std::mem::take(&mut *env.owners.state.lock().unwrap());
+    let mut owners = HashMap::new();
+    for (key, (_, owner_uid)) in &state.owned {
+        let owner = match state.uids.get(owner_uid) {
+            Some(owner) => owner,
+            None => continue,
+        };
+        // owner references can not cross namespaces, but namespaced
+        // objects can be owned by cluster-scoped ones; such objects are
+        // left in their namespaces
+        if owner.namespace == key.namespace && owner != key {
+            owners.insert(key.clone(), owner.clone());
+        }
+    }
+    let mut moves = Vec::new();
+    for key in owners.keys() {
+        let (resource, _) = &state.owned[key];
+        let before = env
+            .layout
+            .object_layout(resource, key.namespace.as_deref(), &key.name)?
+            .location();
+        moves.push((depth(&owners, key), key, resource, before));
+    }
+    // owners are moved before objects they own, so that target directory
+    // of each object is already at its final place
+    moves.sort_by_key(|(depth, ..)| *depth);
+    env.layout.set_owners(owners.clone());
+    for (_, key, resource, before) in moves {
+        let after = env
+            .layout
+            .object_layout(resource, key.namespace.as_deref(), &key.name)?
+            .location();
+        env.storage.rename(&before, &after).await?;
+        env.index
+            .relocate(env.layout.relative(&before), env.layout.relative(&after));
+        if env.opts.by_kind_links {
+            let link = env
+                .layout
+                .kind_link(resource, key.namespace.as_deref(), &key.name);
+            env.storage.symlink(&link, &after).await?;
+        }
+    }
+    tracing::info!("Moved {} objects beneath their owners", owners.len());
+    Ok(())
+}
@@ -0,0 +1,51 @@
+//! Client-side rate limiting of API requests
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Token bucket shared by everything talking to API server
+pub struct RateLimiter {
+    qps: f64,
+    burst: f64,
+    /// Available tokens (negative if requests are already waiting for
+    /// them), and time they were counted at
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(qps: f64, burst: u32) -> RateLimiter {
+        let burst = f64::from(burst.max(1));
+        RateLimiter {
+            qps,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// Waits until one more request can be made
+    pub async fn acquire(&self) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let (tokens, counted_at) = *state;
+            let now = Instant::now();
+            let tokens =
+                (tokens + now.duration_since(counted_at).as_secs_f64() * self.qps).min(self.burst);
+            // token is reserved immediately, so waiting requests are served
+            // in order of arrival
+            *state = (tokens - 1.0, now);
+            if tokens >= 1.0 {
+                return;
+            }
+            Duration::from_secs_f64((1.0 - tokens) / self.qps)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Waits for `limiter`, if rate limiting is enabled
+pub async fn throttle(limiter: Option<&RateLimiter>) {
+    if let Some(limiter) = limiter {
+        limiter.acquire().await;
+    }
+}
@@ -0,0 +1,474 @@
+//! Human-readable reports over the dump
+use crate::layout::ObjectKey;
+use k8s_openapi::api::core::v1::Event;
+use kube::api::{ApiResource, DynamicObject};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Report kind, selected with `--report`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// Static site under `report/`
+    Html,
+    /// `SUMMARY.md` with overview of cluster health
+    Markdown,
+}
+
+impl std::str::FromStr for ReportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "html" => Ok(ReportFormat::Html),
+            "markdown" => Ok(ReportFormat::Markdown),
+            _ => anyhow::bail!("unknown report format: {}", s),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct EventRow {
+    namespace: Option<String>,
+    time: String,
+    type_: String,
+    object: String,
+    reason: String,
+    message: String,
+}
+
+struct NodeRow {
+    name: String,
+    ready: String,
+    roles: String,
+    version: String,
+}
+
+/// Collects things reports need which are not recorded in the index
+#[derive(Default)]
+pub struct ReportData {
+    /// pod -> (container log name, log file)
+    logs: Mutex<HashMap<ObjectKey, Vec<(String, PathBuf)>>>,
+    events: Mutex<Vec<EventRow>>,
+    nodes: Mutex<Vec<NodeRow>>,
+    /// (namespace, pod, what is wrong)
+    failing_pods: Mutex<Vec<(String, String, String)>>,
+}
+
+impl ReportData {
+    /// Remembers details of nodes and pods, called for every dumped object
+    pub fn observe(&self, resource: &ApiResource, object: &DynamicObject) {
+        if !resource.group.is_empty() {
+            return;
+        }
+        let name = object.metadata.name.clone().unwrap_or_default();
+        match resource.kind.as_str() {
+            "Node" => {
+                let ready = object.data["status"]["conditions"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .find(|c| c["type"] == "Ready")
+                    .and_then(|c| c["status"].as_str())
+                    .map_or("Unknown", |status| match status {
+                        "True" => "Ready",
+                        _ => "NotReady",
+                    });
+                let roles = object
+                    .metadata
+                    .labels
+                    .keys()
+                    .filter_map(|label| label.strip_prefix("node-role.kubernetes.io/"))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                let version = object.data["status"]["nodeInfo"]["kubeletVersion"]
+                    .as_str()
+                    .unwrap_or_default();
+                self.nodes.lock().unwrap().push(NodeRow {
+                    name,
+                    ready: ready.to_string(),
+                    roles,
+                    version: version.to_string(),
+                });
+            }
+            "Pod" => {
+                if let Some(problem) = pod_problem(&object.data["status"]) {
+                    self.failing_pods.lock().unwrap().push((
+                        object.metadata.namespace.clone().unwrap_or_default(),
+                        name,
+                        problem,
+                    ));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    pub fn record_log(&self, pod: ObjectKey, name: String, path: PathBuf) {
+        self.logs
+            .lock()
+            .unwrap()
+            .entry(pod)
+            .or_default()
+            .push((name, path));
+    }
+
+    pub fn record_event(&self, event: &Event) {
+        let time = event
+            .last_timestamp
+            .as_ref()
+            .or(event.first_timestamp.as_ref())
+            .map(|t| t.0.to_rfc3339())
+            .or_else(|| event.event_time.as_ref().map(|t| t.0.to_rfc3339()))
+            .unwrap_or_default();
+        let object = format!(
+            "{}/{}",
+            event.involved_object.kind.as_deref().unwrap_or_default(),
+            event.involved_object.name.as_deref().unwrap_or_default()
+        );
+        self.events.lock().unwrap().push(EventRow {
+            namespace: event.involved_object.namespace.clone(),
+            time,
+            type_: event.type_.clone().unwrap_or_default(),
+            object,
+            reason: event.reason.clone().unwrap_or_default(),
+            message: event.message.clone().unwrap_or_default(),
+        });
+    }
+}
+
+/// Writes page with pretty-printed object, called for every dumped object
+pub async fn write_object_page(
+    env: &crate::Environment,
+    key: &ObjectKey,
+    repr: &str,
+) -> anyhow::Result<()> {
+    if !env.opts.report.contains(&ReportFormat::Html) {
+        return Ok(());
+    }
+    let value: serde_json::Value = serde_json::from_str(repr)?;
+    let yaml = serde_yaml::to_string(&value)?;
+    let page = env.layout.report_object_page(key);
+    let title = format!("{} {}", key.kind, key.name);
+    let mut body = String::new();
+    writeln!(
+        body,
+        "<p><a href=\"{}\">{}</a></p>",
+        link(
+            env,
+            &page,
+            &env.layout.report_namespace_page(key.namespace.as_deref())
+        ),
+        escape(key.namespace.as_deref().unwrap_or("cluster-scoped objects"))
+    )?;
+    writeln!(body, "<pre>{}</pre>", escape(&yaml))?;
+    env.storage.write(&page, html_page(&title, &body)).await
+}
+
+/// Describes why pod is not healthy, or returns None if it is fine
+fn pod_problem(status: &serde_json::Value) -> Option<String> {
+    let phase = status["phase"].as_str().unwrap_or("Unknown");
+    let mut problems = Vec::new();
+    if phase != "Running" && phase != "Succeeded" {
+        problems.push(phase.to_string());
+    }
+    for container in status["containerStatuses"].as_array().into_iter().flatten() {
+        let name = container["name"].as_str().unwrap_or_default();
+        if let Some(reason) = container["state"]["waiting"]["reason"].as_str() {
+            problems.push(format!("{}: {}", name, reason));
+        } else if let Some(reason) = container["state"]["terminated"]["reason"].as_str() {
+            if reason != "Completed" {
+                problems.push(format!("{}: {}", name, reason));
+            }
+        }
+    }
+    if problems.is_empty() {
+        None
+    } else {
+        Some(problems.join(", "))
+    }
+}
+
+/// Writes all selected reports. Must be called after all dumpers finished.
+pub async fn write(env: &crate::Environment) -> anyhow::Result<()> {
+    if env.opts.report.contains(&ReportFormat::Html) {
+        write_html(env).await?;
+    }
+    if env.opts.report.contains(&ReportFormat::Markdown) {
+        write_summary(env).await?;
+    }
+    Ok(())
+}
+
+/// How many Warning events summary lists
+const SUMMARY_EVENTS: usize = 20;
+
+async fn write_summary(env: &crate::Environment) -> anyhow::Result<()> {
+    let entries = env.index.entries();
+    let mut per_kind: BTreeMap<String, usize> = BTreeMap::new();
+    let mut per_namespace: BTreeMap<String, usize> = BTreeMap::new();
+    for entry in &entries {
+        let kind = if entry.group.is_empty() {
+            entry.kind.clone()
+        } else {
+            format!("{}.{}", entry.kind, entry.group)
+        };
+        *per_kind.entry(kind).or_default() += 1;
+        let namespace = entry.namespace.as_deref().unwrap_or("(cluster-scoped)");
+        *per_namespace.entry(namespace.to_string()).or_default() += 1;
+    }
+
+    let mut out = String::new();
+    writeln!(out, "# Cluster summary\n")?;
+    writeln!(out, "{} objects dumped.\n", entries.len())?;
+
+    writeln!(out, "## Nodes\n")?;
+    let mut nodes = std::mem::take(&mut *env.report_data.nodes.lock().unwrap());
+    nodes.sort_by(|a, b| a.name.cmp(&b.name));
+    if nodes.is_empty() {
+        writeln!(out, "No nodes were dumped.\n")?;
+    } else {
+        writeln!(out, "| Name | Status | Roles | Version |")?;
+        writeln!(out, "|---|---|---|---|")?;
+        for node in nodes {
+            writeln!(
+                out,
+                "| {} | {} | {} | {} |",
+                md_escape(&node.name),
+                node.ready,
+                md_escape(&node.roles),
+                md_escape(&node.version)
+            )?;
+        }
+        writeln!(out)?;
+    }
+
+    writeln!(out, "## Failing pods\n")?;
+    let mut failing = std::mem::take(&mut *env.report_data.failing_pods.lock().unwrap());
+    failing.sort();
+    if failing.is_empty() {
+        writeln!(out, "None.\n")?;
+    } else {
+        writeln!(out, "| Namespace | Pod | Problem |")?;
+        writeln!(out, "|---|---|---|")?;
+        for (namespace, name, problem) in failing {
+            writeln!(
+                out,
+                "| {} | {} | {} |",
+                md_escape(&namespace),
+                md_escape(&name),
+                md_escape(&problem)
+            )?;
+        }
+        writeln!(out)?;
+    }
+
+    writeln!(out, "## Recent warnings\n")?;
+    let mut warnings = env
+        .report_data
+        .events
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|e| e.type_ == "Warning")
+        .cloned()
+        .collect::<Vec<_>>();
+    warnings.sort_by(|a, b| b.time.cmp(&a.time));
+    if warnings.is_empty() {
+        writeln!(out, "None.\n")?;
+    } else {
+        writeln!(out, "| Time | Namespace | Object | Reason | Message |")?;
+        writeln!(out, "|---|---|---|---|---|")?;
+        for event in warnings.into_iter().take(SUMMARY_EVENTS) {
+            writeln!(
+                out,
+                "| {} | {} | {} | {} | {} |",
+                event.time,
+                md_escape(event.namespace.as_deref().unwrap_or_default()),
+                md_escape(&event.object),
+                md_escape(&event.reason),
+                md_escape(&event.message)
+            )?;
+        }
+        writeln!(out)?;
+    }
+
+    writeln!(out, "## Objects per kind\n")?;
+    writeln!(out, "| Kind | Count |")?;
+    writeln!(out, "|---|---|")?;
+    for (kind, count) in per_kind {
+        writeln!(out, "| {} | {} |", md_escape(&kind), count)?;
+    }
+    writeln!(out, "\n## Objects per namespace\n")?;
+    writeln!(out, "| Namespace | Count |")?;
+    writeln!(out, "|---|---|")?;
+    for (namespace, count) in per_namespace {
+        writeln!(out, "| {} | {} |", md_escape(&namespace), count)?;
+    }
+    env.storage.write(&env.layout.summary(), out).await
+}
+
+/// Makes text safe to put into Markdown table cell
+fn md_escape(s: &str) -> String {
+    s.replace('|', "\\|").replace('\n', " ")
+}
+
+async fn write_html(env: &crate::Environment) -> anyhow::Result<()> {
+    let mut entries = env.index.entries();
+    entries.sort_by(|a, b| (&a.kind, &a.name).cmp(&(&b.kind, &b.name)));
+    let mut namespaces: BTreeMap<Option<String>, Vec<crate::index::Entry>> = BTreeMap::new();
+    for entry in entries {
+        namespaces
+            .entry(entry.namespace.clone())
+            .or_default()
+            .push(entry);
+    }
+    let mut events: BTreeMap<Option<String>, Vec<EventRow>> = BTreeMap::new();
+    for event in env.report_data.events.lock().unwrap().iter() {
+        events
+            .entry(event.namespace.clone())
+            .or_default()
+            .push(event.clone());
+    }
+    let logs = std::mem::take(&mut *env.report_data.logs.lock().unwrap());
+
+    let index_page = env.layout.report_index();
+    let mut body = String::new();
+    writeln!(body, "<table>")?;
+    writeln!(
+        body,
+        "<tr><th>Namespace</th><th>Objects</th><th>Warnings</th></tr>"
+    )?;
+    for (namespace, entries) in &namespaces {
+        let warnings = events.get(namespace).map_or(0, |events| {
+            events.iter().filter(|e| e.type_ == "Warning").count()
+        });
+        writeln!(
+            body,
+            "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>",
+            link(
+                env,
+                &index_page,
+                &env.layout.report_namespace_page(namespace.as_deref())
+            ),
+            escape(namespace.as_deref().unwrap_or("cluster-scoped objects")),
+            entries.len(),
+            warnings
+        )?;
+    }
+    writeln!(body, "</table>")?;
+    env.storage
+        .write(&index_page, html_page("Cluster dump", &body))
+        .await?;
+
+    for (namespace, entries) in namespaces {
+        let page = env.layout.report_namespace_page(namespace.as_deref());
+        let title = namespace.as_deref().unwrap_or("cluster-scoped objects");
+        let mut body = String::new();
+        writeln!(
+            body,
+            "<p><a href=\"{}\">index</a></p>",
+            link(env, &page, &index_page)
+        )?;
+        writeln!(body, "<h2>Objects</h2>\n<table>")?;
+        writeln!(body, "<tr><th>Kind</th><th>Name</th><th>Files</th></tr>")?;
+        for entry in &entries {
+            let key = ObjectKey {
+                group: entry.group.clone(),
+                kind: entry.kind.clone(),
+                namespace: entry.namespace.clone(),
+                name: entry.name.clone(),
+            };
+            let mut files = format!(
+                "<a href=\"{}\">raw</a>",
+                link(env, &page, &env.layout.root().join(&entry.path))
+            );
+            for (name, path) in logs.get(&key).into_iter().flatten() {
+                write!(
+                    files,
+                    " <a href=\"{}\">{}</a>",
+                    link(env, &page, path),
+                    escape(name)
+                )?;
+            }
+            writeln!(
+                body,
+                "<tr><td>{}</td><td><a href=\"{}\">{}</a></td><td>{}</td></tr>",
+                escape(&entry.kind),
+                link(env, &page, &env.layout.report_object_page(&key)),
+                escape(&entry.name),
+                files
+            )?;
+        }
+        writeln!(body, "</table>")?;
+        let mut namespace_events = events.remove(&namespace).unwrap_or_default();
+        if !namespace_events.is_empty() {
+            namespace_events.sort_by(|a, b| a.time.cmp(&b.time));
+            writeln!(body, "<h2>Events</h2>\n<table>")?;
+            writeln!(
+                body,
+                "<tr><th>Time</th><th>Type</th><th>Object</th><th>Reason</th><th>Message</th></tr>"
+            )?;
+            for event in namespace_events {
+                writeln!(
+                    body,
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    escape(&event.time),
+                    escape(&event.type_),
+                    escape(&event.object),
+                    escape(&event.reason),
+                    escape(&event.message)
+                )?;
+            }
+            writeln!(body, "</table>")?;
+        }
+        env.storage.write(&page, html_page(title, &body)).await?;
+    }
+    Ok(())
+}
+
+fn html_page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n\
+        <style>body {{ font-family: sans-serif; }} td, th {{ padding: 2px 8px; text-align: left; }}</style>\n\
+        </head>\n<body>\n<h1>{title}</h1>\n{body}</body>\n</html>\n",
+        title = escape(title),
+        body = body
+    )
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Relative URL of `target` as seen from `page` (both produced by Layout)
+fn link(env: &crate::Environment, page: &Path, target: &Path) -> String {
+    let page_dir = env
+        .layout
+        .relative(page)
+        .parent()
+        .expect("Layout never returns root-path");
+    let mut parts = vec!["..".to_string(); page_dir.components().count()];
+    for component in env.layout.relative(target).components() {
+        parts.push(percent_encode(&component.as_os_str().to_string_lossy()));
+    }
+    parts.join("/")
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            write!(out, "%{:02X}", b).expect("writing to string never fails");
+        }
+    }
+    out
+}
@@ -0,0 +1,189 @@
+//! Applying dumped objects back to a cluster
+use anyhow::Context as _;
+use clap::Clap;
+use kube::{
+    api::{Api, ApiResource, DynamicObject, Patch, PatchParams},
+    discovery::{ApiCapabilities, Discovery},
+};
+use serde_json::Value;
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+/// Re-applies objects from a dump (server-side apply), in dependency order
+#[derive(Clap)]
+pub struct RestoreOpts {
+    /// Dump to restore (directory containing `index.json`)
+    dir: PathBuf,
+    /// Only validate objects on server (server-side dry run)
+    #[clap(long)]
+    dry_run: bool,
+    /// Also restore objects that have owners, keeping their
+    /// ownerReferences (by default they are skipped, because their
+    /// controllers recreate them)
+    #[clap(long)]
+    include_owned: bool,
+}
+
+/// Kinds which are maintained by cluster itself and must not be restored,
+/// as (group, kind)
+pub const SKIPPED_KINDS: &[(&str, &str)] = &[
+    ("", "Event"),
+    ("events.k8s.io", "Event"),
+    ("", "Node"),
+    ("", "Endpoints"),
+    ("discovery.k8s.io", "EndpointSlice"),
+    ("", "ComponentStatus"),
+    ("coordination.k8s.io", "Lease"),
+];
+
+/// Objects are applied in phases: lower phases first
+pub fn phase(group: &str, kind: &str) -> u8 {
+    match (group, kind) {
+        ("", "Namespace") => 0,
+        ("apiextensions.k8s.io", "CustomResourceDefinition") => 1,
+        // things other objects usually refer to
+        ("", "ServiceAccount")
+        | ("", "Secret")
+        | ("", "ConfigMap")
+        | ("", "PersistentVolume")
+        | ("", "PersistentVolumeClaim")
+        | ("storage.k8s.io", "StorageClass")
+        | ("rbac.authorization.k8s.io", _)
+        | ("scheduling.k8s.io", "PriorityClass") => 2,
+        _ => 3,
+    }
+}
+
+/// Phase after which discovery is refreshed, because new CRDs are served
+const CRD_PHASE: u8 = 1;
+
+/// Returns discovered resources, keyed by (group, kind)
+pub async fn discover(
+    client: &kube::Client,
+) -> anyhow::Result<HashMap<(String, String), (ApiResource, ApiCapabilities)>> {
+    let discovery = Discovery::new(client.clone()).run().await?;
+    let mut resources = Vec::new();
+    crate::collect_resources(&discovery, &mut resources);
+    Ok(resources
+        .into_iter()
+        .map(|(res, caps)| ((res.group.clone(), res.kind.clone()), (res, caps)))
+        .collect())
+}
+
+async fn apply(
+    client: &kube::Client,
+    resource: &ApiResource,
+    object: &Value,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    let namespace = object
+        .pointer("/metadata/namespace")
+        .and_then(Value::as_str);
+    let name = object
+        .pointer("/metadata/name")
+        .and_then(Value::as_str)
+        .context("object has no name")?;
+    let api = match namespace {
+        Some(ns) => Api::<DynamicObject>::namespaced_with(client.clone(), ns, resource),
+        None => Api::<DynamicObject>::all_with(client.clone(), resource),
+    };
+    let mut params = PatchParams::apply("kube-dump").force();
+    params.dry_run = dry_run;
+    api.patch(name, &params, &Patch::Apply(object)).await?;
+    Ok(())
+}
+
+pub async fn restore(opts: RestoreOpts) -> anyhow::Result<()> {
+    let mut objects = Vec::new();
+    let mut skipped = 0;
+    for (entry, mut object) in crate::load::load(&opts.dir)? {
+        let owned = object
+            .pointer("/metadata/ownerReferences")
+            .and_then(Value::as_array)
+            .is_some_and(|refs| !refs.is_empty());
+        let maintained = SKIPPED_KINDS.contains(&(entry.group.as_str(), entry.kind.as_str()));
+        if maintained || (owned && !opts.include_owned) {
+            skipped += 1;
+            continue;
+        }
+        // e.g. dumped without permission to read Secret values; applying
+        // it would wipe live values
+        let data_less = entry.group.is_empty()
+            && entry.kind == "Secret"
+            && object.get("data").is_none()
+            && object.get("stringData").is_none();
+        if data_less {
+            tracing::warn!(
+                "Skipping Secret {:?}/{}: dump holds no data for it",
+                entry.namespace,
+                entry.name
+            );
+            skipped += 1;
+            continue;
+        }
+        let owner_references = object.pointer("/metadata/ownerReferences").cloned();
+        crate::generic::export_gitops(&mut object);
+        if let Some(owner_references) = owner_references {
+            object["metadata"]["ownerReferences"] = owner_references;
+        }
+        objects.push((phase(&entry.group, &entry.kind), entry, object));
+    }
+    // sort is stable, so objects keep index order within phase
+    objects.sort_by_key(|(phase, _, _)| *phase);
+    tracing::info!("Restoring {} objects ({} skipped)", objects.len(), skipped);
+
+    let client = kube::Client::try_default()
+        .await
+        .context("connection failed")?;
+    let mut resources = discover(&client).await.context("discovery error")?;
+    let mut rediscovered = false;
+    let mut failed = 0;
+    for (phase, entry, object) in &objects {
+        let key = (entry.group.clone(), entry.kind.clone());
+        if *phase > CRD_PHASE && !rediscovered && !resources.contains_key(&key) {
+            // newly created CRDs may take a while to be served
+            for _ in 0..5 {
+                tokio::time::sleep(Duration::from_secs(2)).await;
+                resources = discover(&client).await.context("discovery error")?;
+                if resources.contains_key(&key) {
+                    break;
+                }
+            }
+            rediscovered = true;
+        }
+        let description = format!("{} {:?}/{}", entry.kind, entry.namespace, entry.name);
+        let (resource, caps) = match resources.get(&key) {
+            Some(r) => r,
+            None => {
+                tracing::warn!("Skipping {}: kind is not served by cluster", description);
+                failed += 1;
+                continue;
+            }
+        };
+        if !caps.supports_operation(kube::discovery::verbs::PATCH) {
+            tracing::warn!("Skipping {}: kind can not be patched", description);
+            skipped += 1;
+            continue;
+        }
+        // dumped version may differ from the preferred one
+        let resource = ApiResource {
+            version: entry.version.clone(),
+            api_version: object["apiVersion"]
+                .as_str()
+                .unwrap_or(&resource.api_version)
+                .to_string(),
+            ..resource.clone()
+        };
+        match apply(&client, &resource, object, opts.dry_run).await {
+            Ok(()) => tracing::info!("Applied {}", description),
+            Err(err) => {
+                tracing::error!("Failed to apply {}: {:#}", description, err);
+                failed += 1;
+            }
+        }
+    }
+    tracing::info!("Restore finished ({} skipped, {} failed)", skipped, failed);
+    if failed > 0 {
+        anyhow::bail!("{} objects could not be restored", failed);
+    }
+    Ok(())
+}
@@ -0,0 +1,186 @@
+//! Progress tracking, so that interrupted dump can be resumed
+use crate::index::Entry;
+use anyhow::Context as _;
+use kube::api::ApiResource;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::Write as _,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// State file inside output directory (hidden, so it is not dump content).
+/// Each line is a `Record`, appended as the dump progresses.
+const STATE_FILE: &str = ".kube-dump-state.jsonl";
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum Record {
+    SnapshotResourceVersion(String),
+    /// All objects of the resource were dumped
+    #[serde(rename_all = "camelCase")]
+    Resource {
+        /// `apiVersion/kind`
+        key: String,
+        resource_version: Option<String>,
+        entries: Vec<Entry>,
+    },
+    /// Typed dumper (e.g. `Pod`) finished
+    Dumper(String),
+}
+
+#[derive(Default)]
+struct State {
+    snapshot_resource_version: Option<String>,
+    /// `apiVersion/kind` -> resourceVersion of the list, for resources
+    /// which were dumped completely
+    resources: BTreeMap<String, Option<String>>,
+    dumpers: BTreeSet<String>,
+    /// Index entries of resources completed by previous run
+    entries: Vec<Entry>,
+}
+
+impl State {
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::SnapshotResourceVersion(version) => {
+                self.snapshot_resource_version = Some(version)
+            }
+            Record::Resource {
+                key,
+                resource_version,
+                entries,
+            } => {
+                self.resources.insert(key, resource_version);
+                self.entries.extend(entries);
+            }
+            Record::Dumper(name) => {
+                self.dumpers.insert(name);
+            }
+        }
+    }
+}
+
+fn resource_key(api_resource: &ApiResource) -> String {
+    format!("{}/{}", api_resource.api_version, api_resource.kind)
+}
+
+/// Records progress into state file after each completed resource
+pub struct Progress {
+    path: PathBuf,
+    state: Mutex<State>,
+    file: Mutex<File>,
+}
+
+impl Progress {
+    /// Starts tracking dump into `out`. If `resume` is set, progress
+    /// recorded by previous run is loaded.
+    pub fn start(out: &Path, resume: bool) -> anyhow::Result<Progress> {
+        let path = out.join(STATE_FILE);
+        let mut state = State::default();
+        let file = if resume {
+            let data = std::fs::read_to_string(&path).map_err(|err| {
+                anyhow::anyhow!(
+                    "nothing to resume, failed to read {}: {}",
+                    path.display(),
+                    err
+                )
+            })?;
+            // last record may be half-written if previous run was killed
+            let mut valid_len = 0;
+            for line in data.split_inclusive('\n') {
+                let record = match serde_json::from_str(line) {
+                    Ok(record) if line.ends_with('\n') => record,
+                    _ => break,
+                };
+                state.apply(record);
+                valid_len += line.len();
+            }
+            let file = std::fs::OpenOptions::new().append(true).open(&path)?;
+            file.set_len(valid_len as u64)?;
+            file
+        } else {
+            File::create(&path)?
+        };
+        Ok(Progress {
+            path,
+            state: Mutex::new(state),
+            file: Mutex::new(file),
+        })
+    }
+
+    fn append(&self, record: &Record) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file
+            .lock()
+            .unwrap()
+            .write_all(&line)
+            .with_context(|| format!("failed to write {}", self.path.display()))
+    }
+
+    /// Index entries of resources dumped by previous run
+    pub fn restored_entries(&self) -> Vec<Entry> {
+        self.state.lock().unwrap().entries.clone()
+    }
+
+    pub fn snapshot_version(&self) -> Option<String> {
+        self.state.lock().unwrap().snapshot_resource_version.clone()
+    }
+
+    pub fn set_snapshot_version(&self, resource_version: &str) -> anyhow::Result<()> {
+        self.state.lock().unwrap().snapshot_resource_version = Some(resource_version.to_string());
+        self.append(&Record::SnapshotResourceVersion(
+            resource_version.to_string(),
+        ))
+    }
+
+    /// Returns resourceVersion resource was listed at, if it was completed
+    pub fn completed(&self, api_resource: &ApiResource) -> Option<Option<String>> {
+        let state = self.state.lock().unwrap();
+        state.resources.get(&resource_key(api_resource)).cloned()
+    }
+
+    /// Records that all objects of the resource were dumped
+    pub fn complete(
+        &self,
+        api_resource: &ApiResource,
+        resource_version: Option<String>,
+        index: &crate::index::DumpIndex,
+    ) -> anyhow::Result<()> {
+        let record = Record::Resource {
+            key: resource_key(api_resource),
+            resource_version: resource_version.clone(),
+            entries: index.entries_of(
+                &api_resource.group,
+                &api_resource.version,
+                &api_resource.kind,
+            ),
+        };
+        self.state
+            .lock()
+            .unwrap()
+            .resources
+            .insert(resource_key(api_resource), resource_version);
+        tokio::task::block_in_place(|| self.append(&record))
+    }
+
+    pub fn dumper_done(&self, name: &str) -> bool {
+        self.state.lock().unwrap().dumpers.contains(name)
+    }
+
+    pub fn complete_dumper(&self, name: &str) -> anyhow::Result<()> {
+        self.state.lock().unwrap().dumpers.insert(name.to_string());
+        self.append(&Record::Dumper(name.to_string()))
+    }
+
+    /// Removes state file after dump finished
+    pub fn finish(&self) -> anyhow::Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Retries of API requests failed because of transient errors
+use crate::listing::StatusError;
+use std::{fmt, future::Future, sync::atomic::Ordering, time::Duration};
+
+/// Delay before the first retry, doubled for every next one
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+fn is_transient_code(code: u64) -> bool {
+    code == 429 || ((500..600).contains(&code) && code != 501)
+}
+
+/// Returns Some (with delay requested by server, if any) if request failed
+/// with `err` may succeed when retried
+fn transient(err: &anyhow::Error) -> Option<Option<Duration>> {
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<kube::Error>() {
+            return match err {
+                kube::Error::Api(response) if is_transient_code(u64::from(response.code)) => {
+                    Some(None)
+                }
+                kube::Error::HyperError(_) | kube::Error::Service(_) => Some(None),
+                _ => None,
+            };
+        }
+        if let Some(err) = cause.downcast_ref::<StatusError>() {
+            if is_transient_code(err.code) {
+                return Some(err.retry_after);
+            }
+            return None;
+        }
+    }
+    None
+}
+
+/// Returned instead of making request after `--deadline` was reached
+#[derive(Debug)]
+pub struct DeadlineReached;
+
+impl fmt::Display for DeadlineReached {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("deadline reached")
+    }
+}
+
+impl std::error::Error for DeadlineReached {}
+
+/// Converts error into one which is never retried (e.g. because request has
+/// side effects that can not be repeated)
+pub fn permanent(err: anyhow::Error) -> anyhow::Error {
+    anyhow::anyhow!("{:#}", err)
+}
+
+/// Makes API request, repeating it with exponential backoff while it fails
+/// with transient errors, at most `--retries` times. Request still running
+/// when `--deadline` is reached is cancelled.
+pub async fn retry<T, F, Fut>(
+    env: &crate::Environment,
+    what: &str,
+    mut request: F,
+) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        crate::ratelimit::throttle(env.limiter.as_deref()).await;
+        let result = match env.deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, request()).await {
+                Ok(result) => result,
+                Err(_) => {
+                    env.interrupted.store(true, Ordering::Relaxed);
+                    return Err(DeadlineReached.into());
+                }
+            },
+            None => request().await,
+        };
+        let err = match result {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+        let retry_after = match transient(&err) {
+            Some(retry_after) if attempt < env.opts.retries => retry_after,
+            _ => return Err(err),
+        };
+        attempt += 1;
+        let delay = retry_after.unwrap_or(backoff);
+        env.status.eprintln(&format!(
+            "Failed to {} (attempt {}), retrying in {:?}: {:#}",
+            what, attempt, delay, err
+        ));
+        tokio::time::sleep(delay).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
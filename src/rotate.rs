@@ -0,0 +1,94 @@
+//! Keeping several timestamped dumps in one parent directory
+use anyhow::Context as _;
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Retention policy, given to `--rotate` as `keep=<count>`
+pub struct RotatePolicy {
+    keep: usize,
+}
+
+impl std::str::FromStr for RotatePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some(("keep", count)) => {
+                let keep: usize = count.parse().context("invalid dump count")?;
+                if keep == 0 {
+                    anyhow::bail!("at least one dump must be kept");
+                }
+                Ok(RotatePolicy { keep })
+            }
+            _ => anyhow::bail!("unknown rotation policy: {}", s),
+        }
+    }
+}
+
+/// Length of `YYYY-MM-DDTHH-MM-SSZ`
+const TIMESTAMP_LEN: usize = 20;
+
+/// Returns true if `name` was produced by `dump_dir`
+fn is_dump_name(name: &str) -> bool {
+    if name.len() < TIMESTAMP_LEN || !name.is_char_boundary(TIMESTAMP_LEN) {
+        return false;
+    }
+    let (timestamp, suffix) = name.split_at(TIMESTAMP_LEN);
+    // `:` is not allowed on Windows, so it is replaced with `-` in times
+    let mut rfc3339 = timestamp.to_string();
+    rfc3339.replace_range(13..14, ":");
+    rfc3339.replace_range(16..17, ":");
+    let suffix_ok = suffix.is_empty()
+        || (suffix.starts_with('-') && suffix[1..].chars().all(|c| c.is_ascii_digit()));
+    suffix_ok && humantime::parse_rfc3339(&rfc3339).is_ok()
+}
+
+/// Chooses directory for new dump in `parent`, named after current time.
+/// Names sort in chronological order.
+pub fn dump_dir(parent: &Path) -> PathBuf {
+    let timestamp = humantime::format_rfc3339_seconds(SystemTime::now())
+        .to_string()
+        .replace(':', "-");
+    let mut dir = parent.join(&timestamp);
+    let mut attempt = 1;
+    while dir.exists() {
+        dir = parent.join(format!("{}-{}", timestamp, attempt));
+        attempt += 1;
+    }
+    dir
+}
+
+/// Deletes oldest dumps in `parent`, so that at most `policy.keep` remain.
+/// Dumps which are still being written are skipped. Returns number of
+/// deleted dumps.
+pub fn cleanup(parent: &Path, policy: &RotatePolicy) -> anyhow::Result<usize> {
+    let mut dumps = Vec::new();
+    for item in std::fs::read_dir(parent)? {
+        let item = item?;
+        let name = item.file_name().to_string_lossy().into_owned();
+        if item.file_type()?.is_dir() && is_dump_name(&name) {
+            dumps.push(name);
+        }
+    }
+    dumps.sort();
+    let excess = dumps.len().saturating_sub(policy.keep);
+    let mut deleted = 0;
+    for name in &dumps[..excess] {
+        let dir = parent.join(name);
+        let lock_path =
+            crate::layout::storage::lock_path(&dir, Some(crate::layout::storage::BackendKind::Dir))
+                .expect("directories are always locked");
+        match crate::lock::OutputLock::acquire(&lock_path) {
+            Ok(_lock) => {
+                std::fs::remove_dir_all(&dir)
+                    .with_context(|| format!("failed to delete {}", dir.display()))?;
+                std::fs::remove_file(&lock_path).ok();
+                deleted += 1;
+            }
+            Err(err) => tracing::warn!("Not deleting {}: {:#}", dir.display(), err),
+        }
+    }
+    Ok(deleted)
+}
@@ -0,0 +1,261 @@
+//! Schedules of periodic dumps, given to `--every`
+use anyhow::Context as _;
+use std::time::{Duration, SystemTime};
+
+pub enum Schedule {
+    /// Fixed interval between starts of dumps (e.g. `6h`)
+    Every(Duration),
+    /// Cron expression (e.g. `0 */6 * * *`), in UTC
+    Cron(Cron),
+}
+
+impl std::str::FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.split_whitespace().count() == 5 {
+            return Ok(Schedule::Cron(s.parse()?));
+        }
+        let interval =
+            humantime::parse_duration(s).with_context(|| format!("invalid schedule: {}", s))?;
+        if interval.as_secs() == 0 {
+            anyhow::bail!("interval must be at least one second");
+        }
+        Ok(Schedule::Every(interval))
+    }
+}
+
+impl Schedule {
+    /// Returns when first dump starts: immediately for intervals, and at
+    /// next matching minute for cron expressions
+    pub fn first(&self, now: SystemTime) -> Option<SystemTime> {
+        match self {
+            Schedule::Every(_) => Some(now),
+            Schedule::Cron(cron) => cron.next(now),
+        }
+    }
+
+    /// Returns when dump following one started at `started` starts. None
+    /// if cron expression never matches.
+    pub fn next(&self, started: SystemTime) -> Option<SystemTime> {
+        match self {
+            Schedule::Every(interval) => Some(started + *interval),
+            Schedule::Cron(cron) => cron.next(started),
+        }
+    }
+}
+
+/// Parsed `minute hour day-of-month month day-of-week` expression. Each
+/// field is a bit set of allowed values.
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Day of month and day of week are both restricted, so matching any
+    /// of them is enough
+    either_day: bool,
+}
+
+/// Parses comma-separated list of `*`, `a`, `a-b`, each optionally followed
+/// by `/step`, into bit set of values in `min..=max`
+fn parse_field(field: &str, min: u32, max: u32) -> anyhow::Result<u64> {
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse().context("invalid step")?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            anyhow::bail!("step must be positive");
+        }
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (start.parse()?, end.parse()?)
+        } else {
+            let value = range.parse()?;
+            // `a/step` means `a-max/step`
+            (value, if part.contains('/') { max } else { value })
+        };
+        if start < min || end > max || start > end {
+            anyhow::bail!("{} is out of range {}-{}", range, min, max);
+        }
+        for value in (start..=end).step_by(step) {
+            set |= 1u64 << value;
+        }
+    }
+    Ok(set)
+}
+
+impl std::str::FromStr for Cron {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<_> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            anyhow::bail!("cron expression must have 5 fields: {}", s);
+        }
+        let field = |i: usize, min, max| {
+            parse_field(fields[i], min, max)
+                .with_context(|| format!("invalid cron field {:?}", fields[i]))
+        };
+        let mut weekdays = field(4, 0, 7)?;
+        // both 0 and 7 are Sunday
+        if weekdays & (1u64 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Cron {
+            minutes: field(0, 0, 59)?,
+            hours: field(1, 0, 23)?,
+            days: field(2, 1, 31)?,
+            months: field(3, 1, 12)?,
+            weekdays,
+            // like in cron, field starting with `*` (e.g. `*/2`) is not a
+            // restriction
+            either_day: !fields[2].starts_with('*') && !fields[4].starts_with('*'),
+        })
+    }
+}
+
+/// Converts days since Unix epoch into (year, month, day)
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl Cron {
+    fn day_matches(&self, days_since_epoch: u64) -> bool {
+        let (_, month, day) = civil_from_days(days_since_epoch);
+        // 1970-01-01 was Thursday
+        let weekday = (days_since_epoch + 4) % 7;
+        let day_of_month = self.days & (1u64 << day) != 0;
+        let day_of_week = self.weekdays & (1u64 << weekday) != 0;
+        let day_ok = if self.either_day {
+            day_of_month || day_of_week
+        } else {
+            day_of_month && day_of_week
+        };
+        self.months & (1u64 << month) != 0 && day_ok
+    }
+
+    /// Returns start of first matching minute after `after`
+    pub fn next(&self, after: SystemTime) -> Option<SystemTime> {
+        let after = after.duration_since(SystemTime::UNIX_EPOCH).ok()?;
+        let mut minute = after.as_secs() / 60 + 1;
+        // every valid expression matches at least once in 4 years
+        let limit = minute + 5 * 366 * 24 * 60;
+        while minute < limit {
+            let day = minute / (24 * 60);
+            if !self.day_matches(day) {
+                minute = (day + 1) * 24 * 60;
+                continue;
+            }
+            if self.hours & (1u64 << (minute / 60 % 24)) == 0 {
+                minute = (minute / 60 + 1) * 60;
+                continue;
+            }
+            if self.minutes & (1u64 << (minute % 60)) == 0 {
+                minute += 1;
+                continue;
+            }
+            return Some(SystemTime::UNIX_EPOCH + Duration::from_secs(minute * 60));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> SystemTime {
+        humantime::parse_rfc3339(s).unwrap()
+    }
+
+    fn next(expression: &str, after: &str) -> Option<SystemTime> {
+        expression.parse::<Cron>().unwrap().next(time(after))
+    }
+
+    #[test]
+    fn parses_fields() {
+        let bits = |values: &[u32]| values.iter().fold(0, |set, v| set | 1u64 << v);
+        assert_eq!(parse_field("*/15", 0, 59).unwrap(), bits(&[0, 15, 30, 45]));
+        assert_eq!(parse_field("1-5/2", 0, 59).unwrap(), bits(&[1, 3, 5]));
+        assert_eq!(parse_field("5/20", 0, 59).unwrap(), bits(&[5, 25, 45]));
+        assert_eq!(parse_field("1,3", 1, 12).unwrap(), bits(&[1, 3]));
+        assert_eq!(
+            parse_field("*", 1, 12).unwrap(),
+            bits(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12])
+        );
+        for invalid in ["60", "0/0", "5-1", "a", "0-"] {
+            assert!(parse_field(invalid, 0, 59).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parses_schedules() {
+        assert!(
+            matches!("6h".parse(), Ok(Schedule::Every(d)) if d == Duration::from_secs(6 * 3600))
+        );
+        assert!(matches!("0 */6 * * *".parse(), Ok(Schedule::Cron(_))));
+        assert!("0s".parse::<Schedule>().is_err());
+        assert!("0 0 * *".parse::<Cron>().is_err());
+        assert!("0 24 * * *".parse::<Cron>().is_err());
+    }
+
+    #[test]
+    fn finds_next_time() {
+        assert_eq!(
+            next("0 */6 * * *", "2021-06-01T01:30:00Z"),
+            Some(time("2021-06-01T06:00:00Z"))
+        );
+        // exactly matching time is not returned again
+        assert_eq!(
+            next("0 */6 * * *", "2021-06-01T06:00:00Z"),
+            Some(time("2021-06-01T12:00:00Z"))
+        );
+        assert_eq!(
+            next("30 12 1 1 *", "2021-06-01T00:00:00Z"),
+            Some(time("2022-01-01T12:30:00Z"))
+        );
+        assert_eq!(
+            next("0 0 29 2 *", "2021-03-01T00:00:00Z"),
+            Some(time("2024-02-29T00:00:00Z"))
+        );
+        assert_eq!(next("0 0 30 2 *", "2021-03-01T00:00:00Z"), None);
+    }
+
+    #[test]
+    fn matches_days() {
+        // 2021-06-01 is Tuesday; both 0 and 7 are Sunday
+        assert_eq!(
+            next("0 0 * * 7", "2021-06-01T00:00:00Z"),
+            Some(time("2021-06-06T00:00:00Z"))
+        );
+        assert_eq!(
+            next("0 0 * * 0", "2021-06-01T00:00:00Z"),
+            Some(time("2021-06-06T00:00:00Z"))
+        );
+        // both days restricted: 13th or Friday
+        assert_eq!(
+            next("0 0 13 * 5", "2021-06-01T00:00:00Z"),
+            Some(time("2021-06-04T00:00:00Z"))
+        );
+        // `*/2` is not a restriction: odd day which is Monday
+        assert_eq!(
+            next("0 0 */2 * 1", "2021-06-01T00:00:00Z"),
+            Some(time("2021-06-07T00:00:00Z"))
+        );
+    }
+}
@@ -0,0 +1,239 @@
+//! Snapshots of HTTP endpoints of pods (metrics, and probes configured by
+//! `--probe-config`), fetched through the API server pod proxy, so pods do
+//! not have to be reachable directly. This needs `get` permission on
+//! `pods/proxy` (rather than `pods/portforward`).
+use crate::{layout::ObjectLayout, selector::PodFilter, Environment};
+use anyhow::Context as _;
+use futures::TryStreamExt as _;
+use k8s_openapi::api::core::v1::Pod;
+use kube::api::ResourceExt;
+use serde::Deserialize;
+use std::{path::Path, sync::Arc, time::Duration};
+
+const SCRAPE_ANNOTATION: &str = "prometheus.io/scrape";
+const PORT_ANNOTATION: &str = "prometheus.io/port";
+const PATH_ANNOTATION: &str = "prometheus.io/path";
+const SCHEME_ANNOTATION: &str = "prometheus.io/scheme";
+
+/// How long metrics or probe response is waited for by default
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Fetches `path` from `port` of the pod, using `http` or `https`
+async fn proxy_get(
+    env: &Environment,
+    pod: &Pod,
+    scheme: &str,
+    port: &str,
+    path: &str,
+    timeout: Duration,
+) -> anyhow::Result<Vec<u8>> {
+    // scheme prefix is only understood for https
+    let target = match scheme {
+        "http" => format!("{}:{}", pod.name(), port),
+        "https" => format!("https:{}:{}", pod.name(), port),
+        _ => anyhow::bail!("unknown scheme: {}", scheme),
+    };
+    let url = format!(
+        "/api/v1/namespaces/{}/pods/{}/proxy/{}",
+        pod.namespace().unwrap_or_default(),
+        target,
+        path.trim_start_matches('/')
+    );
+    let request = http::Request::get(url).body(Vec::new())?;
+    let fetch = async {
+        let chunks: Vec<_> = env
+            .client
+            .request_text_stream(request)
+            .await?
+            .try_collect()
+            .await?;
+        Ok(chunks.concat())
+    };
+    tokio::time::timeout(timeout, fetch)
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out after {:?}", timeout)))
+}
+
+/// Saves snapshot of metrics of the pod, if it has `prometheus.io/scrape`
+/// annotation. Failures are reported, and do not fail the dump.
+pub async fn scrape_pod(
+    pod: Pod,
+    env: Arc<Environment>,
+    layout: ObjectLayout,
+) -> anyhow::Result<()> {
+    let annotations = pod.annotations();
+    if annotations.get(SCRAPE_ANNOTATION).map(String::as_str) != Some("true") {
+        return Ok(());
+    }
+    if !crate::is_running(&pod) {
+        return Ok(());
+    }
+    // same defaults as Prometheus examples use: first declared port and
+    // `/metrics`
+    let port = annotations.get(PORT_ANNOTATION).cloned().or_else(|| {
+        let spec = pod.spec.as_ref()?;
+        spec.containers
+            .iter()
+            .flat_map(|container| &container.ports)
+            .map(|port| port.container_port.to_string())
+            .next()
+    });
+    let port = match port {
+        Some(port) => port,
+        None => return Ok(()),
+    };
+    let path = annotations
+        .get(PATH_ANNOTATION)
+        .map_or("/metrics", String::as_str);
+    let scheme = annotations
+        .get(SCHEME_ANNOTATION)
+        .map_or("http", String::as_str);
+    match proxy_get(&env, &pod, scheme, &port, path, DEFAULT_TIMEOUT).await {
+        Ok(metrics) => env.storage.write(&layout.metrics(), metrics).await?,
+        Err(err) => env.status.error(
+            format!(
+                "metrics of pod {}/{}",
+                pod.namespace().unwrap_or_default(),
+                pod.name()
+            ),
+            format!("{:#}", err),
+        ),
+    }
+    Ok(())
+}
+
+/// Probe of the config, which is a YAML list of them:
+///
+/// ```yaml
+/// - name: api
+///   selector: app=api
+///   namespace: web
+///   port: 8080
+///   scheme: http
+///   paths: [/healthz, /readyz, /debug/pprof/heap]
+///   timeout: 10s
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProbeSpec {
+    /// Responses are saved into `probe-<name>` directory
+    name: String,
+    /// All pods by default
+    selector: Option<String>,
+    /// All namespaces by default
+    namespace: Option<String>,
+    port: PortSpec,
+    /// `http` by default
+    #[serde(default = "default_scheme")]
+    scheme: String,
+    paths: Vec<String>,
+    /// Per path, 10s by default
+    timeout: Option<String>,
+}
+
+/// Port number or name
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortSpec {
+    Number(u16),
+    Name(String),
+}
+
+fn default_scheme() -> String {
+    "http".to_string()
+}
+
+pub struct Probe {
+    name: String,
+    pods: PodFilter,
+    port: String,
+    scheme: String,
+    paths: Vec<String>,
+    timeout: Duration,
+}
+
+/// Reads probes from the config file
+pub fn load_probes(path: &Path) -> anyhow::Result<Vec<Probe>> {
+    let data = std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let specs: Vec<ProbeSpec> = serde_yaml::from_slice(&data).context("invalid probe config")?;
+    specs
+        .into_iter()
+        .map(|spec| {
+            crate::selector::check_rule_name("probe", &spec.name)?;
+            if spec.scheme != "http" && spec.scheme != "https" {
+                anyhow::bail!("unknown scheme of probe {}: {}", spec.name, spec.scheme);
+            }
+            Ok(Probe {
+                pods: PodFilter::new(spec.selector.as_deref(), spec.namespace)?,
+                timeout: match &spec.timeout {
+                    Some(timeout) => humantime::parse_duration(timeout)?,
+                    None => DEFAULT_TIMEOUT,
+                },
+                name: spec.name,
+                port: match spec.port {
+                    PortSpec::Number(port) => port.to_string(),
+                    PortSpec::Name(port) => port,
+                },
+                scheme: spec.scheme,
+                paths: spec.paths,
+            })
+        })
+        .collect()
+}
+
+/// Turns endpoint path (e.g. `/debug/pprof/heap?gc=1`) into file name:
+/// slashes become `_`, and other special characters are percent-encoded,
+/// so different paths never share a name
+pub fn endpoint_file_name(path: &str) -> String {
+    let mut name = String::new();
+    for c in path.trim_matches('/').chars() {
+        if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+            name.push(c);
+        } else if c == '/' {
+            name.push('_');
+        } else {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                name.push_str(&format!("%{:02X}", byte));
+            }
+        }
+    }
+    // trimmed name never starts with `_`
+    match name.as_str() {
+        "" | "." | ".." => format!("_{}", name),
+        _ => name,
+    }
+}
+
+/// Saves responses of all probes matching the pod. Failures are reported,
+/// and do not fail the dump.
+pub async fn probe_pod(
+    pod: Pod,
+    env: Arc<Environment>,
+    layout: ObjectLayout,
+) -> anyhow::Result<()> {
+    if !crate::is_running(&pod) {
+        return Ok(());
+    }
+    for probe in env.probes.iter().filter(|probe| probe.pods.matches(&pod)) {
+        for path in &probe.paths {
+            let response = proxy_get(&env, &pod, &probe.scheme, &probe.port, path, probe.timeout);
+            match response.await {
+                Ok(response) => {
+                    let file = layout.probe_output(&probe.name, &endpoint_file_name(path));
+                    env.storage.write(&file, response).await?;
+                }
+                Err(err) => env.status.error(
+                    format!(
+                        "probe {} of pod {}/{}",
+                        path,
+                        pod.namespace().unwrap_or_default(),
+                        pod.name()
+                    ),
+                    format!("{:#}", err),
+                ),
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,102 @@
+//! Client-side label selectors, and pod filters of config files built on them
+use k8s_openapi::api::core::v1::Pod;
+use kube::api::ResourceExt;
+use std::collections::BTreeMap;
+
+enum Requirement {
+    Equals(String, String),
+    NotEquals(String, String),
+    Exists(String),
+    NotExists(String),
+}
+
+/// Label selector, supporting equality-based requirements (`a=b`, `a==b`,
+/// `a!=b`) and existence checks (`a`, `!a`), separated by commas
+pub struct LabelSelector {
+    requirements: Vec<Requirement>,
+}
+
+impl std::str::FromStr for LabelSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut requirements = Vec::new();
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let req = if let Some((key, value)) = part.split_once("!=") {
+                Requirement::NotEquals(key.trim().to_string(), value.trim().to_string())
+            } else if let Some((key, value)) = part.split_once("==") {
+                Requirement::Equals(key.trim().to_string(), value.trim().to_string())
+            } else if let Some((key, value)) = part.split_once('=') {
+                Requirement::Equals(key.trim().to_string(), value.trim().to_string())
+            } else if let Some(key) = part.strip_prefix('!') {
+                Requirement::NotExists(key.trim().to_string())
+            } else {
+                Requirement::Exists(part.to_string())
+            };
+            match &req {
+                Requirement::Equals(key, _)
+                | Requirement::NotEquals(key, _)
+                | Requirement::Exists(key)
+                | Requirement::NotExists(key)
+                    if key.is_empty() =>
+                {
+                    anyhow::bail!("invalid selector requirement: {}", part)
+                }
+                _ => (),
+            }
+            requirements.push(req);
+        }
+        Ok(LabelSelector { requirements })
+    }
+}
+
+impl LabelSelector {
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.requirements.iter().all(|req| match req {
+            Requirement::Equals(key, value) => labels.get(key) == Some(value),
+            Requirement::NotEquals(key, value) => labels.get(key) != Some(value),
+            Requirement::Exists(key) => labels.contains_key(key),
+            Requirement::NotExists(key) => !labels.contains_key(key),
+        })
+    }
+}
+
+/// Pods a rule of `--exec-config` or `--probe-config` applies to
+pub struct PodFilter {
+    selector: Option<LabelSelector>,
+    namespace: Option<String>,
+}
+
+impl PodFilter {
+    /// Pods matching `selector` in `namespace` (all by default)
+    pub fn new(selector: Option<&str>, namespace: Option<String>) -> anyhow::Result<Self> {
+        Ok(PodFilter {
+            selector: selector.map(str::parse).transpose()?,
+            namespace,
+        })
+    }
+
+    pub fn matches(&self, pod: &Pod) -> bool {
+        if let Some(namespace) = &self.namespace {
+            if pod.namespace().as_ref() != Some(namespace) {
+                return false;
+            }
+        }
+        match &self.selector {
+            Some(selector) => selector.matches(pod.labels()),
+            None => true,
+        }
+    }
+}
+
+/// Checks that name of the rule (`what`) can be used in file names
+pub fn check_rule_name(what: &str, name: &str) -> anyhow::Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        anyhow::bail!("invalid {} name: {:?}", what, name);
+    }
+    Ok(())
+}
@@ -0,0 +1,314 @@
+//! HTTP server triggering dumps and serving resulting archives:
+//!
+//! - `POST /dumps` with optional `{"args": [...]}` body queues a dump
+//! - `GET /dumps` lists dumps, `GET /dumps/<id>` returns state of one
+//! - `GET /dumps/<id>/archive` downloads archive of finished dump
+use anyhow::Context as _;
+use clap::Clap;
+use futures::TryStreamExt as _;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+use tokio::sync::mpsc;
+
+#[derive(Clap)]
+pub struct ServeOpts {
+    /// Address to listen on (e.g. `0.0.0.0:8080`). Port alone (`:8080`)
+    /// listens on localhost
+    #[clap(long, default_value = ":8080")]
+    listen: String,
+    /// Directory archives are written to
+    #[clap(long)]
+    dir: PathBuf,
+    /// File containing token which must be given in `Authorization: Bearer`
+    /// header. Required unless `--insecure-no-auth` is given
+    #[clap(long, required_unless_present = "insecure-no-auth")]
+    token_file: Option<PathBuf>,
+    /// Do not authenticate requests: anyone who can reach the server can
+    /// start dumps and download them, including Secrets
+    #[clap(long, conflicts_with = "token-file")]
+    insecure_no_auth: bool,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct DumpState {
+    id: String,
+    phase: Phase,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    args: Vec<String>,
+    created: String,
+}
+
+#[derive(Deserialize, Default)]
+struct TriggerRequest {
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+struct Server {
+    dir: PathBuf,
+    token: Option<String>,
+    dumps: Mutex<BTreeMap<String, DumpState>>,
+    next_id: AtomicU64,
+    queue: mpsc::UnboundedSender<String>,
+}
+
+impl Server {
+    fn archive(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.tar.gz", id))
+    }
+
+    fn set_phase(&self, id: &str, phase: Phase, message: Option<String>) {
+        if let Some(state) = self.dumps.lock().unwrap().get_mut(id) {
+            state.phase = phase;
+            state.message = message;
+        }
+    }
+}
+
+fn parse_listen(listen: &str) -> anyhow::Result<SocketAddr> {
+    let listen = if listen.starts_with(':') {
+        format!("127.0.0.1{}", listen)
+    } else {
+        listen.to_string()
+    };
+    listen
+        .parse()
+        .with_context(|| format!("invalid listen address {}", listen))
+}
+
+pub async fn serve(opts: ServeOpts) -> anyhow::Result<()> {
+    let addr = parse_listen(&opts.listen)?;
+    let token = match &opts.token_file {
+        Some(path) => Some(
+            std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read {}", path.display()))?
+                .trim()
+                .to_string(),
+        ),
+        None => {
+            // clap requires one of them
+            assert!(opts.insecure_no_auth);
+            tracing::warn!("Requests are not authenticated");
+            None
+        }
+    };
+    std::fs::create_dir_all(&opts.dir)?;
+    let (queue, mut queued) = mpsc::unbounded_channel();
+    let server = Arc::new(Server {
+        dir: opts.dir.clone(),
+        token,
+        dumps: Mutex::new(BTreeMap::new()),
+        next_id: AtomicU64::new(1),
+        queue,
+    });
+    let make_service = {
+        let server = server.clone();
+        make_service_fn(move |_| {
+            let server = server.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let server = server.clone();
+                    async move {
+                        let response = handle(&server, request).await.unwrap_or_else(|err| {
+                            json_response(StatusCode::BAD_REQUEST, &error(err))
+                        });
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        })
+    };
+    let http = hyper::Server::try_bind(&addr)?.serve(make_service);
+    tracing::info!("Listening on {}", addr);
+    // dumps are performed one by one, and not spawned, because dump is not
+    // guaranteed to be Send
+    let worker = async {
+        while let Some(id) = queued.recv().await {
+            let args = match server.dumps.lock().unwrap().get(&id) {
+                Some(state) => state.args.clone(),
+                None => continue,
+            };
+            server.set_phase(&id, Phase::Running, None);
+            let out = server.archive(&id).display().to_string();
+            let result = match crate::parse_requested_dump(&args, &out) {
+                Ok(opts) => crate::dump_and_notify(opts).await,
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(()) if server.archive(&id).is_file() => {
+                    server.set_phase(&id, Phase::Succeeded, None)
+                }
+                Ok(()) => server.set_phase(
+                    &id,
+                    Phase::Failed,
+                    Some("dump did not produce archive".to_string()),
+                ),
+                Err(err) => {
+                    tracing::error!("Dump {} failed: {:#}", id, err);
+                    server.set_phase(&id, Phase::Failed, Some(format!("{:#}", err)));
+                }
+            }
+        }
+    };
+    tokio::select! {
+        result = http => result?,
+        _ = worker => (),
+    }
+    Ok(())
+}
+
+fn error(err: anyhow::Error) -> serde_json::Value {
+    serde_json::json!({ "error": format!("{:#}", err) })
+}
+
+fn json_response(status: StatusCode, body: &impl Serialize) -> Response<Body> {
+    let body = serde_json::to_vec_pretty(body).expect("responses are always serializable");
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))
+        .expect("response is valid")
+}
+
+fn not_found() -> Response<Body> {
+    json_response(
+        StatusCode::NOT_FOUND,
+        &serde_json::json!({"error": "not found"}),
+    )
+}
+
+/// Compares secrets in time which only depends on their lengths
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn handle(server: &Server, request: Request<Body>) -> anyhow::Result<Response<Body>> {
+    if let Some(token) = &server.token {
+        let expected = format!("Bearer {}", token);
+        let given = request
+            .headers()
+            .get(hyper::header::AUTHORIZATION)
+            .map(|value| value.as_bytes())
+            .unwrap_or_default();
+        if !constant_time_eq(given, expected.as_bytes()) {
+            return Ok(json_response(
+                StatusCode::UNAUTHORIZED,
+                &serde_json::json!({"error": "invalid token"}),
+            ));
+        }
+    }
+    let path: Vec<_> = request
+        .uri()
+        .path()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(ToString::to_string)
+        .collect();
+    let path: Vec<_> = path.iter().map(String::as_str).collect();
+    match (request.method(), &path[..]) {
+        (&Method::POST, ["dumps"]) => trigger(server, request).await,
+        (&Method::GET, ["dumps"]) => {
+            let dumps: Vec<_> = server.dumps.lock().unwrap().values().cloned().collect();
+            Ok(json_response(StatusCode::OK, &dumps))
+        }
+        (&Method::GET, ["dumps", id]) => match server.dumps.lock().unwrap().get(*id) {
+            Some(state) => Ok(json_response(StatusCode::OK, state)),
+            None => Ok(not_found()),
+        },
+        (&Method::GET, ["dumps", id, "archive"]) => download(server, id).await,
+        _ => Ok(not_found()),
+    }
+}
+
+async fn trigger(server: &Server, request: Request<Body>) -> anyhow::Result<Response<Body>> {
+    let body = hyper::body::to_bytes(request.into_body()).await?;
+    let trigger: TriggerRequest = if body.is_empty() {
+        TriggerRequest::default()
+    } else {
+        serde_json::from_slice(&body).context("invalid request body")?
+    };
+    let id = format!(
+        "{}-{}",
+        humantime::format_rfc3339_seconds(SystemTime::now())
+            .to_string()
+            .replace(':', "-"),
+        server.next_id.fetch_add(1, Ordering::Relaxed)
+    );
+    // invalid options are reported right away
+    crate::parse_requested_dump(&trigger.args, &server.archive(&id).display().to_string())?;
+    let state = DumpState {
+        id: id.clone(),
+        phase: Phase::Queued,
+        message: None,
+        args: trigger.args,
+        created: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+    };
+    server
+        .dumps
+        .lock()
+        .unwrap()
+        .insert(id.clone(), state.clone());
+    server
+        .queue
+        .send(id)
+        .map_err(|_| anyhow::anyhow!("server is shutting down"))?;
+    Ok(json_response(StatusCode::ACCEPTED, &state))
+}
+
+async fn download(server: &Server, id: &str) -> anyhow::Result<Response<Body>> {
+    let phase = match server.dumps.lock().unwrap().get(id) {
+        Some(state) => state.phase,
+        None => return Ok(not_found()),
+    };
+    if phase != Phase::Succeeded {
+        return Ok(json_response(
+            StatusCode::CONFLICT,
+            &serde_json::json!({"error": "dump is not finished"}),
+        ));
+    }
+    let file = tokio::fs::File::open(server.archive(id)).await?;
+    let chunks = futures::stream::try_unfold(file, |mut file| async move {
+        use tokio::io::AsyncReadExt as _;
+        let mut chunk = vec![0; 64 * 1024];
+        let read = file.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok::<_, std::io::Error>(None);
+        }
+        chunk.truncate(read);
+        Ok(Some((chunk, file)))
+    });
+    Ok(Response::builder()
+        .header("Content-Type", "application/gzip")
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}.tar.gz\"", id),
+        )
+        .body(Body::wrap_stream(chunks.map_ok(hyper::body::Bytes::from)))?)
+}
@@ -0,0 +1,76 @@
+//! Signing checksums manifest, so that it can be proven that dump was not
+//! modified after it was captured
+use crate::encrypt::filter;
+use anyhow::Context as _;
+use std::path::PathBuf;
+use tokio::process::Command;
+
+/// Signing method, given to `--sign`
+pub enum Signer {
+    /// `gpg:<key>`: detached ASCII-armored OpenPGP signature
+    Gpg(String),
+    /// `cosign:<key file>`: cosign signature made with private key
+    Cosign(PathBuf),
+    /// `sigstore`: Sigstore keyless signing, producing cosign bundle with
+    /// signature, certificate and transparency log entry
+    Keyless,
+}
+
+impl std::str::FromStr for Signer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("gpg", key)) if !key.is_empty() => Ok(Signer::Gpg(key.to_string())),
+            Some(("cosign", key)) if !key.is_empty() => Ok(Signer::Cosign(key.into())),
+            None if s == "sigstore" => Ok(Signer::Keyless),
+            _ => anyhow::bail!("unknown signing method: {}", s),
+        }
+    }
+}
+
+impl Signer {
+    /// Extension appended to the name of signed file
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Signer::Gpg(_) => "asc",
+            Signer::Cosign(_) => "sig",
+            Signer::Keyless => "sigstore.json",
+        }
+    }
+
+    /// Returns detached signature of `data`
+    pub async fn sign(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Signer::Gpg(key) => {
+                let mut cmd = Command::new("gpg");
+                cmd.args(["--batch", "--armor", "--detach-sign", "--local-user", key]);
+                filter(cmd, "gpg", data).await
+            }
+            Signer::Cosign(key) => {
+                let mut cmd = Command::new("cosign");
+                // private key password is taken from COSIGN_PASSWORD
+                cmd.args(["sign-blob", "--key"]).arg(key).arg("-");
+                filter(cmd, "cosign", data).await
+            }
+            Signer::Keyless => {
+                // cosign only writes bundles to files
+                let bundle = std::env::temp_dir()
+                    .join(format!("kube-dump-{}.sigstore.json", std::process::id()));
+                let mut cmd = Command::new("cosign");
+                cmd.args(["sign-blob", "--yes", "--bundle"])
+                    .arg(&bundle)
+                    .arg("-");
+                let result = filter(cmd, "cosign", data).await;
+                let signature = match result {
+                    Ok(_) => tokio::fs::read(&bundle)
+                        .await
+                        .context("cosign did not write bundle"),
+                    Err(err) => Err(err),
+                };
+                tokio::fs::remove_file(&bundle).await.ok();
+                signature
+            }
+        }
+    }
+}
@@ -0,0 +1,425 @@
+//! Live status display: current phase, completed resources, written objects
+//! and bytes. Messages are logged (under `kube_dump::status` target), and
+//! console log lines are drawn above the display. When output is not a
+//! terminal, only log lines are printed, and automation can request JSON
+//! events instead (then status is not logged to console).
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use kube::api::ApiResource;
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Status display of the running dump, above which console log lines are
+/// drawn
+static DISPLAY: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+/// Writer of console log lines, which keeps them from breaking the status
+/// display
+pub struct ConsoleWriter;
+
+impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for ConsoleWriter {
+    type Writer = ConsoleLine;
+
+    fn make_writer(&'a self) -> ConsoleLine {
+        ConsoleLine(Vec::new())
+    }
+}
+
+/// Formatted event, written out when dropped
+pub struct ConsoleLine(Vec<u8>);
+
+impl Write for ConsoleLine {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ConsoleLine {
+    fn drop(&mut self) {
+        if self.0.is_empty() {
+            return;
+        }
+        let display = DISPLAY.lock().unwrap();
+        match display.as_ref() {
+            Some(bar) if !bar.is_hidden() && !bar.is_finished() => {
+                bar.println(String::from_utf8_lossy(&self.0).trim_end_matches('\n'))
+            }
+            _ => {
+                std::io::stderr().write_all(&self.0).ok();
+            }
+        }
+    }
+}
+
+/// How progress is reported, selected with `--progress`
+#[derive(Clone, Copy)]
+pub enum ProgressMode {
+    Auto,
+    Plain,
+    /// Events are printed to stdout as JSON objects, one per line
+    Json,
+}
+
+impl std::str::FromStr for ProgressMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ProgressMode::Auto),
+            "plain" => Ok(ProgressMode::Plain),
+            "json" => Ok(ProgressMode::Json),
+            _ => anyhow::bail!("unknown progress mode: {}", s),
+        }
+    }
+}
+
+struct Phase {
+    name: String,
+    started: Instant,
+    /// Set when next phase starts
+    elapsed: Option<Duration>,
+}
+
+#[derive(Serialize, Clone)]
+struct ErrorRecord {
+    /// What was being dumped (e.g. `Deployment.apps/v1 Some("default")/app`)
+    resource: String,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct PhaseTiming {
+    name: String,
+    seconds: f64,
+}
+
+/// Written to `summary.json` and printed at the end of the dump
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunSummary {
+    elapsed_seconds: f64,
+    bytes_written: u64,
+    /// `group/Kind` (just `Kind` for core group) -> number of objects
+    objects: BTreeMap<String, usize>,
+    phases: Vec<PhaseTiming>,
+    errors: Vec<ErrorRecord>,
+}
+
+/// Time spent dumping resources of single API group. Resources are dumped
+/// concurrently, so it can exceed wall time
+#[derive(Serialize, Default, Clone)]
+struct GroupTiming {
+    seconds: f64,
+    /// Kind -> seconds
+    resources: BTreeMap<String, f64>,
+}
+
+#[derive(Serialize, Default, Clone, Copy)]
+struct LogsTiming {
+    fetches: u64,
+    /// Total time of fetches (which are concurrent)
+    seconds: f64,
+}
+
+/// Written to `timings.json`, to find out what dump spends its time on
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Timings {
+    elapsed_seconds: f64,
+    phases: Vec<PhaseTiming>,
+    /// API group (`core` for core group) -> time
+    api_groups: BTreeMap<String, GroupTiming>,
+    logs: LogsTiming,
+}
+
+pub struct Status {
+    bar: ProgressBar,
+    json: bool,
+    objects: AtomicU64,
+    bytes: AtomicU64,
+    /// Resource listed most recently
+    current: Mutex<String>,
+    started: Instant,
+    phases: Mutex<Vec<Phase>>,
+    errors: Mutex<Vec<ErrorRecord>>,
+    groups: Mutex<BTreeMap<String, GroupTiming>>,
+    logs: Mutex<LogsTiming>,
+}
+
+impl Status {
+    pub fn new(mode: ProgressMode) -> Status {
+        // drawn to stderr, and hidden automatically if it is not a terminal
+        let bar = match mode {
+            ProgressMode::Auto => ProgressBar::new(0),
+            ProgressMode::Plain | ProgressMode::Json => ProgressBar::hidden(),
+        };
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template("{spinner} {prefix:.bold} [{bar:30}] {pos}/{len} resources, {msg}")
+                .progress_chars("=> "),
+        );
+        *DISPLAY.lock().unwrap() = Some(bar.clone());
+        Status {
+            bar,
+            json: matches!(mode, ProgressMode::Json),
+            objects: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            current: Mutex::new(String::new()),
+            started: Instant::now(),
+            phases: Mutex::new(Vec::new()),
+            errors: Mutex::new(Vec::new()),
+            groups: Mutex::new(BTreeMap::new()),
+            logs: Mutex::new(LogsTiming::default()),
+        }
+    }
+
+    fn redraw(&self) {
+        let current = self.current.lock().unwrap();
+        self.bar.set_message(format!(
+            "{} objects, {} | {}",
+            self.objects.load(Ordering::Relaxed),
+            HumanBytes(self.bytes.load(Ordering::Relaxed)),
+            current
+        ));
+    }
+
+    fn emit(&self, event: serde_json::Value) {
+        println!("{}", event);
+    }
+
+    /// True if plain lines are printed instead of the status display
+    fn is_plain(&self) -> bool {
+        self.bar.is_hidden() || self.bar.is_finished()
+    }
+
+    /// Logs message, which is printed above the status
+    pub fn println(&self, line: &str) {
+        tracing::info!("{}", line);
+        if self.json {
+            self.emit(json!({"event": "message", "message": line}));
+        }
+    }
+
+    /// Logs warning, which is printed above the status
+    pub fn eprintln(&self, line: &str) {
+        tracing::warn!("{}", line);
+        if self.json {
+            self.emit(json!({"event": "error", "message": line}));
+        }
+    }
+
+    /// Reports error which occurred while dumping `resource`, remembering
+    /// it for the summary
+    pub fn error(&self, resource: String, message: String) {
+        tracing::error!("{}: {}", resource, message);
+        if self.json {
+            self.emit(json!({"event": "error", "resource": resource, "message": message}));
+        }
+        self.errors
+            .lock()
+            .unwrap()
+            .push(ErrorRecord { resource, message });
+    }
+
+    pub fn phase(&self, name: &str) {
+        {
+            let mut phases = self.phases.lock().unwrap();
+            if let Some(previous) = phases.last_mut() {
+                previous.elapsed = Some(previous.started.elapsed());
+            }
+            phases.push(Phase {
+                name: name.to_string(),
+                started: Instant::now(),
+                elapsed: None,
+            });
+        }
+        tracing::info!("Running {}", name);
+        if self.json {
+            self.emit(json!({"event": "phase", "phase": name}));
+        } else if !self.is_plain() {
+            self.bar.set_prefix(name.to_string());
+            self.bar.enable_steady_tick(100);
+        }
+    }
+
+    pub fn set_resources(&self, count: usize) {
+        self.bar.set_length(count as u64);
+    }
+
+    /// Resource listing is started
+    pub fn resource_started(&self, name: String) {
+        if self.json {
+            tracing::debug!(" - {}", name);
+            self.emit(json!({"event": "resourceStarted", "resource": name}));
+        } else if self.is_plain() {
+            tracing::info!(" - {}", name);
+        } else {
+            tracing::debug!(" - {}", name);
+            *self.current.lock().unwrap() = name;
+            self.redraw();
+        }
+    }
+
+    /// Given number of resources were finished (dumped, skipped or failed)
+    pub fn resources_finished(&self, count: usize) {
+        self.bar.inc(count as u64);
+        if self.json {
+            self.emit(json!({
+                "event": "progress",
+                "resources": self.bar.position(),
+                "totalResources": self.bar.length(),
+                "objects": self.objects.load(Ordering::Relaxed),
+                "bytes": self.bytes.load(Ordering::Relaxed),
+            }));
+        }
+    }
+
+    pub fn object_written(&self, size: u64) {
+        self.objects.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(size, Ordering::Relaxed);
+        if !self.is_plain() {
+            self.redraw();
+        }
+    }
+
+    /// Dumping resource (successful or not) took `elapsed`
+    pub fn resource_timing(&self, api_resource: &ApiResource, elapsed: Duration) {
+        let group = if api_resource.group.is_empty() {
+            "core"
+        } else {
+            &api_resource.group
+        };
+        let mut groups = self.groups.lock().unwrap();
+        let timing = groups.entry(group.to_string()).or_default();
+        timing.seconds += elapsed.as_secs_f64();
+        *timing
+            .resources
+            .entry(api_resource.kind.clone())
+            .or_insert(0.0) += elapsed.as_secs_f64();
+    }
+
+    /// Container logs were fetched (or failed to) in `elapsed`
+    pub fn logs_fetched(&self, elapsed: Duration) {
+        let mut logs = self.logs.lock().unwrap();
+        logs.fetches += 1;
+        logs.seconds += elapsed.as_secs_f64();
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.errors.lock().unwrap().len()
+    }
+
+    fn phase_timings(&self) -> Vec<PhaseTiming> {
+        self.phases
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|phase| PhaseTiming {
+                name: phase.name.clone(),
+                seconds: phase
+                    .elapsed
+                    .unwrap_or_else(|| phase.started.elapsed())
+                    .as_secs_f64(),
+            })
+            .collect()
+    }
+
+    pub fn timings(&self) -> Timings {
+        Timings {
+            elapsed_seconds: self.started.elapsed().as_secs_f64(),
+            phases: self.phase_timings(),
+            api_groups: self.groups.lock().unwrap().clone(),
+            logs: *self.logs.lock().unwrap(),
+        }
+    }
+
+    /// Summarizes the dump of objects described by `entries`
+    pub fn run_summary(&self, entries: &[crate::index::Entry], bytes_written: u64) -> RunSummary {
+        let mut objects = BTreeMap::new();
+        for entry in entries {
+            let kind = if entry.group.is_empty() {
+                entry.kind.clone()
+            } else {
+                format!("{}/{}", entry.group, entry.kind)
+            };
+            *objects.entry(kind).or_insert(0) += 1;
+        }
+        let phases = self.phase_timings();
+        let errors = self.errors.lock().unwrap().clone();
+        RunSummary {
+            elapsed_seconds: self.started.elapsed().as_secs_f64(),
+            bytes_written,
+            objects,
+            phases,
+            errors,
+        }
+    }
+
+    /// Logs summary as tables (or prints single event in JSON mode)
+    pub fn print_summary(&self, summary: &RunSummary) {
+        if self.json {
+            self.emit(json!({"event": "summary", "summary": summary}));
+            return;
+        }
+        let width = summary
+            .objects
+            .keys()
+            .map(String::len)
+            .chain(summary.phases.iter().map(|phase| phase.name.len()))
+            .max()
+            .unwrap_or(0)
+            .max(5);
+        tracing::info!("{:<width$}  {:>8}", "KIND", "OBJECTS", width = width);
+        for (kind, count) in &summary.objects {
+            tracing::info!("{:<width$}  {:>8}", kind, count, width = width);
+        }
+        tracing::info!("");
+        tracing::info!("{:<width$}  {:>8}", "PHASE", "SECONDS", width = width);
+        for phase in &summary.phases {
+            tracing::info!(
+                "{:<width$}  {:>8.1}",
+                phase.name,
+                phase.seconds,
+                width = width
+            );
+        }
+        tracing::info!("");
+        tracing::info!(
+            "{} written in {:.1}s",
+            HumanBytes(summary.bytes_written),
+            summary.elapsed_seconds
+        );
+        if !summary.errors.is_empty() {
+            tracing::info!("{} error(s):", summary.errors.len());
+            for error in &summary.errors {
+                tracing::info!(" - {}: {}", error.resource, error.message);
+            }
+        }
+    }
+
+    /// Removes the status, logging totals
+    pub fn finish(&self) {
+        self.bar.finish_and_clear();
+        DISPLAY.lock().unwrap().take();
+        let objects = self.objects.load(Ordering::Relaxed);
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        if self.json {
+            self.emit(json!({"event": "finished", "objects": objects, "bytes": bytes}));
+        } else {
+            tracing::info!("Dumped {} objects ({})", objects, HumanBytes(bytes));
+        }
+    }
+}
@@ -0,0 +1,410 @@
+//! Produces anonymized copies of dumps, suitable for sharing as fixtures
+use crate::layout::{Escape, Layout};
+use anyhow::Context as _;
+use clap::Clap;
+use serde_json::Value;
+use std::{
+    collections::{hash_map::RandomState, HashSet},
+    hash::BuildHasher,
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+
+/// Anonymizes existing dump, preserving its shape
+#[derive(Clap)]
+pub struct SynthesizeOpts {
+    /// Dump to anonymize
+    #[clap(long)]
+    from: PathBuf,
+    /// Path anonymized dump should be written to
+    #[clap(long)]
+    out: PathBuf,
+}
+
+/// Files which do not contain anything cluster-specific
+const VERBATIM_FILES: &[&str] = &["apis.json", "cluster-version.json"];
+
+/// String values of these keys describe shape of objects and are kept as is
+const VERBATIM_KEYS: &[&str] = &["apiVersion", "kind", "group", "version", "plural", "type"];
+
+/// Keys of string maps under these keys are chosen by users (label names,
+/// ConfigMap keys, etc), so they are scrambled too
+const USER_MAP_KEYS: &[&str] = &[
+    "labels",
+    "annotations",
+    "data",
+    "stringData",
+    "binaryData",
+    "matchLabels",
+    "nodeSelector",
+    "selector",
+];
+
+/// Timestamps are moved into the past by a random duration of at most this
+/// long, so that intervals between them are preserved
+const MAX_TIME_SHIFT: u64 = 365 * 24 * 60 * 60;
+
+/// Length of RFC 3339 UTC timestamp (e.g. `2021-06-01T12:00:00.5Z`) at the
+/// start of `text`, if there is one
+fn timestamp_len(text: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let pattern = b"dddd-dd-ddTdd:dd:dd";
+    if bytes.len() < pattern.len() {
+        return None;
+    }
+    let matches = bytes.iter().zip(pattern).all(|(byte, expected)| {
+        if *expected == b'd' {
+            byte.is_ascii_digit()
+        } else {
+            byte == expected
+        }
+    });
+    if !matches {
+        return None;
+    }
+    let mut len = pattern.len();
+    if bytes.get(len) == Some(&b'.') {
+        let digits = bytes[len + 1..]
+            .iter()
+            .take_while(|byte| byte.is_ascii_digit())
+            .count();
+        if digits == 0 {
+            return None;
+        }
+        len += 1 + digits;
+    }
+    if bytes.get(len) != Some(&b'Z') {
+        return None;
+    }
+    Some(len + 1)
+}
+
+/// Replaces every alphanumeric token with a pseudonym of the same length and
+/// character classes. Same tokens always get same pseudonyms (within one
+/// run), so references between objects are preserved, but pseudonyms are
+/// keyed with a random seed, so they can not be reversed.
+struct Scrambler {
+    seed: RandomState,
+    /// Path components that are kept verbatim (API groups, kinds, etc)
+    verbatim_components: HashSet<String>,
+    /// Names given by the layout, which are also kept at the end of object
+    /// name prefixed file names
+    fixed_names: HashSet<String>,
+    /// `index.json` of the dump, paths in which are renamed like files
+    index: PathBuf,
+    /// Timestamps are shifted (and not scrambled), so they remain valid
+    time_shift: Duration,
+}
+
+impl Scrambler {
+    fn pseudonym(&self, token: &str) -> String {
+        let mut state = self.seed.hash_one(token) | 1;
+        token
+            .chars()
+            .map(|ch| {
+                // xorshift64
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                let pick = |alphabet: &[u8]| alphabet[(state % alphabet.len() as u64) as usize];
+                let replacement = if ch.is_ascii_digit() {
+                    pick(b"0123456789")
+                } else if ch.is_ascii_uppercase() {
+                    pick(b"ABCDEFGHIJKLMNOPQRSTUVWXYZ")
+                } else {
+                    pick(b"abcdefghijklmnopqrstuvwxyz")
+                };
+                replacement as char
+            })
+            .collect()
+    }
+
+    fn shift_timestamp(&self, timestamp: &str) -> Option<String> {
+        let time = humantime::parse_rfc3339_weak(timestamp).ok()?;
+        let time = time.checked_sub(self.time_shift)?;
+        // precision of the original is kept
+        let fraction = timestamp
+            .find('.')
+            .map_or(0, |dot| timestamp.len() - dot - 2);
+        let shifted = match fraction {
+            0 => humantime::format_rfc3339_seconds(time),
+            1..=3 => humantime::format_rfc3339_millis(time),
+            4..=6 => humantime::format_rfc3339_micros(time),
+            _ => humantime::format_rfc3339_nanos(time),
+        };
+        Some(shifted.to_string())
+    }
+
+    fn scramble_text(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut token = String::new();
+        let mut rest = text;
+        while let Some(ch) = rest.chars().next() {
+            if token.is_empty() {
+                let shifted = timestamp_len(rest)
+                    .and_then(|len| Some((len, self.shift_timestamp(&rest[..len])?)));
+                if let Some((len, shifted)) = shifted {
+                    out.push_str(&shifted);
+                    rest = &rest[len..];
+                    continue;
+                }
+            }
+            rest = &rest[ch.len_utf8()..];
+            if ch.is_alphanumeric() {
+                token.push(ch);
+                continue;
+            }
+            if !token.is_empty() {
+                out.push_str(&self.pseudonym(&token));
+                token.clear();
+            }
+            out.push(ch);
+        }
+        if !token.is_empty() {
+            out.push_str(&self.pseudonym(&token));
+        }
+        out
+    }
+
+    fn scramble_json(&self, value: &mut Value) {
+        match value {
+            Value::String(s) => *s = self.scramble_text(s),
+            Value::Array(items) => items.iter_mut().for_each(|item| self.scramble_json(item)),
+            Value::Object(fields) => {
+                for (key, value) in fields.iter_mut() {
+                    if VERBATIM_KEYS.contains(&key.as_str()) && value.is_string() {
+                        continue;
+                    }
+                    if USER_MAP_KEYS.contains(&key.as_str()) {
+                        if let Value::Object(map) = value {
+                            if map.values().all(Value::is_string) {
+                                *map = std::mem::take(map)
+                                    .into_iter()
+                                    .map(|(key, value)| (self.scramble_text(&key), value))
+                                    .collect();
+                            }
+                        }
+                    }
+                    self.scramble_json(value);
+                }
+            }
+            Value::Null | Value::Bool(_) | Value::Number(_) => (),
+        }
+    }
+
+    fn scramble_component(&self, component: &str, is_file: bool) -> String {
+        if self.verbatim_components.contains(component) || self.fixed_names.contains(component) {
+            return component.to_string();
+        }
+        if is_file {
+            // flat layout prefixes artifacts with the object name
+            let artifact = component
+                .match_indices('.')
+                .map(|(dot, _)| component.split_at(dot))
+                .find(|(_, artifact)| self.fixed_names.contains(&artifact[1..]));
+            if let Some((prefix, artifact)) = artifact {
+                return format!("{}{}", self.scramble_text(prefix), artifact);
+            }
+            if let Some(name) = component.strip_prefix("logs-") {
+                let (name, compression) = match name.rfind(".txt") {
+                    Some(end) => name.split_at(end + ".txt".len()),
+                    None => (name, ""),
+                };
+                let (container, suffix) = if let Some(c) = name.strip_suffix("-prev.txt") {
+                    (c, "-prev.txt")
+                } else {
+                    (name.strip_suffix(".txt").unwrap_or(name), ".txt")
+                };
+                let (prefix, container) = match container.split_once('.') {
+                    Some((kind, container)) => (format!("{}.", kind), container),
+                    None => (String::new(), container),
+                };
+                return format!(
+                    "logs-{}{}{}{}",
+                    prefix,
+                    self.scramble_text(container),
+                    suffix,
+                    compression
+                );
+            }
+            if let Some(key) = component.strip_prefix("data-") {
+                // same as key in `raw.json`, which is scrambled as a whole
+                let (key, compression) = match key.strip_suffix(".zst") {
+                    Some(key) => (key, ".zst"),
+                    None => (key, ""),
+                };
+                return format!("data-{}{}", self.scramble_text(key), compression);
+            }
+        }
+        self.scramble_text(component)
+    }
+
+    /// Renames every component of `path`, the last one being file if
+    /// `is_file` is set
+    fn scramble_path(&self, path: &Path, is_file: bool) -> anyhow::Result<PathBuf> {
+        let last = path.components().count().saturating_sub(1);
+        path.components()
+            .enumerate()
+            .map(|(i, component)| match component {
+                Component::Normal(name) => {
+                    let name = name
+                        .to_str()
+                        .with_context(|| format!("non-utf8 path {}", path.display()))?;
+                    Ok(PathBuf::from(
+                        self.scramble_component(name, i == last && is_file),
+                    ))
+                }
+                other => Ok(PathBuf::from(other.as_os_str())),
+            })
+            .collect()
+    }
+
+    /// Scrambles `index.json`, renaming paths of entries like files
+    fn scramble_index(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut entries: Vec<Value> = serde_json::from_slice(data)?;
+        for entry in &mut entries {
+            let mut paths = Vec::new();
+            for key in &["path", "patch"] {
+                if let Some(path) = entry.get(key).and_then(Value::as_str) {
+                    let path = self.scramble_path(Path::new(path), true)?;
+                    paths.push((key, path.display().to_string()));
+                }
+            }
+            self.scramble_json(entry);
+            for (key, path) in paths {
+                entry[*key] = Value::String(path);
+            }
+        }
+        Ok(serde_json::to_string_pretty(&entries)?.into_bytes())
+    }
+
+    fn scramble_file(&self, file_name: &str, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        if VERBATIM_FILES.contains(&file_name) {
+            return Ok(data);
+        }
+        if let Some(inner) = file_name.strip_suffix(".zst") {
+            let data = zstd::stream::decode_all(&data[..])?;
+            let data = self.scramble_file(inner, data)?;
+            return Ok(zstd::stream::encode_all(&data[..], 0)?);
+        }
+        if let Some(inner) = file_name.strip_suffix(".gz") {
+            use std::io::{Read as _, Write as _};
+            let mut decompressed = Vec::new();
+            flate2::read::GzDecoder::new(&data[..]).read_to_end(&mut decompressed)?;
+            let data = self.scramble_file(inner, decompressed)?;
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&data)?;
+            return Ok(encoder.finish()?);
+        }
+        let text = match String::from_utf8(data) {
+            Ok(t) => t,
+            Err(_) => anyhow::bail!("binary files can not be anonymized"),
+        };
+        if file_name.ends_with(".ndjson") || file_name.ends_with(".jsonl") {
+            let mut out = String::new();
+            for line in text.lines() {
+                let mut value: Value = serde_json::from_str(line)?;
+                self.scramble_json(&mut value);
+                out.push_str(&serde_json::to_string(&value)?);
+                out.push('\n');
+            }
+            return Ok(out.into_bytes());
+        }
+        if file_name.ends_with(".json") {
+            if let Ok(mut value) = serde_json::from_str::<Value>(&text) {
+                self.scramble_json(&mut value);
+                return Ok(serde_json::to_string_pretty(&value)?.into_bytes());
+            }
+        }
+        Ok(self.scramble_text(&text).into_bytes())
+    }
+}
+
+/// Collects names of API groups and kinds from `apis.json`
+fn load_verbatim_components(from: &Path) -> anyhow::Result<HashSet<String>> {
+    let mut components = HashSet::new();
+    let apis = match std::fs::read(from.join("apis.json")) {
+        Ok(a) => a,
+        Err(err) => {
+            tracing::warn!(
+                "Failed to read apis.json, kinds will be anonymized: {}",
+                err
+            );
+            return Ok(components);
+        }
+    };
+    let apis: Vec<Value> = serde_json::from_slice(&apis).context("invalid apis.json")?;
+    for api in apis {
+        for key in &["group", "kind"] {
+            if let Some(v) = api.get(key).and_then(Value::as_str) {
+                components.insert(v.to_string());
+            }
+        }
+    }
+    Ok(components)
+}
+
+fn synthesize_dir(
+    scrambler: &Scrambler,
+    from: &Path,
+    out: &Path,
+    counter: &mut usize,
+) -> anyhow::Result<()> {
+    std::fs::create_dir_all(out)?;
+    for item in std::fs::read_dir(from)? {
+        let item = item?;
+        let file_name = item.file_name();
+        let file_name = file_name
+            .to_str()
+            .with_context(|| format!("non-utf8 path in {}", from.display()))?;
+        let file_type = item.file_type()?;
+        if file_type.is_symlink() {
+            // e.g. `by-kind` links, which are recreated pointing to
+            // anonymized objects
+            let target = std::fs::read_link(item.path())?;
+            let target_is_file = std::fs::metadata(item.path()).is_ok_and(|meta| meta.is_file());
+            let dest = out.join(scrambler.scramble_component(file_name, target_is_file));
+            let target = scrambler.scramble_path(&target, target_is_file)?;
+            crate::layout::storage::create_symlink(&target, &dest)?;
+            continue;
+        }
+        let is_file = file_type.is_file();
+        let dest = out.join(scrambler.scramble_component(file_name, is_file));
+        if is_file {
+            let data = std::fs::read(item.path())?;
+            let data = if item.path() == scrambler.index {
+                scrambler.scramble_index(&data)
+            } else {
+                scrambler.scramble_file(file_name, data)
+            };
+            let data =
+                data.with_context(|| format!("failed to anonymize {}", item.path().display()))?;
+            std::fs::write(dest, data)?;
+            *counter += 1;
+        } else {
+            synthesize_dir(scrambler, &item.path(), &dest, counter)?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn synthesize(opts: SynthesizeOpts) -> anyhow::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let seed = RandomState::new();
+        let time_shift = Duration::from_secs(seed.hash_one("time shift") % MAX_TIME_SHIFT);
+        let scrambler = Scrambler {
+            seed,
+            verbatim_components: load_verbatim_components(&opts.from)?,
+            fixed_names: crate::layout::fixed_names(),
+            index: Layout::from_root(opts.from.clone(), Escape::None).index(),
+            time_shift,
+        };
+        let mut counter = 0;
+        synthesize_dir(&scrambler, &opts.from, &opts.out, &mut counter)?;
+        tracing::info!("Anonymized {} files", counter);
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
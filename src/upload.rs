@@ -0,0 +1,419 @@
+//! Uploading dumps to remote object stores
+use anyhow::Context as _;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{io::AsyncWriteExt as _, process::Command, sync::Semaphore, task::JoinHandle};
+
+const MAX_CONCURRENT_UPLOADS: usize = 8;
+const MAX_ATTEMPTS: u32 = 4;
+
+/// Remote location, given as URL to `--upload`
+#[derive(Clone)]
+pub enum UploadTarget {
+    /// `gs://<bucket>/<prefix>`
+    Gcs { bucket: String, prefix: String },
+    /// `s3://<bucket>/<prefix>`
+    S3 { bucket: String, prefix: String },
+    /// `sftp://[user@]host/path`
+    Sftp { base: String },
+    /// `webdav://host/path` (over HTTPS) or `webdav+http://host/path`
+    WebDav { base: String },
+}
+
+impl std::str::FromStr for UploadTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = match s.find("://") {
+            Some(pos) => (&s[..pos], &s[pos + 3..]),
+            None => anyhow::bail!("upload target must be URL, e.g. gs://bucket/path"),
+        };
+        let (bucket, prefix) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], rest[pos + 1..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        if bucket.is_empty() {
+            anyhow::bail!("bucket is missing in {}", s);
+        }
+        let base = format!("{}/{}", bucket, prefix);
+        let base = base.trim_end_matches('/');
+        match scheme {
+            "gs" => Ok(UploadTarget::Gcs {
+                bucket: bucket.to_string(),
+                prefix: prefix.to_string(),
+            }),
+            "s3" => Ok(UploadTarget::S3 {
+                bucket: bucket.to_string(),
+                prefix: prefix.to_string(),
+            }),
+            "sftp" => Ok(UploadTarget::Sftp {
+                base: format!("sftp://{}", base),
+            }),
+            "webdav" => Ok(UploadTarget::WebDav {
+                base: format!("https://{}", base),
+            }),
+            "webdav+http" => Ok(UploadTarget::WebDav {
+                base: format!("http://{}", base),
+            }),
+            _ => anyhow::bail!("unknown upload scheme: {}", scheme),
+        }
+    }
+}
+
+impl UploadTarget {
+    pub fn store(&self) -> Arc<dyn ObjectStore> {
+        match self {
+            UploadTarget::Gcs { bucket, prefix } => Arc::new(GcsStore {
+                bucket: bucket.clone(),
+                prefix: prefix.clone(),
+            }),
+            UploadTarget::S3 { bucket, prefix } => Arc::new(S3Store {
+                bucket: bucket.clone(),
+                prefix: prefix.clone(),
+            }),
+            UploadTarget::Sftp { base } => Arc::new(SftpStore { base: base.clone() }),
+            UploadTarget::WebDav { base } => Arc::new(WebDavStore {
+                base: base.clone(),
+                collections: Mutex::new(HashSet::new()),
+            }),
+        }
+    }
+}
+
+/// Destination accepting whole files by key (relative path with `/`
+/// separators)
+#[async_trait::async_trait]
+pub trait ObjectStore: Send + Sync {
+    async fn put(&self, key: &str, data: Vec<u8>) -> anyhow::Result<()>;
+
+    /// Uploads whole directory with single command, skipping hidden files
+    /// and symlinks. Returns false if store can not do that, so files must
+    /// be put one by one.
+    async fn put_dir(&self, _dir: &Path) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+}
+
+/// Runs command feeding `data` to its stdin, failing if it exits
+/// unsuccessfully
+async fn run_tool(command: &mut Command, data: &[u8]) -> anyhow::Result<()> {
+    let mut child = command
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("failed to run {:?}", command.as_std().get_program()))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    stdin.write_all(data).await?;
+    drop(stdin);
+    let out = child.wait_with_output().await?;
+    if !out.status.success() {
+        anyhow::bail!("{}", String::from_utf8_lossy(&out.stderr).trim());
+    }
+    Ok(())
+}
+
+/// URL of the bucket directory, always ending with `/`
+fn bucket_url(scheme: &str, bucket: &str, prefix: &str) -> String {
+    if prefix.is_empty() {
+        format!("{}://{}/", scheme, bucket)
+    } else {
+        format!("{}://{}/{}/", scheme, bucket, prefix)
+    }
+}
+
+/// Google Cloud Storage, accessed through `gsutil` (so that usual gcloud
+/// credentials are used)
+struct GcsStore {
+    bucket: String,
+    prefix: String,
+}
+
+#[async_trait::async_trait]
+impl ObjectStore for GcsStore {
+    async fn put(&self, key: &str, data: Vec<u8>) -> anyhow::Result<()> {
+        let url = format!("{}{}", bucket_url("gs", &self.bucket, &self.prefix), key);
+        run_tool(Command::new("gsutil").args(["-q", "cp", "-", &url]), &data)
+            .await
+            .with_context(|| format!("failed to upload {} with gsutil", url))
+    }
+
+    async fn put_dir(&self, dir: &Path) -> anyhow::Result<bool> {
+        // `cp -r` of directory itself would upload it under its own name,
+        // so its entries are passed instead
+        let mut entries = Vec::new();
+        for item in std::fs::read_dir(dir)? {
+            let item = item?;
+            if !item.file_name().to_string_lossy().starts_with('.') {
+                entries.push(item.path());
+            }
+        }
+        if entries.is_empty() {
+            return Ok(true);
+        }
+        let url = bucket_url("gs", &self.bucket, &self.prefix);
+        run_tool(
+            Command::new("gsutil")
+                .args(["-q", "-m", "cp", "-r", "-e"])
+                .args(&entries)
+                .arg(&url),
+            &[],
+        )
+        .await
+        .with_context(|| format!("failed to upload {} with gsutil", url))?;
+        Ok(true)
+    }
+}
+
+/// Amazon S3 (or compatible store), accessed through `aws` CLI (so that
+/// usual AWS credentials and endpoint settings are used)
+struct S3Store {
+    bucket: String,
+    prefix: String,
+}
+
+#[async_trait::async_trait]
+impl ObjectStore for S3Store {
+    async fn put(&self, key: &str, data: Vec<u8>) -> anyhow::Result<()> {
+        let url = format!("{}{}", bucket_url("s3", &self.bucket, &self.prefix), key);
+        run_tool(
+            Command::new("aws").args(["s3", "cp", "--only-show-errors", "-", &url]),
+            &data,
+        )
+        .await
+        .with_context(|| format!("failed to upload {} with aws", url))
+    }
+
+    async fn put_dir(&self, dir: &Path) -> anyhow::Result<bool> {
+        let url = bucket_url("s3", &self.bucket, &self.prefix);
+        run_tool(
+            Command::new("aws")
+                .args(["s3", "cp", "--recursive", "--only-show-errors"])
+                .args([
+                    "--no-follow-symlinks",
+                    "--exclude",
+                    ".*",
+                    "--exclude",
+                    "*/.*",
+                ])
+                .arg(dir)
+                .arg(&url),
+            &[],
+        )
+        .await
+        .with_context(|| format!("failed to upload {} with aws", url))?;
+        Ok(true)
+    }
+}
+
+/// Runs curl (credentials are taken from URL or `~/.netrc`), feeding `data`
+/// to its stdin
+pub async fn curl(args: &[&str], data: &[u8]) -> anyhow::Result<()> {
+    run_tool(
+        Command::new("curl")
+            .args(["--silent", "--show-error", "--fail", "--netrc-optional"])
+            .args(args),
+        data,
+    )
+    .await
+}
+
+/// SFTP server, accessed through curl
+struct SftpStore {
+    base: String,
+}
+
+#[async_trait::async_trait]
+impl ObjectStore for SftpStore {
+    async fn put(&self, key: &str, data: Vec<u8>) -> anyhow::Result<()> {
+        let url = format!("{}/{}", self.base, key);
+        curl(&["--ftp-create-dirs", "--upload-file", "-", &url], &data)
+            .await
+            .with_context(|| format!("failed to upload {}", url))
+    }
+}
+
+/// WebDAV server, accessed through curl
+struct WebDavStore {
+    base: String,
+    /// Collections (directories) that were already created
+    collections: Mutex<HashSet<String>>,
+}
+
+#[async_trait::async_trait]
+impl ObjectStore for WebDavStore {
+    async fn put(&self, key: &str, data: Vec<u8>) -> anyhow::Result<()> {
+        // unlike SFTP, WebDAV requires parent collections to exist
+        let mut collection = String::new();
+        for component in key
+            .split('/')
+            .rev()
+            .skip(1)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+        {
+            collection.push_str(component);
+            collection.push('/');
+            if self.collections.lock().unwrap().contains(&collection) {
+                continue;
+            }
+            let url = format!("{}/{}", self.base, collection);
+            // fails if collection already exists, which is fine
+            curl(&["--request", "MKCOL", &url], &[]).await.ok();
+            self.collections.lock().unwrap().insert(collection.clone());
+        }
+        let url = format!("{}/{}", self.base, key);
+        curl(&["--upload-file", "-", &url], &data)
+            .await
+            .with_context(|| format!("failed to upload {}", url))
+    }
+}
+
+/// Uploads files in background, with bounded concurrency and retries
+pub struct Uploader {
+    store: Arc<dyn ObjectStore>,
+    sem: Arc<Semaphore>,
+    tasks: Mutex<Vec<JoinHandle<anyhow::Result<()>>>>,
+}
+
+impl Uploader {
+    pub fn new(store: Arc<dyn ObjectStore>) -> Uploader {
+        Uploader {
+            store,
+            sem: Arc::new(Semaphore::new(MAX_CONCURRENT_UPLOADS)),
+            tasks: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Starts uploading the file. Waits if too many uploads are already in
+    /// progress, so that memory used by pending data stays bounded.
+    pub async fn submit(&self, key: String, data: Vec<u8>) -> anyhow::Result<()> {
+        let permit = self.sem.clone().acquire_owned().await?;
+        let store = self.store.clone();
+        let task = tokio::spawn(async move {
+            let result = put_with_retry(&*store, &key, data).await;
+            drop(permit);
+            result
+        });
+        self.tasks.lock().unwrap().push(task);
+        Ok(())
+    }
+
+    /// Waits until all submitted uploads are finished
+    pub async fn wait(&self) -> anyhow::Result<()> {
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        let mut first_error = None;
+        for task in tasks {
+            if let Err(err) = task.await? {
+                tracing::error!("{:#}", err);
+                first_error.get_or_insert(err);
+            }
+        }
+        match first_error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+async fn put_with_retry(store: &dyn ObjectStore, key: &str, data: Vec<u8>) -> anyhow::Result<()> {
+    let mut backoff = Duration::from_secs(1);
+    for attempt in 1.. {
+        match store.put(key, data.clone()).await {
+            Ok(()) => break,
+            Err(err) if attempt < MAX_ATTEMPTS => {
+                tracing::warn!(
+                    "Upload of {} failed (attempt {}), retrying: {:#}",
+                    key,
+                    attempt,
+                    err
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(err) => return Err(err.context(format!("giving up uploading {}", key))),
+        }
+    }
+    Ok(())
+}
+
+/// Converts relative path to object key, which always uses `/` separators
+pub fn object_key(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Uploads finished dump (directory or single file). Hidden files and
+/// symlinks are skipped. Returns number of uploaded files.
+pub async fn upload(target: &UploadTarget, out: &Path) -> anyhow::Result<usize> {
+    let store = target.store();
+    let files = if out.is_dir() {
+        let mut files = Vec::new();
+        collect_files(out, out, &mut files)?;
+        if store.put_dir(out).await? {
+            return Ok(files.len());
+        }
+        files
+    } else if !out.exists() {
+        // archive was split
+        let mut files = Vec::new();
+        for part in crate::layout::storage::split_parts(out)? {
+            let name = part.file_name().context("part has no file name")?;
+            let name = name.to_string_lossy().into_owned();
+            files.push((part, name));
+        }
+        if files.is_empty() {
+            anyhow::bail!("{} does not exist", out.display());
+        }
+        files
+    } else {
+        let name = out.file_name().context("output path has no file name")?;
+        vec![(out.to_path_buf(), name.to_string_lossy().into_owned())]
+    };
+    let uploader = Uploader::new(store);
+    for (path, key) in &files {
+        let data = tokio::fs::read(path)
+            .await
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        uploader.submit(key.clone(), data).await?;
+    }
+    uploader.wait().await?;
+    Ok(files.len())
+}
+
+/// Collects all non-hidden files in `dir`, along with their paths
+/// relative to `root` (with `/` separators). Symlinks (e.g. made by
+/// `--by-kind-links`) are skipped, because they only point to other files
+/// of the dump.
+pub fn collect_files(
+    root: &Path,
+    dir: &Path,
+    files: &mut Vec<(PathBuf, String)>,
+) -> anyhow::Result<()> {
+    for item in std::fs::read_dir(dir)? {
+        let item = item?;
+        if item.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = item.path();
+        let file_type = item.file_type()?;
+        if file_type.is_symlink() {
+            continue;
+        }
+        if file_type.is_dir() {
+            collect_files(root, &path, files)?;
+        } else {
+            let key = object_key(path.strip_prefix(root)?);
+            files.push((path, key));
+        }
+    }
+    Ok(())
+}
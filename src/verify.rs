@@ -0,0 +1,188 @@
+//! Checking integrity of existing dumps
+use crate::{
+    index::Entry,
+    load::Key,
+    status::{ProgressMode, Status},
+};
+use anyhow::Context as _;
+use clap::Clap;
+use serde_json::Value;
+use sha2::{Digest as _, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+/// Checks that dump is complete and not corrupted
+#[derive(Clap)]
+pub struct VerifyOpts {
+    /// Dump to verify (directory containing `index.json`)
+    dir: PathBuf,
+}
+
+/// Accumulates found problems
+struct Report {
+    status: Status,
+    problems: usize,
+}
+
+impl Report {
+    fn problem(&mut self, message: String) {
+        self.status.eprintln(&message);
+        self.problems += 1;
+    }
+}
+
+fn is_encrypted(path: &str) -> bool {
+    path.ends_with(".age") || path.ends_with(".gpg")
+}
+
+/// Checks files against `SHA256SUMS`, and that there are no files missing
+/// from it
+fn check_checksums(
+    dir: &Path,
+    files: &[(PathBuf, String)],
+    report: &mut Report,
+) -> anyhow::Result<()> {
+    let manifest_path = dir.join("SHA256SUMS");
+    let manifest = match std::fs::read_to_string(&manifest_path) {
+        Ok(m) => m,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            tracing::warn!("Dump has no checksums manifest, skipping checksum verification");
+            return Ok(());
+        }
+        Err(err) => {
+            return Err(err).with_context(|| format!("failed to read {}", manifest_path.display()))
+        }
+    };
+    let mut listed = HashSet::new();
+    for line in manifest.lines() {
+        let (hash, path) = match line.split_once("  ") {
+            Some(l) => l,
+            None => {
+                report.problem(format!("SHA256SUMS: malformed line {:?}", line));
+                continue;
+            }
+        };
+        listed.insert(path.to_string());
+        match std::fs::read(dir.join(path)) {
+            Ok(data) => {
+                if format!("{:x}", Sha256::digest(&data)) != hash {
+                    report.problem(format!("{}: checksum mismatch", path));
+                }
+            }
+            Err(err) => report.problem(format!("{}: {}", path, err)),
+        }
+    }
+    for (_, key) in files {
+        if !listed.contains(key) && key != "SHA256SUMS" && !key.starts_with("SHA256SUMS.") {
+            report.problem(format!("{}: not listed in SHA256SUMS", key));
+        }
+    }
+    Ok(())
+}
+
+/// Parses files written by kube-dump itself (indexed object files and
+/// top-level JSON manifests), returning objects found in them. Other files
+/// (e.g. ConfigMap data) hold user payloads, which need not be valid.
+fn check_parsing(
+    entries: &[Entry],
+    files: &[(PathBuf, String)],
+    report: &mut Report,
+) -> BTreeMap<String, HashMap<Key, Value>> {
+    let indexed: HashSet<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+    let mut parsed = BTreeMap::new();
+    for (path, key) in files {
+        let manifest = !key.contains('/') && key.ends_with(".json");
+        if !manifest && !indexed.contains(key.as_str()) {
+            continue;
+        }
+        match crate::load::parse_file(path) {
+            Ok(objects) => {
+                let objects = objects
+                    .into_iter()
+                    .filter_map(|object| Some((crate::load::object_key(&object)?, object)))
+                    .collect();
+                parsed.insert(key.clone(), objects);
+            }
+            Err(err) => report.problem(format!("{}: {:#}", key, err)),
+        }
+    }
+    parsed
+}
+
+/// Checks that every indexed object is present where index says it is
+fn check_index(
+    dir: &Path,
+    entries: &[Entry],
+    parsed: &BTreeMap<String, HashMap<Key, Value>>,
+    report: &mut Report,
+) {
+    let mut encrypted = 0;
+    for entry in entries {
+        let description = format!("{} {:?}/{}", entry.kind, entry.namespace, entry.name);
+        if let Some(patch) = &entry.patch {
+            if !dir.join(patch).exists() {
+                report.problem(format!("{}: patch {} is missing", description, patch));
+            }
+        }
+        let objects = match parsed.get(&entry.path) {
+            Some(objects) => objects,
+            None if dir.join(&entry.path).exists() => {
+                // file exists but failed to parse, which is already reported
+                continue;
+            }
+            None => {
+                let stored = ["age", "gpg"]
+                    .iter()
+                    .any(|ext| dir.join(format!("{}.{}", entry.path, ext)).exists());
+                if stored || is_encrypted(&entry.path) {
+                    encrypted += 1;
+                } else {
+                    report.problem(format!("{}: {} is missing", description, entry.path));
+                }
+                continue;
+            }
+        };
+        let key = (
+            Some(entry.kind.clone()),
+            entry.namespace.clone(),
+            entry.name.clone(),
+        );
+        if !objects.contains_key(&key) && !objects.contains_key(&(None, key.1, key.2)) {
+            report.problem(format!("{}: not found in {}", description, entry.path));
+        }
+    }
+    if encrypted > 0 {
+        tracing::warn!("{} objects are encrypted and were not checked", encrypted);
+    }
+}
+
+fn verify_dump(dir: &Path) -> anyhow::Result<()> {
+    let mut files = Vec::new();
+    crate::upload::collect_files(dir, dir, &mut files)
+        .with_context(|| format!("failed to list {}", dir.display()))?;
+    let mut report = Report {
+        status: Status::new(ProgressMode::Plain),
+        problems: 0,
+    };
+    check_checksums(dir, &files, &mut report)?;
+    let entries = crate::load::read_index(dir)?;
+    let parsed = check_parsing(&entries, &files, &mut report);
+    check_index(dir, &entries, &parsed, &mut report);
+    if report.problems > 0 {
+        anyhow::bail!("found {} problems", report.problems);
+    }
+    tracing::info!(
+        "Dump is valid: {} files, {} indexed objects",
+        files.len(),
+        entries.len()
+    );
+    Ok(())
+}
+
+pub async fn verify(opts: VerifyOpts) -> anyhow::Result<()> {
+    tokio::task::spawn_blocking(move || verify_dump(&opts.dir))
+        .await
+        .unwrap()
+}
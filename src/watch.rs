@@ -0,0 +1,610 @@
+//! Watch mode: after the dump is finished, changes of all resources are
+//! applied to it and appended to the change journal. Container logs can be
+//! followed as well
+use crate::{
+    journal::{ChangeKind, Entry},
+    layout::{ContainerKind, LogsKind},
+    Environment,
+};
+use clap::Clap;
+use futures::{
+    future::{AbortHandle, Abortable},
+    stream::FuturesUnordered,
+    StreamExt as _, TryStreamExt as _,
+};
+use k8s_openapi::api::core::v1::Pod;
+use kube::{
+    api::{
+        Api, ApiResource, DynamicObject, ListParams, LogParams, Request, Resource, ResourceExt,
+        WatchEvent,
+    },
+    discovery::verbs,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
+use tokio::sync::{mpsc, oneshot};
+
+/// Server closes watch after this many seconds, and it is reestablished
+const WATCH_TIMEOUT: u32 = 290;
+
+/// Options of `record` command
+#[derive(Clap)]
+pub struct RecordOpts {
+    /// Path dump should be written to
+    out: PathBuf,
+    /// How long changes are recorded after the dump is finished (e.g. `30m`)
+    #[clap(long, parse(try_from_str = humantime::parse_duration))]
+    duration: Duration,
+}
+
+impl RecordOpts {
+    /// Turns dump options into the recording ones
+    pub fn apply(self, opts: &mut crate::Opts) {
+        opts.out = Some(self.out);
+        opts.watch = true;
+        opts.watch_duration = Some(self.duration);
+    }
+}
+
+/// Watches all listed resources until interrupted with Ctrl-C, until
+/// `--watch-duration` passed or until deadline
+pub async fn watch(env: &Environment) -> anyhow::Result<()> {
+    let list_versions = env.list_versions.lock().unwrap().clone();
+    let watched: Vec<_> = env
+        .apis
+        .iter()
+        .filter(|(_, caps)| caps.supports_operation(verbs::WATCH))
+        .filter_map(|(api_resource, _)| {
+            let key = format!("{}/{}", api_resource.api_version, api_resource.plural);
+            let resource_version = list_versions.get(&key)?.clone()?;
+            Some((api_resource, resource_version))
+        })
+        .collect();
+    env.status.println(&format!(
+        "Watching {} resources, press Ctrl-C to stop",
+        watched.len()
+    ));
+    let changes = AtomicU64::new(0);
+    let (pods, changed_pods) = mpsc::unbounded_channel();
+    let watchers = futures::future::try_join_all(watched.into_iter().map(
+        |(api_resource, resource_version)| {
+            watch_resource(env, api_resource, resource_version, &changes, &pods)
+        },
+    ));
+    let follower = async {
+        if env.opts.follow_logs {
+            follow_logs(env, changed_pods).await
+        } else {
+            futures::future::pending().await
+        }
+    };
+    let stop = env
+        .opts
+        .watch_duration
+        .map(|duration| tokio::time::Instant::now() + duration);
+    let stop = match (stop, env.deadline) {
+        (Some(stop), Some(deadline)) => Some(stop.min(deadline)),
+        (stop, deadline) => stop.or(deadline),
+    };
+    let deadline = async {
+        match stop {
+            Some(stop) => tokio::time::sleep_until(stop).await,
+            None => futures::future::pending().await,
+        }
+    };
+    tokio::select! {
+        result = watchers => {
+            result?;
+        }
+        result = follower => {
+            result?;
+        }
+        result = tokio::signal::ctrl_c() => {
+            result?;
+        }
+        _ = deadline => (),
+    }
+    env.status.println(&format!(
+        "Recorded {} changes",
+        changes.load(Ordering::Relaxed)
+    ));
+    Ok(())
+}
+
+/// Objects of single resource present in the dump: (namespace, name) ->
+/// resourceVersion
+type Known = HashMap<(Option<String>, String), Option<String>>;
+
+async fn watch_resource(
+    env: &Environment,
+    api_resource: &ApiResource,
+    mut resource_version: String,
+    changes: &AtomicU64,
+    pods: &mpsc::UnboundedSender<PodChange>,
+) -> anyhow::Result<()> {
+    let api = Api::<DynamicObject>::all_with(env.client.clone(), api_resource);
+    // bookmarks are requested by default
+    let params = ListParams::default().timeout(WATCH_TIMEOUT);
+    let what = format!("watch {}", api_resource.plural);
+    let mut known: Known = env
+        .index
+        .entries()
+        .into_iter()
+        .filter(|entry| entry.group == api_resource.group && entry.kind == api_resource.kind)
+        .map(|entry| ((entry.namespace, entry.name), entry.resource_version))
+        .collect();
+    loop {
+        let mut events = crate::retry::retry(env, &what, || async {
+            Ok(api.watch(&params, &resource_version).await?.boxed())
+        })
+        .await?;
+        loop {
+            let event = match events.try_next().await {
+                Ok(Some(event)) => event,
+                Ok(None) => break,
+                Err(err) => {
+                    tracing::debug!("watch of {} failed: {}", api_resource.plural, err);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    break;
+                }
+            };
+            let (change, object) = match event {
+                WatchEvent::Added(object) => (ChangeKind::Added, object),
+                WatchEvent::Modified(object) => (ChangeKind::Modified, object),
+                WatchEvent::Deleted(object) => (ChangeKind::Deleted, object),
+                WatchEvent::Bookmark(bookmark) => {
+                    resource_version = bookmark.metadata.resource_version;
+                    continue;
+                }
+                // resourceVersion is too old
+                WatchEvent::Error(err) if err.code == 410 => {
+                    tracing::info!("Watch of {} expired, listing it again", api_resource.plural);
+                    resource_version =
+                        resync(env, &api, api_resource, &mut known, changes, pods).await?;
+                    break;
+                }
+                WatchEvent::Error(err) => return Err(kube::Error::Api(err).into()),
+            };
+            if let Some(version) = &object.metadata.resource_version {
+                resource_version = version.clone();
+            }
+            record(env, api_resource, change, object, &mut known, changes, pods).await?;
+        }
+    }
+}
+
+/// Lists the resource again after its watch expired, and records changes
+/// which were missed as if they were observed now. Returns resourceVersion
+/// watch should be continued from
+async fn resync(
+    env: &Environment,
+    api: &Api<DynamicObject>,
+    api_resource: &ApiResource,
+    known: &mut Known,
+    changes: &AtomicU64,
+    pods: &mpsc::UnboundedSender<PodChange>,
+) -> anyhow::Result<String> {
+    let what = format!("list {}", api_resource.plural);
+    let list = crate::retry::retry(env, &what, || async {
+        Ok(api.list(&ListParams::default()).await?)
+    })
+    .await?;
+    let mut deleted = known.clone();
+    for object in list.items {
+        let key = (object.namespace(), object.name());
+        let change = match deleted.remove(&key) {
+            None => ChangeKind::Added,
+            Some(version) if version != object.metadata.resource_version => ChangeKind::Modified,
+            Some(_) => continue,
+        };
+        record(env, api_resource, change, object, known, changes, pods).await?;
+    }
+    for (namespace, name) in deleted.into_keys() {
+        // last known state is the one in the dump
+        let object_layout = env
+            .layout
+            .object_layout(api_resource, namespace.as_deref(), &name)?;
+        let stored = env.storage.read(&object_layout.representation()).await?;
+        let object = match stored.and_then(|stored| serde_json::from_slice(&stored).ok()) {
+            Some(object) => object,
+            None => {
+                let object = DynamicObject::new(&name, api_resource);
+                match &namespace {
+                    Some(namespace) => object.within(namespace),
+                    None => object,
+                }
+            }
+        };
+        record(
+            env,
+            api_resource,
+            ChangeKind::Deleted,
+            object,
+            known,
+            changes,
+            pods,
+        )
+        .await?;
+    }
+    Ok(list.metadata.resource_version.unwrap_or_default())
+}
+
+/// Applies change of the object, remembering it in `known`
+async fn record(
+    env: &Environment,
+    api_resource: &ApiResource,
+    change: ChangeKind,
+    object: DynamicObject,
+    known: &mut Known,
+    changes: &AtomicU64,
+    pods: &mpsc::UnboundedSender<PodChange>,
+) -> anyhow::Result<()> {
+    let key = (object.namespace(), object.name());
+    if change == ChangeKind::Deleted {
+        known.remove(&key);
+    } else {
+        known.insert(key, object.metadata.resource_version.clone());
+    }
+    let is_pod = api_resource.group.is_empty() && api_resource.plural == "pods";
+    if is_pod && env.opts.follow_logs {
+        // follower only stops when watch is stopped
+        if change == ChangeKind::Deleted {
+            let (done, stopped) = oneshot::channel();
+            let deleted = PodChange::Deleted {
+                namespace: object.namespace().unwrap_or_default(),
+                name: object.name(),
+                done,
+            };
+            if pods.send(deleted).is_ok() {
+                stopped.await.ok();
+            }
+        } else {
+            pods.send(PodChange::Updated(Box::new(object.clone()))).ok();
+        }
+    }
+    apply(env, api_resource, change, object).await?;
+    changes.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Updates object in the dump, and records change in the journal
+async fn apply(
+    env: &Environment,
+    api_resource: &ApiResource,
+    change: ChangeKind,
+    mut object: DynamicObject,
+) -> anyhow::Result<()> {
+    let name = object.metadata.name.clone().unwrap_or_default();
+    let object_layout =
+        env.layout
+            .object_layout(api_resource, object.metadata.namespace.as_deref(), &name)?;
+    tracing::debug!(
+        namespace = ?object.metadata.namespace,
+        %name,
+        ?change,
+        "applying change"
+    );
+    let mut entry = Entry {
+        time: humantime::format_rfc3339(SystemTime::now()).to_string(),
+        change,
+        group: api_resource.group.clone(),
+        version: api_resource.version.clone(),
+        kind: api_resource.kind.clone(),
+        plural: api_resource.plural.clone(),
+        object: serde_json::to_value(&object)?,
+        patch: None,
+    };
+    match change {
+        ChangeKind::Added | ChangeKind::Modified => {
+            let repr_path = object_layout.representation();
+            let repr = crate::generic::representation(env, &mut object)?;
+            if change == ChangeKind::Modified {
+                if let Some(previous) = env.storage.read(&repr_path).await? {
+                    let previous: serde_json::Value = serde_json::from_slice(&previous)?;
+                    let current: serde_json::Value = serde_json::from_str(&repr)?;
+                    let mut patch = Vec::new();
+                    crate::diff::json_patch(&previous, &current, "", &mut patch);
+                    entry.patch = Some(serde_json::to_value(&patch)?);
+                }
+            }
+            env.storage.write(&repr_path, repr).await?;
+        }
+        ChangeKind::Deleted => env.storage.remove(&object_layout.location()).await?,
+    }
+    let mut line = serde_json::to_string(&entry)?;
+    line.push('\n');
+    env.storage.append(&env.layout.change_journal(), line).await
+}
+
+/// Change of pod, sent to log follower
+enum PodChange {
+    /// Containers of the pod could have started
+    Updated(Box<DynamicObject>),
+    /// Following of pod's containers must stop before its directory is
+    /// removed, so that it is not recreated. `done` is sent when it stopped.
+    Deleted {
+        namespace: String,
+        name: String,
+        done: oneshot::Sender<()>,
+    },
+}
+
+/// Current logs of the container written by the dump, continued by follower
+pub struct DumpedLogs {
+    path: PathBuf,
+    size: u64,
+    /// Timestamp of the last line, from which logs are followed
+    last_timestamp: Option<String>,
+}
+
+impl DumpedLogs {
+    /// Describes `logs` (fetched with timestamps) written to `path`
+    pub fn new(path: PathBuf, logs: &str) -> Self {
+        let last_timestamp = logs
+            .lines()
+            .rev()
+            .filter_map(|line| line.split(' ').next())
+            .find(|timestamp| humantime::parse_rfc3339(timestamp).is_ok())
+            .map(ToString::to_string);
+        DumpedLogs {
+            path,
+            size: logs.len() as u64,
+            last_timestamp,
+        }
+    }
+}
+
+/// Container instance, which is followed at most once
+#[derive(Clone, Hash, PartialEq, Eq)]
+struct ContainerRun {
+    namespace: String,
+    pod: String,
+    container: String,
+    restart_count: i32,
+}
+
+/// Follows logs of all containers until watch is stopped, starting with
+/// the ones of pods existing now, and then of containers which are started
+/// by `changed_pods`
+async fn follow_logs(
+    env: &Environment,
+    mut changed_pods: mpsc::UnboundedReceiver<PodChange>,
+) -> anyhow::Result<()> {
+    let mut seen = HashSet::new();
+    let mut followers = FuturesUnordered::new();
+    // (namespace, pod) -> its followers
+    let mut handles: HashMap<(String, String), Vec<AbortHandle>> = HashMap::new();
+    let follow = |handles: &mut HashMap<_, Vec<_>>, container_kind, run: ContainerRun, start| {
+        let (handle, registration) = AbortHandle::new_pair();
+        handles
+            .entry((run.namespace.clone(), run.pod.clone()))
+            .or_default()
+            .push(handle);
+        Abortable::new(
+            follow_container(env, container_kind, run, start),
+            registration,
+        )
+    };
+    let api = match &env.opts.namespace {
+        Some(namespace) => Api::<Pod>::namespaced(env.client.clone(), namespace),
+        None => Api::<Pod>::all(env.client.clone()),
+    };
+    let existing = crate::retry::retry(env, "list pods", || async {
+        Ok(api.list(&ListParams::default()).await?)
+    })
+    .await?;
+    for pod in existing {
+        for (container_kind, run) in started_containers(env, &pod, &mut seen) {
+            let key = (
+                run.namespace.clone(),
+                run.pod.clone(),
+                run.container.clone(),
+            );
+            // logs written before the dump are already in it
+            let start = match env.dumped_logs.lock().unwrap().remove(&key) {
+                Some(dumped) => FollowStart::After(dumped),
+                None => FollowStart::Now,
+            };
+            followers.push(follow(&mut handles, container_kind, run, start));
+        }
+    }
+    loop {
+        tokio::select! {
+            change = changed_pods.recv() => {
+                let pod = match change {
+                    Some(PodChange::Updated(pod)) => *pod,
+                    Some(PodChange::Deleted { namespace, name, done }) => {
+                        for handle in handles.remove(&(namespace, name)).unwrap_or_default() {
+                            handle.abort();
+                        }
+                        done.send(()).ok();
+                        continue;
+                    }
+                    None => return Ok(()),
+                };
+                let pod: Pod = serde_json::from_value(serde_json::to_value(&pod)?)?;
+                for (container_kind, run) in started_containers(env, &pod, &mut seen) {
+                    followers.push(follow(&mut handles, container_kind, run, FollowStart::Beginning));
+                }
+            }
+            Some(result) = followers.next(), if !followers.is_empty() => {
+                if let Ok(Err(err)) = result {
+                    tracing::warn!("Failed to follow logs: {:#}", err);
+                }
+            }
+        }
+    }
+}
+
+/// Where following of container logs starts
+enum FollowStart {
+    /// After logs written by the dump
+    After(DumpedLogs),
+    /// At current time, because logs of the container were not dumped
+    Now,
+    /// At container start, because it started after the dump
+    Beginning,
+}
+
+/// Returns containers of the pod, which were started and are not followed
+/// yet
+fn started_containers(
+    env: &Environment,
+    pod: &Pod,
+    seen: &mut HashSet<ContainerRun>,
+) -> Vec<(ContainerKind, ContainerRun)> {
+    if !crate::logs_wanted(&env.opts, pod) {
+        return Vec::new();
+    }
+    let status = match &pod.status {
+        Some(status) => status,
+        None => return Vec::new(),
+    };
+    let statuses = status
+        .init_container_statuses
+        .iter()
+        .map(|status| (ContainerKind::Init, status))
+        .chain(
+            status
+                .container_statuses
+                .iter()
+                .map(|status| (ContainerKind::Regular, status)),
+        )
+        .chain(
+            status
+                .ephemeral_container_statuses
+                .iter()
+                .map(|status| (ContainerKind::Ephemeral, status)),
+        );
+    statuses
+        .filter(|(_, status)| {
+            status
+                .state
+                .as_ref()
+                .is_some_and(|state| state.running.is_some() || state.terminated.is_some())
+        })
+        .map(|(container_kind, status)| {
+            let run = ContainerRun {
+                namespace: pod.namespace().unwrap_or_default(),
+                pod: pod.name(),
+                container: status.name.clone(),
+                restart_count: status.restart_count,
+            };
+            (container_kind, run)
+        })
+        .filter(|(_, run)| seen.insert(run.clone()))
+        .collect()
+}
+
+/// Appends logs of the container to its log file until it exits, moving
+/// the file aside each time it grows larger than `--logs-rotate-size`. Runs
+/// of the container started after the dump get separate files.
+async fn follow_container(
+    env: &Environment,
+    container_kind: ContainerKind,
+    run: ContainerRun,
+    start: FollowStart,
+) -> anyhow::Result<()> {
+    let object_layout = env.layout.object_layout(
+        &ApiResource::erase::<Pod>(&()),
+        Some(&run.namespace),
+        &run.pod,
+    )?;
+    let path = match start {
+        FollowStart::Beginning if run.restart_count > 0 => {
+            object_layout.restarted_logs(container_kind, &run.container, run.restart_count)
+        }
+        _ => object_layout.logs(container_kind, LogsKind::Current, &run.container, 0),
+    };
+    let tail_lines = match start {
+        FollowStart::Now => Some(0),
+        _ => None,
+    };
+    let (mut size, since_time) = match start {
+        // dumped logs could have been compressed into another file
+        FollowStart::After(dumped) if dumped.path == path => (dumped.size, dumped.last_timestamp),
+        FollowStart::After(dumped) => (0, dumped.last_timestamp),
+        _ => (0, None),
+    };
+    let params = LogParams {
+        container: Some(run.container.clone()),
+        follow: true,
+        tail_lines,
+        timestamps: true,
+        ..LogParams::default()
+    };
+    tracing::debug!(
+        namespace = %run.namespace,
+        pod = %run.pod,
+        container = %run.container,
+        ?since_time,
+        "following logs"
+    );
+    let mut request =
+        Request::new(Pod::url_path(&(), Some(&run.namespace))).logs(&run.pod, &params)?;
+    if let Some(since_time) = &since_time {
+        let since = form_urlencoded::Serializer::new(String::new())
+            .append_pair("sinceTime", since_time)
+            .finish();
+        let uri = request.uri();
+        let uri = match uri.query() {
+            Some(query) => format!("{}?{}&{}", uri.path(), query, since),
+            None => format!("{}?{}", uri.path(), since),
+        };
+        *request.uri_mut() = uri.parse()?;
+    }
+    let mut logs = env.client.request_text_stream(request).await?.boxed();
+    // lines of the last dumped second are returned again
+    let mut skipped = since_time.map(|since| (since, Vec::new()));
+    while let Some(chunk) = logs.try_next().await? {
+        let chunk = match &mut skipped {
+            Some((since, pending)) => {
+                pending.extend_from_slice(&chunk);
+                match skip_dumped_lines(pending, since) {
+                    Some(rest) => {
+                        skipped = None;
+                        rest
+                    }
+                    None => continue,
+                }
+            }
+            None => chunk.to_vec(),
+        };
+        if size > 0 && size + chunk.len() as u64 > env.opts.logs_rotate_size {
+            let rotated = (1..)
+                .map(|n| object_layout.rotated_logs(&path, n))
+                .find(|rotated| !env.storage.exists(rotated))
+                .expect("range is infinite");
+            env.storage.rename(&path, &rotated).await?;
+            size = 0;
+        }
+        size += chunk.len() as u64;
+        env.storage.append(&path, &chunk).await?;
+    }
+    Ok(())
+}
+
+/// Drops complete lines of `pending` which are not later than `since`
+/// (last dumped timestamp). Returns the rest once first later line is
+/// found, or None if more data is needed.
+fn skip_dumped_lines(pending: &mut Vec<u8>, since: &str) -> Option<Vec<u8>> {
+    let since = humantime::parse_rfc3339(since).ok()?;
+    let mut start = 0;
+    while let Some(end) = pending[start..].iter().position(|&b| b == b'\n') {
+        let line = String::from_utf8_lossy(&pending[start..start + end]);
+        let timestamp = line
+            .split(' ')
+            .next()
+            .and_then(|timestamp| humantime::parse_rfc3339(timestamp).ok());
+        match timestamp {
+            Some(timestamp) if timestamp <= since => start += end + 1,
+            _ => return Some(pending.split_off(start)),
+        }
+    }
+    pending.drain(..start);
+    None
+}